@@ -0,0 +1,124 @@
+//! Detection of well-known language servers on PATH, for filetypes that have no language
+//! configured. Enabled by `auto_detect_servers = true` at the top level of the config.
+
+use crate::types::LanguageConfig;
+use crate::util::find_command;
+use std::collections::HashMap;
+
+/// A language server that works without further configuration.
+struct KnownServer {
+    language: &'static str,
+    filetypes: &'static [&'static str],
+    roots: &'static [&'static str],
+    command: &'static str,
+    args: &'static [&'static str],
+}
+
+/// Servers to probe, in order of preference.
+const KNOWN_SERVERS: &[KnownServer] = &[
+    KnownServer {
+        language: "rust",
+        filetypes: &["rust"],
+        roots: &["Cargo.toml"],
+        command: "rust-analyzer",
+        args: &[],
+    },
+    KnownServer {
+        language: "go",
+        filetypes: &["go"],
+        roots: &["Gopkg.toml", "go.mod", ".git", ".hg"],
+        command: "gopls",
+        args: &[],
+    },
+    KnownServer {
+        language: "python",
+        filetypes: &["python"],
+        roots: &[
+            "pyproject.toml",
+            "requirements.txt",
+            "setup.py",
+            ".git",
+            ".hg",
+        ],
+        command: "pyright-langserver",
+        args: &["--stdio"],
+    },
+    KnownServer {
+        language: "c_cpp",
+        filetypes: &["c", "cpp"],
+        roots: &["compile_commands.json", ".clangd", ".git", ".hg"],
+        command: "clangd",
+        args: &[],
+    },
+    KnownServer {
+        language: "typescript",
+        filetypes: &["typescript", "javascript"],
+        roots: &["package.json", "tsconfig.json", ".git", ".hg"],
+        command: "typescript-language-server",
+        args: &["--stdio"],
+    },
+];
+
+fn known_servers(filetype: &str) -> impl Iterator<Item = &'static KnownServer> + '_ {
+    KNOWN_SERVERS
+        .iter()
+        .filter(move |server| server.filetypes.contains(&filetype))
+}
+
+/// Returns the language ID and configuration of the first known server for `filetype`
+/// that is installed.
+pub fn detect_server(filetype: &str) -> Option<(String, LanguageConfig)> {
+    let server = known_servers(filetype).find(|server| find_command(server.command).is_some())?;
+    let to_strings = |strs: &[&str]| strs.iter().map(|s| s.to_string()).collect();
+    let config = LanguageConfig {
+        filetypes: to_strings(server.filetypes),
+        roots: to_strings(server.roots),
+        command: server.command.to_string(),
+        args: to_strings(server.args),
+        envs: HashMap::default(),
+        language_ids: HashMap::default(),
+        settings_section: None,
+        settings: None,
+        initialization_options_command: None,
+        offset_encoding: None,
+        lazy_start: false,
+        forward_server_requests: vec![],
+        forward_server_notifications: vec![],
+        reinitialize_on_settings_change: false,
+        diagnostics_update_interval_ms: 0,
+        max_diagnostics_per_file: None,
+        formatter: None,
+        hover_providers: vec![],
+        hover_definition_lines: 0,
+        hover_fences: HashMap::new(),
+        hover_code_as_filetype: false,
+        middleware: vec![],
+        preview_edits_of_commands: vec![],
+        stream_output_of_commands: vec![],
+        confirm_edits: None,
+        diagnostic_commands: vec![],
+        completion_coalesce_window_ms: None,
+        completion_precedence: vec![],
+        markdown: Default::default(),
+        workaround_server_sends_plaintext_labeled_as_markdown: None,
+    };
+    Some((server.language.to_string(), config))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_known_servers() {
+        let commands = |filetype| {
+            known_servers(filetype)
+                .map(|server| server.command)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(commands("rust"), vec!["rust-analyzer"]);
+        assert_eq!(commands("cpp"), vec!["clangd"]);
+        assert_eq!(commands("javascript"), vec!["typescript-language-server"]);
+        assert!(commands("kak").is_empty());
+    }
+}
@@ -1,6 +1,9 @@
 use crate::context::*;
 use crate::controller;
-use crate::settings::request_initialization_options_from_kakoune;
+use crate::server_cache;
+use crate::settings::{
+    initialization_options_from_command, request_initialization_options_from_kakoune,
+};
 use crate::types::*;
 use crate::util::*;
 use indoc::formatdoc;
@@ -8,13 +11,28 @@ use itertools::Itertools;
 use lsp_types::notification::*;
 use lsp_types::request::*;
 use lsp_types::*;
+use serde::Deserialize;
+use serde_json::{json, Value};
 use std::collections::HashSet;
 use std::ops::Deref;
 use std::process;
 use url::Url;
 
+/// The work done token of the initialize request.
+const INITIALIZE_PROGRESS_TOKEN: &str = "kak-lsp-initialize";
+
 pub fn initialize(root_path: &str, meta: EditorMeta, ctx: &mut Context) {
+    let lazy_start = ctx.config.language[&ctx.language_id].lazy_start;
+    if lazy_start {
+        // Lazily started servers are typically slow to start, so show that we are on it.
+        ctx.exec(meta.clone(), warm_up_progress_command(ctx, false));
+    }
     let initialization_options = request_initialization_options_from_kakoune(&meta, ctx);
+    let initialization_options =
+        initialization_options_from_command(&meta, ctx).or(initialization_options);
+    // Servers may report progress while initializing, for example when loading a large project.
+    let progress_token = NumberOrString::String(INITIALIZE_PROGRESS_TOKEN.to_string());
+    ctx.work_done_progress.insert(progress_token.clone(), None);
     #[allow(deprecated)] // for root_path
     let params = InitializeParams {
         capabilities: ClientCapabilities {
@@ -36,7 +54,7 @@ pub fn initialize(root_path: &str, meta: EditorMeta, ctx: &mut Context) {
                     ),
                 }),
                 did_change_configuration: Some(DynamicRegistrationClientCapabilities {
-                    dynamic_registration: Some(false),
+                    dynamic_registration: Some(true),
                 }),
                 did_change_watched_files: None,
                 symbol: Some(WorkspaceSymbolClientCapabilities {
@@ -78,13 +96,15 @@ pub fn initialize(root_path: &str, meta: EditorMeta, ctx: &mut Context) {
                 }),
                 workspace_folders: Some(true),
                 configuration: Some(true),
-                semantic_tokens: None,
+                semantic_tokens: Some(SemanticTokensWorkspaceClientCapabilities {
+                    refresh_support: Some(true),
+                }),
                 code_lens: Some(CodeLensWorkspaceClientCapabilities {
-                    refresh_support: None,
+                    refresh_support: Some(true),
                 }),
                 file_operations: None,
                 inlay_hint: Some(InlayHintWorkspaceClientCapabilities {
-                    refresh_support: Some(false),
+                    refresh_support: Some(true),
                 }),
             }),
             text_document: Some(TextDocumentClientCapabilities {
@@ -106,7 +126,7 @@ pub fn initialize(root_path: &str, meta: EditorMeta, ctx: &mut Context) {
                         deprecated_support: Some(false),
                         preselect_support: Some(false),
                         tag_support: None,
-                        insert_replace_support: None,
+                        insert_replace_support: Some(true),
                         resolve_support: Some(CompletionItemCapabilityResolveSupport {
                             properties: vec![
                                 "additionalTextEdits".to_string(),
@@ -115,7 +135,7 @@ pub fn initialize(root_path: &str, meta: EditorMeta, ctx: &mut Context) {
                             ],
                         }),
                         insert_text_mode_support: None,
-                        label_details_support: None,
+                        label_details_support: Some(true),
                     }),
                     completion_item_kind: Some(CompletionItemKindCapability {
                         value_set: Some(vec![
@@ -245,17 +265,20 @@ pub fn initialize(root_path: &str, meta: EditorMeta, ctx: &mut Context) {
                     related_information: Some(true),
                     tag_support: None,
                     version_support: None,
-                    code_description_support: None,
+                    code_description_support: Some(true),
                     data_support: None,
                 }),
-                folding_range: None,
+                folding_range: Some(FoldingRangeClientCapabilities {
+                    line_folding_only: Some(true),
+                    ..FoldingRangeClientCapabilities::default()
+                }),
                 selection_range: Some(SelectionRangeClientCapabilities {
                     dynamic_registration: None,
                 }),
                 semantic_tokens: Some(SemanticTokensClientCapabilities {
                     dynamic_registration: Some(false),
                     requests: SemanticTokensClientCapabilitiesRequests {
-                        range: Some(false),
+                        range: Some(true),
                         full: Some(SemanticTokensFullOptions::Bool(true)),
                     },
                     token_types: ctx
@@ -288,11 +311,24 @@ pub fn initialize(root_path: &str, meta: EditorMeta, ctx: &mut Context) {
                     dynamic_registration: Some(false),
                 }),
                 moniker: None,
-                inlay_hint: Some(Default::default()),
+                inlay_hint: Some(InlayHintClientCapabilities {
+                    dynamic_registration: None,
+                    resolve_support: Some(InlayHintResolveClientCapabilities {
+                        properties: vec![
+                            "tooltip".to_string(),
+                            "label.tooltip".to_string(),
+                            "label.location".to_string(),
+                        ],
+                    }),
+                }),
             }),
             window: Some(WindowClientCapabilities {
                 work_done_progress: Some(true),
-                show_message: None,
+                show_message: Some(ShowMessageRequestClientCapabilities {
+                    message_action_item: Some(MessageActionItemCapabilities {
+                        additional_properties_support: Some(false),
+                    }),
+                }),
                 show_document: None,
             }),
             general: Some(GeneralClientCapabilities {
@@ -312,7 +348,10 @@ pub fn initialize(root_path: &str, meta: EditorMeta, ctx: &mut Context) {
                 .map(|s| s.to_string())
                 .collect(),
             ),
-            experimental: None,
+            experimental: Some(json!({
+                // Lets rust-analyzer send rust-analyzer/serverStatus.
+                "serverStatusNotification": true,
+            })),
         },
         initialization_options,
         process_id: Some(process::id()),
@@ -330,21 +369,92 @@ pub fn initialize(root_path: &str, meta: EditorMeta, ctx: &mut Context) {
         locale: None,
     };
 
-    ctx.call::<Initialize, _>(meta, params, move |ctx: &mut Context, _meta, result| {
-        ctx.capabilities = Some(result.capabilities);
-        if let Some(encoding) = result.offset_encoding {
-            match encoding.deref() {
-                "utf-8" => ctx.offset_encoding = OffsetEncoding::Utf8,
-                "utf-16" => ctx.offset_encoding = OffsetEncoding::Utf16,
-                _ => error!(
-                    "Language server sent unexpected offset encoding: '{}'",
-                    encoding
-                ),
-            }
-        }
-        ctx.notify::<Initialized>(InitializedParams {});
-        controller::dispatch_pending_editor_requests(ctx)
+    // lsp-types does not know about all LSP 3.17 capabilities yet, so we add them manually.
+    let mut params = serde_json::to_value(params).unwrap();
+    params["capabilities"]["textDocument"]["inlineValue"] = json!({
+        "dynamicRegistration": false,
+    });
+    params["capabilities"]["workspace"]["inlineValue"] = json!({
+        "refreshSupport": false,
+    });
+    params["capabilities"]["textDocument"]["completion"]["completionList"] = json!({
+        "itemDefaults": ["commitCharacters", "editRange", "insertTextFormat", "insertTextMode", "data"],
     });
+    params["workDoneToken"] = json!(progress_token);
+
+    ctx.call::<InitializeWithExtensions, _>(
+        meta,
+        params,
+        move |ctx: &mut Context, meta, result| {
+            if let Some(capabilities) = result.get("capabilities") {
+                match ExtraServerCapabilities::deserialize(capabilities) {
+                    Ok(extra_capabilities) => ctx.extra_capabilities = extra_capabilities,
+                    Err(e) => error!("Failed to parse server capabilities: {}", e),
+                }
+            }
+            let result = InitializeResult::deserialize(result).expect("Failed to parse response");
+            let (server_name, server_version) = match result.server_info.clone() {
+                Some(info) => (Some(info.name), info.version),
+                None => (None, None),
+            };
+            server_cache::record_capabilities(
+                ctx,
+                serde_json::to_value(&result.capabilities).unwrap(),
+                server_name,
+                server_version,
+            );
+            ctx.experimental_capabilities = result
+                .capabilities
+                .experimental
+                .clone()
+                .unwrap_or(Value::Null);
+            ctx.capabilities = Some(result.capabilities);
+            if let Some(encoding) = result.offset_encoding {
+                match encoding.deref() {
+                    "utf-8" => ctx.offset_encoding = OffsetEncoding::Utf8,
+                    "utf-16" => ctx.offset_encoding = OffsetEncoding::Utf16,
+                    _ => error!(
+                        "Language server sent unexpected offset encoding: '{}'",
+                        encoding
+                    ),
+                }
+            }
+            ctx.notify::<Initialized>(InitializedParams {});
+            // Keep the token if initialization progress is still ongoing, otherwise forget it.
+            if let Some(None) = ctx.work_done_progress.get(&progress_token) {
+                ctx.work_done_progress.remove(&progress_token);
+            }
+            if lazy_start {
+                ctx.exec(meta, warm_up_progress_command(ctx, true));
+            }
+            controller::dispatch_pending_editor_requests(ctx)
+        },
+    );
+}
+
+fn warm_up_progress_command(ctx: &Context, done: bool) -> String {
+    format!(
+        "lsp-handle-progress kak-lsp-warm-up {} false '' '' {}",
+        editor_quote(&format!("{} language server warming up", ctx.language_id)),
+        done
+    )
+}
+
+/// Like the initialize request from lsp-types, but leaves the parameters and the result untyped,
+/// so we can handle capabilities that lsp-types does not know about.
+pub enum InitializeWithExtensions {}
+
+impl Request for InitializeWithExtensions {
+    type Params = Value;
+    type Result = Value;
+    const METHOD: &'static str = Initialize::METHOD;
+}
+
+/// Server capabilities that are missing from lsp_types::ServerCapabilities.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExtraServerCapabilities {
+    pub inline_value_provider: Option<OneOf<bool, Value>>,
 }
 
 pub const CAPABILITY_CALL_HIERARCHY: &str = "lsp-incoming-calls, lsp-outgoing-calls";
@@ -352,13 +462,17 @@ pub const CAPABILITY_CODE_ACTIONS: &str = "lsp-code-actions";
 pub const CAPABILITY_CODE_LENS: &str = "lsp-code-lens";
 pub const CAPABILITY_COMPLETION: &str = "lsp-completion (hooked on InsertIdle)";
 pub const CAPABILITY_DEFINITION: &str = "lsp-definition (mapped to `gd` by default)";
+pub const CAPABILITY_DOCUMENT_COLOR: &str = "lsp-pick-color";
+pub const CAPABILITY_DOCUMENT_LINK: &str = "lsp-goto-file";
 pub const CAPABILITY_DOCUMENT_HIGHLIGHT: &str = "lsp-highlight-references";
 pub const CAPABILITY_DOCUMENT_SYMBOL: &str = "lsp-document-symbol";
 pub const CAPABILITY_EXECUTE_COMMANDS: &str = "lsp-execute-commands";
+pub const CAPABILITY_FOLDING_RANGE: &str = "lsp-object (using folding ranges)";
 pub const CAPABILITY_FORMATTING: &str = "lsp-formatting";
 pub const CAPABILITY_HOVER: &str = "lsp-hover";
 pub const CAPABILITY_IMPLEMENTATION: &str = "lsp-implementation";
 pub const CAPABILITY_INLAY_HINTS: &str = "lsp-inlay-hints";
+pub const CAPABILITY_INLINE_VALUES: &str = "lsp-inline-values";
 pub const CAPABILITY_RANGE_FORMATTING: &str = "lsp-range-formatting";
 pub const CAPABILITY_REFERENCES: &str = "lsp-references (mapped to `gr` by default)";
 pub const CAPABILITY_RENAME: &str = "lsp-rename";
@@ -380,12 +494,60 @@ pub fn attempt_server_capability(ctx: &Context, feature: &'static str) -> bool {
     false
 }
 
+/// Whether the server announced the extension in its `experimental` capabilities. Extensions
+/// are only used when announced, instead of guessing them from the server's name.
+pub fn server_has_experimental_capability(ctx: &Context, name: &str) -> bool {
+    !matches!(
+        ctx.experimental_capabilities.get(name),
+        None | Some(Value::Null) | Some(Value::Bool(false))
+    )
+}
+
+/// Gate for extensions that servers don't announce in their capabilities. A method is refused
+/// once the server answered MethodNotFound to it.
+pub fn attempt_server_extension(meta: &EditorMeta, ctx: &Context, method: &str) -> bool {
+    if !ctx.unsupported_methods.contains(method) {
+        return true;
+    }
+    let msg = format!(
+        "{} language server does not support {}",
+        ctx.language_id, method
+    );
+    ctx.exec(
+        meta.clone(),
+        format!("lsp-show-error {}", editor_quote(&msg)),
+    );
+    false
+}
+
+/// Like `server_has_experimental_capability`, but tells the user if the extension is missing.
+pub fn attempt_experimental_capability(meta: &EditorMeta, ctx: &Context, name: &str) -> bool {
+    if server_has_experimental_capability(ctx, name) {
+        return true;
+    }
+    let msg = format!(
+        "{} language server does not support the {} extension",
+        ctx.language_id, name
+    );
+    ctx.exec(
+        meta.clone(),
+        format!("lsp-show-error {}", editor_quote(&msg)),
+    );
+    false
+}
+
 pub fn server_has_capability(ctx: &Context, feature: &'static str) -> bool {
-    let server_capabilities = match ctx.capabilities.as_ref() {
-        Some(caps) => caps,
-        None => return false,
-    };
+    match ctx.capabilities.as_ref() {
+        Some(caps) => has_capability(caps, &ctx.extra_capabilities, feature),
+        None => false,
+    }
+}
 
+pub fn has_capability(
+    server_capabilities: &ServerCapabilities,
+    extra_capabilities: &ExtraServerCapabilities,
+    feature: &'static str,
+) -> bool {
     match feature {
         CAPABILITY_CODE_ACTIONS => match server_capabilities.code_action_provider {
             Some(CodeActionProviderCapability::Simple(ok)) => ok,
@@ -410,11 +572,22 @@ pub fn server_has_capability(ctx: &Context, feature: &'static str) -> bool {
             Some(OneOf::Right(_)) => true,
             None => false,
         },
+        CAPABILITY_DOCUMENT_COLOR => match server_capabilities.color_provider {
+            Some(ColorProviderCapability::Simple(ok)) => ok,
+            Some(_) => true,
+            None => false,
+        },
+        CAPABILITY_DOCUMENT_LINK => server_capabilities.document_link_provider.is_some(),
         CAPABILITY_DOCUMENT_SYMBOL => match server_capabilities.document_symbol_provider {
             Some(OneOf::Left(ok)) => ok,
             Some(OneOf::Right(_)) => true,
             None => false,
         },
+        CAPABILITY_FOLDING_RANGE => match server_capabilities.folding_range_provider {
+            Some(FoldingRangeProviderCapability::Simple(ok)) => ok,
+            Some(_) => true,
+            None => false,
+        },
         CAPABILITY_FORMATTING => match server_capabilities.document_formatting_provider {
             Some(OneOf::Left(ok)) => ok,
             Some(OneOf::Right(_)) => true,
@@ -435,6 +608,11 @@ pub fn server_has_capability(ctx: &Context, feature: &'static str) -> bool {
             Some(OneOf::Right(_)) => true,
             None => false,
         },
+        CAPABILITY_INLINE_VALUES => match extra_capabilities.inline_value_provider {
+            Some(OneOf::Left(ok)) => ok,
+            Some(OneOf::Right(_)) => true,
+            None => false,
+        },
         CAPABILITY_RANGE_FORMATTING => match server_capabilities.document_range_formatting_provider
         {
             Some(OneOf::Left(ok)) => ok,
@@ -472,38 +650,49 @@ pub fn server_has_capability(ctx: &Context, feature: &'static str) -> bool {
     }
 }
 
-pub fn capabilities(meta: EditorMeta, ctx: &mut Context) {
-    let mut features: Vec<String> = vec![];
-
-    fn probe_feature(ctx: &mut Context, features: &mut Vec<String>, feature: &'static str) {
-        if server_has_capability(ctx, feature) {
-            features.push(feature.to_string());
-        }
-    }
-
-    probe_feature(ctx, &mut features, CAPABILITY_SELECTION_RANGE);
-    probe_feature(ctx, &mut features, CAPABILITY_HOVER);
-    probe_feature(ctx, &mut features, CAPABILITY_COMPLETION);
-    probe_feature(ctx, &mut features, CAPABILITY_SIGNATURE_HELP);
-    probe_feature(ctx, &mut features, CAPABILITY_DEFINITION);
-    probe_feature(ctx, &mut features, CAPABILITY_TYPE_DEFINITION);
-    probe_feature(ctx, &mut features, CAPABILITY_IMPLEMENTATION);
-    probe_feature(ctx, &mut features, CAPABILITY_REFERENCES);
-    probe_feature(ctx, &mut features, CAPABILITY_DOCUMENT_HIGHLIGHT);
-    probe_feature(ctx, &mut features, CAPABILITY_DOCUMENT_SYMBOL);
-    probe_feature(ctx, &mut features, CAPABILITY_WORKSPACE_SYMBOL);
-    probe_feature(ctx, &mut features, CAPABILITY_FORMATTING);
-    probe_feature(ctx, &mut features, CAPABILITY_RANGE_FORMATTING);
-    probe_feature(ctx, &mut features, CAPABILITY_RENAME);
-    probe_feature(ctx, &mut features, CAPABILITY_CODE_ACTIONS);
-    probe_feature(ctx, &mut features, CAPABILITY_CODE_LENS);
-    probe_feature(ctx, &mut features, CAPABILITY_CALL_HIERARCHY);
-    features.push("lsp-diagnostics".to_string());
-    probe_feature(ctx, &mut features, CAPABILITY_INLAY_HINTS);
+/// The kak-lsp commands that are supported by a server with the given capabilities.
+pub fn supported_features(
+    server_capabilities: &ServerCapabilities,
+    extra_capabilities: &ExtraServerCapabilities,
+) -> Vec<String> {
+    const DIAGNOSTICS: &str = "lsp-diagnostics";
+    [
+        CAPABILITY_SELECTION_RANGE,
+        CAPABILITY_HOVER,
+        CAPABILITY_COMPLETION,
+        CAPABILITY_SIGNATURE_HELP,
+        CAPABILITY_DEFINITION,
+        CAPABILITY_TYPE_DEFINITION,
+        CAPABILITY_IMPLEMENTATION,
+        CAPABILITY_REFERENCES,
+        CAPABILITY_DOCUMENT_HIGHLIGHT,
+        CAPABILITY_DOCUMENT_SYMBOL,
+        CAPABILITY_WORKSPACE_SYMBOL,
+        CAPABILITY_DOCUMENT_COLOR,
+        CAPABILITY_FORMATTING,
+        CAPABILITY_RANGE_FORMATTING,
+        CAPABILITY_RENAME,
+        CAPABILITY_CODE_ACTIONS,
+        CAPABILITY_CODE_LENS,
+        CAPABILITY_CALL_HIERARCHY,
+        DIAGNOSTICS,
+        CAPABILITY_INLAY_HINTS,
+        CAPABILITY_INLINE_VALUES,
+    ]
+    .iter()
+    .copied()
+    .filter(|feature| {
+        *feature == DIAGNOSTICS || has_capability(server_capabilities, extra_capabilities, feature)
+    })
+    .map(|feature| feature.to_string())
+    .collect()
+}
 
+pub fn capabilities(meta: EditorMeta, ctx: &mut Context) {
     // NOTE controller should park request for capabilities until they are available thus it should
     // be safe to unwrap here (otherwise something unexpectedly wrong and it's better to panic)
     let server_capabilities = ctx.capabilities.as_ref().unwrap();
+    let mut features = supported_features(server_capabilities, &ctx.extra_capabilities);
 
     if let Some(ref provider) = server_capabilities.execute_command_provider {
         features.push(format!(
@@ -538,6 +727,15 @@ pub fn capabilities(meta: EditorMeta, ctx: &mut Context) {
         ));
     }
 
+    if let Some(experimental) = ctx.experimental_capabilities.as_object() {
+        if !experimental.is_empty() {
+            features.push(format!(
+                "experimental: [{}]",
+                experimental.keys().join(", ")
+            ));
+        }
+    }
+
     let command = formatdoc!(
         "info 'kak-lsp commands supported by {} language server:
 
@@ -0,0 +1,232 @@
+//! Output of long-running commands, like build tasks started from code lenses. For the commands
+//! in `stream_output_of_commands`, the progress and log messages the server sends while the
+//! command runs are written to a fifo buffer, so the user can watch the task.
+
+use crate::context::*;
+use crate::types::*;
+use crate::util::*;
+use crossbeam_channel::{unbounded, Sender};
+use lsp_types::*;
+use std::collections::HashSet;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long to wait for the editor to read the fifo before dropping the output.
+const OPEN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The fifo buffer of the command being executed.
+pub struct CommandOutput {
+    sender: Sender<String>,
+    // Progress that began while the command ran. The output ends when the command returned and
+    // all of it is done.
+    tokens: HashSet<ProgressToken>,
+    returned: bool,
+}
+
+/// Opens a fifo buffer for the output of the command, if it is configured to be streamed.
+pub fn start(meta: &EditorMeta, command: &str, ctx: &mut Context) {
+    let stream = ctx
+        .language_config()
+        .stream_output_of_commands
+        .iter()
+        .any(|pattern| pattern == "*" || pattern == command);
+    if !stream {
+        return;
+    }
+    let fifo = match temp_fifo() {
+        Some(fifo) => fifo,
+        None => {
+            error!("Failed to create fifo for the output of {}", command);
+            return;
+        }
+    };
+    let (sender, receiver) = unbounded::<String>();
+    let path = fifo.path.clone();
+    thread::spawn(move || {
+        let mut file = match open_fifo(&fifo.path) {
+            Ok(file) => file,
+            Err(err) => {
+                error!("Failed to open command output fifo: {}", err);
+                return;
+            }
+        };
+        for line in receiver {
+            if writeln!(file, "{}", line).is_err() {
+                return;
+            }
+        }
+    });
+    let mut meta = meta.clone();
+    meta.fifo = None;
+    meta.command_fifo = None;
+    meta.write_response_to_fifo = false;
+    ctx.exec(
+        meta,
+        format!(
+            "lsp-show-command-output {} {}",
+            editor_quote(&format!("*lsp-output-{}*", command)),
+            editor_quote(&path),
+        ),
+    );
+    let _ = sender.send(format!("$ {}", command));
+    // Replacing the output of a previous command ends it.
+    ctx.command_output = Some(CommandOutput {
+        sender,
+        tokens: HashSet::new(),
+        returned: false,
+    });
+}
+
+/// Opens the fifo for writing once the editor reads it. A blocking open would hang forever if the
+/// editor never does, for example because the client went away.
+fn open_fifo(path: &str) -> io::Result<File> {
+    let deadline = Instant::now() + OPEN_TIMEOUT;
+    loop {
+        match OpenOptions::new()
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(path)
+        {
+            Ok(file) => {
+                // Wait for the editor when the fifo is full, instead of failing to write.
+                unsafe { libc::fcntl(file.as_raw_fd(), libc::F_SETFL, 0) };
+                return Ok(file);
+            }
+            // There is no reader yet.
+            Err(err) if err.raw_os_error() == Some(libc::ENXIO) && Instant::now() < deadline => {
+                thread::sleep(Duration::from_millis(50));
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Writes a message to the output of the command being executed, if any.
+pub fn write(message: &str, ctx: &Context) {
+    if let Some(output) = &ctx.command_output {
+        for line in strip_ansi_escapes(message).lines() {
+            let _ = output.sender.send(line.to_string());
+        }
+    }
+}
+
+/// Writes progress to the output of the command being executed, if it began while the command
+/// ran.
+pub fn progress(token: &ProgressToken, progress: &WorkDoneProgress, ctx: &mut Context) {
+    let output = match &mut ctx.command_output {
+        Some(output) => output,
+        None => return,
+    };
+    let line = match progress {
+        WorkDoneProgress::Begin(begin) => {
+            if output.returned {
+                return;
+            }
+            output.tokens.insert(token.clone());
+            progress_line(&begin.title, &begin.message, begin.percentage)
+        }
+        WorkDoneProgress::Report(report) if output.tokens.contains(token) => {
+            progress_line("", &report.message, report.percentage)
+        }
+        WorkDoneProgress::End(end) if output.tokens.remove(token) => {
+            progress_line("done", &end.message, None)
+        }
+        _ => return,
+    };
+    let line = line.trim();
+    if !line.is_empty() {
+        write(line, ctx);
+    }
+    end_if_done(ctx);
+}
+
+/// Notes that the command returned. Its output ends once its progress is done.
+pub fn command_returned(ctx: &mut Context) {
+    if let Some(output) = &mut ctx.command_output {
+        output.returned = true;
+    }
+    end_if_done(ctx);
+}
+
+/// Ends the output of the command after the server failed to execute it, since its progress may
+/// never be done.
+pub fn command_failed(message: &str, ctx: &mut Context) {
+    write(&format!("error: {}", message), ctx);
+    ctx.command_output = None;
+}
+
+fn end_if_done(ctx: &mut Context) {
+    if matches!(&ctx.command_output, Some(output) if output.returned && output.tokens.is_empty()) {
+        // Closing the channel closes the fifo.
+        ctx.command_output = None;
+    }
+}
+
+fn progress_line(title: &str, message: &Option<String>, percentage: Option<u32>) -> String {
+    let mut line = title.to_string();
+    if let Some(message) = message {
+        if !line.is_empty() {
+            line.push_str(": ");
+        }
+        line.push_str(message);
+    }
+    if let Some(percentage) = percentage {
+        line.push_str(&format!(" ({}%)", percentage));
+    }
+    line
+}
+
+/// Removes ANSI escape sequences, like colors, from command output.
+fn strip_ansi_escapes(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\x1b' {
+            result.push(c);
+            continue;
+        }
+        match chars.next() {
+            // Control sequences end with a character in the range @ to ~.
+            Some('[') => {
+                for c in chars.by_ref() {
+                    if ('@'..='~').contains(&c) {
+                        break;
+                    }
+                }
+            }
+            // Operating system commands, like window titles, end with BEL or ESC \.
+            Some(']') => {
+                while let Some(c) = chars.next() {
+                    if c == '\x07' {
+                        break;
+                    }
+                    if c == '\x1b' && chars.peek() == Some(&'\\') {
+                        chars.next();
+                        break;
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strip_ansi_escapes() {
+        assert_eq!(
+            strip_ansi_escapes("\x1b[1;31merror\x1b[0m: failed"),
+            "error: failed"
+        );
+        assert_eq!(strip_ansi_escapes("\x1b]0;title\x07done"), "done");
+        assert_eq!(strip_ansi_escapes("plain"), "plain");
+    }
+}
@@ -1,12 +1,27 @@
+use crate::capabilities::ExtraServerCapabilities;
+use crate::command_output::CommandOutput;
+use crate::diagnostic_commands::RunningDiagnosticCommands;
+use crate::edit_conflict::PendingConflict;
+use crate::edit_preview::PendingEdit;
+use crate::language_features::document_symbol::DocumentSymbols;
+use crate::language_features::formatting::RunningFormatter;
+use crate::language_features::hover::RunningHoverProviders;
+use crate::language_features::prefetch::Prefetched;
+use crate::language_server_transport::ServerOutput;
+use crate::middleware::rewrite_params;
+use crate::modified_lines::{LineRange, ModifiedLines};
+use crate::resource_usage::ResourceUsage;
+use crate::server_cache::{CapabilityChange, ServerCacheEntry};
 use crate::types::*;
-use crossbeam_channel::Sender;
+use crossbeam_channel::{unbounded, Receiver, Sender};
 use jsonrpc_core::{self, Call, Error, Failure, Id, Output, Success, Value, Version};
 use lsp_types::notification::Notification;
 use lsp_types::request::*;
 use lsp_types::*;
 use serde::Deserialize;
+use serde_json::value::RawValue;
 use std::borrow::Cow;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::{fs, time};
 
 // Copy of Kakoune's timestamped buffer content.
@@ -15,50 +30,192 @@ pub struct Document {
     // It's passed to a language server as a version and is used to tag selections, highlighters and
     // other timestamp sensitive parameters in commands sent to kakoune.
     pub version: i32,
+    // Version of the document in the language server. It equals `version` unless the server is
+    // shared by several editor sessions, whose timestamps are unrelated; it only ever increases.
+    pub server_version: i32,
     // Buffer content.
     // It's used to translate between LSP and Kakoune coordinates.
     pub text: ropey::Rope,
+    // Whether this server is one of the additional servers for this buffer (see `lsp_servers`).
+    // Additional servers receive the buffer content but their results are not shown.
+    pub secondary: bool,
+    // Language ID that was sent in textDocument/didOpen.
+    pub language_id: String,
+}
+
+// State of one editor client, like the completion menu shown in it. Two clients may edit
+// different buffers at the same time, so they must not share it. It is dropped when the client
+// closes.
+#[derive(Default)]
+pub struct ClientState {
+    // The items of the last completion in this client, to resolve the selected one, and the
+    // language of the server that sent each of them.
+    pub completion_items: Vec<CompletionItem>,
+    pub completion_item_sources: Vec<LanguageId>,
+    // The last completion request of this client, and the items that each server of the buffer
+    // answered it with.
+    pub completion_request: Option<(EditorMeta, TextDocumentCompletionParams)>,
+    pub completion_sources: HashMap<LanguageId, Vec<CompletionItem>>,
+    // How many servers answer the last completion request, when to show the items of those that
+    // answered if the others are late, and whether the menu was shown already.
+    pub completion_servers: usize,
+    pub completion_menu_due: Option<time::Instant>,
+    pub completion_menu_shown: bool,
+    // When the last completion request of this client was sent, and the request that waits for the
+    // end of the coalescing window to be sent, see `completion_coalesce_window_ms`.
+    pub completion_sent: Option<time::Instant>,
+    pub deferred_completion: Option<(time::Instant, EditorMeta, TextDocumentCompletionParams)>,
+}
+
+/// A request to the language server that is waiting for its response.
+pub struct OutstandingRequest {
+    pub meta: EditorMeta,
+    pub method: String,
+    pub batch_id: BatchNumber,
+    // The position of the request in its batch.
+    pub batch_index: usize,
+    pub sent: time::Instant,
+    // Whether the request was already reported as stale, see `STALE_REQUEST_TIMEOUT`.
+    pub reported_stale: bool,
 }
 
-pub type ResponsesCallback = Box<dyn FnOnce(&mut Context, EditorMeta, Vec<Value>) -> ()>;
+/// Requests that are not answered after this long were probably dropped by the server. They are
+/// logged once and listed by `lsp-status`.
+pub const STALE_REQUEST_TIMEOUT: time::Duration = time::Duration::from_secs(5 * 60);
+
+pub type ResponsesCallback =
+    Box<dyn FnOnce(&mut Context, EditorMeta, Vec<Option<Box<RawValue>>>) -> ()>;
 type BatchNumber = usize;
 type BatchCount = BatchNumber;
 
+/// Requests whose callback runs once all of them are answered. Results are kept in the order of
+/// the requests, whatever order the responses arrive in.
+pub struct Batch {
+    pub remaining: BatchCount,
+    pub results: Vec<Option<Box<RawValue>>>,
+    // Whether a failed request leaves a hole in the results, instead of dropping the batch and
+    // telling the user.
+    pub lenient: bool,
+    pub callback: ResponsesCallback,
+}
+
 pub struct Context {
     batch_counter: BatchNumber,
-    pub batches:
-        HashMap<BatchNumber, (BatchCount, Vec<serde_json::value::Value>, ResponsesCallback)>,
+    pub batches: HashMap<BatchNumber, Batch>,
     pub capabilities: Option<ServerCapabilities>,
-    pub completion_items: Vec<CompletionItem>,
-    // We currently only track one client's completion items, to simplify cleanup (else we
-    // might need to hook into ClientClose). Track the client name, so we can check if the
-    // completions are valid.
-    pub completion_last_client: Option<String>,
+    // Whether the server registered for workspace/didChangeConfiguration or asked for
+    // workspace/configuration, so we know that it picks up settings changes.
+    pub did_change_configuration_support: bool,
+    // Capabilities that changed since the server was last started for this project.
+    pub capability_changes: Vec<CapabilityChange>,
+    pub extra_capabilities: ExtraServerCapabilities,
+    // The server's `experimental` capabilities, which announce its non-standard extensions.
+    pub experimental_capabilities: Value,
+    // Methods the server answered with MethodNotFound.
+    pub unsupported_methods: HashSet<String>,
+    // Per-client state, by session and client name.
+    pub clients: HashMap<(SessionId, String), ClientState>,
     pub config: Config,
     pub dynamic_config: DynamicConfig,
+    // Diagnostics that this server published. They are shown together with the ones below, see
+    // `buffer_diagnostics`.
     pub diagnostics: HashMap<String, Vec<Diagnostic>>,
+    // Diagnostics that the additional servers of each buffer published, by their language.
+    pub secondary_diagnostics: HashMap<String, HashMap<LanguageId, Vec<Diagnostic>>>,
+    // When the diagnostics of each buffer were last shown in the editor, and the buffers whose
+    // diagnostics are waiting to be shown, see `diagnostics_update_interval_ms`.
+    pub diagnostics_last_update: HashMap<String, time::Instant>,
+    pub pending_diagnostics: HashSet<String>,
+    // Diagnostics from the last run of `diagnostic_commands`, and the run in progress.
+    pub command_diagnostics: HashMap<String, Vec<Diagnostic>>,
+    pub running_diagnostic_commands: Option<RunningDiagnosticCommands>,
+    // The hover that waits for the output of its hover providers.
+    pub running_hover_providers: Option<RunningHoverProviders>,
+    // The external formatter of a formatting request, see `formatter`.
+    pub running_formatter: Option<RunningFormatter>,
+    // If set, only diagnostics on lines modified since this git revision are shown.
+    pub diagnostics_git_base: Option<String>,
+    // Modified lines per buffer, with the document version they were computed for. None if they
+    // could not be computed, for example because the file is not in a git repository.
+    pub modified_lines: HashMap<String, (Option<i32>, Option<Vec<LineRange>>)>,
+    // Buffers whose modified lines are being computed in the background, and the channel the
+    // results arrive on.
+    pub modified_lines_pending: HashSet<String>,
+    pub modified_lines_channel: (Sender<ModifiedLines>, Receiver<ModifiedLines>),
     pub code_lenses: HashMap<String, Vec<CodeLens>>,
+    // The last inlay hints received for each buffer, for lsp-inlay-hint-goto and tooltips.
+    pub inlay_hints: HashMap<String, Vec<InlayHint>>,
+    // Hover info and definitions prefetched for the visible symbols of each buffer.
+    pub prefetched: HashMap<String, Prefetched>,
+    // Buffers in large file mode, and buffers where it was disabled by the user.
+    pub large_files: HashSet<String>,
+    pub large_file_overrides: HashSet<String>,
+    // Buffers that are not sent to the language server because they are not valid UTF-8.
+    pub invalid_utf8_buffers: HashSet<String>,
+    // Buffers whose lsp_document_symbols option is set.
+    pub document_symbols_buffers: HashSet<String>,
+    // The document symbols of each buffer, shared by the features that need them.
+    pub document_symbols: HashMap<String, DocumentSymbols>,
     pub editor_tx: Sender<EditorResponse>,
     pub lang_srv_tx: Sender<ServerMessage>,
+    // Requests to other controllers, routed by the session like editor requests.
+    pub session_tx: Sender<EditorRequest>,
     pub language_id: String,
+    pub ltex_settings: HashMap<String, HashMap<String, Vec<String>>>,
+    // The last health reported by rust-analyzer/serverStatus, to only alert when it changes.
+    pub rust_analyzer_health: String,
     pub pending_requests: Vec<EditorRequest>,
+    pub pending_server_requests: HashMap<String, Id>,
+    // Edits from the server waiting to be applied or discarded by the user, by request ID.
+    pub pending_edits: HashMap<String, PendingEdit>,
+    // Edits to buffers whose files changed on disk, waiting for the user's decision.
+    pub pending_conflicts: HashMap<u64, PendingConflict>,
+    // The workspace/executeCommand request in flight, and the command name.
+    pub executing_command: Option<(EditorMeta, String)>,
+    // The fifo buffer that shows the output of the command in flight, or of its progress.
+    pub command_output: Option<CommandOutput>,
     pub request_counter: u64,
-    pub response_waitlist: HashMap<Id, (EditorMeta, &'static str, BatchNumber)>,
+    pub response_waitlist: HashMap<Id, OutstandingRequest>,
+    // Set to restart the language server with this request once the current request is handled.
+    pub restart_request: Option<EditorRequest>,
     pub root_path: String,
+    // When the session state is due to be written, if it changed since it was last written.
+    pub session_state_due: Option<time::Instant>,
+    pub server_cache: Option<ServerCacheEntry>,
+    // Recent stderr lines of the language server, kept across restarts.
+    pub server_output: ServerOutput,
+    // Memory and CPU usage of the language server processes, sampled periodically.
+    pub resource_usage: Option<ResourceUsage>,
+    // The editor session of the last request. Messages that are not about a buffer go there.
     pub session: SessionId,
+    // The editor session of each buffer, which differs from `session` if the language server is
+    // shared by several sessions, see `share_servers`. If several sessions have the buffer open,
+    // this is the one whose content the server has.
+    pub buffer_sessions: HashMap<String, SessionId>,
+    // The timestamp of each buffer in each editor session that has it open. Sessions that share a
+    // language server have unrelated timestamps, so changes are checked against their own.
+    pub buffer_versions: HashMap<String, HashMap<SessionId, i32>>,
+    // Hash of the settings last sent with workspace/didChangeConfiguration.
+    pub settings_hash: Option<u64>,
     pub documents: HashMap<String, Document>,
+    // Modification time of each buffer's file when the buffer was last known to match it, that
+    // is, when it was opened or saved.
+    pub disk_mtimes: HashMap<String, time::SystemTime>,
     pub offset_encoding: OffsetEncoding,
     pub preferred_offset_encoding: Option<OffsetEncoding>,
     pub work_done_progress: HashMap<NumberOrString, Option<WorkDoneProgressBegin>>,
     pub work_done_progress_report_timestamp: time::Instant,
+    pub yaml_schemas: HashMap<String, String>,
 }
 
 impl Context {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         language_id: &str,
         initial_request: EditorRequest,
         lang_srv_tx: Sender<ServerMessage>,
         editor_tx: Sender<EditorResponse>,
+        session_tx: Sender<EditorRequest>,
         config: Config,
         root_path: String,
         offset_encoding: Option<OffsetEncoding>,
@@ -68,25 +225,65 @@ impl Context {
             batch_counter: 0,
             batches: HashMap::default(),
             capabilities: None,
-            completion_items: vec![],
-            completion_last_client: None,
+            did_change_configuration_support: false,
+            capability_changes: vec![],
+            extra_capabilities: ExtraServerCapabilities::default(),
+            experimental_capabilities: Value::Null,
+            unsupported_methods: HashSet::default(),
+            clients: HashMap::default(),
             config,
             dynamic_config: DynamicConfig::default(),
             diagnostics: HashMap::default(),
+            secondary_diagnostics: HashMap::default(),
+            diagnostics_last_update: HashMap::default(),
+            pending_diagnostics: HashSet::default(),
+            command_diagnostics: HashMap::default(),
+            running_diagnostic_commands: None,
+            running_hover_providers: None,
+            running_formatter: None,
+            diagnostics_git_base: None,
+            modified_lines: HashMap::default(),
+            modified_lines_pending: HashSet::default(),
+            modified_lines_channel: unbounded(),
             code_lenses: HashMap::default(),
+            inlay_hints: HashMap::default(),
+            prefetched: HashMap::default(),
+            large_files: HashSet::default(),
+            large_file_overrides: HashSet::default(),
+            invalid_utf8_buffers: HashSet::default(),
+            document_symbols_buffers: HashSet::default(),
+            document_symbols: HashMap::default(),
             editor_tx,
             lang_srv_tx,
+            session_tx,
             language_id: language_id.to_string(),
+            ltex_settings: HashMap::default(),
+            rust_analyzer_health: "ok".to_string(),
             pending_requests: vec![initial_request],
+            pending_server_requests: HashMap::default(),
+            pending_edits: HashMap::default(),
+            pending_conflicts: HashMap::default(),
+            executing_command: None,
+            command_output: None,
             request_counter: 0,
             response_waitlist: HashMap::default(),
+            restart_request: None,
             root_path,
+            session_state_due: None,
+            server_cache: None,
+            server_output: ServerOutput::default(),
+            resource_usage: None,
             session,
+            buffer_sessions: HashMap::default(),
+            buffer_versions: HashMap::default(),
+            settings_hash: None,
             documents: HashMap::default(),
+            disk_mtimes: HashMap::default(),
             offset_encoding: offset_encoding.unwrap_or(OffsetEncoding::Utf16),
             preferred_offset_encoding: offset_encoding,
             work_done_progress: HashMap::default(),
             work_done_progress_report_timestamp: time::Instant::now(),
+            yaml_schemas: HashMap::default(),
         }
     }
 
@@ -127,48 +324,164 @@ impl Context {
     ) where
         R::Params: IntoParams,
         R::Result: for<'a> Deserialize<'a>,
+    {
+        self.send_batch::<R>(
+            meta,
+            ops,
+            false,
+            Box::new(move |ctx, meta, vals| {
+                let results: Vec<R::Result> = vals
+                    .into_iter()
+                    .map(|val| {
+                        let val = val.expect("Missing response in batch");
+                        serde_json::from_str(val.get()).expect("Failed to parse response")
+                    })
+                    .collect();
+                callback(ctx, meta, results)
+            }),
+        );
+    }
+
+    /// Like `batch_call`, but a request that fails gives `None` instead of dropping the whole
+    /// batch, and its error is only logged. This suits requests the user did not ask for
+    /// explicitly, or lists where a missing entry is not a problem.
+    pub fn batch_call_lenient<
+        R: Request,
+        F: for<'a> FnOnce(&'a mut Context, EditorMeta, Vec<Option<R::Result>>) -> () + 'static,
+    >(
+        &mut self,
+        meta: EditorMeta,
+        ops: Vec<R::Params>,
+        callback: F,
+    ) where
+        R::Params: IntoParams,
+        R::Result: for<'a> Deserialize<'a>,
+    {
+        self.send_batch::<R>(
+            meta,
+            ops,
+            true,
+            Box::new(move |ctx, meta, vals| {
+                let results: Vec<Option<R::Result>> = vals
+                    .into_iter()
+                    .map(|val| {
+                        val.and_then(|val| match serde_json::from_str(val.get()) {
+                            Ok(result) => Some(result),
+                            Err(err) => {
+                                error!("Failed to parse response: {}", err);
+                                None
+                            }
+                        })
+                    })
+                    .collect();
+                callback(ctx, meta, results)
+            }),
+        );
+    }
+
+    fn send_batch<R: Request>(
+        &mut self,
+        meta: EditorMeta,
+        ops: Vec<R::Params>,
+        lenient: bool,
+        callback: ResponsesCallback,
+    ) where
+        R::Params: IntoParams,
     {
         let batch_id = self.next_batch_id();
         self.batches.insert(
             batch_id,
-            (
-                ops.len(),
-                Vec::with_capacity(ops.len()),
-                Box::new(move |ctx, meta, vals| {
-                    let results: Vec<R::Result> = vals
-                        .into_iter()
-                        .map(|val| serde_json::from_value(val).expect("Failed to parse response"))
-                        .collect();
-                    callback(ctx, meta, results)
-                }),
-            ),
+            Batch {
+                remaining: ops.len(),
+                results: ops.iter().map(|_| None).collect(),
+                lenient,
+                callback,
+            },
         );
-        for params in ops {
+        for (batch_index, params) in ops.into_iter().enumerate() {
             let params = params.into_params();
             if params.is_err() {
                 error!("Failed to convert params");
                 return;
             }
-            let id = self.next_request_id();
-            self.response_waitlist
-                .insert(id.clone(), (meta.clone(), R::METHOD, batch_id));
-
-            let call = jsonrpc_core::MethodCall {
-                jsonrpc: Some(Version::V2),
-                id,
-                method: R::METHOD.into(),
-                params: params.unwrap(),
-            };
-            if self
-                .lang_srv_tx
-                .send(ServerMessage::Request(Call::MethodCall(call)))
-                .is_err()
-            {
-                error!("Failed to call language server");
-            };
+            self.send_request(
+                meta.clone(),
+                R::METHOD,
+                params.unwrap(),
+                batch_id,
+                batch_index,
+            );
         }
     }
 
+    /// Like `call`, but for requests whose method is only known at runtime.
+    pub fn call_method<F: for<'a> FnOnce(&'a mut Context, EditorMeta, Value) -> () + 'static>(
+        &mut self,
+        meta: EditorMeta,
+        method: &str,
+        params: jsonrpc_core::Params,
+        callback: F,
+    ) {
+        let batch_id = self.next_batch_id();
+        self.batches.insert(
+            batch_id,
+            Batch {
+                remaining: 1,
+                results: vec![None],
+                lenient: false,
+                callback: Box::new(move |ctx, meta, mut vals| {
+                    if let Some(val) = vals.pop().flatten() {
+                        let val =
+                            serde_json::from_str(val.get()).expect("Failed to parse response");
+                        callback(ctx, meta, val)
+                    }
+                }),
+            },
+        );
+        self.send_request(meta, method, params, batch_id, 0);
+    }
+
+    fn send_request(
+        &mut self,
+        meta: EditorMeta,
+        method: &str,
+        params: jsonrpc_core::Params,
+        batch_id: BatchNumber,
+        batch_index: usize,
+    ) {
+        let id = self.next_request_id();
+        self.response_waitlist.insert(
+            id.clone(),
+            OutstandingRequest {
+                meta,
+                method: method.to_string(),
+                batch_id,
+                batch_index,
+                sent: time::Instant::now(),
+                reported_stale: false,
+            },
+        );
+        let params = rewrite_params(&self.language_config().middleware, method, params);
+
+        let call = jsonrpc_core::MethodCall {
+            jsonrpc: Some(Version::V2),
+            id,
+            method: method.into(),
+            params,
+        };
+        if self
+            .lang_srv_tx
+            .send(ServerMessage::Request(Call::MethodCall(call)))
+            .is_err()
+        {
+            error!("Failed to call language server");
+        };
+    }
+
+    pub fn language_config(&self) -> &LanguageConfig {
+        &self.config.language[&self.language_id]
+    }
+
     pub fn reply(&mut self, id: Id, result: Result<Value, Error>) {
         let output = match result {
             Ok(result) => Output::Success(Success {
@@ -200,10 +513,15 @@ impl Context {
             error!("Failed to convert params");
             return;
         }
+        let params = rewrite_params(
+            &self.language_config().middleware,
+            N::METHOD,
+            params.unwrap(),
+        );
         let notification = jsonrpc_core::Notification {
             jsonrpc: Some(Version::V2),
             method: N::METHOD.into(),
-            params: params.unwrap(),
+            params,
         };
         if self
             .lang_srv_tx
@@ -238,6 +556,19 @@ impl Context {
         }
     }
 
+    /// Like `exec`, but for a sequence of commands such as those built by
+    /// `set_list_option_commands`. They are sent separately, unless the editor is waiting for a
+    /// single response on a fifo.
+    pub fn exec_chunked(&self, meta: EditorMeta, commands: Vec<String>) {
+        if meta.fifo.is_some() || meta.command_fifo.is_some() || commands.len() == 1 {
+            self.exec(meta, commands.join("\n"));
+            return;
+        }
+        for command in commands {
+            self.exec(meta.clone(), command);
+        }
+    }
+
     fn next_batch_id(&mut self) -> BatchNumber {
         let id = self.batch_counter;
         self.batch_counter += 1;
@@ -261,17 +592,68 @@ impl Context {
             command_fifo: None,
             write_response_to_fifo: false,
             hook: false,
+            window_width: 0,
+            presentation: None,
         }
     }
 
     pub fn meta_for_buffer(&self, client: Option<String>, buffile: &str) -> Option<EditorMeta> {
         let document = self.documents.get(buffile)?;
         let mut meta = self.meta_for_session(client);
+        meta.session = self.buffer_session(buffile).to_string();
         meta.buffile = buffile.to_string();
         meta.version = document.version;
         Some(meta)
     }
 
+    /// Returns the editor session that has the buffer open.
+    pub fn buffer_session(&self, buffile: &str) -> &str {
+        self.buffer_sessions.get(buffile).unwrap_or(&self.session)
+    }
+
+    /// Returns the timestamp of the buffer in the given editor session, which differs from the
+    /// document version if another session that shares the server changed the buffer last.
+    pub fn document_version(&self, session: &str, buffile: &str) -> Option<i32> {
+        let document = self.documents.get(buffile)?;
+        Some(
+            self.buffer_versions
+                .get(buffile)
+                .and_then(|versions| versions.get(session).copied())
+                .unwrap_or(document.version),
+        )
+    }
+
+    /// Returns the editor sessions that have the buffer open, with the buffer's timestamp in
+    /// each. The buffer session comes first.
+    pub fn buffer_versions(&self, buffile: &str) -> Vec<(SessionId, i32)> {
+        let session = self.buffer_session(buffile);
+        let mut versions: Vec<(SessionId, i32)> = match self.buffer_versions.get(buffile) {
+            Some(versions) => versions
+                .iter()
+                .map(|(session, version)| (session.clone(), *version))
+                .collect(),
+            None => vec![],
+        };
+        versions.sort_by_key(|(other, _)| other != session);
+        if versions.is_empty() {
+            if let Some(document) = self.documents.get(buffile) {
+                versions.push((session.to_string(), document.version));
+            }
+        }
+        versions
+    }
+
+    /// Returns the state of the client of `meta`, creating it if needed. Requests without a
+    /// client share one state.
+    pub fn client_state(&mut self, meta: &EditorMeta) -> &mut ClientState {
+        self.clients
+            .entry((
+                meta.session.clone(),
+                meta.client.clone().unwrap_or_default(),
+            ))
+            .or_default()
+    }
+
     pub fn meta_for_buffer_version(
         &self,
         client: Option<String>,
@@ -279,6 +661,7 @@ impl Context {
         version: i32,
     ) -> EditorMeta {
         let mut meta = self.meta_for_session(client);
+        meta.session = self.buffer_session(buffile).to_string();
         meta.buffile = buffile.to_string();
         meta.version = version;
         meta
@@ -2,26 +2,47 @@ use std::borrow::Cow;
 
 use crate::capabilities;
 use crate::capabilities::initialize;
+use crate::command_output;
 use crate::context::*;
+use crate::custom_requests;
+use crate::diagnostic_commands::{self, CommandResult};
 use crate::diagnostics;
+use crate::edit_conflict;
+use crate::edit_preview;
+use crate::editor_transport::INVALID_UTF8_METHOD;
+use crate::info_box::{self, InfoBoxOwner};
 use crate::language_features::{selection_range, *};
-use crate::language_server_transport;
+use crate::language_server_transport::{self, ServerOutput};
+use crate::large_file;
+use crate::middleware;
+use crate::modified_lines::ModifiedLines;
 use crate::progress;
+use crate::resource_usage::ResourceUsage;
+use crate::server_cache;
+use crate::session::is_passive;
+use crate::session_state;
 use crate::text_sync::*;
 use crate::types::*;
 use crate::util::*;
 use crate::workspace;
-use crossbeam_channel::{select, Receiver, Sender};
-use jsonrpc_core::{Call, ErrorCode, MethodCall, Output, Params};
+use crossbeam_channel::{after, never, select, Receiver, Sender};
+use itertools::Itertools;
+use jsonrpc_core::{Call, ErrorCode, Failure, Id, MethodCall, Params};
 use lsp_types::notification::Notification;
 use lsp_types::request::Request;
 use lsp_types::*;
 use serde::Serialize;
+use serde_json::value::RawValue;
+use std::collections::HashMap;
+use std::time::Instant;
 
 // This is an error code defined by the language server protocol, signifying that a request was
 // cancelled because the content changed before it could be fulfilled. In this case, the user
 // should not be notified.
 const CONTENT_MODIFIED: i64 = -32801;
+// The error code of requests that were cancelled by kak-lsp, like completions that a newer
+// keystroke made stale.
+const REQUEST_CANCELLED: i64 = -32800;
 
 /// Start controller.
 ///
@@ -30,39 +51,98 @@ const CONTENT_MODIFIED: i64 = -32801;
 /// responses back to editor.
 pub fn start(
     to_editor: Sender<EditorResponse>,
+    to_session: Sender<EditorRequest>,
     from_editor: Receiver<EditorRequest>,
     route: &Route,
     initial_request: EditorRequest,
     config: Config,
 ) {
+    let mut request = initial_request;
+    let mut documents = HashMap::default();
+    let server_output = ServerOutput::default();
+    loop {
+        match run(
+            to_editor.clone(),
+            to_session.clone(),
+            &from_editor,
+            route,
+            request,
+            config.clone(),
+            documents,
+            server_output.clone(),
+        ) {
+            RunResult::Done => return,
+            RunResult::Restart(restart_request, open_documents) => {
+                info!("Restarting {} language server", route.language);
+                request = restart_request;
+                documents = open_documents;
+            }
+            RunResult::Failed(meta, err, open_documents) => {
+                match wait_for_retry(
+                    &to_editor,
+                    &from_editor,
+                    meta,
+                    &err,
+                    open_documents,
+                    route,
+                    &server_output,
+                ) {
+                    Some((retry_request, open_documents)) => {
+                        info!("Retrying to start {} language server", route.language);
+                        request = retry_request;
+                        documents = open_documents;
+                    }
+                    None => return,
+                }
+            }
+        }
+    }
+}
+
+enum RunResult {
+    /// The editor went away.
+    Done,
+    /// The server needs to be restarted, carries the request that triggered the restart and the
+    /// documents to reopen.
+    Restart(EditorRequest, HashMap<String, Document>),
+    /// The server could not be started or exited unexpectedly, carries the error message and the
+    /// documents to reopen once it is started again.
+    Failed(EditorMeta, String, HashMap<String, Document>),
+}
+
+/// Run a language server until the editor goes away, or the server needs to be restarted.
+#[allow(clippy::too_many_arguments)]
+fn run(
+    to_editor: Sender<EditorResponse>,
+    to_session: Sender<EditorRequest>,
+    from_editor: &Receiver<EditorRequest>,
+    route: &Route,
+    initial_request: EditorRequest,
+    config: Config,
+    mut documents: HashMap<String, Document>,
+    server_output: ServerOutput,
+) -> RunResult {
     let lang_srv: language_server_transport::LanguageServerTransport;
     let offset_encoding;
+    let command_line;
     {
         // should be fine to unwrap because request was already routed which means language is configured
         let lang = &config.language[&route.language];
         offset_encoding = lang.offset_encoding;
-        lang_srv = match language_server_transport::start(&lang.command, &lang.args, &lang.envs) {
+        command_line = std::iter::once(&lang.command).chain(&lang.args).join(" ");
+        lang_srv = match language_server_transport::start(
+            &lang.command,
+            &lang.args,
+            &lang.envs,
+            server_output.clone(),
+        ) {
             Ok(ls) => ls,
             Err(err) => {
-                error!("failed to start language server: {}", err);
-                // If the server command isn't from a hook (e.g. auto-hover),
-                // then send a prominent error to the editor.
-                if !initial_request.meta.hook {
-                    let command = format!(
-                        "lsp-show-error {}",
-                        editor_quote(&format!("failed to start language server: {}", err)),
-                    );
-                    if to_editor
-                        .send(EditorResponse {
-                            meta: initial_request.meta,
-                            command: Cow::from(command),
-                        })
-                        .is_err()
-                    {
-                        error!("Failed to send command to editor");
-                    }
-                }
-                return;
+                let err = format!(
+                    "failed to start {} language server `{}`: {}",
+                    route.language, command_line, err
+                );
+                return RunResult::Failed(initial_request.meta, err, documents);
             }
         }
     }
@@ -76,132 +156,495 @@ pub fn start(
         initial_request,
         lang_srv.to_lang_server.sender().clone(),
         to_editor,
+        to_session,
         config,
         route.root.clone(),
         offset_encoding,
     );
+    ctx.server_output = server_output;
+    ctx.resource_usage = Some(ResourceUsage::new(lang_srv.pid));
+    // After kak-lsp itself was restarted, reopen the buffers that were open before.
+    if documents.is_empty() {
+        if let Some((restored_documents, diagnostics, command_diagnostics)) =
+            session_state::load(&ctx)
+        {
+            documents = restored_documents;
+            documents.retain(|buffile, _| {
+                !ctx.pending_requests.iter().any(|request| {
+                    request.method == notification::DidOpenTextDocument::METHOD
+                        && request.meta.buffile == *buffile
+                })
+            });
+            ctx.diagnostics = diagnostics;
+            ctx.command_diagnostics = command_diagnostics;
+        }
+    }
+    // After a restart, tell the new server about the buffers that were open in the old one.
+    let reopen_requests: Vec<EditorRequest> = documents
+        .into_iter()
+        .map(|(buffile, document)| reopen_request(&ctx, &buffile, document))
+        .collect();
+    ctx.pending_requests.splice(0..0, reopen_requests);
 
     initialize(&route.root, initial_request_meta.clone(), &mut ctx);
 
-    'event_loop: loop {
-        select! {
-            recv(from_editor) -> msg => {
-                if msg.is_err() {
-                    break 'event_loop;
-                }
-                let msg = msg.unwrap();
-                // initialize request must be first request from client to language server
-                // initialized response contains capabilities which we save for future use
-                // capabilities also serve as a marker of completing initialization
-                // we park all requests from editor before initialization is complete
-                // and then dispatch them
-                if ctx.capabilities.is_some() {
-                    dispatch_editor_request(msg, &mut ctx);
-                } else {
-                    debug!("Language server is not initialized, parking request");
-                    {
-                        let method: &str = &msg.method;
-                        match method {
-                            notification::DidOpenTextDocument::METHOD => (),
-                            notification::DidChangeTextDocument::METHOD => (),
-                            notification::DidCloseTextDocument::METHOD => (),
-                            notification::DidSaveTextDocument::METHOD => (),
-                            // TODO if auto-hover or auto-hl-references is not enabled we might want warning about parking as well
-                            request::HoverRequest::METHOD => (),
-                            request::CodeActionRequest::METHOD => (),
-                            request::DocumentHighlightRequest::METHOD => (),
-                            _ => ctx.exec(
-                                msg.meta.clone(),
-                                "lsp-show-error 'language server is not initialized, parking request'"
-                                    .to_string(),
-                            ),
-                        }
-                    }
-                    ctx.pending_requests.push(msg);
+    // Whether the editor asked the server to exit, so it is expected to go away.
+    let mut exiting = false;
+    let mut next_orphans_check = Instant::now() + ORPHANED_DOCUMENTS_CHECK_INTERVAL;
+    loop {
+        match next_event(
+            &ctx,
+            next_orphans_check,
+            from_editor,
+            lang_srv.from_lang_server.receiver(),
+        ) {
+            Event::DiagnosticsDue => diagnostics::flush_pending_diagnostics(&mut ctx),
+            Event::OrphansCheckDue => {
+                next_orphans_check = Instant::now() + ORPHANED_DOCUMENTS_CHECK_INTERVAL;
+                close_orphaned_documents(&mut ctx);
+            }
+            Event::StaleRequestsDue => report_stale_requests(&mut ctx),
+            Event::SessionStateDue => session_state::store(&mut ctx),
+            Event::CompletionDue => completion::completion_due(&mut ctx),
+            Event::DiagnosticCommandsDone(results) => {
+                diagnostic_commands::commands_done(results, &mut ctx)
+            }
+            Event::ModifiedLinesDone(result) => diagnostics::modified_lines_done(result, &mut ctx),
+            Event::HoverProvidersDone(outputs) => hover::hover_providers_done(outputs, &mut ctx),
+            Event::FormatterDone(result) => formatting::formatter_done(result, &mut ctx),
+            Event::Editor(request) => {
+                if request.method == notification::Exit::METHOD {
+                    exiting = true;
                 }
+                handle_editor_request(request, &mut ctx);
             }
-            recv(lang_srv.from_lang_server.receiver()) -> msg => {
-                if msg.is_err() {
-                    break 'event_loop;
+            Event::EditorGone => break,
+            Event::Server(message) => {
+                handle_server_message(message, &initial_request_meta, &mut ctx)
+            }
+            Event::ServerGone => {
+                if exiting {
+                    break;
                 }
-                let msg = msg.unwrap();
-                match msg {
-                    ServerMessage::Request(call) => {
-                        match call {
-                            Call::MethodCall(request) => {
-                              dispatch_server_request(request, &mut ctx);
-                            }
-                            Call::Notification(notification) => {
-                                dispatch_server_notification(
-                                    initial_request_meta.clone(),
-                                    &notification.method,
-                                    notification.params,
-                                    &mut ctx,
-                                );
-                            }
-                            Call::Invalid {id} => {
-                                error!("Invalid call from language server: {:?}", id);
-                            }
-                        }
-                    }
-                    ServerMessage::Response(output) => {
-                        match output {
-                            Output::Success(success) => {
-                                if let Some((meta, _, batch_id)) = ctx.response_waitlist.remove(&success.id) {
-                                    if meta.write_response_to_fifo {
-                                        write_response_to_fifo(meta, &success);
-                                        continue;
-                                    }
-                                    if let Some((batch_amt, mut vals, callback)) = ctx.batches.remove(&batch_id) {
-                                        vals.push(success.result);
-                                        if batch_amt == 1 {
-                                            callback(&mut ctx, meta, vals);
-                                        } else {
-                                            ctx.batches.insert(batch_id, (batch_amt - 1, vals, callback));
-                                        }
-                                    }
-                                } else {
-                                    error!("Id {:?} is not in waitlist!", success.id);
-                                }
-                            }
-                            Output::Failure(failure) => {
-                                error!("Error response from server: {:?}", failure);
-                                if let Some(request) = ctx.response_waitlist.remove(&failure.id) {
-                                    let (meta, method, _) = request;
-                                    if meta.write_response_to_fifo {
-                                        write_response_to_fifo(meta, failure);
-                                        continue;
-                                    }
-                                    match failure.error.code {
-                                        code if code == ErrorCode::ServerError(CONTENT_MODIFIED) || method == request::CodeActionRequest::METHOD => {
-                                            // Nothing to do, but sending command back to the editor is required to handle case when
-                                            // editor is blocked waiting for response via fifo.
-                                            ctx.exec(meta, "nop".to_string());
-                                        },
-                                        code => {
-                                            let msg = match code {
-                                                ErrorCode::MethodNotFound => format!(
-                                                    "{} language server doesn't support method {}",
-                                                    ctx.language_id, method
-                                                ),
-                                                _ => format!(
-                                                    "{} language server error: {}",
-                                                    ctx.language_id, editor_quote(&failure.error.message)
-                                                ),
-                                            };
-                                            ctx.exec(meta, format!("lsp-show-error {}", editor_quote(&msg)));
-                                        }
-                                    }
-                                } else {
-                                    error!("Id {:?} is not in waitlist!", failure.id);
-                                }
-                            }
-                        }
+                let err = if ctx.capabilities.is_none() {
+                    format!(
+                        "{} language server `{}` exited during initialization, see the kak-lsp log for its error output",
+                        route.language, command_line
+                    )
+                } else {
+                    format!(
+                        "{} language server `{}` exited unexpectedly",
+                        route.language, command_line
+                    )
+                };
+                // Requests parked during initialization would leave their sender waiting.
+                for request in std::mem::take(&mut ctx.pending_requests) {
+                    if let Some(fifo) = request.meta.fifo {
+                        let command = format!("lsp-show-error {}", editor_quote(&err));
+                        let _ = std::fs::write(fifo, command);
                     }
                 }
+                return RunResult::Failed(
+                    initial_request_meta,
+                    err,
+                    std::mem::take(&mut ctx.documents),
+                );
+            }
+        }
+        if let Some(request) = ctx.restart_request.take() {
+            ctx.notify::<notification::Exit>(());
+            return RunResult::Restart(request, std::mem::take(&mut ctx.documents));
+        }
+    }
+    RunResult::Done
+}
+
+/// Something the controller of a language server reacts to. Timers are events too, so that all
+/// state lives in the `Context` and is only touched by the handler of one event at a time.
+enum Event {
+    /// Pending diagnostics are due to be shown, see `diagnostics_update_interval_ms`.
+    DiagnosticsDue,
+    /// Some requests were not answered for `STALE_REQUEST_TIMEOUT`.
+    StaleRequestsDue,
+    /// Time to look for documents whose buffers are gone.
+    OrphansCheckDue,
+    /// Time to write the changed session state.
+    SessionStateDue,
+    /// Completion requests are due to be sent, see `completion_coalesce_window_ms`, or completion
+    /// menus to be shown without the servers that did not answer yet.
+    CompletionDue,
+    /// The `diagnostic_commands` finished, with their output.
+    DiagnosticCommandsDone(Vec<CommandResult>),
+    /// The modified lines of a buffer were computed, see `lsp-diagnostics-modified-lines-enable`.
+    ModifiedLinesDone(ModifiedLines),
+    /// The hover providers finished, with their output, see `hover_providers`.
+    HoverProvidersDone(Vec<Option<String>>),
+    /// The external formatter finished, with the formatted text, see `formatter`.
+    FormatterDone(Result<String, String>),
+    Editor(EditorRequest),
+    /// The editor session ended.
+    EditorGone,
+    Server(IncomingMessage),
+    /// The language server exited.
+    ServerGone,
+}
+
+/// Waits for the next event from the editor, the language server or a timer.
+fn next_event(
+    ctx: &Context,
+    next_orphans_check: Instant,
+    from_editor: &Receiver<EditorRequest>,
+    from_lang_server: &Receiver<IncomingMessage>,
+) -> Event {
+    let diagnostics_timeout = match diagnostics::next_pending_diagnostics_update(ctx) {
+        Some(deadline) => after(deadline.saturating_duration_since(Instant::now())),
+        None => never(),
+    };
+    let stale_requests_timeout = match next_stale_request(ctx) {
+        Some(deadline) => after(deadline.saturating_duration_since(Instant::now())),
+        None => never(),
+    };
+    let completion_timeout = match completion::next_completion_due(ctx) {
+        Some(deadline) => after(deadline.saturating_duration_since(Instant::now())),
+        None => never(),
+    };
+    let session_state_timeout = match ctx.session_state_due {
+        Some(deadline) => after(deadline.saturating_duration_since(Instant::now())),
+        None => never(),
+    };
+    let diagnostic_commands = match &ctx.running_diagnostic_commands {
+        Some(running) => running.receiver.clone(),
+        None => never(),
+    };
+    let hover_providers = match &ctx.running_hover_providers {
+        Some(running) => running.receiver.clone(),
+        None => never(),
+    };
+    let formatter = match &ctx.running_formatter {
+        Some(running) => running.receiver.clone(),
+        None => never(),
+    };
+    select! {
+        recv(diagnostics_timeout) -> _ => Event::DiagnosticsDue,
+        recv(stale_requests_timeout) -> _ => Event::StaleRequestsDue,
+        recv(after(next_orphans_check.saturating_duration_since(Instant::now()))) -> _ => Event::OrphansCheckDue,
+        recv(session_state_timeout) -> _ => Event::SessionStateDue,
+        recv(completion_timeout) -> _ => Event::CompletionDue,
+        recv(diagnostic_commands) -> msg => Event::DiagnosticCommandsDone(msg.unwrap_or_default()),
+        recv(hover_providers) -> msg => Event::HoverProvidersDone(msg.unwrap_or_default()),
+        recv(formatter) -> msg => Event::FormatterDone(
+            msg.unwrap_or_else(|_| Err("the formatter thread died".to_string())),
+        ),
+        // The context holds a sender, so the channel is never disconnected.
+        recv(ctx.modified_lines_channel.1) -> msg => Event::ModifiedLinesDone(msg.unwrap()),
+        recv(from_editor) -> msg => msg.map_or(Event::EditorGone, Event::Editor),
+        recv(from_lang_server) -> msg => msg.map_or(Event::ServerGone, Event::Server),
+    }
+}
+
+/// Returns when the oldest request that was not reported as stale yet becomes stale.
+fn next_stale_request(ctx: &Context) -> Option<Instant> {
+    ctx.response_waitlist
+        .values()
+        .filter(|request| !request.reported_stale)
+        .map(|request| request.sent + STALE_REQUEST_TIMEOUT)
+        .min()
+}
+
+/// Logs requests that the server did not answer in time, once each. They are kept, in case the
+/// server answers after all.
+fn report_stale_requests(ctx: &mut Context) {
+    let now = Instant::now();
+    let language_id = &ctx.language_id;
+    for (id, request) in ctx.response_waitlist.iter_mut() {
+        if request.reported_stale || now < request.sent + STALE_REQUEST_TIMEOUT {
+            continue;
+        }
+        request.reported_stale = true;
+        warn!(
+            "{} language server did not answer {} request {:?} in {}s, it was probably dropped",
+            language_id,
+            request.method,
+            id,
+            now.duration_since(request.sent).as_secs()
+        );
+    }
+}
+
+fn handle_editor_request(request: EditorRequest, ctx: &mut Context) {
+    // initialize request must be first request from client to language server
+    // initialized response contains capabilities which we save for future use
+    // capabilities also serve as a marker of completing initialization
+    // we park all requests from editor before initialization is complete
+    // and then dispatch them
+    if ctx.capabilities.is_some() {
+        dispatch_editor_request(request, ctx);
+    } else {
+        debug!("Language server is not initialized, parking request");
+        // Say why explicit requests take a while. Lazily started servers show their warm-up
+        // status instead.
+        if !is_passive(&request)
+            && request.meta.client.is_some()
+            && !ctx.language_config().lazy_start
+        {
+            let message = format!(
+                "kak-lsp: {} language server is still initializing, the request is sent once it is ready",
+                ctx.language_id
+            );
+            ctx.exec(
+                request.meta.clone(),
+                format!("echo {}", editor_quote(&message)),
+            );
+        }
+        ctx.pending_requests.push(request);
+    }
+}
+
+fn handle_server_message(message: IncomingMessage, meta: &EditorMeta, ctx: &mut Context) {
+    match message {
+        IncomingMessage::Request(Call::MethodCall(request)) => {
+            dispatch_server_request(request, ctx);
+        }
+        IncomingMessage::Request(Call::Notification(notification)) => {
+            // A shared server reports to the session that used it last.
+            let meta = EditorMeta {
+                session: ctx.session.clone(),
+                ..meta.clone()
+            };
+            dispatch_server_notification(meta, &notification.method, notification.params, ctx);
+        }
+        IncomingMessage::Request(Call::Invalid { id }) => {
+            error!("Invalid call from language server: {:?}", id);
+            if id != Id::Null {
+                ctx.reply(id, Err(jsonrpc_core::Error::invalid_request()));
+            }
+        }
+        IncomingMessage::Response(RawOutput::Success(success)) => {
+            handle_server_success(success, ctx);
+        }
+        IncomingMessage::Response(RawOutput::Failure(failure)) => {
+            handle_server_failure(failure, ctx);
+        }
+    }
+}
+
+/// Hands the result of a request to its callback, once all requests of its batch are answered.
+fn handle_server_success(mut success: RawSuccess, ctx: &mut Context) {
+    let OutstandingRequest {
+        meta,
+        method,
+        batch_id,
+        batch_index,
+        ..
+    } = match ctx.response_waitlist.remove(&success.id) {
+        Some(request) => request,
+        None => {
+            error!("Id {:?} is not in waitlist!", success.id);
+            return;
+        }
+    };
+    success.result =
+        middleware::rewrite_result(&ctx.language_config().middleware, &method, success.result);
+    if meta.write_response_to_fifo {
+        write_response_to_fifo(meta, &success);
+        return;
+    }
+    complete_batch_request(batch_id, batch_index, Some(success.result), meta, ctx);
+}
+
+/// Stores the result of one request of a batch, and runs the batch's callback once it was the
+/// last one.
+fn complete_batch_request(
+    batch_id: usize,
+    batch_index: usize,
+    result: Option<Box<RawValue>>,
+    meta: EditorMeta,
+    ctx: &mut Context,
+) {
+    let batch = match ctx.batches.get_mut(&batch_id) {
+        Some(batch) => batch,
+        None => return,
+    };
+    batch.results[batch_index] = result;
+    batch.remaining -= 1;
+    if batch.remaining == 0 {
+        let batch = ctx.batches.remove(&batch_id).unwrap();
+        (batch.callback)(ctx, meta, batch.results);
+    }
+}
+
+fn handle_server_failure(failure: Failure, ctx: &mut Context) {
+    error!("Error response from server: {:?}", failure);
+    let OutstandingRequest {
+        meta,
+        method,
+        batch_id,
+        batch_index,
+        ..
+    } = match ctx.response_waitlist.remove(&failure.id) {
+        Some(request) => request,
+        None => {
+            error!("Id {:?} is not in waitlist!", failure.id);
+            return;
+        }
+    };
+    if matches!(ctx.batches.get(&batch_id), Some(batch) if batch.lenient) {
+        complete_batch_request(batch_id, batch_index, None, meta, ctx);
+        return;
+    }
+    // The batch's callback needs all results, so it will never run. Drop it, and cancel the
+    // other requests of the batch.
+    ctx.batches.remove(&batch_id);
+    let siblings: Vec<Id> = ctx
+        .response_waitlist
+        .iter()
+        .filter(|(_, request)| request.batch_id == batch_id)
+        .map(|(id, _)| id.clone())
+        .collect();
+    for id in siblings {
+        ctx.response_waitlist.remove(&id);
+        let id = match id {
+            Id::Num(id) => NumberOrString::Number(id as i32),
+            Id::Str(id) => NumberOrString::String(id),
+            Id::Null => continue,
+        };
+        ctx.notify::<notification::Cancel>(CancelParams { id });
+    }
+    if method == request::ExecuteCommand::METHOD {
+        ctx.executing_command = None;
+        command_output::command_failed(&failure.error.message, ctx);
+    }
+    if meta.write_response_to_fifo {
+        write_response_to_fifo(meta, failure);
+        return;
+    }
+    if failure.error.code == ErrorCode::MethodNotFound {
+        ctx.unsupported_methods.insert(method.clone());
+    }
+    if method == request::DocumentSymbolRequest::METHOD {
+        document_symbol::document_symbols_failed(&meta, ctx);
+    }
+    match failure.error.code {
+        code if code == ErrorCode::ServerError(CONTENT_MODIFIED)
+            || code == ErrorCode::ServerError(REQUEST_CANCELLED)
+            || method == request::CodeActionRequest::METHOD =>
+        {
+            // Nothing to do, but sending command back to the editor is required to handle case when
+            // editor is blocked waiting for response via fifo.
+            ctx.exec(meta, "nop".to_string());
+        }
+        code => {
+            let msg = match code {
+                ErrorCode::MethodNotFound => format!(
+                    "{} language server doesn't support method {}",
+                    ctx.language_id, method
+                ),
+                _ => format!(
+                    "{} language server error: {}",
+                    ctx.language_id,
+                    editor_quote(&failure.error.message)
+                ),
+            };
+            info_box::claim(&meta, InfoBoxOwner::Message);
+            ctx.exec(meta, format!("lsp-show-error {}", editor_quote(&msg)));
+        }
+    }
+}
+
+/// After a language server failed, report the error and reject requests until the user runs
+/// `lsp-retry-server`, presumably after installing or fixing the server. Returns the retry
+/// request and the documents to open in the new server, or None if the editor went away.
+fn wait_for_retry(
+    to_editor: &Sender<EditorResponse>,
+    from_editor: &Receiver<EditorRequest>,
+    meta: EditorMeta,
+    err: &str,
+    mut documents: HashMap<String, Document>,
+    route: &Route,
+    server_output: &ServerOutput,
+) -> Option<(EditorRequest, HashMap<String, Document>)> {
+    error!("{}", err);
+    let send_command = |mut meta: EditorMeta, command: String| {
+        if let Some(fifo) = meta.fifo.take() {
+            // The editor is blocked waiting for a response.
+            std::fs::write(fifo, command).expect("Failed to write command to fifo");
+            return;
+        }
+        let response = EditorResponse {
+            meta,
+            command: Cow::from(command),
+        };
+        if to_editor.send(response).is_err() {
+            error!("Failed to send command to editor");
+        }
+    };
+    let send = |meta: EditorMeta, message: &str| {
+        send_command(meta, format!("lsp-show-error {}", editor_quote(message)))
+    };
+    // Report the failure once, even if it was triggered by a hook.
+    send(
+        meta,
+        &format!("{}. Run lsp-retry-server to start it again", err),
+    );
+    for request in from_editor {
+        match request.method.as_str() {
+            "kak-lsp/retry-server" => return Some((request, documents)),
+            notification::DidCloseTextDocument::METHOD => {
+                documents.remove(&request.meta.buffile);
             }
+            notification::Exit::METHOD => return None,
+            "kak-lsp/show-server-output" => send_command(
+                request.meta,
+                show_server_output_command(&route.language, server_output),
+            ),
+            _ if request.meta.hook && request.meta.fifo.is_none() => (),
+            notification::DidOpenTextDocument::METHOD
+            | notification::DidChangeTextDocument::METHOD
+            | notification::DidSaveTextDocument::METHOD => (),
+            _ => send(
+                request.meta,
+                &format!("{}. Run lsp-retry-server to start it again", err),
+            ),
         }
     }
+    None
+}
+
+/// Returns the command to show the recent stderr of a language server in a scratch buffer.
+fn show_server_output_command(language: &str, server_output: &ServerOutput) -> String {
+    let output = server_output.lock().unwrap();
+    let content = if output.is_empty() {
+        format!("{} language server has not written to stderr\n", language)
+    } else {
+        format!(
+            "{} language server stderr:\n{}\n",
+            language,
+            output.iter().join("\n")
+        )
+    };
+    format!("lsp-show-server-output-buffer {}", editor_quote(&content))
+}
+
+fn reopen_request(ctx: &Context, buffile: &str, document: Document) -> EditorRequest {
+    let mut params = toml::value::Table::default();
+    params.insert(
+        "draft".to_string(),
+        toml::Value::String(document.text.to_string()),
+    );
+    params.insert(
+        "language_id".to_string(),
+        toml::Value::String(document.language_id),
+    );
+    params.insert(
+        "secondary".to_string(),
+        toml::Value::Boolean(document.secondary),
+    );
+    EditorRequest {
+        meta: ctx.meta_for_buffer_version(None, buffile, document.version),
+        method: notification::DidOpenTextDocument::METHOD.to_string(),
+        params: toml::Value::Table(params),
+        ranges: None,
+    }
 }
 
 pub fn write_response_to_fifo<T: Serialize>(meta: EditorMeta, response: T) {
@@ -219,6 +662,29 @@ pub fn dispatch_pending_editor_requests(ctx: &mut Context) {
 }
 
 fn dispatch_editor_request(request: EditorRequest, ctx: &mut Context) {
+    if request.method == INVALID_UTF8_METHOD {
+        return reject_invalid_utf8(request.meta, ctx);
+    }
+    if ctx.invalid_utf8_buffers.contains(&request.meta.buffile)
+        && request.method != notification::DidChangeTextDocument::METHOD
+        && request.method != notification::DidCloseTextDocument::METHOD
+    {
+        debug!(
+            "Ignoring {} for {}, which is not valid UTF-8",
+            request.method, request.meta.buffile
+        );
+        return;
+    }
+    // Not from the editor, so the buffer is not opened for it.
+    if request.method == "kak-lsp/secondary-diagnostics" {
+        return diagnostics::receive_secondary_diagnostics(request.meta, request.params, ctx);
+    }
+    if request.method == "kak-lsp/secondary-completion" {
+        return completion::receive_secondary_completion(request.meta, request.params, ctx);
+    }
+    if request.method != "kak-lsp/session-closed" {
+        ctx.session = request.meta.session.clone();
+    }
     ensure_did_open(&request, ctx);
     let meta = request.meta;
     let params = request.params;
@@ -237,9 +703,17 @@ fn dispatch_editor_request(request: EditorRequest, ctx: &mut Context) {
         notification::DidSaveTextDocument::METHOD => {
             text_document_did_save(meta, ctx);
         }
+        "kak-lsp/session-closed" => {
+            close_session(meta, ctx);
+        }
         notification::DidChangeConfiguration::METHOD => {
             workspace::did_change_configuration(meta, params, ctx);
         }
+        "kak-lsp/client-close" => {
+            if let Some(client) = meta.client {
+                ctx.clients.remove(&(meta.session, client));
+            }
+        }
         request::CallHierarchyPrepare::METHOD => {
             call_hierarchy::call_hierarchy_prepare(meta, params, ctx);
         }
@@ -261,6 +735,27 @@ fn dispatch_editor_request(request: EditorRequest, ctx: &mut Context) {
         request::GotoDefinition::METHOD => {
             goto::text_document_definition(meta, params, ctx);
         }
+        "kak-lsp/definition-selections" => {
+            all_selections::definition_selections(meta, params, ctx);
+        }
+        "kak-lsp/hover-selections" => {
+            all_selections::hover_selections(meta, params, ctx);
+        }
+        "kak-lsp/diagnostic-commands" => {
+            diagnostic_commands::run(meta, ctx);
+        }
+        "kak-lsp/rename-prompt" => {
+            rename::rename_prompt(meta, params, ctx);
+        }
+        "kak-lsp/search-docs" => {
+            search_docs::search_docs(meta, params, ctx);
+        }
+        "kak-lsp/review" => {
+            review::review_request(meta, params, ctx);
+        }
+        "kak-lsp/pick-color" => {
+            color::pick_color(meta, params, ctx);
+        }
         request::GotoImplementation::METHOD => {
             goto::text_document_implementation(meta, params, ctx);
         }
@@ -288,9 +783,56 @@ fn dispatch_editor_request(request: EditorRequest, ctx: &mut Context) {
         request::DocumentSymbolRequest::METHOD => {
             document_symbol::text_document_document_symbol(meta, ctx);
         }
+        "kak-lsp/document-symbols-option" => {
+            document_symbol::document_symbols_option(meta, ctx);
+        }
         "kak-lsp/next-or-previous-symbol" => {
             document_symbol::next_or_prev_symbol(meta, params, ctx);
         }
+        "kak-lsp/rehydrate" => {
+            // Sent when kak-lsp is restarted, the documents were reopened on startup.
+        }
+        "kak-lsp/retry-server" | "kak-lsp/start-detected-server" => {
+            let command = format!(
+                "echo {}",
+                editor_quote(&format!(
+                    "kak-lsp: started {} language server",
+                    ctx.language_id
+                ))
+            );
+            ctx.exec(meta, command);
+        }
+        "kak-lsp/show-server-output" => {
+            let command = show_server_output_command(&ctx.language_id, &ctx.server_output);
+            ctx.exec(meta, command);
+        }
+        "kak-lsp/import-symbol" => {
+            completion::import_symbol(meta, params, ctx);
+        }
+        "kak-lsp/confirm-edit" => {
+            edit_preview::confirm_edit(meta, params, ctx);
+        }
+        "kak-lsp/resolve-edit-conflict" => {
+            edit_conflict::resolve(meta, params, ctx);
+        }
+        "kak-lsp/prefetch" => {
+            prefetch::prefetch(meta, params, ctx);
+        }
+        "kak-lsp/goto-file" => {
+            goto::goto_file(meta, params, ctx);
+        }
+        "kak-lsp/definition-of-symbol" => {
+            goto::definition_of_symbol(meta, params, ctx);
+        }
+        "kak-lsp/inlay-hint-goto" => {
+            inlay_hints::inlay_hint_goto(meta, params, ctx);
+        }
+        "kak-lsp/inlay-hint-tooltip" => {
+            inlay_hints::inlay_hint_tooltip(meta, params, ctx);
+        }
+        "kak-lsp/large-file-mode-disable" => {
+            large_file::large_file_mode_disable(meta, ctx);
+        }
         "kak-lsp/object" => {
             document_symbol::object(meta, params, ctx);
         }
@@ -309,26 +851,45 @@ fn dispatch_editor_request(request: EditorRequest, ctx: &mut Context) {
         request::WorkspaceSymbol::METHOD => {
             workspace::workspace_symbol(meta, params, ctx);
         }
+        "kak-lsp/workspace-symbol-candidates" => {
+            workspace::workspace_symbol_candidates(meta, params, ctx);
+        }
         request::Rename::METHOD => {
             rename::text_document_rename(meta, params, ctx);
         }
+        "kak-lsp/explain-error" => {
+            hover::explain_error(meta, params, ctx);
+        }
+        "kak-lsp/diagnostics-export" => {
+            diagnostics::diagnostics_export(meta, params, ctx);
+        }
+        "kak-lsp/diagnostics-modified-lines" => {
+            diagnostics::diagnostics_modified_lines(meta, params, ctx);
+        }
         "textDocument/diagnostics" => {
             diagnostics::editor_diagnostics(meta, ctx);
         }
         "capabilities" => {
             capabilities::capabilities(meta, ctx);
         }
+        "status" => {
+            server_cache::status(meta, ctx);
+        }
         "apply-workspace-edit" => {
             workspace::apply_edit_from_editor(meta, params, ctx);
         }
         request::SemanticTokensFullRequest::METHOD => {
-            semantic_tokens::tokens_request(meta, ctx);
+            semantic_tokens::tokens_request(meta, params, ctx);
         }
 
         request::InlayHintRequest::METHOD => {
             inlay_hints::inlay_hints(meta, params, ctx);
         }
 
+        inline_value::InlineValueRequest::METHOD => {
+            inline_value::inline_values(meta, params, ctx);
+        }
+
         // CCLS
         ccls::NavigateRequest::METHOD => {
             ccls::navigate(meta, params, ctx);
@@ -356,10 +917,42 @@ fn dispatch_editor_request(request: EditorRequest, ctx: &mut Context) {
             eclipse_jdt_ls::organize_imports(meta, ctx);
         }
 
+        "kak-lsp/customRequest" => {
+            custom_requests::custom_request(meta, params, ctx);
+        }
+        "kak-lsp/replyServerRequest" => {
+            custom_requests::reply_server_request(meta, params, ctx);
+        }
+
+        // eslint
+        "eslint/applyAllFixes" => {
+            eslint::apply_all_fixes(meta, ctx);
+        }
+
         // rust-analyzer
         rust_analyzer::ExpandMacroRequest::METHOD => {
             rust_analyzer::expand_macro(meta, params, ctx);
         }
+        rust_analyzer::RunFlycheck::METHOD => {
+            rust_analyzer::run_flycheck(meta, ctx);
+        }
+        rust_analyzer::CancelFlycheck::METHOD => {
+            rust_analyzer::cancel_flycheck(meta, ctx);
+        }
+        rust_analyzer::ReloadWorkspace::METHOD => {
+            rust_analyzer::reload_workspace(meta, ctx);
+        }
+        rust_analyzer::ParentModule::METHOD => {
+            rust_analyzer::parent_module(meta, params, ctx);
+        }
+        rust_analyzer::OpenCargoToml::METHOD => {
+            rust_analyzer::open_cargo_toml(meta, ctx);
+        }
+
+        // terraform-ls
+        "terraform-ls/moduleCommand" => {
+            terraform_ls::module_command(meta, params, ctx);
+        }
 
         // texlab
         texlab::Build::METHOD => {
@@ -369,6 +962,11 @@ fn dispatch_editor_request(request: EditorRequest, ctx: &mut Context) {
             texlab::forward_search(meta, params, ctx);
         }
 
+        // yaml-language-server
+        "yaml-language-server/setSchema" => {
+            yaml_language_server::set_schema(meta, params, ctx);
+        }
+
         _ => {
             warn!("Unsupported method: {}", method);
         }
@@ -378,9 +976,17 @@ fn dispatch_editor_request(request: EditorRequest, ctx: &mut Context) {
 fn dispatch_server_request(request: MethodCall, ctx: &mut Context) {
     let method: &str = &request.method;
     let result = match method {
-        request::ApplyWorkspaceEdit::METHOD => {
-            workspace::apply_edit_from_server(request.params, ctx)
-        }
+        request::ApplyWorkspaceEdit::METHOD => match edit_preview::previewed_command(ctx) {
+            Some(meta) => {
+                // Replied to once the user applies or discards the edit.
+                edit_preview::preview_edit(request.id, meta, request.params, ctx);
+                return;
+            }
+            None => {
+                workspace::apply_edit_from_server(request.id, request.params, ctx);
+                return;
+            }
+        },
         request::RegisterCapability::METHOD => {
             let params: RegistrationParams = request
                 .params
@@ -392,6 +998,11 @@ fn dispatch_server_request(request: MethodCall, ctx: &mut Context) {
                 if registration.method == notification::DidChangeWorkspaceFolders::METHOD {
                     continue;
                 }
+                // We always send settings changes, registering just tells us that they are read.
+                if registration.method == notification::DidChangeConfiguration::METHOD {
+                    ctx.did_change_configuration_support = true;
+                    continue;
+                }
                 warn!("Unsupported registration: {}", registration.method);
             }
             Ok(serde_json::Value::Null)
@@ -408,11 +1019,30 @@ fn dispatch_server_request(request: MethodCall, ctx: &mut Context) {
             progress::work_done_progress_create(request.params, ctx)
         }
         request::WorkspaceConfiguration::METHOD => workspace::configuration(request.params, ctx),
+        request::CodeLensRefresh::METHOD
+        | request::SemanticTokensRefresh::METHOD
+        | request::InlayHintRefreshRequest::METHOD => workspace::refresh(method, ctx),
+        request::ShowMessageRequest::METHOD => {
+            // Replied to once the user picks an action.
+            custom_requests::show_message_request(request.id, request.params, ctx);
+            return;
+        }
+        "eslint/confirmESLintExecution" => {
+            // Replied to once the user picks an answer.
+            eslint::confirm_execution(request.id, request.params, ctx);
+            return;
+        }
+        "eslint/openDoc" | "eslint/noConfig" | "eslint/noLibrary" | "eslint/probeFailed" => {
+            Ok(eslint::server_request(method, request.params, ctx))
+        }
+        _ if custom_requests::forwards_request(ctx, method) => {
+            let method = method.to_string();
+            custom_requests::forward_server_request(request.id, &method, request.params, ctx);
+            return;
+        }
         _ => {
-            warn!("Unsupported method: {}", method);
-            Err(jsonrpc_core::Error::new(
-                jsonrpc_core::ErrorCode::MethodNotFound,
-            ))
+            warn!("Unsupported request from language server: {}", method);
+            Err(jsonrpc_core::Error::method_not_found())
         }
     };
 
@@ -427,12 +1057,6 @@ fn dispatch_server_notification(meta: EditorMeta, method: &str, params: Params,
         notification::PublishDiagnostics::METHOD => {
             diagnostics::publish_diagnostics(params, ctx);
         }
-        "$cquery/publishSemanticHighlighting" => {
-            cquery::publish_semantic_highlighting(params, ctx);
-        }
-        "$ccls/publishSemanticHighlight" => {
-            ccls::publish_semantic_highlighting(params, ctx);
-        }
         notification::Exit::METHOD => {
             debug!("Language server exited");
         }
@@ -459,6 +1083,7 @@ fn dispatch_server_notification(meta: EditorMeta, method: &str, params: Params,
             let params: LogMessageParams = params
                 .parse()
                 .expect("Failed to parse LogMessageParams params");
+            command_output::write(&params.message, ctx);
             ctx.exec(
                 meta,
                 format!("lsp-show-message-log {}", editor_quote(&params.message)),
@@ -467,12 +1092,41 @@ fn dispatch_server_notification(meta: EditorMeta, method: &str, params: Params,
         "telemetry/event" => {
             debug!("{:?}", params);
         }
-        _ => {
-            warn!("Unsupported method: {}", method);
+        // Let the user override the built-in handlers of custom notifications.
+        _ if custom_requests::forwards_notification(ctx, method) => {
+            custom_requests::forward_server_notification(meta, method, params, ctx);
         }
+        _ => match NOTIFICATION_HANDLERS
+            .iter()
+            .find(|(name, _)| *name == method)
+        {
+            Some((_, handler)) => handler(meta, params, ctx),
+            None => warn!("Unsupported method: {}", method),
+        },
     }
 }
 
+type NotificationHandler = fn(EditorMeta, Params, &mut Context);
+
+/// Handlers of server-specific notifications that are not part of the LSP spec.
+const NOTIFICATION_HANDLERS: &[(&str, NotificationHandler)] = &[
+    (
+        "$cquery/publishSemanticHighlighting",
+        cquery::publish_semantic_highlighting,
+    ),
+    (
+        "$ccls/publishSemanticHighlight",
+        ccls::publish_semantic_highlighting,
+    ),
+    ("eslint/status", eslint::status),
+    ("metals/status", metals::status),
+    ("rust-analyzer/serverStatus", rust_analyzer::server_status),
+    (
+        "$/typescriptVersion",
+        typescript_language_server::typescript_version,
+    ),
+];
+
 /// Ensure that textDocument/didOpen is sent for the given buffer before any other request, if possible.
 ///
 /// kak-lsp tries to not bother Kakoune side of the plugin with bookkeeping status of kak-lsp server
@@ -487,18 +1141,24 @@ fn dispatch_server_notification(meta: EditorMeta, method: &str, params: Params,
 /// most of the time in `if buffile.is_empty() || ctx.documents.contains_key(buffile)` condition.
 fn ensure_did_open(request: &EditorRequest, ctx: &mut Context) {
     let buffile = &request.meta.buffile;
-    if buffile.is_empty() || ctx.documents.contains_key(buffile) {
+    if buffile.is_empty()
+        || ctx.documents.contains_key(buffile)
+        || request.method == notification::DidCloseTextDocument::METHOD
+    {
         return;
     };
     if request.method == notification::DidChangeTextDocument::METHOD {
         return text_document_did_open(request.meta.clone(), request.params.clone(), ctx);
     }
-    match read_document(buffile) {
+    match read_utf8_document(buffile) {
         Ok(draft) => {
             let mut params = toml::value::Table::default();
             params.insert("draft".to_string(), toml::Value::String(draft));
             text_document_did_open(request.meta.clone(), toml::Value::Table(params), ctx);
         }
+        Err(err) if err.kind() == std::io::ErrorKind::InvalidData => {
+            reject_invalid_utf8(request.meta.clone(), ctx)
+        }
         Err(err) => error!(
             "Failed to read file {} to simulate textDocument/didOpen: {}",
             buffile, err
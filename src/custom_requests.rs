@@ -0,0 +1,183 @@
+//! Pass-through of requests and notifications that kak-lsp does not know about, for servers
+//! that need the user to take part in custom protocols, like an authentication handshake.
+
+use crate::context::*;
+use crate::types::*;
+use crate::util::*;
+use jsonrpc_core::{Id, Params};
+use lsp_types::*;
+use serde::Deserialize;
+use serde_json::Value;
+
+#[derive(Deserialize, Debug)]
+struct CustomRequestParams {
+    method: String,
+    #[serde(default)]
+    params: String,
+}
+
+/// Send a request with arbitrary method and parameters, and show the response.
+pub fn custom_request(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
+    let params = CustomRequestParams::deserialize(params).unwrap();
+    let req_params = match parse_json_param(&params.params) {
+        Ok(req_params) => req_params,
+        Err(err) => {
+            let msg = format!("invalid parameters for {}: {}", params.method, err);
+            ctx.exec(meta, format!("lsp-show-error {}", editor_quote(&msg)));
+            return;
+        }
+    };
+    let req_params = match req_params {
+        Value::Null => Params::None,
+        Value::Array(params) => Params::Array(params),
+        Value::Object(params) => Params::Map(params),
+        _ => {
+            let msg = format!(
+                "parameters for {} must be an object or array",
+                params.method
+            );
+            ctx.exec(meta, format!("lsp-show-error {}", editor_quote(&msg)));
+            return;
+        }
+    };
+    let method = params.method;
+    let title = method.clone();
+    ctx.call_method(meta, &method, req_params, move |ctx, meta, result| {
+        let result = serde_json::to_string_pretty(&result).unwrap();
+        let command = format!(
+            "info -title {} -- {}",
+            editor_quote(&title),
+            editor_quote(&result)
+        );
+        ctx.exec(meta, command);
+    });
+}
+
+fn parse_json_param(param: &str) -> Result<Value, serde_json::Error> {
+    if param.trim().is_empty() {
+        Ok(Value::Null)
+    } else {
+        serde_json::from_str(param)
+    }
+}
+
+pub fn id_to_string(id: &Id) -> String {
+    match id {
+        Id::Num(n) => n.to_string(),
+        Id::Str(s) => s.clone(),
+        Id::Null => "null".to_string(),
+    }
+}
+
+/// Whether the user configured this server-to-client request to be handled by the editor.
+pub fn forwards_request(ctx: &Context, method: &str) -> bool {
+    ctx.config.language[&ctx.language_id]
+        .forward_server_requests
+        .iter()
+        .any(|m| m == method)
+}
+
+/// Whether the user configured this server-to-client notification to be handled by the editor.
+pub fn forwards_notification(ctx: &Context, method: &str) -> bool {
+    ctx.config.language[&ctx.language_id]
+        .forward_server_notifications
+        .iter()
+        .any(|m| m == method)
+}
+
+/// Hand a server request to the editor. The server gets its response once the user runs
+/// `lsp-reply-server-request`.
+pub fn forward_server_request(id: Id, method: &str, params: Params, ctx: &mut Context) {
+    let id_string = id_to_string(&id);
+    ctx.pending_server_requests.insert(id_string.clone(), id);
+    let params: Value = params.into();
+    let command = format!(
+        "lsp-handle-server-request {} {} {}",
+        editor_quote(method),
+        editor_quote(&id_string),
+        editor_quote(&serde_json::to_string_pretty(&params).unwrap()),
+    );
+    let meta = ctx.meta_for_session(None);
+    ctx.exec(meta, command);
+}
+
+pub fn forward_server_notification(
+    meta: EditorMeta,
+    method: &str,
+    params: Params,
+    ctx: &mut Context,
+) {
+    let params: Value = params.into();
+    let command = format!(
+        "lsp-handle-server-notification {} {}",
+        editor_quote(method),
+        editor_quote(&serde_json::to_string_pretty(&params).unwrap()),
+    );
+    ctx.exec(meta, command);
+}
+
+/// Let the user pick one of the actions of a `window/showMessageRequest`.
+pub fn show_message_request(id: Id, params: Params, ctx: &mut Context) {
+    let params: ShowMessageRequestParams = match params.parse() {
+        Ok(params) => params,
+        Err(err) => {
+            ctx.reply(id, Err(err));
+            return;
+        }
+    };
+    let id_string = id_to_string(&id);
+    ctx.pending_server_requests.insert(id_string.clone(), id);
+    let actions = params
+        .actions
+        .unwrap_or_default()
+        .into_iter()
+        .map(|action| {
+            let result = serde_json::to_string(&action).unwrap();
+            format!(
+                "{} {}",
+                editor_quote(&action.title),
+                editor_quote(&format!(
+                    "lsp-reply-server-request {} {}",
+                    editor_quote(&id_string),
+                    editor_quote(&result)
+                ))
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(" ");
+    let command = format!(
+        "lsp-show-message-request {} {} {}",
+        editor_quote(&id_string),
+        editor_quote(&params.message),
+        actions
+    );
+    let meta = ctx.meta_for_session(None);
+    ctx.exec(meta, command);
+}
+
+#[derive(Deserialize, Debug)]
+struct ReplyServerRequestParams {
+    id: String,
+    #[serde(default)]
+    result: String,
+}
+
+pub fn reply_server_request(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
+    let params = ReplyServerRequestParams::deserialize(params).unwrap();
+    let id = match ctx.pending_server_requests.remove(&params.id) {
+        Some(id) => id,
+        None => {
+            let msg = format!("no pending server request with id {}", params.id);
+            ctx.exec(meta, format!("lsp-show-error {}", editor_quote(&msg)));
+            return;
+        }
+    };
+    match parse_json_param(&params.result) {
+        Ok(result) => ctx.reply(id, Ok(result)),
+        Err(err) => {
+            ctx.pending_server_requests.insert(params.id, id);
+            let msg = format!("invalid result: {}", err);
+            ctx.exec(meta, format!("lsp-show-error {}", editor_quote(&msg)));
+        }
+    }
+}
@@ -0,0 +1,298 @@
+//! Diagnostics from commands like test runners and linters, for languages whose servers don't run
+//! them. The `diagnostic_commands` of the language run in the background in the project root, and
+//! each match of their `pattern` in the output becomes a diagnostic. They are shown together with
+//! the server's diagnostics, until the commands run again.
+
+use crate::context::*;
+use crate::diagnostics;
+use crate::position::*;
+use crate::session_state;
+use crate::types::*;
+use crate::util::*;
+use crossbeam_channel::{bounded, Receiver};
+use lsp_types::*;
+use regex::Regex;
+use ropey::Rope;
+use std::collections::HashMap;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::thread;
+
+/// The commands running in the background, and the request that started them.
+pub struct RunningDiagnosticCommands {
+    meta: EditorMeta,
+    pub receiver: Receiver<Vec<CommandResult>>,
+}
+
+/// The output of a command, stdout followed by stderr, or why it could not run.
+pub struct CommandResult {
+    command: DiagnosticCommand,
+    output: Result<String, String>,
+}
+
+/// Runs the language's diagnostic commands in a background thread. Their diagnostics replace the
+/// ones of the previous run once all of them are done.
+pub fn run(meta: EditorMeta, ctx: &mut Context) {
+    if ctx.running_diagnostic_commands.is_some() {
+        ctx.exec(
+            meta,
+            "lsp-show-error 'diagnostic commands are still running'",
+        );
+        return;
+    }
+    let commands = ctx.language_config().diagnostic_commands.clone();
+    if commands.is_empty() {
+        let msg = format!("no diagnostic_commands configured for {}", ctx.language_id);
+        ctx.exec(meta, format!("lsp-show-error {}", editor_quote(&msg)));
+        return;
+    }
+    let root_path = ctx.root_path.clone();
+    let (sender, receiver) = bounded(1);
+    thread::spawn(move || {
+        let results = commands
+            .into_iter()
+            .map(|command| {
+                let output = run_command(&command, &root_path);
+                CommandResult { command, output }
+            })
+            .collect();
+        let _ = sender.send(results);
+    });
+    ctx.running_diagnostic_commands = Some(RunningDiagnosticCommands { meta, receiver });
+}
+
+fn run_command(command: &DiagnosticCommand, root_path: &str) -> Result<String, String> {
+    debug!("Running diagnostic command `{}`", command.command);
+    let output = Command::new(&command.command)
+        .args(&command.args)
+        .current_dir(root_path)
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|err| format!("failed to run `{}`: {}", command.command, err))?;
+    // Test runners fail if a test fails, which is what we are looking for.
+    let mut text = String::from_utf8_lossy(&output.stdout).into_owned();
+    text.push_str(&String::from_utf8_lossy(&output.stderr));
+    Ok(text)
+}
+
+/// Replaces the diagnostics of the previous run by the ones found in the output of the commands.
+pub fn commands_done(results: Vec<CommandResult>, ctx: &mut Context) {
+    let meta = match ctx.running_diagnostic_commands.take() {
+        Some(running) => running.meta,
+        None => return,
+    };
+    let mut errors = vec![];
+    let mut found: HashMap<String, Vec<Diagnostic>> = HashMap::new();
+    for result in results {
+        let output = match result.output {
+            Ok(output) => output,
+            Err(err) => {
+                errors.push(err);
+                continue;
+            }
+        };
+        let matches = match parse_output(&result.command, &output) {
+            Ok(matches) => matches,
+            Err(err) => {
+                errors.push(err);
+                continue;
+            }
+        };
+        for (file, diagnostic) in matches {
+            let path = Path::new(&ctx.root_path).join(file);
+            let buffile = path.to_str().unwrap_or_default().to_string();
+            found.entry(buffile).or_default().push(diagnostic);
+        }
+    }
+    for (buffile, diagnostics) in &mut found {
+        if let Some(text) = get_file_contents(buffile, ctx) {
+            for diagnostic in diagnostics {
+                diagnostic.range = byte_range_to_lsp(&diagnostic.range, &text, ctx.offset_encoding);
+            }
+        }
+    }
+
+    let count: usize = found.values().map(Vec::len).sum();
+    let previous = std::mem::replace(&mut ctx.command_diagnostics, found);
+    let buffiles: Vec<String> = previous
+        .into_keys()
+        .chain(ctx.command_diagnostics.keys().cloned())
+        .collect();
+    session_state::schedule_store(ctx);
+    for buffile in buffiles {
+        diagnostics::schedule_update_diagnostics(&buffile, ctx);
+    }
+
+    let command = if errors.is_empty() {
+        format!(
+            "echo -markup {}",
+            editor_quote(&format!(
+                "{{Information}}{} diagnostics from commands",
+                count
+            ))
+        )
+    } else {
+        format!("lsp-show-error {}", editor_quote(&errors.join("\n")))
+    };
+    ctx.exec(meta, command);
+}
+
+/// Converts a range whose columns count bytes, like in the output of commands, to the server's
+/// offset encoding.
+fn byte_range_to_lsp(range: &Range, text: &Rope, offset_encoding: OffsetEncoding) -> Range {
+    let convert = |position: &Position| {
+        let position = KakounePosition {
+            line: position.line + 1,
+            column: position.character + 1,
+        };
+        kakoune_position_to_lsp(&position, text, offset_encoding)
+    };
+    let start = convert(&range.start);
+    let mut end = convert(&range.end);
+    // A range of one byte of a multibyte character must still cover that character.
+    if end <= start {
+        end = Position::new(start.line, start.character + 1);
+    }
+    Range { start, end }
+}
+
+/// Finds the diagnostics in the output of a command, with the file they are about, as given in
+/// the output. Lines and columns in the output start at 1, and columns count bytes; the returned
+/// ranges are 0-based, but still count bytes.
+fn parse_output(
+    command: &DiagnosticCommand,
+    output: &str,
+) -> Result<Vec<(String, Diagnostic)>, String> {
+    let pattern = Regex::new(&command.pattern)
+        .map_err(|err| format!("invalid pattern of `{}`: {}", command.command, err))?;
+    let number = |captures: &regex::Captures, name: &str| {
+        captures
+            .name(name)
+            .and_then(|m| m.as_str().parse::<u32>().ok())
+            .map(|n| n.saturating_sub(1))
+    };
+    let default_severity = command
+        .severity
+        .as_deref()
+        .and_then(parse_severity)
+        .unwrap_or(DiagnosticSeverity::ERROR);
+    let mut diagnostics = vec![];
+    for captures in pattern.captures_iter(output) {
+        let (file, line) = match (captures.name("file"), number(&captures, "line")) {
+            (Some(file), Some(line)) => (file.as_str().to_string(), line),
+            _ => continue,
+        };
+        let start = Position {
+            line,
+            character: number(&captures, "column").unwrap_or(0),
+        };
+        // Without an end, the diagnostic covers the character at the column, or the whole line.
+        let end = match (
+            number(&captures, "end_line"),
+            number(&captures, "end_column"),
+        ) {
+            (end_line, Some(end_column)) => Position {
+                line: end_line.unwrap_or(line),
+                character: end_column + 1,
+            },
+            _ if captures.name("column").is_some() => Position {
+                line,
+                character: start.character + 1,
+            },
+            _ => Position {
+                line: line + 1,
+                character: 0,
+            },
+        };
+        let severity = captures
+            .name("severity")
+            .and_then(|severity| parse_severity(severity.as_str()))
+            .unwrap_or(default_severity);
+        let message = captures
+            .name("message")
+            .map(|message| message.as_str().trim().to_string())
+            .unwrap_or_default();
+        diagnostics.push((
+            file,
+            Diagnostic {
+                range: Range { start, end },
+                severity: Some(severity),
+                source: Some(command.command.clone()),
+                message,
+                ..Diagnostic::default()
+            },
+        ));
+    }
+    Ok(diagnostics)
+}
+
+/// Understands severities like "error", "Warning", "note" or "I".
+fn parse_severity(severity: &str) -> Option<DiagnosticSeverity> {
+    match severity.chars().next()?.to_ascii_lowercase() {
+        'e' | 'f' => Some(DiagnosticSeverity::ERROR),
+        'w' => Some(DiagnosticSeverity::WARNING),
+        'i' | 'n' => Some(DiagnosticSeverity::INFORMATION),
+        'h' => Some(DiagnosticSeverity::HINT),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use indoc::indoc;
+
+    #[test]
+    fn test_parse_output() {
+        let command = DiagnosticCommand {
+            command: "pytest".to_string(),
+            args: vec![],
+            pattern: r"(?m)^(?P<file>[^:\s]+):(?P<line>\d+):((?P<column>\d+):)? (?P<severity>\w+): (?P<message>.*)$".to_string(),
+            severity: None,
+        };
+        let output = indoc! {"
+            collected 2 items
+            tests/test_a.py:12: Error: assert 1 == 2
+            src/a.py:3:5: warning: unused import
+        "};
+        let diagnostics = parse_output(&command, output).unwrap();
+        assert_eq!(diagnostics.len(), 2);
+        let (file, diagnostic) = &diagnostics[0];
+        assert_eq!(file, "tests/test_a.py");
+        assert_eq!(diagnostic.message, "assert 1 == 2");
+        assert_eq!(diagnostic.severity, Some(DiagnosticSeverity::ERROR));
+        assert_eq!(diagnostic.range.start, Position::new(11, 0));
+        assert_eq!(diagnostic.range.end, Position::new(12, 0));
+        let (file, diagnostic) = &diagnostics[1];
+        assert_eq!(file, "src/a.py");
+        assert_eq!(diagnostic.severity, Some(DiagnosticSeverity::WARNING));
+        assert_eq!(diagnostic.range.start, Position::new(2, 4));
+        assert_eq!(diagnostic.range.end, Position::new(2, 5));
+
+        let command = DiagnosticCommand {
+            pattern: "(".to_string(),
+            ..command
+        };
+        assert!(parse_output(&command, output).is_err());
+    }
+
+    #[test]
+    fn test_byte_range_to_lsp() {
+        let text = Rope::from_str("let é = 1;\n");
+        // The column of `=` counts the two bytes of `é`.
+        let range = Range::new(Position::new(0, 7), Position::new(0, 8));
+        assert_eq!(
+            byte_range_to_lsp(&range, &text, OffsetEncoding::Utf16),
+            Range::new(Position::new(0, 6), Position::new(0, 7))
+        );
+        assert_eq!(
+            byte_range_to_lsp(&range, &text, OffsetEncoding::Utf8),
+            range
+        );
+        let range = Range::new(Position::new(0, 4), Position::new(0, 5));
+        assert_eq!(
+            byte_range_to_lsp(&range, &text, OffsetEncoding::Utf16),
+            Range::new(Position::new(0, 4), Position::new(0, 5))
+        );
+    }
+}
@@ -1,31 +1,385 @@
 use crate::context::*;
 use crate::controller::write_response_to_fifo;
+use crate::large_file::{self, is_large_file};
 use crate::markup::escape_kakoune_markup;
+use crate::modified_lines::{modified_lines, touches_lines, ModifiedLines};
 use crate::position::*;
+use crate::session_state;
 use crate::types::*;
 use crate::util::*;
 use itertools::EitherOrBoth;
 use itertools::Itertools;
 use jsonrpc_core::Params;
 use lsp_types::*;
+use ropey::Rope;
+use serde::Deserialize;
+use serde_json::{json, Value};
 use std::collections::HashMap;
 use std::fmt::Write as _;
+use std::thread;
+use std::time::{Duration, Instant};
+use url::Url;
+
+/// Files with more diagnostics than this only show the most severe ones, unless the language's
+/// `max_diagnostics_per_file` says otherwise.
+const DEFAULT_MAX_DIAGNOSTICS_PER_FILE: usize = 1000;
+
+pub fn severity_rank(severity: Option<DiagnosticSeverity>) -> DiagnosticSeverity {
+    severity.unwrap_or(DiagnosticSeverity::WARNING)
+}
+
+/// Sorts diagnostics by range and severity, removes duplicates that some servers publish, and
+/// keeps at most `max` of them, the most severe first. Dropped diagnostics are summarized by a
+/// diagnostic at the position of the first one.
+fn normalize_diagnostics(mut diagnostics: Vec<Diagnostic>, max: usize) -> Vec<Diagnostic> {
+    let position_key = |d: &Diagnostic| {
+        (
+            d.range.start.line,
+            d.range.start.character,
+            d.range.end.line,
+            d.range.end.character,
+            severity_rank(d.severity),
+        )
+    };
+    diagnostics.sort_by_key(position_key);
+    let mut normalized: Vec<Diagnostic> = Vec::with_capacity(diagnostics.len());
+    for diagnostic in diagnostics {
+        let duplicate = normalized
+            .iter()
+            .rev()
+            .take_while(|d| position_key(d) == position_key(&diagnostic))
+            .any(|d| *d == diagnostic);
+        if !duplicate {
+            normalized.push(diagnostic);
+        }
+    }
+    if normalized.len() <= max {
+        return normalized;
+    }
+    // Stable, so diagnostics of the same severity stay in order.
+    normalized.sort_by_key(|d| severity_rank(d.severity));
+    let dropped = normalized.split_off(max);
+    let first_dropped = dropped.iter().min_by_key(|d| position_key(d)).unwrap();
+    let summary = Diagnostic {
+        range: Range::new(first_dropped.range.start, first_dropped.range.start),
+        severity: Some(DiagnosticSeverity::INFORMATION),
+        source: Some("kak-lsp".to_string()),
+        message: format!("and {} more diagnostics", dropped.len()),
+        ..Diagnostic::default()
+    };
+    normalized.push(summary);
+    normalized.sort_by_key(position_key);
+    normalized
+}
+
+fn max_diagnostics_per_file(ctx: &Context) -> usize {
+    ctx.language_config()
+        .max_diagnostics_per_file
+        .unwrap_or(DEFAULT_MAX_DIAGNOSTICS_PER_FILE)
+}
+
+/// Returns the diagnostics of a buffer from all their sources: this server, the additional
+/// servers of the buffer and `diagnostic_commands`. The sources are stored separately, so each
+/// one replaces only its own diagnostics.
+fn buffer_diagnostics(ctx: &Context, buffile: &str) -> Vec<Diagnostic> {
+    let own = ctx.diagnostics.get(buffile).into_iter().flatten();
+    let secondary = ctx
+        .secondary_diagnostics
+        .get(buffile)
+        .into_iter()
+        .flat_map(|diagnostics| diagnostics.values().flatten());
+    let commands = ctx.command_diagnostics.get(buffile).into_iter().flatten();
+    normalize_diagnostics(
+        own.chain(secondary).chain(commands).cloned().collect(),
+        max_diagnostics_per_file(ctx),
+    )
+}
+
+/// Returns the files that have diagnostics from any source.
+fn diagnostic_buffiles(ctx: &Context) -> Vec<String> {
+    ctx.diagnostics
+        .keys()
+        .chain(ctx.secondary_diagnostics.keys())
+        .chain(ctx.command_diagnostics.keys())
+        .sorted()
+        .dedup()
+        .cloned()
+        .collect()
+}
 
 pub fn publish_diagnostics(params: Params, ctx: &mut Context) {
     let params: PublishDiagnosticsParams = params.parse().expect("Failed to parse params");
     let path = params.uri.to_file_path().unwrap();
     let buffile = path.to_str().unwrap();
-    ctx.diagnostics
-        .insert(buffile.to_string(), params.diagnostics);
+    let diagnostics = normalize_diagnostics(params.diagnostics, max_diagnostics_per_file(ctx));
+    if matches!(ctx.documents.get(buffile), Some(document) if document.secondary) {
+        send_secondary_diagnostics(buffile, &diagnostics, ctx);
+    }
+    ctx.diagnostics.insert(buffile.to_string(), diagnostics);
+    session_state::schedule_store(ctx);
+    schedule_update_diagnostics(buffile, ctx);
+}
+
+/// Shows the diagnostics of a buffer, or once `diagnostics_update_interval_ms` has passed since
+/// they were last shown.
+pub fn schedule_update_diagnostics(buffile: &str, ctx: &mut Context) {
+    let interval = diagnostics_update_interval(ctx, buffile);
+    if !interval.is_zero() {
+        let now = Instant::now();
+        let too_early = matches!(
+            ctx.diagnostics_last_update.get(buffile),
+            Some(last_update) if now < *last_update + interval
+        );
+        if too_early {
+            ctx.pending_diagnostics.insert(buffile.to_string());
+            return;
+        }
+        ctx.diagnostics_last_update.insert(buffile.to_string(), now);
+    }
+    update_diagnostics(buffile, ctx);
+}
+
+/// Sends the diagnostics of a buffer that this server sees as additional server to the buffer's
+/// primary server, through the session.
+fn send_secondary_diagnostics(buffile: &str, diagnostics: &[Diagnostic], ctx: &Context) {
+    let meta = match ctx.meta_for_buffer(None, buffile) {
+        Some(meta) => meta,
+        None => return,
+    };
+    let mut params = toml::value::Table::default();
+    params.insert(
+        "language_id".to_string(),
+        toml::Value::String(ctx.language_id.clone()),
+    );
+    params.insert(
+        "offset_encoding".to_string(),
+        toml::Value::try_from(ctx.offset_encoding).unwrap(),
+    );
+    params.insert(
+        "diagnostics".to_string(),
+        toml::Value::String(serde_json::to_string(diagnostics).unwrap()),
+    );
+    let request = EditorRequest {
+        meta,
+        method: "kak-lsp/secondary-diagnostics".to_string(),
+        params: toml::Value::Table(params),
+        ranges: None,
+    };
+    if let Err(err) = ctx.session_tx.send(request) {
+        error!("Failed to send diagnostics to the primary server: {}", err);
+    }
+}
+
+/// Receives the diagnostics of an additional server of a buffer, to show them with the buffer's
+/// other diagnostics until that server publishes new ones. Their positions are converted to this
+/// server's offset encoding, and they are labeled with the other server's language unless they
+/// give their source.
+pub fn receive_secondary_diagnostics(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
+    let params = SecondaryDiagnosticsParams::deserialize(params)
+        .expect("Params should follow SecondaryDiagnosticsParams structure");
+    let document = match ctx.documents.get(&meta.buffile) {
+        Some(document) => document,
+        None => return,
+    };
+    let diagnostics: Vec<Diagnostic> = match serde_json::from_str(&params.diagnostics) {
+        Ok(diagnostics) => diagnostics,
+        Err(err) => {
+            error!(
+                "Failed to parse diagnostics of {}: {}",
+                params.language_id, err
+            );
+            return;
+        }
+    };
+    let convert = |position: &Position| {
+        let position = lsp_position_to_kakoune(position, &document.text, params.offset_encoding);
+        kakoune_position_to_lsp(&position, &document.text, ctx.offset_encoding)
+    };
+    let diagnostics: Vec<Diagnostic> = diagnostics
+        .into_iter()
+        .map(|diagnostic| Diagnostic {
+            range: Range::new(
+                convert(&diagnostic.range.start),
+                convert(&diagnostic.range.end),
+            ),
+            source: diagnostic
+                .source
+                .or_else(|| Some(params.language_id.clone())),
+            ..diagnostic
+        })
+        .collect();
+    ctx.secondary_diagnostics
+        .entry(meta.buffile.clone())
+        .or_default()
+        .insert(params.language_id, diagnostics);
+    schedule_update_diagnostics(&meta.buffile, ctx);
+}
+
+fn diagnostics_update_interval(ctx: &Context, buffile: &str) -> Duration {
+    let interval =
+        Duration::from_millis(ctx.config.language[&ctx.language_id].diagnostics_update_interval_ms);
+    if is_large_file(ctx, buffile) {
+        interval.max(large_file::DIAGNOSTICS_UPDATE_INTERVAL)
+    } else {
+        interval
+    }
+}
+
+/// Returns when the next throttled diagnostics update is due, if any.
+pub fn next_pending_diagnostics_update(ctx: &Context) -> Option<Instant> {
+    ctx.pending_diagnostics
+        .iter()
+        .filter_map(|buffile| {
+            let last_update = ctx.diagnostics_last_update.get(buffile)?;
+            Some(*last_update + diagnostics_update_interval(ctx, buffile))
+        })
+        .min()
+}
+
+/// Shows the latest diagnostics of buffers whose throttled update is due.
+pub fn flush_pending_diagnostics(ctx: &mut Context) {
+    let now = Instant::now();
+    let due: Vec<String> = ctx
+        .pending_diagnostics
+        .iter()
+        .filter(|buffile| {
+            !matches!(
+                ctx.diagnostics_last_update.get(*buffile),
+                Some(last_update) if now < *last_update + diagnostics_update_interval(ctx, buffile)
+            )
+        })
+        .cloned()
+        .collect();
+    for buffile in due {
+        ctx.pending_diagnostics.remove(&buffile);
+        ctx.diagnostics_last_update.insert(buffile.clone(), now);
+        update_diagnostics(&buffile, ctx);
+    }
+}
+
+/// Only shows diagnostics on lines modified since the given git revision, or all diagnostics if
+/// no revision is given.
+pub fn diagnostics_modified_lines(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
+    let params = DiagnosticsModifiedLinesParams::deserialize(params)
+        .expect("Params should follow DiagnosticsModifiedLinesParams structure");
+    let base = params.base.filter(|base| !base.is_empty());
+    if let (Some(base), Some(document)) = (&base, ctx.documents.get(&meta.buffile)) {
+        if let Err(err) = modified_lines(&meta.buffile, &document.text, base) {
+            ctx.exec(meta, format!("lsp-show-error {}", editor_quote(&err)));
+            return;
+        }
+    }
+    let message = match &base {
+        Some(base) => format!(
+            "kak-lsp: showing {} diagnostics on lines modified since {}",
+            ctx.language_id, base
+        ),
+        None => format!("kak-lsp: showing all {} diagnostics", ctx.language_id),
+    };
+    ctx.diagnostics_git_base = base;
+    ctx.modified_lines.clear();
+    for buffile in diagnostic_buffiles(ctx) {
+        update_diagnostics(&buffile, ctx);
+    }
+    ctx.exec(meta, format!("echo {}", editor_quote(&message)));
+}
+
+/// Starts computing the lines of a buffer that were modified since the git revision diagnostics
+/// are filtered by, unless they are known for the current version already or being computed.
+/// This runs git, so it happens in a background thread; until it is done, the lines of an older
+/// version are used. Buffers that are not open are read from disk.
+fn refresh_modified_lines(buffile: &str, ctx: &mut Context) {
+    let base = match &ctx.diagnostics_git_base {
+        Some(base) => base.clone(),
+        None => return,
+    };
+    let version = ctx.documents.get(buffile).map(|document| document.version);
+    if matches!(ctx.modified_lines.get(buffile), Some((v, _)) if *v == version)
+        || ctx.modified_lines_pending.contains(buffile)
+    {
+        return;
+    }
+    let text = ctx
+        .documents
+        .get(buffile)
+        .map(|document| document.text.clone());
+    let buffile = buffile.to_string();
+    ctx.modified_lines_pending.insert(buffile.clone());
+    let sender = ctx.modified_lines_channel.0.clone();
+    thread::spawn(move || {
+        let text = match text {
+            Some(text) => Ok(text),
+            None => read_document(&buffile)
+                .map(|text| Rope::from_str(&text))
+                .map_err(|err| err.to_string()),
+        };
+        let lines = text.and_then(|text| modified_lines(&buffile, &text, &base));
+        let _ = sender.send(ModifiedLines {
+            buffile,
+            version,
+            base,
+            lines,
+        });
+    });
+}
+
+/// Stores the modified lines computed in the background, and shows the diagnostics of the buffer
+/// accordingly. Results for a revision that is no longer used are dropped.
+pub fn modified_lines_done(result: ModifiedLines, ctx: &mut Context) {
+    let ModifiedLines {
+        buffile,
+        version,
+        base,
+        lines,
+    } = result;
+    ctx.modified_lines_pending.remove(&buffile);
+    if ctx.diagnostics_git_base != Some(base) {
+        // The revision changed while this was computed, so start over.
+        update_diagnostics(&buffile, ctx);
+        return;
+    }
+    let lines = match lines {
+        Ok(lines) => Some(lines),
+        Err(err) => {
+            warn!("Showing all diagnostics of {}: {}", buffile, err);
+            None
+        }
+    };
+    ctx.modified_lines.insert(buffile.clone(), (version, lines));
+    update_diagnostics(&buffile, ctx);
+}
+
+/// Returns the diagnostics of a buffer that are shown, which are only the ones on modified lines
+/// if diagnostics are filtered by a git revision.
+fn shown_diagnostics(ctx: &Context, buffile: &str) -> Vec<Diagnostic> {
+    let lines = ctx
+        .diagnostics_git_base
+        .as_ref()
+        .and(ctx.modified_lines.get(buffile))
+        .and_then(|(_, lines)| lines.as_ref());
+    let mut diagnostics = buffer_diagnostics(ctx, buffile);
+    if let Some(lines) = lines {
+        diagnostics.retain(|diagnostic| touches_lines(&diagnostic.range, lines));
+    }
+    diagnostics
+}
+
+/// Sends the diagnostics of a buffer to the editor, to update its highlighters and counters.
+pub fn update_diagnostics(buffile: &str, ctx: &mut Context) {
+    refresh_modified_lines(buffile, ctx);
     let document = ctx.documents.get(buffile);
     if document.is_none() {
         return;
     }
     let document = document.unwrap();
-    let version = document.version;
-    let diagnostics = &ctx.diagnostics[buffile];
+    if document.secondary {
+        return;
+    }
+    let thresholds = &ctx.config.diagnostics;
+    let diagnostics = shown_diagnostics(ctx, buffile);
     let inline_diagnostics = diagnostics
         .iter()
+        .filter(|x| thresholds.underline.includes(x.severity))
         .sorted_unstable_by_key(|x| x.severity)
         .rev()
         .map(|x| {
@@ -48,7 +402,10 @@ pub fn publish_diagnostics(params: Params, ctx: &mut Context) {
 
     // Assemble a list of diagnostics by line number
     let mut lines_with_diagnostics = HashMap::new();
-    for diagnostic in diagnostics {
+    for diagnostic in diagnostics
+        .iter()
+        .filter(|x| thresholds.inline.includes(x.severity))
+    {
         let face = match diagnostic.severity {
             Some(DiagnosticSeverity::ERROR) => "InlayDiagnosticError",
             Some(DiagnosticSeverity::HINT) => "InlayDiagnosticHint",
@@ -115,10 +472,12 @@ pub fn publish_diagnostics(params: Params, ctx: &mut Context) {
     let (line_flags, error_count, hint_count, info_count, warning_count) =
         gather_line_flags(ctx, buffile);
 
-    // Always show a space on line one if no other highlighter is there,
-    // to make sure the column always has the right width
-    // Also wrap line_flags in another eval and quotes, to make sure the %opt[] tags are expanded
-    let command = format!(
+    // Every session that has the buffer open shows the diagnostics, tagged with its own timestamp.
+    for (session, version) in ctx.buffer_versions(buffile) {
+        // Always show a space on line one if no other highlighter is there,
+        // to make sure the column always has the right width
+        // Also wrap line_flags in another eval and quotes, to make sure the %opt[] tags are expanded
+        let command = format!(
         "set-option buffer lsp_diagnostic_error_count {error_count}; \
          set-option buffer lsp_diagnostic_hint_count {hint_count}; \
          set-option buffer lsp_diagnostic_info_count {info_count}; \
@@ -127,17 +486,18 @@ pub fn publish_diagnostics(params: Params, ctx: &mut Context) {
          evaluate-commands \"set-option buffer lsp_diagnostic_lines {version} {line_flags} '0|%opt[lsp_diagnostic_line_error_sign]'\"; \
          set-option buffer lsp_inlay_diagnostics {version} {inlay_diagnostics}"
     );
-    let command = format!(
-        "evaluate-commands -buffer {} %§{}§",
-        editor_quote(buffile),
-        command.replace('§', "§§")
-    );
-    let meta = ctx.meta_for_buffer_version(None, buffile, version);
-    ctx.exec(meta, command);
+        let command = format!(
+            "evaluate-commands -buffer {} %§{}§",
+            editor_quote(buffile),
+            command.replace('§', "§§")
+        );
+        let mut meta = ctx.meta_for_buffer_version(None, buffile, version);
+        meta.session = session;
+        ctx.exec(meta, command);
+    }
 }
 
 pub fn gather_line_flags(ctx: &Context, buffile: &str) -> (String, u32, u32, u32, u32) {
-    let diagnostics = ctx.diagnostics.get(buffile);
     let mut error_count: u32 = 0;
     let mut warning_count: u32 = 0;
     let mut info_count: u32 = 0;
@@ -151,33 +511,35 @@ pub fn gather_line_flags(ctx: &Context, buffile: &str) -> (String, u32, u32, u32
         .iter()
         .map(|lens| (lens.range.start.line, "%opt[lsp_code_lens_sign]"));
 
-    let empty = vec![];
-    let diagnostics = diagnostics.unwrap_or(&empty).iter().map(|x| {
-        (
-            x.range.start.line,
-            match x.severity {
-                Some(DiagnosticSeverity::ERROR) => {
-                    error_count += 1;
-                    "{LineFlagError}%opt[lsp_diagnostic_line_error_sign]"
-                }
-                Some(DiagnosticSeverity::HINT) => {
-                    hint_count += 1;
-                    "{LineFlagHint}%opt[lsp_diagnostic_line_hint_sign]"
-                }
-                Some(DiagnosticSeverity::INFORMATION) => {
-                    info_count += 1;
-                    "{LineFlagInfo}%opt[lsp_diagnostic_line_info_sign]"
-                }
-                Some(DiagnosticSeverity::WARNING) | None => {
-                    warning_count += 1;
-                    "{LineFlagWarning}%opt[lsp_diagnostic_line_warning_sign]"
-                }
-                Some(_) => {
-                    warn!("Unexpected DiagnosticSeverity: {:?}", x.severity);
-                    ""
-                }
-            },
-        )
+    // The counters include the diagnostics whose flags are hidden.
+    let gutter = ctx.config.diagnostics.gutter;
+    let diagnostics = shown_diagnostics(ctx, buffile);
+    let diagnostics = diagnostics.iter().filter_map(|x| {
+        let flag = match x.severity {
+            Some(DiagnosticSeverity::ERROR) => {
+                error_count += 1;
+                "{LineFlagError}%opt[lsp_diagnostic_line_error_sign]"
+            }
+            Some(DiagnosticSeverity::HINT) => {
+                hint_count += 1;
+                "{LineFlagHint}%opt[lsp_diagnostic_line_hint_sign]"
+            }
+            Some(DiagnosticSeverity::INFORMATION) => {
+                info_count += 1;
+                "{LineFlagInfo}%opt[lsp_diagnostic_line_info_sign]"
+            }
+            Some(DiagnosticSeverity::WARNING) | None => {
+                warning_count += 1;
+                "{LineFlagWarning}%opt[lsp_diagnostic_line_warning_sign]"
+            }
+            Some(_) => {
+                warn!("Unexpected DiagnosticSeverity: {:?}", x.severity);
+                ""
+            }
+        };
+        gutter
+            .includes(x.severity)
+            .then_some((x.range.start.line, flag))
     });
 
     let line_flags = diagnostics
@@ -200,16 +562,24 @@ pub fn gather_line_flags(ctx: &Context, buffile: &str) -> (String, u32, u32, u32
 }
 
 pub fn editor_diagnostics(meta: EditorMeta, ctx: &mut Context) {
+    let buffiles = diagnostic_buffiles(ctx);
     if meta.write_response_to_fifo {
-        write_response_to_fifo(meta, &ctx.diagnostics);
+        let diagnostics: HashMap<&String, Vec<Diagnostic>> = buffiles
+            .iter()
+            .map(|buffile| (buffile, buffer_diagnostics(ctx, buffile)))
+            .collect();
+        write_response_to_fifo(meta, &diagnostics);
         return;
     }
-    let content = ctx
-        .diagnostics
+    for buffile in &buffiles {
+        refresh_modified_lines(buffile, ctx);
+    }
+    let content = buffiles
         .iter()
-        .flat_map(|(filename, diagnostics)| {
-            diagnostics
-                .iter()
+        .flat_map(|filename| {
+            shown_diagnostics(ctx, filename)
+                .into_iter()
+                .filter(|x| ctx.config.diagnostics.list.includes(x.severity))
                 .map(|x| {
                     let p = match get_kakoune_position(filename, &x.range.start, ctx) {
                         Some(position) => position,
@@ -234,7 +604,7 @@ pub fn editor_diagnostics(meta: EditorMeta, ctx: &mut Context) {
                             }
                         },
                         x.message,
-                        format_related_information(x, ctx).unwrap_or_default()
+                        format_related_information(&x, ctx).unwrap_or_default()
                     )
                 })
                 .collect::<Vec<_>>()
@@ -248,6 +618,177 @@ pub fn editor_diagnostics(meta: EditorMeta, ctx: &mut Context) {
     ctx.exec(meta, command);
 }
 
+/// Writes the diagnostics of all files in the project to a file, as a JSON array or a SARIF log,
+/// for CI annotations and other tools. Lines and columns start at 1; columns count Unicode code
+/// points, whatever the server's offset encoding, and end columns are exclusive.
+pub fn diagnostics_export(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
+    let params = DiagnosticsExportParams::deserialize(params)
+        .expect("Params should follow DiagnosticsExportParams structure");
+    if !std::path::Path::new(&params.path).is_absolute() {
+        let command = format!(
+            "lsp-show-error {}",
+            editor_quote(&format!(
+                "diagnostics export needs an absolute path, got '{}'",
+                params.path
+            ))
+        );
+        ctx.exec(meta, command);
+        return;
+    }
+    let all_diagnostics: Vec<(String, Vec<Diagnostic>)> = diagnostic_buffiles(ctx)
+        .into_iter()
+        .map(|buffile| {
+            let diagnostics = buffer_diagnostics(ctx, &buffile);
+            (buffile, diagnostics)
+        })
+        .collect();
+    let mut diagnostics: Vec<(&String, &Diagnostic)> = all_diagnostics
+        .iter()
+        .flat_map(|(buffile, diagnostics)| diagnostics.iter().map(move |d| (buffile, d)))
+        .collect();
+    diagnostics.sort_by_key(|(buffile, d)| (*buffile, d.range.start));
+    let mut texts: HashMap<&String, Option<Rope>> = HashMap::new();
+    let diagnostics: Vec<(&String, &Diagnostic, ExportRange)> = diagnostics
+        .into_iter()
+        .filter_map(|(buffile, d)| {
+            let text = texts
+                .entry(buffile)
+                .or_insert_with(|| get_file_contents(buffile, ctx))
+                .as_ref();
+            let text = match text {
+                Some(text) => text,
+                None => {
+                    warn!("Not exporting diagnostic of unreadable file {}", buffile);
+                    return None;
+                }
+            };
+            let range = ExportRange {
+                start: export_position(&d.range.start, text, ctx.offset_encoding),
+                end: export_position(&d.range.end, text, ctx.offset_encoding),
+            };
+            Some((buffile, d, range))
+        })
+        .collect();
+    let count = diagnostics.len();
+    let export = match params.format {
+        DiagnosticsExportFormat::Json => Value::Array(
+            diagnostics
+                .into_iter()
+                .map(|(buffile, d, range)| {
+                    json!({
+                        "file": buffile,
+                        "line": range.start.line,
+                        "column": range.start.column,
+                        "endLine": range.end.line,
+                        "endColumn": range.end.column,
+                        "severity": severity_name(d.severity),
+                        "code": d.code,
+                        "source": d.source,
+                        "message": d.message,
+                    })
+                })
+                .collect(),
+        ),
+        DiagnosticsExportFormat::Sarif => {
+            let results = diagnostics
+                .into_iter()
+                .map(|(buffile, d, range)| {
+                    let level = match d.severity {
+                        Some(DiagnosticSeverity::ERROR) => "error",
+                        Some(DiagnosticSeverity::WARNING) | None => "warning",
+                        Some(_) => "note",
+                    };
+                    let rule_id = diagnostic_code(d);
+                    json!({
+                        "ruleId": rule_id,
+                        "level": level,
+                        "message": { "text": d.message },
+                        "locations": [{
+                            "physicalLocation": {
+                                "artifactLocation": {
+                                    "uri": Url::from_file_path(buffile).unwrap(),
+                                },
+                                "region": {
+                                    "startLine": range.start.line,
+                                    "startColumn": range.start.column,
+                                    "endLine": range.end.line,
+                                    "endColumn": range.end.column,
+                                },
+                            },
+                        }],
+                    })
+                })
+                .collect::<Vec<_>>();
+            json!({
+                "$schema": "https://json.schemastore.org/sarif-2.1.0.json",
+                "version": "2.1.0",
+                "runs": [{
+                    "tool": {
+                        "driver": {
+                            "name": ctx.language_config().command,
+                        },
+                    },
+                    "columnKind": "unicodeCodePoints",
+                    "results": results,
+                }],
+            })
+        }
+    };
+    let contents = serde_json::to_string_pretty(&export).unwrap() + "\n";
+    let command = match std::fs::write(&params.path, contents) {
+        Ok(()) => format!(
+            "echo {}",
+            editor_quote(&format!(
+                "kak-lsp: exported {} {} diagnostics to {}",
+                count, ctx.language_id, params.path
+            ))
+        ),
+        Err(err) => format!(
+            "lsp-show-error {}",
+            editor_quote(&format!("failed to write {}: {}", params.path, err))
+        ),
+    };
+    ctx.exec(meta, command);
+}
+
+struct ExportRange {
+    start: KakounePosition,
+    end: KakounePosition,
+}
+
+/// Converts an LSP position to 1-based line and code point column numbers.
+fn export_position(
+    position: &Position,
+    text: &Rope,
+    offset_encoding: OffsetEncoding,
+) -> KakounePosition {
+    let position = lsp_position_to_kakoune(position, text, offset_encoding);
+    let line = get_line(position.line as usize - 1, text);
+    let byte = (position.column as usize - 1).min(line.len_bytes());
+    KakounePosition {
+        line: position.line,
+        column: line.byte_to_char(byte) as u32 + 1,
+    }
+}
+
+pub fn severity_name(severity: Option<DiagnosticSeverity>) -> &'static str {
+    match severity {
+        Some(DiagnosticSeverity::ERROR) => "error",
+        Some(DiagnosticSeverity::HINT) => "hint",
+        Some(DiagnosticSeverity::INFORMATION) => "info",
+        _ => "warning",
+    }
+}
+
+/// The code of a diagnostic, like `E0308`, whether the server sent it as number or string.
+pub fn diagnostic_code(d: &Diagnostic) -> Option<String> {
+    match &d.code {
+        Some(NumberOrString::Number(code)) => Some(code.to_string()),
+        Some(NumberOrString::String(code)) => Some(code.clone()),
+        None => None,
+    }
+}
+
 pub fn format_related_information(d: &Diagnostic, ctx: &Context) -> Option<String> {
     d.related_information.as_ref().map(|infos| {
         "\n".to_string()
@@ -272,3 +813,42 @@ pub fn format_related_information(d: &Diagnostic, ctx: &Context) -> Option<Strin
                 .join("\n")
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn diagnostic(line: u32, severity: DiagnosticSeverity, message: &str) -> Diagnostic {
+        Diagnostic {
+            range: Range::new(Position::new(line, 0), Position::new(line, 1)),
+            severity: Some(severity),
+            message: message.to_string(),
+            ..Diagnostic::default()
+        }
+    }
+
+    #[test]
+    fn test_normalize_diagnostics() {
+        let diagnostics = vec![
+            diagnostic(2, DiagnosticSeverity::WARNING, "b"),
+            diagnostic(1, DiagnosticSeverity::ERROR, "a"),
+            diagnostic(2, DiagnosticSeverity::WARNING, "c"),
+            diagnostic(2, DiagnosticSeverity::WARNING, "b"),
+            diagnostic(3, DiagnosticSeverity::HINT, "d"),
+        ];
+        let messages = |diagnostics: Vec<Diagnostic>| {
+            diagnostics
+                .into_iter()
+                .map(|d| format!("{}:{}", d.range.start.line, d.message))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            messages(normalize_diagnostics(diagnostics.clone(), 10)),
+            vec!["1:a", "2:b", "2:c", "3:d"]
+        );
+        assert_eq!(
+            messages(normalize_diagnostics(diagnostics, 2)),
+            vec!["1:a", "2:and 2 more diagnostics", "2:b"]
+        );
+    }
+}
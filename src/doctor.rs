@@ -0,0 +1,234 @@
+use crate::capabilities::{supported_features, ExtraServerCapabilities};
+use crate::language_server_transport::{self, ServerOutput};
+use crate::types::*;
+use crate::util::find_command;
+use crossbeam_channel::RecvTimeoutError;
+use itertools::Itertools;
+use jsonrpc_core::{Call, Failure, Id, MethodCall, Output, Params, Version};
+use lsp_types::notification::Notification;
+use lsp_types::request::Request;
+use lsp_types::*;
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use std::{env, process};
+use url::Url;
+
+/// How long to wait for a language server to respond to the initialize request.
+const INITIALIZE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Check the configuration and every installed language server, printing a report.
+/// Returns the exit code: 1 if there were errors, 0 otherwise.
+pub fn run(config: &Config, config_path: Option<&Path>) -> i32 {
+    let mut errors = 0;
+    match config_path {
+        Some(path) => println!("config: {}", path.display()),
+        None => println!("config: built-in default"),
+    }
+
+    for problem in check_filetypes(config) {
+        println!("error: {}", problem);
+        errors += 1;
+    }
+
+    let languages: BTreeMap<_, _> = config.language.iter().collect();
+    for (language_id, language) in languages {
+        for warning in check_language(language) {
+            println!("{}: warning: {}", language_id, warning);
+        }
+        let command = match find_command(&language.command) {
+            Some(command) => command,
+            None => {
+                println!("{}: {}: not installed", language_id, language.command);
+                continue;
+            }
+        };
+        match initialize(language) {
+            Ok((server_info, capabilities, extra_capabilities)) => {
+                let server = match server_info {
+                    Some(ServerInfo {
+                        name,
+                        version: Some(version),
+                    }) => format!("{} {}", name, version),
+                    Some(ServerInfo {
+                        name,
+                        version: None,
+                    }) => name,
+                    None => language.command.clone(),
+                };
+                println!("{}: {}: ok ({})", language_id, command.display(), server);
+                println!(
+                    "    {}",
+                    supported_features(&capabilities, &extra_capabilities)
+                        .iter()
+                        // Strip notes like "(mapped to `gd` by default)".
+                        .map(|feature| feature.split(' ').next().unwrap().trim_end_matches(','))
+                        .join(" ")
+                );
+            }
+            Err(err) => {
+                println!("{}: {}: error: {}", language_id, command.display(), err);
+                errors += 1;
+            }
+        }
+    }
+
+    if errors == 0 {
+        0
+    } else {
+        1
+    }
+}
+
+/// Every filetype must map to exactly one language, otherwise the one that is used is arbitrary.
+fn check_filetypes(config: &Config) -> Vec<String> {
+    let mut languages_by_filetype: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+    for (language_id, language) in &config.language {
+        for filetype in &language.filetypes {
+            languages_by_filetype
+                .entry(filetype)
+                .or_default()
+                .push(language_id);
+        }
+    }
+    languages_by_filetype
+        .into_iter()
+        .filter(|(_, languages)| languages.len() > 1)
+        .map(|(filetype, languages)| {
+            format!(
+                "filetype {} is mapped to several languages: {}",
+                filetype,
+                languages.into_iter().sorted().join(", ")
+            )
+        })
+        .collect()
+}
+
+fn check_language(language: &LanguageConfig) -> Vec<String> {
+    let mut warnings = vec![];
+    if language.filetypes.is_empty() {
+        warnings.push("no filetypes, the server will never be used".to_string());
+    }
+    if language.roots.is_empty() {
+        warnings.push("no roots, the project root is the file's directory".to_string());
+    }
+    if let (Some(section), Some(settings)) = (&language.settings_section, &language.settings) {
+        if settings.get(section).is_none() {
+            warnings.push(format!(
+                "settings_section {} is missing from settings",
+                section
+            ));
+        }
+    }
+    warnings
+}
+
+/// Start the server and send it a minimal initialize request.
+fn initialize(
+    language: &LanguageConfig,
+) -> Result<
+    (
+        Option<ServerInfo>,
+        ServerCapabilities,
+        ExtraServerCapabilities,
+    ),
+    String,
+> {
+    let lang_srv = language_server_transport::start(
+        &language.command,
+        &language.args,
+        &language.envs,
+        ServerOutput::default(),
+    )?;
+    let root = env::current_dir().map_err(|err| err.to_string())?;
+    #[allow(deprecated)] // for root_path
+    let params = InitializeParams {
+        process_id: Some(process::id()),
+        root_uri: Url::from_directory_path(&root).ok(),
+        initialization_options: language
+            .settings_section
+            .as_ref()
+            .and_then(|section| language.settings.as_ref()?.get(section).cloned()),
+        client_info: Some(ClientInfo {
+            name: env!("CARGO_PKG_NAME").to_string(),
+            version: Some(env!("CARGO_PKG_VERSION").to_string()),
+        }),
+        root_path: None,
+        capabilities: ClientCapabilities::default(),
+        trace: None,
+        workspace_folders: None,
+        locale: None,
+    };
+    let params = match serde_json::to_value(params).unwrap() {
+        Value::Object(params) => Params::Map(params),
+        _ => unreachable!(),
+    };
+    let id = Id::Num(0);
+    let call = MethodCall {
+        jsonrpc: Some(Version::V2),
+        id: id.clone(),
+        method: request::Initialize::METHOD.to_string(),
+        params,
+    };
+    let send = |message| {
+        lang_srv
+            .to_lang_server
+            .sender()
+            .send(message)
+            .map_err(|_| "failed to send message to language server".to_string())
+    };
+    send(ServerMessage::Request(Call::MethodCall(call)))?;
+
+    let deadline = Instant::now() + INITIALIZE_TIMEOUT;
+    let result = loop {
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        match lang_srv.from_lang_server.receiver().recv_timeout(timeout) {
+            Ok(IncomingMessage::Response(RawOutput::Success(success))) if success.id == id => {
+                break serde_json::from_str::<Value>(success.result.get())
+                    .map_err(|err| format!("failed to parse initialize response: {}", err));
+            }
+            Ok(IncomingMessage::Response(RawOutput::Failure(failure))) if failure.id == id => {
+                break Err(format!("initialize failed: {}", failure.error.message))
+            }
+            // Some servers wait for replies to their requests before finishing initialization.
+            Ok(IncomingMessage::Request(Call::MethodCall(request))) => {
+                send(ServerMessage::Response(Output::Failure(Failure {
+                    jsonrpc: Some(Version::V2),
+                    error: jsonrpc_core::Error::method_not_found(),
+                    id: request.id,
+                })))?;
+            }
+            // Notifications sent during initialization, like progress reports.
+            Ok(_) => continue,
+            Err(RecvTimeoutError::Timeout) => {
+                break Err(format!(
+                    "no response to initialize after {} seconds",
+                    INITIALIZE_TIMEOUT.as_secs()
+                ))
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                break Err("language server exited during initialization".to_string())
+            }
+        }
+    };
+
+    send(ServerMessage::Request(Call::Notification(
+        jsonrpc_core::Notification {
+            jsonrpc: Some(Version::V2),
+            method: notification::Exit::METHOD.to_string(),
+            params: Params::None,
+        },
+    )))
+    .ok();
+
+    let result = result?;
+    let extra_capabilities = result
+        .get("capabilities")
+        .and_then(|capabilities| ExtraServerCapabilities::deserialize(capabilities).ok())
+        .unwrap_or_default();
+    let result = InitializeResult::deserialize(result)
+        .map_err(|err| format!("failed to parse initialize response: {}", err))?;
+    Ok((result.server_info, result.capabilities, extra_capabilities))
+}
@@ -0,0 +1,217 @@
+//! Detection of workspace edits to buffers whose files were changed on disk by another program,
+//! for example a code generator. Applying such an edit to the stale buffer and saving it would
+//! silently revert the other program's changes, so the user is asked to reload the buffers and
+//! discard the edit, apply the edit anyway, or abort it. An edit is only valid for the buffer
+//! versions it was computed for, so it is never applied after the buffers changed.
+
+use crate::context::*;
+use crate::types::*;
+use crate::util::*;
+use crate::workspace::apply_edit_and_write;
+use itertools::Itertools;
+use jsonrpc_core::Id;
+use lsp_types::*;
+use serde::Deserialize;
+use std::fs;
+
+/// An edit that waits for the user's decision, the ID of the server request it came from, the
+/// versions of the buffers it touches when the user was asked, and whether to write them once it
+/// is applied.
+pub struct PendingConflict {
+    id: Option<Id>,
+    edit: WorkspaceEdit,
+    versions: Vec<(String, i32)>,
+    write_buffers: bool,
+}
+
+/// Remembers the modification time of a buffer's file, when the buffer matches the file.
+pub fn record_disk_state(buffile: &str, ctx: &mut Context) {
+    match fs::metadata(buffile).and_then(|metadata| metadata.modified()) {
+        Ok(mtime) => {
+            ctx.disk_mtimes.insert(buffile.to_string(), mtime);
+        }
+        Err(_) => {
+            ctx.disk_mtimes.remove(buffile);
+        }
+    }
+}
+
+/// Returns the files touched by the edit.
+fn edited_files(edit: &WorkspaceEdit) -> Vec<String> {
+    let mut uris: Vec<&Url> = vec![];
+    if let Some(document_changes) = &edit.document_changes {
+        match document_changes {
+            DocumentChanges::Edits(edits) => {
+                uris.extend(edits.iter().map(|edit| &edit.text_document.uri))
+            }
+            DocumentChanges::Operations(ops) => uris.extend(ops.iter().filter_map(|op| match op {
+                DocumentChangeOperation::Edit(edit) => Some(&edit.text_document.uri),
+                DocumentChangeOperation::Op(_) => None,
+            })),
+        }
+    } else if let Some(changes) = &edit.changes {
+        uris.extend(changes.keys());
+    }
+    uris.into_iter()
+        .filter_map(|uri| uri.to_file_path().ok()?.to_str().map(String::from))
+        .unique()
+        .collect()
+}
+
+/// Returns the files touched by the edit that are open in the editor, as opposed to edited on
+/// disk.
+pub fn edited_buffers(edit: &WorkspaceEdit, ctx: &Context) -> Vec<String> {
+    edited_files(edit)
+        .into_iter()
+        .filter(|buffile| ctx.documents.contains_key(buffile))
+        .sorted()
+        .collect()
+}
+
+/// Returns the open buffers touched by the edit whose files were modified since the buffers were
+/// opened or saved, and differ from the buffer content.
+pub fn changed_on_disk(edit: &WorkspaceEdit, ctx: &Context) -> Vec<String> {
+    edited_files(edit)
+        .into_iter()
+        .filter(|buffile| {
+            let document = match ctx.documents.get(buffile) {
+                Some(document) => document,
+                None => return false,
+            };
+            let mtime = fs::metadata(buffile).and_then(|metadata| metadata.modified());
+            match (mtime, ctx.disk_mtimes.get(buffile)) {
+                (Ok(mtime), Some(recorded)) if mtime != *recorded => {
+                    match fs::read_to_string(buffile) {
+                        Ok(contents) => document.text != contents.as_str(),
+                        Err(_) => false,
+                    }
+                }
+                _ => false,
+            }
+        })
+        .sorted()
+        .collect()
+}
+
+/// Keeps the edit until the user decides what to do with the buffers in `buffiles`, and shows
+/// them a menu. If the edit came from a server request, it is replied to after the decision.
+pub fn ask(
+    id: Option<Id>,
+    mut meta: EditorMeta,
+    edit: WorkspaceEdit,
+    buffiles: Vec<String>,
+    write_buffers: bool,
+    ctx: &mut Context,
+) {
+    let key = ctx.pending_conflicts.keys().max().map_or(0, |key| key + 1);
+    let versions = document_versions(&edit, ctx);
+    ctx.pending_conflicts.insert(
+        key,
+        PendingConflict {
+            id,
+            edit,
+            versions,
+            write_buffers,
+        },
+    );
+    let resolve = |action: &str| {
+        format!(
+            "lsp-resolve-edit-conflict {} {} {} {}",
+            key,
+            action,
+            editor_quote(&meta.buffile),
+            editor_quote(&meta.filetype),
+        )
+    };
+    let reload = buffiles
+        .iter()
+        .map(|buffile| format!("evaluate-commands -buffer {} edit!", editor_quote(buffile)))
+        .chain(std::iter::once(resolve("reload")))
+        .join("\n");
+    let files = buffiles
+        .iter()
+        .map(|buffile| short_file_path(buffile, &ctx.root_path))
+        .join("\n");
+    let menu = format!(
+        "lsp-menu {} {} {} {} {} {}",
+        editor_quote("Reload from disk and discard edit"),
+        editor_quote(&reload),
+        editor_quote("Apply edit anyway"),
+        editor_quote(&resolve("apply")),
+        editor_quote("Abort edit"),
+        editor_quote(&resolve("abort")),
+    );
+    meta.fifo = None;
+    meta.command_fifo = None;
+    meta.write_response_to_fifo = false;
+    ctx.exec(
+        meta,
+        format!(
+            "lsp-show-edit-conflict {} {}",
+            editor_quote(&files),
+            editor_quote(&menu)
+        ),
+    );
+}
+
+#[derive(Deserialize)]
+struct ResolveEditConflictParams {
+    id: u64,
+    action: String,
+}
+
+/// Returns the versions of the open buffers touched by the edit.
+fn document_versions(edit: &WorkspaceEdit, ctx: &Context) -> Vec<(String, i32)> {
+    edited_files(edit)
+        .into_iter()
+        .filter_map(|buffile| {
+            let version = ctx.documents.get(&buffile)?.version;
+            Some((buffile, version))
+        })
+        .collect()
+}
+
+/// Applies or aborts an edit that conflicts with changes on disk, and tells the server.
+pub fn resolve(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
+    let params = ResolveEditConflictParams::deserialize(params).unwrap();
+    let PendingConflict {
+        id,
+        edit,
+        versions,
+        write_buffers,
+    } = match ctx.pending_conflicts.remove(&params.id) {
+        Some(pending) => pending,
+        None => {
+            let msg = format!("no pending edit with id {}", params.id);
+            ctx.exec(meta, format!("lsp-show-error {}", editor_quote(&msg)));
+            return;
+        }
+    };
+    let failure_reason = match params.action.as_str() {
+        "apply" if document_versions(&edit, ctx) != versions => {
+            let msg = "buffers changed since the edit was computed, not applying it";
+            ctx.exec(meta, format!("lsp-show-error {}", editor_quote(msg)));
+            msg
+        }
+        "apply" => {
+            let meta = ctx.meta_for_session(None);
+            let response = apply_edit_and_write(meta, edit, write_buffers, ctx);
+            if let Some(id) = id {
+                ctx.reply(id, Ok(serde_json::to_value(response).unwrap()));
+            }
+            return;
+        }
+        // The editor reloaded the buffers before sending this request. The edit was computed for
+        // their old content, so it is discarded; the user can request it again.
+        "reload" => "discarded by the user, files were reloaded from disk",
+        _ => "aborted by the user, files changed on disk",
+    };
+    let response = ApplyWorkspaceEditResponse {
+        applied: false,
+        failure_reason: Some(failure_reason.to_string()),
+        failed_change: None,
+    };
+    if let Some(id) = id {
+        ctx.reply(id, Ok(serde_json::to_value(response).unwrap()));
+    }
+}
@@ -0,0 +1,486 @@
+//! Previews of the edits a language server applies while executing a command, like the assists
+//! of rust-analyzer. For the commands in `preview_edits_of_commands`, the edit is shown as a
+//! unified diff, and the server only gets its `workspace/applyEdit` response once the user
+//! applies or discards it. Edits above the `confirm_edits` thresholds are previewed the same way,
+//! with a summary of how many files and changes they touch.
+
+use crate::context::*;
+use crate::edit_conflict;
+use crate::position::get_file_contents;
+use crate::text_edit::{apply_text_edits_to_text, TextEditish};
+use crate::types::*;
+use crate::util::*;
+use crate::workspace::apply_edit_and_write;
+use jsonrpc_core::{Id, Params};
+use lsp_types::*;
+use ropey::Rope;
+use serde::Deserialize;
+use std::collections::HashSet;
+
+/// Lines of context around each change in the diff.
+const CONTEXT_LINES: usize = 3;
+
+/// An edit from the language server that waits for the user's decision, and the ID of the server
+/// request it came from, if any.
+pub struct PendingEdit {
+    id: Option<Id>,
+    edit: WorkspaceEdit,
+    // Whether to write the buffers the edit touches once it is applied.
+    write_buffers: bool,
+}
+
+/// What a workspace edit changes.
+#[derive(Debug, Default, PartialEq)]
+pub struct EditStats {
+    files: usize,
+    edits: usize,
+    // Creations, renames and deletions of files.
+    file_operations: usize,
+}
+
+impl std::fmt::Display for EditStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} files, {} edits, {} creations/renames/deletions",
+            self.files, self.edits, self.file_operations
+        )
+    }
+}
+
+fn edit_stats(edit: &WorkspaceEdit) -> EditStats {
+    let mut files = HashSet::new();
+    let mut stats = EditStats::default();
+    if let Some(document_changes) = &edit.document_changes {
+        let ops: Vec<DocumentChangeOperation> = match document_changes {
+            DocumentChanges::Edits(edits) => edits
+                .iter()
+                .cloned()
+                .map(DocumentChangeOperation::Edit)
+                .collect(),
+            DocumentChanges::Operations(ops) => ops.clone(),
+        };
+        for op in ops {
+            match op {
+                DocumentChangeOperation::Edit(edit) => {
+                    files.insert(edit.text_document.uri);
+                    stats.edits += edit.edits.len();
+                }
+                DocumentChangeOperation::Op(op) => {
+                    stats.file_operations += 1;
+                    files.insert(match op {
+                        ResourceOp::Create(op) => op.uri,
+                        ResourceOp::Rename(op) => op.old_uri,
+                        ResourceOp::Delete(op) => op.uri,
+                    });
+                }
+            }
+        }
+    } else if let Some(changes) = &edit.changes {
+        for (uri, edits) in changes {
+            files.insert(uri.clone());
+            stats.edits += edits.len();
+        }
+    }
+    stats.files = files.len();
+    stats
+}
+
+/// Returns the edit if it can be applied right away. If it exceeds one of the `confirm_edits`
+/// thresholds, it is shown as diff and kept until the user applies or discards it; a server
+/// request it came from is replied to then, and the buffers are written then if `write_buffers`
+/// is set.
+pub fn confirm_large_edit(
+    id: Option<Id>,
+    mut meta: EditorMeta,
+    edit: WorkspaceEdit,
+    write_buffers: bool,
+    ctx: &mut Context,
+) -> Option<WorkspaceEdit> {
+    let thresholds = match &ctx.language_config().confirm_edits {
+        Some(thresholds) => thresholds.clone(),
+        None => return Some(edit),
+    };
+    let stats = edit_stats(&edit);
+    meta.fifo = None;
+    meta.command_fifo = None;
+    meta.write_response_to_fifo = false;
+    let exceeds =
+        |count: usize, threshold: Option<usize>| matches!(threshold, Some(t) if count > t);
+    if !(exceeds(stats.files, thresholds.files)
+        || exceeds(stats.edits, thresholds.edits)
+        || exceeds(stats.file_operations, thresholds.file_operations))
+    {
+        ctx.exec(
+            meta,
+            format!(
+                "echo -- {}",
+                editor_quote(&format!("Applying edit: {}", stats))
+            ),
+        );
+        return Some(edit);
+    }
+    let diff = format!("# {}\n{}", stats, edit_diff(&edit, ctx));
+    let key = (0..)
+        .map(|i| format!("edit-{}", i))
+        .find(|key| !ctx.pending_edits.contains_key(key))
+        .unwrap();
+    ctx.pending_edits.insert(
+        key.clone(),
+        PendingEdit {
+            id,
+            edit,
+            write_buffers,
+        },
+    );
+    let menu = confirm_menu(&key, "edit", &meta);
+    ctx.exec(
+        meta,
+        format!(
+            "lsp-show-edit-preview {} {}",
+            editor_quote(&diff),
+            editor_quote(&menu)
+        ),
+    );
+    None
+}
+
+fn confirm_menu(key: &str, label: &str, meta: &EditorMeta) -> String {
+    let confirm = |apply: bool| {
+        format!(
+            "lsp-confirm-edit {} {} {} {}",
+            editor_quote(key),
+            apply,
+            editor_quote(&meta.buffile),
+            editor_quote(&meta.filetype),
+        )
+    };
+    format!(
+        "lsp-menu {} {} {} {}",
+        editor_quote(&format!("Apply {}", label)),
+        editor_quote(&confirm(true)),
+        editor_quote(&format!("Discard {}", label)),
+        editor_quote(&confirm(false)),
+    )
+}
+
+/// Returns the editor request of the command being executed, if its edits should be previewed.
+pub fn previewed_command(ctx: &Context) -> Option<EditorMeta> {
+    let (meta, command) = ctx.executing_command.as_ref()?;
+    let preview = ctx
+        .language_config()
+        .preview_edits_of_commands
+        .iter()
+        .any(|pattern| pattern == "*" || pattern == command);
+    if preview {
+        Some(meta.clone())
+    } else {
+        None
+    }
+}
+
+/// Shows the edit of a `workspace/applyEdit` request as diff, and asks whether to apply it.
+pub fn preview_edit(id: Id, mut meta: EditorMeta, params: Params, ctx: &mut Context) {
+    let params: ApplyWorkspaceEditParams = match params.parse() {
+        Ok(params) => params,
+        Err(err) => {
+            ctx.reply(id, Err(err));
+            return;
+        }
+    };
+    let diff = edit_diff(&params.edit, ctx);
+    let id_string = match &id {
+        Id::Num(n) => n.to_string(),
+        Id::Str(s) => s.clone(),
+        Id::Null => "null".to_string(),
+    };
+    ctx.pending_edits.insert(
+        id_string.clone(),
+        PendingEdit {
+            id: Some(id),
+            edit: params.edit,
+            write_buffers: false,
+        },
+    );
+    let label = params.label.unwrap_or_else(|| "edit".to_string());
+    let menu = confirm_menu(&id_string, &label, &meta);
+    meta.fifo = None;
+    meta.command_fifo = None;
+    meta.write_response_to_fifo = false;
+    ctx.exec(
+        meta,
+        format!(
+            "lsp-show-edit-preview {} {}",
+            editor_quote(&diff),
+            editor_quote(&menu)
+        ),
+    );
+}
+
+#[derive(Deserialize)]
+struct ConfirmEditParams {
+    id: String,
+    apply: bool,
+}
+
+/// Applies or discards a previewed edit, and tells the server.
+pub fn confirm_edit(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
+    let params = ConfirmEditParams::deserialize(params).unwrap();
+    let PendingEdit {
+        id,
+        edit,
+        write_buffers,
+    } = match ctx.pending_edits.remove(&params.id) {
+        Some(pending) => pending,
+        None => {
+            let msg = format!("no pending edit with id {}", params.id);
+            ctx.exec(meta, format!("lsp-show-error {}", editor_quote(&msg)));
+            return;
+        }
+    };
+    let response = if params.apply {
+        let meta = ctx.meta_for_session(None);
+        let conflicts = edit_conflict::changed_on_disk(&edit, ctx);
+        if !conflicts.is_empty() {
+            edit_conflict::ask(id, meta, edit, conflicts, write_buffers, ctx);
+            return;
+        }
+        apply_edit_and_write(meta, edit, write_buffers, ctx)
+    } else {
+        ApplyWorkspaceEditResponse {
+            applied: false,
+            failure_reason: Some("discarded by the user".to_string()),
+            failed_change: None,
+        }
+    };
+    if let Some(id) = id {
+        ctx.reply(id, Ok(serde_json::to_value(response).unwrap()));
+    }
+}
+
+/// Renders a workspace edit as unified diff, with file operations as comments.
+fn edit_diff(edit: &WorkspaceEdit, ctx: &Context) -> String {
+    let mut diff = String::new();
+    if let Some(document_changes) = &edit.document_changes {
+        let edit_text = |edit: &TextDocumentEdit| {
+            edit.edits
+                .iter()
+                .map(|edit| edit.as_ref().clone())
+                .collect::<Vec<_>>()
+        };
+        match document_changes {
+            DocumentChanges::Edits(edits) => {
+                for edit in edits {
+                    diff.push_str(&file_diff(&edit.text_document.uri, &edit_text(edit), ctx));
+                }
+            }
+            DocumentChanges::Operations(ops) => {
+                for op in ops {
+                    match op {
+                        DocumentChangeOperation::Edit(edit) => diff.push_str(&file_diff(
+                            &edit.text_document.uri,
+                            &edit_text(edit),
+                            ctx,
+                        )),
+                        DocumentChangeOperation::Op(op) => {
+                            let path = |uri: &Url| {
+                                let path = uri.to_file_path().unwrap();
+                                short_file_path(path.to_str().unwrap(), &ctx.root_path).to_string()
+                            };
+                            let line = match op {
+                                ResourceOp::Create(op) => format!("# create {}\n", path(&op.uri)),
+                                ResourceOp::Rename(op) => format!(
+                                    "# rename {} to {}\n",
+                                    path(&op.old_uri),
+                                    path(&op.new_uri)
+                                ),
+                                ResourceOp::Delete(op) => format!("# delete {}\n", path(&op.uri)),
+                            };
+                            diff.push_str(&line);
+                        }
+                    }
+                }
+            }
+        }
+    } else if let Some(changes) = &edit.changes {
+        let mut changes: Vec<_> = changes.iter().collect();
+        changes.sort_by_key(|(uri, _)| uri.as_str());
+        for (uri, edits) in changes {
+            diff.push_str(&file_diff(uri, edits, ctx));
+        }
+    }
+    diff
+}
+
+fn file_diff(uri: &Url, edits: &[TextEdit], ctx: &Context) -> String {
+    let path = uri.to_file_path().unwrap();
+    let path = path.to_str().unwrap();
+    let old = get_file_contents(path, ctx).unwrap_or_else(|| Rope::from_str(""));
+    let short_path = short_file_path(path, &ctx.root_path);
+    match apply_text_edits_to_text(&old, edits, ctx.offset_encoding) {
+        Some(new) => unified_diff(short_path, &old, &new),
+        None => format!("# edit out of range of {}\n", short_path),
+    }
+}
+
+/// The changed lines as (old start, old length, new start, new length).
+#[derive(Default)]
+struct LineChanges(Vec<(usize, usize, usize, usize)>);
+
+impl diffs::Diff for LineChanges {
+    type Error = ();
+    fn delete(&mut self, old: usize, len: usize, new: usize) -> Result<(), ()> {
+        self.0.push((old, len, new, 0));
+        Ok(())
+    }
+    fn insert(&mut self, old: usize, new: usize, new_len: usize) -> Result<(), ()> {
+        self.0.push((old, 0, new, new_len));
+        Ok(())
+    }
+    fn replace(
+        &mut self,
+        old: usize,
+        old_len: usize,
+        new: usize,
+        new_len: usize,
+    ) -> Result<(), ()> {
+        self.0.push((old, old_len, new, new_len));
+        Ok(())
+    }
+}
+
+fn unified_diff(path: &str, old: &Rope, new: &Rope) -> String {
+    let old = old.to_string();
+    let new = new.to_string();
+    let oldv: Vec<&str> = old.lines().collect();
+    let newv: Vec<&str> = new.lines().collect();
+    let mut changes = diffs::Replace::new(LineChanges::default());
+    let _result = diffs::myers::diff(&mut changes, &oldv, 0, oldv.len(), &newv, 0, newv.len());
+    let changes = changes.into_inner().0;
+    if changes.is_empty() {
+        return String::new();
+    }
+    let mut diff = format!("--- a/{}\n+++ b/{}\n", path, path);
+    let mut hunk_changes = vec![];
+    for (i, change) in changes.iter().enumerate() {
+        hunk_changes.push(*change);
+        let (old_start, old_len, _, _) = *change;
+        let last = i + 1 == changes.len();
+        if !last && changes[i + 1].0 <= old_start + old_len + 2 * CONTEXT_LINES {
+            continue;
+        }
+        let (first_old, _, first_new, _) = hunk_changes[0];
+        let hunk_old_start = first_old.saturating_sub(CONTEXT_LINES);
+        let hunk_new_start = first_new - (first_old - hunk_old_start);
+        let hunk_old_end = (old_start + old_len + CONTEXT_LINES).min(oldv.len());
+        let mut lines = vec![];
+        let mut cursor = hunk_old_start;
+        let mut new_len = 0;
+        for (old_start, old_len, new_start, change_new_len) in hunk_changes.drain(..) {
+            for line in &oldv[cursor..old_start] {
+                lines.push(format!(" {}", line));
+                new_len += 1;
+            }
+            for line in &oldv[old_start..old_start + old_len] {
+                lines.push(format!("-{}", line));
+            }
+            for line in &newv[new_start..new_start + change_new_len] {
+                lines.push(format!("+{}", line));
+            }
+            new_len += change_new_len;
+            cursor = old_start + old_len;
+        }
+        for line in &oldv[cursor..hunk_old_end] {
+            lines.push(format!(" {}", line));
+            new_len += 1;
+        }
+        diff.push_str(&format!(
+            "@@ -{},{} +{},{} @@\n",
+            hunk_old_start + 1,
+            hunk_old_end - hunk_old_start,
+            hunk_new_start + 1,
+            new_len
+        ));
+        for line in lines {
+            diff.push_str(&line);
+            diff.push('\n');
+        }
+    }
+    diff
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use indoc::indoc;
+
+    #[test]
+    fn test_unified_diff() {
+        let old = Rope::from_str("a\nb\nc\nd\ne\nf\ng\nh\ni\nj\nk\nl\nm\n");
+        let new = Rope::from_str("a\nB\nc\nd\ne\nf\ng\nh\ni\nj\nk\nl\nm\nn\n");
+        assert_eq!(
+            unified_diff("x.txt", &old, &new),
+            indoc! {"
+                --- a/x.txt
+                +++ b/x.txt
+                @@ -1,5 +1,5 @@
+                 a
+                -b
+                +B
+                 c
+                 d
+                 e
+                @@ -11,3 +11,4 @@
+                 k
+                 l
+                 m
+                +n
+            "}
+        );
+        assert_eq!(unified_diff("x.txt", &old, &old), "");
+    }
+
+    #[test]
+    fn test_edit_stats() {
+        let uri = |path: &str| Url::from_file_path(path).unwrap();
+        let text_edit = || TextEdit {
+            range: Range::default(),
+            new_text: "x".to_string(),
+        };
+        let document_edit = |path: &str, count: usize| {
+            DocumentChangeOperation::Edit(TextDocumentEdit {
+                text_document: OptionalVersionedTextDocumentIdentifier {
+                    uri: uri(path),
+                    version: None,
+                },
+                edits: (0..count).map(|_| OneOf::Left(text_edit())).collect(),
+            })
+        };
+        let edit = WorkspaceEdit {
+            document_changes: Some(DocumentChanges::Operations(vec![
+                DocumentChangeOperation::Op(ResourceOp::Rename(RenameFile {
+                    old_uri: uri("/a.rs"),
+                    new_uri: uri("/b.rs"),
+                    options: None,
+                    annotation_id: None,
+                })),
+                document_edit("/b.rs", 2),
+                document_edit("/c.rs", 3),
+            ])),
+            ..WorkspaceEdit::default()
+        };
+        let stats = edit_stats(&edit);
+        assert_eq!(
+            stats,
+            EditStats {
+                files: 3,
+                edits: 5,
+                file_operations: 1,
+            }
+        );
+        assert_eq!(
+            stats.to_string(),
+            "3 files, 5 edits, 1 creations/renames/deletions"
+        );
+    }
+}
@@ -5,9 +5,17 @@ use crossbeam_channel::{bounded, Receiver, Sender};
 use std::borrow::Cow;
 use std::fs;
 use std::io::{Read, Write};
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
 use std::os::unix::net::{UnixListener, UnixStream};
 use std::path;
 use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Inode of the socket this process listens on, so we don't remove a socket that a newer server
+/// created in its place.
+static SOCKET_INODE: AtomicU64 = AtomicU64::new(0);
 
 pub struct EditorTransport {
     // Not using Worker here as listener blocks forever and joining its thread
@@ -16,15 +24,24 @@ pub struct EditorTransport {
     pub to_editor: Worker<EditorResponse, Void>,
 }
 
-pub fn start(session: &str, initial_request: Option<String>) -> Result<EditorTransport, i32> {
+pub fn start(
+    session: &str,
+    initial_request: Option<Vec<u8>>,
+    force_new: bool,
+) -> Result<EditorTransport, i32> {
     // NOTE 1024 is arbitrary
     let channel_capacity = 1024;
 
     let (sender, receiver) = bounded(channel_capacity);
     let mut path = temp_dir();
+    if let Err(err) = check_private_dir(&path) {
+        error!("Refusing to create session socket: {}", err);
+        return Err(1);
+    }
     path.push(&session);
     if path.exists() {
-        if UnixStream::connect(&path).is_err() {
+        // With --force-new, the old server keeps running without a socket until it times out.
+        if force_new || UnixStream::connect(&path).is_err() {
             if fs::remove_file(&path).is_err() {
                 error!(
                     "Failed to clean up dead session at {}",
@@ -39,8 +56,8 @@ pub fn start(session: &str, initial_request: Option<String>) -> Result<EditorTra
     }
     std::thread::spawn(move || {
         if let Some(initial_request) = initial_request {
-            let initial_request: EditorRequest =
-                toml::from_str(&initial_request).expect("Failed to parse initial request");
+            let initial_request =
+                parse_toml_request(&initial_request).expect("Failed to parse initial request");
             if sender.send(initial_request).is_err() {
                 return;
             };
@@ -108,6 +125,12 @@ pub fn start(session: &str, initial_request: Option<String>) -> Result<EditorTra
     })
 }
 
+/// Returns true if the socket at the given path is the one this process created.
+pub fn owns_socket(path: &path::Path) -> bool {
+    let inode = SOCKET_INODE.load(Ordering::SeqCst);
+    inode != 0 && fs::metadata(path).is_ok_and(|metadata| metadata.ino() == inode)
+}
+
 pub fn start_unix(path: &path::Path, sender: Sender<EditorRequest>) {
     let listener = match UnixListener::bind(&path) {
         Ok(listener) => listener,
@@ -116,19 +139,34 @@ pub fn start_unix(path: &path::Path, sender: Sender<EditorRequest>) {
             return;
         }
     };
+    // The directory is private already, this is just another line of defense.
+    if let Err(e) = fs::set_permissions(path, fs::Permissions::from_mode(0o600)) {
+        error!(
+            "Failed to restrict permissions of {}: {}",
+            path.display(),
+            e
+        );
+    }
+    if let Ok(metadata) = fs::metadata(path) {
+        SOCKET_INODE.store(metadata.ino(), Ordering::SeqCst);
+    }
 
     for stream in listener.incoming() {
         match stream {
             Ok(mut stream) => {
-                let mut request = String::new();
-                match stream.read_to_string(&mut request) {
+                let mut request = Vec::new();
+                match stream.read_to_end(&mut request) {
                     Ok(_) => {
                         if request.is_empty() {
                             continue;
                         }
-                        debug!("From editor: {}", request);
-                        let request: EditorRequest =
-                            toml::from_str(&request).expect("Failed to parse editor request");
+                        let request = match decode_request(&request) {
+                            Ok(request) => request,
+                            Err(e) => {
+                                error!("Failed to parse editor request: {}", e);
+                                continue;
+                            }
+                        };
                         if sender.send(request).is_err() {
                             return;
                         };
@@ -144,3 +182,154 @@ pub fn start_unix(path: &path::Path, sender: Sender<EditorRequest>) {
         }
     }
 }
+
+/// Editor requests are written by Kakoune as TOML. `kak-lsp --request` parses them and forwards
+/// them to the server in a length-prefixed frame, so the server doesn't have to parse TOML, which
+/// is slow for large buffer contents, and can detect truncated requests. The header is the magic
+/// bytes, the protocol version, the payload format and the payload length as big-endian u64.
+/// Anything not starting with the magic bytes is parsed as TOML, like before.
+const FRAME_MAGIC: &[u8] = b"\0kak-lsp";
+const FRAME_VERSION: u8 = 1;
+const FRAME_FORMAT_JSON: u8 = b'j';
+const FRAME_HEADER_LENGTH: usize = FRAME_MAGIC.len() + 2 + 8;
+
+/// Hands the initial request, if any, over to the server that is already running for the
+/// session, instead of starting a second one. Returns whether there is such a server. A server
+/// that is still starting up gets `timeout` to create its socket.
+pub fn adopt_running_server(
+    session: &str,
+    initial_request: Option<&[u8]>,
+    timeout: Duration,
+) -> bool {
+    let path = temp_dir().join(session);
+    if check_private_dir(&temp_dir()).is_err() {
+        return false;
+    }
+    let deadline = Instant::now() + timeout;
+    let mut stream = loop {
+        if let Ok(stream) = UnixStream::connect(&path) {
+            break stream;
+        }
+        if Instant::now() >= deadline {
+            return false;
+        }
+        thread::sleep(Duration::from_millis(50));
+    };
+    if let Some(request) = initial_request {
+        let request = encode_request(request).unwrap_or_else(|_| request.to_vec());
+        if let Err(err) = stream.write_all(&request) {
+            error!(
+                "Failed to hand initial request over to running server: {}",
+                err
+            );
+        }
+    }
+    true
+}
+
+/// Converts a request in Kakoune's TOML format to a frame to send to the server.
+pub fn encode_request(request: &[u8]) -> Result<Vec<u8>, String> {
+    let request = parse_toml_request(request)?;
+    let payload = serde_json::to_vec(&request).map_err(|e| e.to_string())?;
+    let mut frame = Vec::with_capacity(FRAME_HEADER_LENGTH + payload.len());
+    frame.extend_from_slice(FRAME_MAGIC);
+    frame.push(FRAME_VERSION);
+    frame.push(FRAME_FORMAT_JSON);
+    frame.extend_from_slice(&(payload.len() as u64).to_be_bytes());
+    frame.extend_from_slice(&payload);
+    Ok(frame)
+}
+
+/// Sent instead of requests that are not valid UTF-8, which usually means that the buffer uses
+/// another encoding, like Latin-1. Language servers only accept UTF-8 documents.
+pub const INVALID_UTF8_METHOD: &str = "kak-lsp/invalid-utf8";
+
+/// Parses a request in Kakoune's TOML format. A request that is not valid UTF-8 is replaced by
+/// an `INVALID_UTF8_METHOD` request for the same buffer, so the user can be told about it.
+fn parse_toml_request(request: &[u8]) -> Result<EditorRequest, String> {
+    if let Ok(request) = std::str::from_utf8(request) {
+        return toml::from_str(request).map_err(|e| e.to_string());
+    }
+    let request = String::from_utf8_lossy(request);
+    let mut request: EditorRequest = toml::from_str(&request).map_err(|e| e.to_string())?;
+    request.method = INVALID_UTF8_METHOD.to_string();
+    request.params = toml::Value::Table(Default::default());
+    Ok(request)
+}
+
+fn decode_request(request: &[u8]) -> Result<EditorRequest, String> {
+    let frame = match request.strip_prefix(FRAME_MAGIC) {
+        Some(frame) => frame,
+        None => {
+            debug!("From editor: {}", String::from_utf8_lossy(request));
+            return parse_toml_request(request);
+        }
+    };
+    if frame.len() < FRAME_HEADER_LENGTH - FRAME_MAGIC.len() {
+        return Err("truncated frame header".to_string());
+    }
+    let (version, format) = (frame[0], frame[1]);
+    if version != FRAME_VERSION {
+        return Err(format!("unsupported protocol version {}", version));
+    }
+    let mut length = [0; 8];
+    length.copy_from_slice(&frame[2..10]);
+    let length = u64::from_be_bytes(length);
+    let payload = &frame[10..];
+    if payload.len() as u64 != length {
+        return Err(format!(
+            "expected {} bytes of payload but got {}",
+            length,
+            payload.len()
+        ));
+    }
+    match format {
+        FRAME_FORMAT_JSON => {
+            debug!("From editor: {}", String::from_utf8_lossy(payload));
+            serde_json::from_slice(payload).map_err(|e| e.to_string())
+        }
+        _ => Err(format!("unsupported payload format {}", format)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_decode_request() {
+        let toml = r#"
+session  = "session"
+client   = "client0"
+buffile  = "/tmp/main.rs"
+filetype = "rust"
+version  = 42
+method   = "textDocument/didChange"
+hook     = true
+[params]
+draft    = """
+fn main() {}
+"""
+"#;
+        let frame = encode_request(toml.as_bytes()).unwrap();
+        let request = decode_request(&frame).unwrap();
+        assert_eq!(request.meta.client.as_deref(), Some("client0"));
+        assert_eq!(request.meta.version, 42);
+        assert!(request.meta.hook);
+        assert_eq!(request.params["draft"].as_str(), Some("fn main() {}\n"));
+
+        let legacy = decode_request(toml.as_bytes()).unwrap();
+        assert_eq!(legacy.method, request.method);
+
+        assert!(decode_request(&frame[..frame.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_invalid_utf8_request() {
+        let latin1 = b"session = \"session\"\nbuffile = \"/tmp/cafe.txt\"\nfiletype = \"text\"\nversion = 1\nmethod = \"textDocument/didOpen\"\n[params]\ndraft = \"caf\xe9\"\n";
+        let request = decode_request(&encode_request(latin1).unwrap()).unwrap();
+        assert_eq!(request.method, INVALID_UTF8_METHOD);
+        assert_eq!(request.meta.buffile, "/tmp/cafe.txt");
+        assert!(request.params.get("draft").is_none());
+    }
+}
@@ -0,0 +1,107 @@
+//! Kakoune shows at most one info box per client, so features that show one would overwrite
+//! each other. This module decides which feature owns a client's info box.
+//!
+//! Commands that the user runs explicitly always show their info box. Updates triggered by hooks,
+//! like auto-hover and signature help in insert mode, are suppressed while a feature of higher
+//! priority owns the info box. Ownership expires after a timeout, because we are not told when
+//! Kakoune closes the info box.
+
+use crate::types::EditorMeta;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Features that show info boxes, in ascending order of priority.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum InfoBoxOwner {
+    SignatureHelp,
+    InlayHintTooltip,
+    AutoHover,
+    CompletionDocs,
+    Hover,
+    Message,
+}
+
+impl InfoBoxOwner {
+    /// How long the info box is assumed to be visible.
+    fn timeout(self) -> Duration {
+        match self {
+            InfoBoxOwner::SignatureHelp | InfoBoxOwner::InlayHintTooltip => Duration::from_secs(1),
+            InfoBoxOwner::AutoHover | InfoBoxOwner::CompletionDocs => Duration::from_secs(2),
+            InfoBoxOwner::Hover | InfoBoxOwner::Message => Duration::from_secs(5),
+        }
+    }
+}
+
+lazy_static::lazy_static! {
+    /// The current owner of each client's info box, keyed by session and client.
+    static ref OWNERS: Mutex<HashMap<(String, String), (InfoBoxOwner, Instant)>> =
+        Mutex::new(HashMap::new());
+}
+
+/// Returns whether `owner` may show an info box in the client of `meta` now.
+/// If so, it becomes the owner of that info box.
+pub fn claim(meta: &EditorMeta, owner: InfoBoxOwner) -> bool {
+    let client = match &meta.client {
+        Some(client) => client.clone(),
+        None => return true,
+    };
+    let key = (meta.session.clone(), client);
+    let now = Instant::now();
+    let mut owners = OWNERS.lock().unwrap();
+    if !may_replace(owners.get(&key).copied(), owner, !meta.hook, now) {
+        debug!(
+            "Not showing {:?} info box because {:?} owns it",
+            owner, owners[&key].0
+        );
+        return false;
+    }
+    owners.insert(key, (owner, now));
+    true
+}
+
+/// Forgets the info box of a client that was closed.
+pub fn forget_client(session: &str, client: &str) {
+    OWNERS
+        .lock()
+        .unwrap()
+        .remove(&(session.to_string(), client.to_string()));
+}
+
+fn may_replace(
+    current: Option<(InfoBoxOwner, Instant)>,
+    owner: InfoBoxOwner,
+    explicit: bool,
+    now: Instant,
+) -> bool {
+    match current {
+        Some((current, since)) if !explicit => {
+            owner >= current || now.duration_since(since) >= current.timeout()
+        }
+        _ => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_may_replace() {
+        let now = Instant::now();
+        let later = now + Duration::from_secs(10);
+        let hover = Some((InfoBoxOwner::Hover, now));
+        assert!(may_replace(None, InfoBoxOwner::AutoHover, false, now));
+        assert!(!may_replace(hover, InfoBoxOwner::AutoHover, false, now));
+        assert!(may_replace(hover, InfoBoxOwner::AutoHover, true, now));
+        assert!(may_replace(hover, InfoBoxOwner::AutoHover, false, later));
+        assert!(may_replace(hover, InfoBoxOwner::Message, false, now));
+        let signature_help = Some((InfoBoxOwner::SignatureHelp, now));
+        assert!(may_replace(
+            signature_help,
+            InfoBoxOwner::CompletionDocs,
+            false,
+            now
+        ));
+    }
+}
@@ -0,0 +1,165 @@
+//! Hover info and definitions for the cursors of all selections, instead of only the main one.
+//! The results are shown in one goto buffer, with a section for each cursor that starts with
+//! its location, so it is easy to jump back and forth.
+
+use crate::capabilities::{attempt_server_capability, CAPABILITY_HOVER};
+use crate::context::*;
+use crate::language_features::goto::{definition_locations, format_locations};
+use crate::language_features::hover::{hover_contents_to_markdown, word_at};
+use crate::position::*;
+use crate::types::*;
+use crate::util::*;
+use lsp_types::request::*;
+use lsp_types::*;
+use serde::Deserialize;
+use url::Url;
+
+/// The maximum number of cursors to send requests for, to not flood the server.
+const MAX_CURSORS: usize = 100;
+
+#[derive(Deserialize)]
+struct SelectionsParams {
+    selections_desc: String,
+}
+
+/// Returns the distinct cursors of the selections, in order.
+fn cursors(params: EditorParams) -> Vec<KakounePosition> {
+    let params = SelectionsParams::deserialize(params).unwrap();
+    let mut cursors: Vec<KakounePosition> = params
+        .selections_desc
+        .split_ascii_whitespace()
+        .map(|desc| parse_kakoune_range(desc).1)
+        .collect();
+    cursors.dedup();
+    cursors.truncate(MAX_CURSORS);
+    cursors
+}
+
+fn text_document_positions(
+    meta: &EditorMeta,
+    cursors: &[KakounePosition],
+    ctx: &Context,
+) -> Option<Vec<TextDocumentPositionParams>> {
+    let document = ctx.documents.get(&meta.buffile)?;
+    let uri = Url::from_file_path(&meta.buffile).unwrap();
+    let positions = cursors
+        .iter()
+        .map(|cursor| TextDocumentPositionParams {
+            text_document: TextDocumentIdentifier { uri: uri.clone() },
+            position: kakoune_position_to_lsp(cursor, &document.text, ctx.offset_encoding),
+        })
+        .collect();
+    Some(positions)
+}
+
+/// The heading of a cursor's section: its location and the word under it.
+fn cursor_heading(meta: &EditorMeta, cursor: KakounePosition, ctx: &Context) -> String {
+    let word = ctx
+        .documents
+        .get(&meta.buffile)
+        .and_then(|document| word_at(&document.text, cursor))
+        .unwrap_or_default();
+    format!(
+        "{}:{}:{}: {}\n",
+        short_file_path(&meta.buffile, &ctx.root_path),
+        cursor.line,
+        cursor.column,
+        word
+    )
+}
+
+fn show_sections(meta: EditorMeta, buffer: &str, sections: Vec<String>, ctx: &mut Context) {
+    let command = format!(
+        "lsp-show-goto-buffer {} {} {}",
+        buffer,
+        editor_quote(&ctx.root_path),
+        editor_quote(&sections.join("\n")),
+    );
+    ctx.exec(meta, command);
+}
+
+pub fn hover_selections(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
+    if !attempt_server_capability(ctx, CAPABILITY_HOVER) {
+        return;
+    }
+    let cursors = cursors(params);
+    let ops: Vec<HoverParams> = match text_document_positions(&meta, &cursors, ctx) {
+        Some(positions) => positions
+            .into_iter()
+            .map(|text_document_position_params| HoverParams {
+                text_document_position_params,
+                work_done_progress_params: Default::default(),
+            })
+            .collect(),
+        None => return,
+    };
+    if ops.is_empty() {
+        return;
+    }
+    // Results are in the order of the cursors. A failed request only affects its own cursor.
+    ctx.batch_call_lenient::<HoverRequest, _>(meta, ops, move |ctx, meta, results| {
+        let sections = cursors
+            .into_iter()
+            .zip(results)
+            .map(|(cursor, result)| {
+                let contents = match result {
+                    Some(result) => result
+                        .map(|hover| hover_contents_to_markdown(hover.contents))
+                        .filter(|contents| !contents.trim().is_empty())
+                        .unwrap_or_else(|| "No hover info".to_string()),
+                    None => "Hover request failed".to_string(),
+                };
+                let mut section = cursor_heading(&meta, cursor, ctx);
+                for line in contents.trim().lines() {
+                    section.push_str("  ");
+                    section.push_str(line);
+                    section.push('\n');
+                }
+                section
+            })
+            .collect();
+        show_sections(meta, "*hover-selections*", sections, ctx);
+    });
+}
+
+pub fn definition_selections(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
+    let cursors = cursors(params);
+    let ops: Vec<GotoDefinitionParams> = match text_document_positions(&meta, &cursors, ctx) {
+        Some(positions) => positions
+            .into_iter()
+            .map(|text_document_position_params| GotoDefinitionParams {
+                text_document_position_params,
+                work_done_progress_params: Default::default(),
+                partial_result_params: Default::default(),
+            })
+            .collect(),
+        None => return,
+    };
+    if ops.is_empty() {
+        return;
+    }
+    ctx.batch_call_lenient::<GotoDefinition, _>(meta, ops, move |ctx, meta, results| {
+        let sections = cursors
+            .into_iter()
+            .zip(results)
+            .map(|(cursor, result)| {
+                let mut section = cursor_heading(&meta, cursor, ctx);
+                let result = match result {
+                    Some(result) => result,
+                    None => {
+                        section.push_str("  Definition request failed\n");
+                        return section;
+                    }
+                };
+                let locations = result.map(definition_locations).unwrap_or_default();
+                if locations.is_empty() {
+                    section.push_str("  No definition found\n");
+                } else {
+                    section.push_str(&format_locations(&locations, "  ", ctx));
+                }
+                section
+            })
+            .collect();
+        show_sections(meta, "*goto*", sections, ctx);
+    });
+}
@@ -1,3 +1,4 @@
+use crate::capabilities::attempt_server_extension;
 use crate::context::*;
 use crate::language_features::goto;
 use crate::position::*;
@@ -35,6 +36,9 @@ impl Request for NavigateRequest {
 }
 
 pub fn navigate(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
+    if !attempt_server_extension(&meta, ctx, NavigateRequest::METHOD) {
+        return;
+    }
     let params = KakouneNavigateParams::deserialize(params).unwrap();
     let req_params = NavigateParams {
         text_document: TextDocumentIdentifier {
@@ -73,6 +77,9 @@ impl Request for VarsRequest {
 }
 
 pub fn vars(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
+    if !attempt_server_extension(&meta, ctx, VarsRequest::METHOD) {
+        return;
+    }
     let params = PositionParams::deserialize(params).unwrap();
     let req_params = VarsParams {
         text_document: TextDocumentIdentifier {
@@ -112,6 +119,9 @@ impl Request for InheritanceRequest {
 }
 
 pub fn inheritance(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
+    if !attempt_server_extension(&meta, ctx, InheritanceRequest::METHOD) {
+        return;
+    }
     let params = KakouneInheritanceParams::deserialize(params).unwrap();
     let req_params = InheritanceParams {
         text_document: TextDocumentIdentifier {
@@ -151,6 +161,9 @@ impl Request for CallRequest {
 }
 
 pub fn call(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
+    if !attempt_server_extension(&meta, ctx, CallRequest::METHOD) {
+        return;
+    }
     let params = KakouneCallParams::deserialize(params).unwrap();
     let req_params = CallParams {
         text_document: TextDocumentIdentifier {
@@ -189,6 +202,9 @@ impl Request for MemberRequest {
 }
 
 pub fn member(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
+    if !attempt_server_extension(&meta, ctx, MemberRequest::METHOD) {
+        return;
+    }
     let params = KakouneMemberParams::deserialize(params).unwrap();
     let req_params = MemberParams {
         text_document: TextDocumentIdentifier {
@@ -351,7 +367,7 @@ pub struct PublishSemanticHighlightingParams {
     pub symbols: Vec<SemanticSymbol>,
 }
 
-pub fn publish_semantic_highlighting(params: Params, ctx: &mut Context) {
+pub fn publish_semantic_highlighting(_meta: EditorMeta, params: Params, ctx: &mut Context) {
     let params: PublishSemanticHighlightingParams =
         params.parse().expect("Failed to parse semhl params");
     let path = params.uri.to_file_path().unwrap();
@@ -1,3 +1,4 @@
+use crate::capabilities::attempt_server_extension;
 use crate::context::*;
 use crate::types::*;
 use crate::util::*;
@@ -13,6 +14,9 @@ impl Request for SwitchSourceHeaderRequest {
 }
 
 pub fn switch_source_header(meta: EditorMeta, ctx: &mut Context) {
+    if !attempt_server_extension(&meta, ctx, SwitchSourceHeaderRequest::METHOD) {
+        return;
+    }
     let req_params = TextDocumentIdentifier {
         uri: Url::from_file_path(&meta.buffile).unwrap(),
     };
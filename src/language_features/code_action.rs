@@ -1,10 +1,11 @@
 use crate::capabilities::attempt_server_capability;
 use crate::capabilities::CAPABILITY_CODE_ACTIONS;
 use crate::context::*;
+use crate::modified_lines::{modified_lines, restrict_workspace_edit};
 use crate::position::*;
+use crate::presentation::{choose_command, Choice};
 use crate::types::*;
 use crate::util::*;
-use itertools::Itertools;
 use lsp_types::request::*;
 use lsp_types::*;
 use serde::Deserialize;
@@ -78,6 +79,16 @@ pub fn editor_code_actions(
                 None => CodeActionOrCommand::CodeAction(action),
             },
         })
+        // Actions that need `codeAction/resolve` to compute their edit can't be performed.
+        .filter(|c| match c {
+            CodeActionOrCommand::Command(_) => true,
+            CodeActionOrCommand::CodeAction(action) => {
+                if action.edit.is_none() {
+                    warn!("Skipping code action without edit: {}", action.title);
+                }
+                action.edit.is_some()
+            }
+        })
         .collect::<Vec<_>>();
 
     if let Some(pattern) = params.code_action_pattern.as_ref() {
@@ -113,6 +124,12 @@ pub fn editor_code_actions(
         .to_string();
         let command = match matches.len() {
             0 => fail + " 'no matching action available'",
+            1 if params.modified_lines_only => {
+                match restrict_code_action(matches[0].clone(), &meta.buffile, ctx) {
+                    Ok(action) => code_action_to_editor_command(&action, sync),
+                    Err(err) => format!("{} {}", fail, editor_quote(&err)),
+                }
+            }
             1 => code_action_to_editor_command(matches[0], sync),
             _ => fail + " 'multiple matching actions'",
         };
@@ -120,38 +137,64 @@ pub fn editor_code_actions(
         return;
     }
 
-    let titles_and_commands = actions
+    let choices: Vec<Choice> = actions
         .iter()
         .map(|c| {
-            let mut title: &str = match c {
+            let title = match c {
                 CodeActionOrCommand::Command(command) => &command.title,
                 CodeActionOrCommand::CodeAction(action) => &action.title,
             };
-            if let Some((head, _)) = title.split_once('\n') {
-                title = head
+            Choice {
+                title: title.clone(),
+                command: code_action_to_editor_command(c, false),
             }
-            let select_cmd = code_action_to_editor_command(c, false);
-            format!("{} {}", editor_quote(title), editor_quote(&select_cmd))
         })
-        .join(" ");
+        .collect();
 
+    // Code actions have no location for a goto buffer, so that presentation uses the prompt.
+    let presentation = meta.presentation.unwrap_or(Presentation::Prompt);
     #[allow(clippy::collapsible_else_if)]
     let command = if params.perform_code_action {
         if actions.is_empty() {
             "lsp-show-error 'no actions available'".to_string()
         } else {
-            format!("lsp-perform-code-action {}\n", titles_and_commands)
+            choose_command(presentation, "lsp-perform-code-action", &choices) + "\n"
         }
     } else {
         if actions.is_empty() {
             "lsp-hide-code-actions\n".to_string()
         } else {
-            format!("lsp-show-code-actions {}\n", titles_and_commands)
+            choose_command(Presentation::Prompt, "lsp-show-code-actions", &choices) + "\n"
         }
     };
     ctx.exec(meta, command);
 }
 
+/// Drops the edits of a code action to lines of `buffile` that were not modified since the last
+/// commit. Code actions that are commands are run by the server, so they cannot be restricted,
+/// and neither can actions whose edit is only computed by `codeAction/resolve`.
+fn restrict_code_action(
+    mut action: CodeActionOrCommand,
+    buffile: &str,
+    ctx: &Context,
+) -> Result<CodeActionOrCommand, String> {
+    let edit = match &mut action {
+        CodeActionOrCommand::Command(_) => {
+            return Err("cannot restrict a command to modified lines".to_string())
+        }
+        CodeActionOrCommand::CodeAction(action) => match action.edit.as_mut() {
+            Some(edit) => edit,
+            None => {
+                return Err("cannot restrict an action without edit to modified lines".to_string())
+            }
+        },
+    };
+    let text = &ctx.documents[buffile].text;
+    let lines = modified_lines(buffile, text, "HEAD")?;
+    restrict_workspace_edit(edit, &Url::from_file_path(buffile).unwrap(), text, &lines);
+    Ok(action)
+}
+
 fn code_action_to_editor_command(action: &CodeActionOrCommand, sync: bool) -> String {
     match action {
         CodeActionOrCommand::Command(command) => execute_command_editor_command(command, sync),
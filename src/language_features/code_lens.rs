@@ -37,8 +37,8 @@ fn editor_code_lens(meta: EditorMeta, result: Option<Vec<CodeLens>>, ctx: &mut C
     ctx.code_lenses.insert(meta.buffile.clone(), lenses);
 
     let buffile = &meta.buffile;
-    let version = match ctx.documents.get(buffile) {
-        Some(document) => document.version,
+    let version = match ctx.document_version(&meta.session, buffile) {
+        Some(version) => version,
         None => {
             ctx.code_lenses.remove(buffile);
             return;
@@ -0,0 +1,159 @@
+//! A color picker on top of `textDocument/documentColor` and `textDocument/colorPresentation`.
+//! It offers the server's presentations of the color at the cursor, like `#ff0000` and
+//! `rgb(255, 0, 0)`, and of a few lighter and darker shades of it.
+
+use crate::capabilities::{attempt_server_capability, CAPABILITY_DOCUMENT_COLOR};
+use crate::context::*;
+use crate::position::*;
+use crate::text_edit::apply_text_edits_to_buffer;
+use crate::types::*;
+use crate::util::*;
+use itertools::Itertools;
+use lsp_types::request::*;
+use lsp_types::*;
+use serde::Deserialize;
+use url::Url;
+
+/// The shades offered besides the color itself, as amount of white (positive) or black
+/// (negative) to mix in.
+const SHADES: &[f32] = &[0.4, 0.2, -0.2, -0.4];
+
+pub fn pick_color(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
+    if !attempt_server_capability(ctx, CAPABILITY_DOCUMENT_COLOR) {
+        return;
+    }
+    let params = PositionParams::deserialize(params).unwrap();
+    let req_params = DocumentColorParams {
+        text_document: TextDocumentIdentifier {
+            uri: Url::from_file_path(&meta.buffile).unwrap(),
+        },
+        work_done_progress_params: Default::default(),
+        partial_result_params: Default::default(),
+    };
+    ctx.call::<DocumentColor, _>(meta, req_params, move |ctx, meta, colors| {
+        let position = match get_lsp_position(&meta.buffile, &params.position, ctx) {
+            Some(position) => position,
+            None => return,
+        };
+        let color = colors
+            .into_iter()
+            .find(|color| color.range.start <= position && position <= color.range.end);
+        match color {
+            Some(color) => request_color_presentations(meta, color, ctx),
+            None => ctx.exec(meta, "lsp-show-error 'no color at the cursor'"),
+        }
+    });
+}
+
+fn request_color_presentations(meta: EditorMeta, color: ColorInformation, ctx: &mut Context) {
+    let uri = Url::from_file_path(&meta.buffile).unwrap();
+    let mut colors = vec![(String::new(), color.color.clone())];
+    for &amount in SHADES {
+        let label = if amount > 0.0 {
+            format!(" ({}% lighter)", (amount * 100.0).round())
+        } else {
+            format!(" ({}% darker)", (-amount * 100.0).round())
+        };
+        colors.push((label, shade(&color.color, amount)));
+    }
+    let ops = colors
+        .iter()
+        .map(|(_, color_value)| ColorPresentationParams {
+            text_document: TextDocumentIdentifier { uri: uri.clone() },
+            color: color_value.clone(),
+            range: color.range,
+            work_done_progress_params: Default::default(),
+            partial_result_params: Default::default(),
+        })
+        .collect();
+    ctx.batch_call_lenient::<ColorPresentationRequest, _>(meta, ops, move |ctx, meta, results| {
+        let document = match ctx.documents.get(&meta.buffile) {
+            Some(document) => document,
+            None => return,
+        };
+        let mut entries = vec![];
+        // Results are in the order of the colors, so the first one is the color itself.
+        for (i, ((suffix, _), presentations)) in colors.iter().zip(results).enumerate() {
+            let presentations = match presentations {
+                Some(presentations) => presentations,
+                None => continue,
+            };
+            // All presentations of the color itself, but only the first one of each shade.
+            let count = if i == 0 { presentations.len() } else { 1 };
+            for presentation in presentations.into_iter().take(count) {
+                let ColorPresentation {
+                    label,
+                    text_edit,
+                    additional_text_edits,
+                } = presentation;
+                let mut edits = vec![text_edit.unwrap_or_else(|| TextEdit {
+                    range: color.range,
+                    new_text: label.clone(),
+                })];
+                edits.extend(additional_text_edits.unwrap_or_default());
+                let command = apply_text_edits_to_buffer(
+                    &meta.client,
+                    Some(&uri),
+                    edits,
+                    &document.text,
+                    ctx.document_version(&meta.session, &meta.buffile),
+                    ctx.offset_encoding,
+                );
+                if let Some(command) = command {
+                    entries.push((format!("{}{}", label, suffix), command));
+                }
+            }
+        }
+        if entries.is_empty() {
+            ctx.exec(meta, "lsp-show-error 'no color presentations'");
+            return;
+        }
+        let menu = entries
+            .iter()
+            .map(|(label, command)| format!("{} {}", editor_quote(label), editor_quote(command)))
+            .join(" ");
+        ctx.exec(meta, format!("lsp-menu {}", menu));
+    });
+}
+
+/// Mixes a color with white for positive amounts, or with black for negative ones.
+fn shade(color: &Color, amount: f32) -> Color {
+    let mix = |component: f32| {
+        if amount > 0.0 {
+            component + (1.0 - component) * amount
+        } else {
+            component * (1.0 + amount)
+        }
+    };
+    Color {
+        red: mix(color.red),
+        green: mix(color.green),
+        blue: mix(color.blue),
+        alpha: color.alpha,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shade() {
+        let color = Color {
+            red: 1.0,
+            green: 0.5,
+            blue: 0.0,
+            alpha: 0.8,
+        };
+        let lighter = shade(&color, 0.5);
+        assert_eq!(
+            (lighter.red, lighter.green, lighter.blue, lighter.alpha),
+            (1.0, 0.75, 0.5, 0.8)
+        );
+        let darker = shade(&color, -0.5);
+        assert_eq!(
+            (darker.red, darker.green, darker.blue, darker.alpha),
+            (0.5, 0.25, 0.0, 0.8)
+        );
+    }
+}
@@ -1,74 +1,486 @@
+use crate::capabilities::{
+    server_has_capability, CAPABILITY_COMPLETION, CAPABILITY_SIGNATURE_HELP,
+};
 use crate::context::*;
+use crate::info_box::{self, InfoBoxOwner};
+use crate::language_features::code_action::apply_workspace_edit_editor_command;
 use crate::markup::*;
 use crate::position::*;
+use crate::snippet;
 use crate::text_edit::apply_text_edits;
 use crate::types::*;
 use crate::util::*;
 use indoc::formatdoc;
 use itertools::Itertools;
+use jsonrpc_core::Id;
+use lsp_types::notification::Cancel;
 use lsp_types::request::*;
 use lsp_types::*;
 use regex::Regex;
-use serde::Deserialize;
+use ropey::Rope;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
 use url::Url;
 
+/// At most one completion request per client is sent within this window by default, see
+/// `completion_coalesce_window_ms`.
+const DEFAULT_COMPLETION_COALESCE_WINDOW: Duration = Duration::from_millis(100);
+
 pub fn text_document_completion(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
     let params = TextDocumentCompletionParams::deserialize(params).unwrap();
+    let window = completion_coalesce_window(ctx);
+    // Requests that the editor waits for are answered right away.
+    if window.is_zero() || meta.fifo.is_some() {
+        return send_completion(meta, params, ctx);
+    }
+    cancel_superseded_completions(&meta, ctx);
+    let now = Instant::now();
+    let state = ctx.client_state(&meta);
+    if let Some(sent) = state.completion_sent {
+        if now < sent + window {
+            // During fast typing, only the latest request is sent once the window is over.
+            state.deferred_completion = Some((sent + window, meta, params));
+            return;
+        }
+    }
+    state.deferred_completion = None;
+    state.completion_sent = Some(now);
+    send_completion(meta, params, ctx);
+}
+
+/// The additional servers of a buffer get this long to answer a completion request after the
+/// first server answered it, so the menu is shown once with the items of all of them.
+const SECONDARY_COMPLETION_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// Returns when the first completion request that waits for its coalescing window, or the first
+/// menu that waits for late servers, is due.
+pub fn next_completion_due(ctx: &Context) -> Option<Instant> {
+    ctx.clients
+        .values()
+        .flat_map(|state| {
+            let request = state.deferred_completion.as_ref().map(|(due, _, _)| *due);
+            request.into_iter().chain(state.completion_menu_due)
+        })
+        .min()
+}
+
+/// Sends the completion requests whose coalescing window is over, unless the buffer changed since
+/// they were made, which means a newer request is on its way. Shows the completion menus whose
+/// servers did not all answer in time.
+pub fn completion_due(ctx: &mut Context) {
+    let now = Instant::now();
+    let mut requests = vec![];
+    let mut menus = vec![];
+    for state in ctx.clients.values_mut() {
+        if matches!(&state.deferred_completion, Some((deadline, _, _)) if *deadline <= now) {
+            let (_, meta, params) = state.deferred_completion.take().unwrap();
+            state.completion_sent = Some(now);
+            requests.push((meta, params));
+        }
+        if matches!(state.completion_menu_due, Some(deadline) if deadline <= now) {
+            state.completion_menu_due = None;
+            menus.extend(state.completion_request.clone());
+        }
+    }
+    for (meta, params) in requests {
+        if ctx.document_version(&meta.session, &meta.buffile) == Some(meta.version) {
+            send_completion(meta, params, ctx);
+        }
+    }
+    for (meta, params) in menus {
+        show_completions(meta, params, ctx);
+    }
+}
+
+fn completion_coalesce_window(ctx: &Context) -> Duration {
+    ctx.language_config()
+        .completion_coalesce_window_ms
+        .map_or(DEFAULT_COMPLETION_COALESCE_WINDOW, Duration::from_millis)
+}
+
+fn send_completion(meta: EditorMeta, params: TextDocumentCompletionParams, ctx: &mut Context) {
+    if params.secondary {
+        return secondary_completion(meta, params, ctx);
+    }
+    let position = match get_lsp_position(&meta.buffile, &params.position, ctx) {
+        Some(position) => position,
+        None => return,
+    };
+    let state = ctx.client_state(&meta);
+    state.completion_request = Some((meta.clone(), params.clone()));
+    state.completion_sources.clear();
+    state.completion_servers = 1 + params.secondary_servers;
+    state.completion_menu_due = None;
+    state.completion_menu_shown = false;
     let req_params = CompletionParams {
         text_document_position: TextDocumentPositionParams {
             text_document: TextDocumentIdentifier {
                 uri: Url::from_file_path(&meta.buffile).unwrap(),
             },
-            position: get_lsp_position(&meta.buffile, &params.position, ctx).unwrap(),
+            position,
         },
         context: None,
         work_done_progress_params: Default::default(),
         partial_result_params: Default::default(),
     };
-    ctx.call::<Completion, _>(meta, req_params, |ctx: &mut Context, meta, result| {
-        editor_completion(meta, params, result, ctx)
-    });
+    ctx.call::<CompletionWithItemDefaults, _>(
+        meta,
+        req_params,
+        |ctx: &mut Context, meta, result| editor_completion(meta, params, result, ctx),
+    );
+}
+
+/// Answers a completion request as additional server of the buffer, see `lsp_servers`. The items
+/// are sent to the buffer's primary server, which shows them together with its own. The primary
+/// server waits for the answer, so it is sent even if there are no items.
+fn secondary_completion(meta: EditorMeta, params: TextDocumentCompletionParams, ctx: &mut Context) {
+    let position = params.position;
+    let lsp_position = match get_lsp_position(&meta.buffile, &position, ctx) {
+        Some(lsp_position) if server_has_capability(ctx, CAPABILITY_COMPLETION) => lsp_position,
+        _ => return send_secondary_completion(meta, position, vec![], ctx),
+    };
+    let req_params = CompletionParams {
+        text_document_position: TextDocumentPositionParams {
+            text_document: TextDocumentIdentifier {
+                uri: Url::from_file_path(&meta.buffile).unwrap(),
+            },
+            position: lsp_position,
+        },
+        context: None,
+        work_done_progress_params: Default::default(),
+        partial_result_params: Default::default(),
+    };
+    ctx.batch_call_lenient::<CompletionWithItemDefaults, _>(
+        meta,
+        vec![req_params],
+        move |ctx: &mut Context, meta, mut results| {
+            let items = results
+                .pop()
+                .flatten()
+                .flatten()
+                .map(|items| items.0)
+                .unwrap_or_default();
+            send_secondary_completion(meta, position, items, ctx);
+        },
+    );
+}
+
+fn send_secondary_completion(
+    meta: EditorMeta,
+    position: KakounePosition,
+    items: Vec<CompletionItem>,
+    ctx: &Context,
+) {
+    let mut params = toml::value::Table::default();
+    params.insert(
+        "language_id".to_string(),
+        toml::Value::String(ctx.language_id.clone()),
+    );
+    params.insert(
+        "offset_encoding".to_string(),
+        toml::Value::try_from(ctx.offset_encoding).unwrap(),
+    );
+    params.insert(
+        "position".to_string(),
+        toml::Value::try_from(position).unwrap(),
+    );
+    params.insert(
+        "items".to_string(),
+        toml::Value::String(serde_json::to_string(&items).unwrap()),
+    );
+    let request = EditorRequest {
+        meta,
+        method: "kak-lsp/secondary-completion".to_string(),
+        params: toml::Value::Table(params),
+        ranges: None,
+    };
+    if let Err(err) = ctx.session_tx.send(request) {
+        error!("Failed to send completions to the primary server: {}", err);
+    }
+}
+
+/// Cancels the completion requests in flight for the same client and buffer, since the keystroke
+/// of the new request makes their results stale. Their responses are dropped, so only the latest
+/// completions are shown.
+fn cancel_superseded_completions(meta: &EditorMeta, ctx: &mut Context) {
+    let superseded: Vec<(Id, usize)> = ctx
+        .response_waitlist
+        .iter()
+        .filter(|(_, request)| {
+            request.method == Completion::METHOD
+                && request.meta.session == meta.session
+                && request.meta.client == meta.client
+                && request.meta.buffile == meta.buffile
+                && ctx.batches.contains_key(&request.batch_id)
+        })
+        .map(|(id, request)| (id.clone(), request.batch_id))
+        .collect();
+    for (id, batch_id) in superseded {
+        ctx.batches.remove(&batch_id);
+        let id = match id {
+            Id::Num(id) => NumberOrString::Number(id as i32),
+            Id::Str(id) => NumberOrString::String(id),
+            Id::Null => continue,
+        };
+        debug!("Cancelling completion request {:?}", id);
+        ctx.notify::<Cancel>(CancelParams { id });
+    }
+}
+
+/// Like the completion request from lsp-types, but applies the `itemDefaults` of a completion
+/// list from LSP 3.17 to its items, since lsp-types does not know about them.
+pub enum CompletionWithItemDefaults {}
+
+impl Request for CompletionWithItemDefaults {
+    type Params = CompletionParams;
+    type Result = Option<CompletionItems>;
+    const METHOD: &'static str = Completion::METHOD;
+}
+
+/// The items of a completion response, with the defaults of the list applied.
+#[derive(Serialize)]
+pub struct CompletionItems(pub Vec<CompletionItem>);
+
+impl<'de> Deserialize<'de> for CompletionItems {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let items = match Value::deserialize(deserializer)? {
+            Value::Array(items) => items,
+            mut list => {
+                let mut items = match list.get_mut("items").map(Value::take) {
+                    Some(Value::Array(items)) => items,
+                    _ => vec![],
+                };
+                if let Some(defaults) = list.get("itemDefaults") {
+                    for item in &mut items {
+                        apply_item_defaults(item, defaults);
+                    }
+                }
+                items
+            }
+        };
+        Vec::<CompletionItem>::deserialize(Value::Array(items))
+            .map(CompletionItems)
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// Fills in the properties that the item leaves to the defaults of its completion list.
+fn apply_item_defaults(item: &mut Value, defaults: &Value) {
+    let item = match item.as_object_mut() {
+        Some(item) => item,
+        None => return,
+    };
+    for (default, property) in [
+        ("commitCharacters", "commitCharacters"),
+        ("insertTextFormat", "insertTextFormat"),
+        ("insertTextMode", "insertTextMode"),
+        ("data", "data"),
+    ] {
+        if let Some(value) = defaults.get(default) {
+            item.entry(property).or_insert_with(|| value.clone());
+        }
+    }
+    let edit_range = match defaults.get("editRange") {
+        Some(edit_range) if !item.contains_key("textEdit") => edit_range,
+        _ => return,
+    };
+    // The text of the edit is the item's textEditText, or else its label.
+    let new_text = item
+        .get("textEditText")
+        .or_else(|| item.get("label"))
+        .cloned()
+        .unwrap_or_default();
+    let text_edit = if edit_range.get("insert").is_some() {
+        json!({
+            "insert": edit_range["insert"],
+            "replace": edit_range["replace"],
+            "newText": new_text,
+        })
+    } else {
+        json!({
+            "range": edit_range,
+            "newText": new_text,
+        })
+    };
+    item.insert("textEdit".to_string(), text_edit);
 }
 
 pub fn editor_completion(
     meta: EditorMeta,
     params: TextDocumentCompletionParams,
-    result: Option<CompletionResponse>,
+    result: Option<CompletionItems>,
     ctx: &mut Context,
 ) {
-    let items = match result {
-        Some(CompletionResponse::Array(items)) => items,
-        Some(CompletionResponse::List(list)) => list.items,
-        None => vec![],
+    let items = result.map(|items| items.0).unwrap_or_default();
+    let language_id = ctx.language_id.clone();
+    add_completion_source(meta, params, language_id, items, ctx);
+}
+
+/// Receives the completions of an additional server of a buffer. Their edits are converted to
+/// this server's offset encoding.
+pub fn receive_secondary_completion(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
+    let params = SecondaryCompletionParams::deserialize(params)
+        .expect("Params should follow SecondaryCompletionParams structure");
+    let request = match ctx.client_state(&meta).completion_request.as_ref() {
+        Some((request_meta, request))
+            if request_meta.version == meta.version && request.position == params.position =>
+        {
+            request.clone()
+        }
+        // Answers an older request.
+        _ => return,
+    };
+    let document = match ctx.documents.get(&meta.buffile) {
+        Some(document) => document,
+        None => return,
+    };
+    let items: Vec<CompletionItem> = match serde_json::from_str(&params.items) {
+        Ok(items) => items,
+        Err(err) => {
+            error!(
+                "Failed to parse completions of {}: {}",
+                params.language_id, err
+            );
+            return;
+        }
+    };
+    let convert = |range: &mut Range| {
+        for position in [&mut range.start, &mut range.end] {
+            let kakoune_position =
+                lsp_position_to_kakoune(position, &document.text, params.offset_encoding);
+            *position =
+                kakoune_position_to_lsp(&kakoune_position, &document.text, ctx.offset_encoding);
+        }
     };
+    let items = items
+        .into_iter()
+        .map(|mut item| {
+            match &mut item.text_edit {
+                Some(CompletionTextEdit::Edit(edit)) => convert(&mut edit.range),
+                Some(CompletionTextEdit::InsertAndReplace(edit)) => {
+                    convert(&mut edit.insert);
+                    convert(&mut edit.replace);
+                }
+                None => (),
+            }
+            for edit in item.additional_text_edits.iter_mut().flatten() {
+                convert(&mut edit.range);
+            }
+            item
+        })
+        .collect();
+    add_completion_source(meta, request, params.language_id, items, ctx);
+}
 
-    ctx.completion_items = items;
-    let items = &ctx.completion_items;
-    if ctx.completion_last_client != meta.client {
-        ctx.completion_last_client = meta.client.clone();
+/// Adds the items that one server answered the client's completion request with. Once all servers
+/// answered, or the others are late, shows the items of all servers that answered. The menu is
+/// only built once, since rebuilding it would lose the selection and make it flicker.
+fn add_completion_source(
+    meta: EditorMeta,
+    params: TextDocumentCompletionParams,
+    language_id: LanguageId,
+    items: Vec<CompletionItem>,
+    ctx: &mut Context,
+) {
+    let state = ctx.client_state(&meta);
+    if state.completion_menu_shown {
+        debug!("Dropping completions of {} that came too late", language_id);
+        return;
     }
+    state.completion_sources.insert(language_id, items);
+    if state.completion_sources.len() < state.completion_servers {
+        if state.completion_menu_due.is_none() {
+            state.completion_menu_due = Some(Instant::now() + SECONDARY_COMPLETION_TIMEOUT);
+        }
+        return;
+    }
+    state.completion_menu_due = None;
+    show_completions(meta, params, ctx);
+}
+
+/// Shows the completion menu with the items of the servers that answered.
+fn show_completions(meta: EditorMeta, params: TextDocumentCompletionParams, ctx: &mut Context) {
+    let precedence = ctx.language_config().completion_precedence.clone();
+    let primary = ctx.language_id.clone();
+    let state = ctx.client_state(&meta);
+    state.completion_menu_shown = true;
+    let (sources, items) =
+        merge_completion_sources(&state.completion_sources, &precedence, &primary)
+            .into_iter()
+            .unzip();
+    state.completion_items = items;
+    state.completion_item_sources = sources;
+    let annotate_sources = state.completion_sources.len() > 1;
+
+    let key = (
+        meta.session.clone(),
+        meta.client.clone().unwrap_or_default(),
+    );
+    let state = &ctx.clients[&key];
+    let items = &state.completion_items;
+    let sources = &state.completion_item_sources;
 
     if items.is_empty() {
         return;
     }
 
-    // Length of the longest label in the current completion list
-    let maxlen = items.iter().map(|x| x.label.len()).max().unwrap_or(0);
+    let labels: Vec<MenuLabel> = items
+        .iter()
+        .map(|x| menu_label(x, params.label_max_width))
+        .collect();
+    // Display width of the longest label and description in the current completion list
+    let maxlen = labels.iter().map(MenuLabel::width).max().unwrap_or(0);
+    let max_description_len = labels
+        .iter()
+        .map(|label| label.description.width())
+        .max()
+        .unwrap_or(0);
 
     let mut inferred_offset: Option<u32> = None;
     let mut can_infer_offset = true;
 
+    // Snippets are indented like the line they are inserted into.
+    let snippet_indent: String = ctx
+        .documents
+        .get(&meta.buffile)
+        .map(|document| {
+            get_line(
+                params.position.line.saturating_sub(1) as usize,
+                &document.text,
+            )
+            .chars()
+            .take_while(|c| *c == ' ' || *c == '\t')
+            .collect()
+        })
+        .unwrap_or_default();
+    let indent_unit = if params.indent_width == 0 {
+        "\t".to_string()
+    } else {
+        " ".repeat(params.indent_width)
+    };
+    let has_signature_help = server_has_capability(ctx, CAPABILITY_SIGNATURE_HELP);
+    let markdown_options = ctx.language_config().markdown;
+    let can_resolve = ctx
+        .capabilities
+        .as_ref()
+        .and_then(|caps| caps.completion_provider.as_ref())
+        .and_then(|compl| compl.resolve_provider)
+        .unwrap_or(false);
+
     let items = items
         .iter()
         .enumerate()
         .map(|(completion_item_index, x)| {
-            let maybe_resolve = if ctx
-                .capabilities
-                .as_ref()
-                .and_then(|caps| caps.completion_provider.as_ref())
-                .and_then(|compl| compl.resolve_provider)
-                .unwrap_or(false)
-            {
+            let source = &sources[completion_item_index];
+            // Only our own server can resolve its items.
+            let maybe_resolve = if can_resolve && *source == ctx.language_id {
                 "lsp-completion-item-resolve\n"
             } else {
                 ""
@@ -76,40 +488,44 @@ pub fn editor_completion(
             let on_select = formatdoc!(
                 "lsp-completion-item-selected {completion_item_index}
                  {maybe_resolve}info -markup -style menu -- %§{}§",
-                completion_menu_text(x).replace('§', "§§")
+                completion_menu_text(x, &markdown_options).replace('§', "§§")
             );
 
-            let entry = match x.kind {
-                Some(k) => format!(
-                    "{}{} {{MenuInfo}}{:?}",
-                    escape_kakoune_markup(&x.label),
-                    " ".repeat(maxlen - x.label.len()),
-                    k
-                ),
-                None => escape_kakoune_markup(&x.label),
-            };
+            let label = &labels[completion_item_index];
+            let mut entry = escape_kakoune_markup(&label.label);
+            if !label.detail.is_empty() {
+                entry.push_str("{CompletionLabelDetail}");
+                entry.push_str(&escape_kakoune_markup(&label.detail));
+            }
+            let mut padding = maxlen - label.width();
+            if max_description_len > 0 {
+                entry.push_str(&" ".repeat(padding));
+                entry.push_str(" {CompletionLabelDescription}");
+                entry.push_str(&escape_kakoune_markup(&label.description));
+                padding = max_description_len - label.description.width();
+            }
+            if let Some(k) = x.kind {
+                entry.push_str(&format!("{} {{MenuInfo}}{:?}", " ".repeat(padding), k));
+            }
+            if annotate_sources {
+                entry.push_str(&format!(" {{MenuInfo}}[{}]", escape_kakoune_markup(source)));
+            }
+            if let Some(color) = completion_item_color(x) {
+                entry.push_str(&format!(" {{default,{}}}  ", color));
+            }
 
             let maybe_filter_text = if !params.have_kakoune_feature_filtertext {
                 None
             } else {
                 let specified_filter_text = x.filter_text.as_ref().unwrap_or(&x.label);
-                let specified_insert_text = x
-                    .text_edit
-                    .as_ref()
-                    .map(|cte| match cte {
-                        CompletionTextEdit::Edit(text_edit) => &text_edit.new_text,
-                        CompletionTextEdit::InsertAndReplace(text_edit) => &text_edit.new_text,
-                    })
-                    .or(x.insert_text.as_ref())
-                    .unwrap_or(&x.label);
-                if specified_filter_text == specified_insert_text {
+                if specified_filter_text == specified_insert_text(x) {
                     None
                 } else {
                     Some(specified_filter_text.clone())
                 }
             };
 
-            let insert_text = x.text_edit.as_ref().and_then(|cte| {
+            let edit = x.text_edit.as_ref().and_then(|cte| {
                 let document = match ctx.documents.get(&meta.buffile) {
                     Some(doc) => doc,
                     None => {
@@ -119,49 +535,71 @@ pub fn editor_completion(
                     }
                 };
 
-                match cte {
-                    CompletionTextEdit::Edit(text_edit) => {
-                        // The generic textEdit property is not supported yet (#40).  However,
-                        // we can support simple text edits that only replace the token left
-                        // of the cursor. Kakoune will do this very edit if we simply pass it
-                        // the replacement string as completion.
-                        let range = lsp_range_to_kakoune(
-                            &text_edit.range,
-                            &document.text,
-                            ctx.offset_encoding,
-                        );
-
-                        if can_infer_offset {
-                            match inferred_offset {
-                                None => inferred_offset = Some(range.start.column),
-                                Some(offset) if offset != range.start.column => {
-                                    can_infer_offset = false;
-                                    inferred_offset = None
-                                }
-                                _ => (),
-                            }
+                // For an insert-and-replace edit, Kakoune inserts the text over the insert range.
+                // In replace mode, the rest of the replace range is deleted once the completion
+                // is accepted.
+                let (lsp_range, new_text, replaced_suffix) = match cte {
+                    CompletionTextEdit::Edit(text_edit) => (&text_edit.range, &text_edit.new_text, 0),
+                    CompletionTextEdit::InsertAndReplace(edit) => {
+                        let replaced_suffix = if params.insert_mode == "replace" {
+                            suffix_length(
+                                &document.text,
+                                edit.insert.end,
+                                edit.replace.end,
+                                ctx.offset_encoding,
+                            )
+                        } else {
+                            0
                         };
+                        (&edit.insert, &edit.new_text, replaced_suffix)
+                    }
+                };
+                // The generic textEdit property is not supported yet (#40).  However,
+                // we can support simple text edits that only replace the token left
+                // of the cursor. Kakoune will do this very edit if we simply pass it
+                // the replacement string as completion.
+                let range = lsp_range_to_kakoune(lsp_range, &document.text, ctx.offset_encoding);
 
-                        if range.start.line == params.position.line
-                            && range.end.line == params.position.line
-                            // Not sure why this case happens, see #455
-                            && (range.end.column == params.position.column
-                                || range.end.column + 1 == params.position.column)
-                        {
-                            Some(text_edit.new_text.clone())
-                        } else {
-                            None
+                if can_infer_offset {
+                    match inferred_offset {
+                        None => inferred_offset = Some(range.start.column),
+                        Some(offset) if offset != range.start.column => {
+                            can_infer_offset = false;
+                            inferred_offset = None
                         }
+                        _ => (),
                     }
-                    CompletionTextEdit::InsertAndReplace(_) => {
-                        can_infer_offset = false;
-                        None
-                    }
+                };
+
+                if range.start.line == params.position.line
+                    && range.end.line == params.position.line
+                    // Not sure why this case happens, see #455
+                    && (range.end.column == params.position.column
+                        || range.end.column + 1 == params.position.column)
+                {
+                    Some((new_text.clone(), replaced_suffix))
+                } else {
+                    None
                 }
             });
-            let insert_text = insert_text
-                .or_else(|| x.insert_text.clone())
-                .unwrap_or_else(|| x.label.clone());
+            let (insert_text, replaced_suffix) = edit.unwrap_or_else(|| {
+                (x.insert_text.clone().unwrap_or_else(|| x.label.clone()), 0)
+            });
+            let on_select = if replaced_suffix > 0 {
+                format!("{}\nlsp-completion-replace-suffix {}", on_select, replaced_suffix)
+            } else {
+                on_select
+            };
+            // Once a function call is inserted, show its signature. This is registered last, so it
+            // runs after the snippet is inserted.
+            let maybe_signature_help = if has_signature_help
+                && is_callable(x.kind)
+                && insert_text.contains('(')
+            {
+                "\nlsp-completion-signature-help"
+            } else {
+                ""
+            };
 
             fn completion_entry(
                 insert_text: &str,
@@ -204,30 +642,192 @@ pub fn editor_completion(
                 // There's some issue with multiline insert texts, and they also don't work well in the UI, so display on one line
                 let insert_text = insert_text.replace('\n', "");
 
+                let expanded = snippet::indent(snippet::parse(&snippet), &snippet_indent, &indent_unit);
                 let command = formatdoc!(
                     "{on_select}
-                     lsp-snippets-insert-completion {}",
-                    editor_quote(&snippet)
+                     lsp-snippets-insert-completion {} {} {}{maybe_signature_help}",
+                    editor_quote(&snippet),
+                    editor_quote(&expanded.text),
+                    editor_quote(&snippet_placeholders(&expanded)),
                 );
 
                 completion_entry(&insert_text, &maybe_filter_text, &command, &entry)
             } else {
-                completion_entry(&insert_text, &maybe_filter_text, &on_select, &entry)
+                let command = format!("{}{}", on_select, maybe_signature_help);
+                completion_entry(&insert_text, &maybe_filter_text, &command, &entry)
             }
         })
-        .join(" ");
+        .collect::<Vec<_>>();
 
     let p = params.position;
     let offset = inferred_offset.unwrap_or(params.completion.offset);
-    let command = format!(
-        "set-option window lsp_completions {}.{}@{} {}\n",
-        p.line, offset, meta.version, items
-    );
+    let header = format!("{}.{}@{}", p.line, offset, meta.version);
+    let commands = set_list_option_commands("window", "lsp_completions", &header, &items);
+    ctx.exec_chunked(meta, commands);
+}
+
+/// Orders the items of the servers that answered a completion request, with their server's
+/// language. Servers listed in `precedence` come first, then the buffer's primary server, then the
+/// others. Of identical items, only the one from the first server is kept. Some servers also
+/// send the same item more than once, for example once per overload.
+fn merge_completion_sources(
+    sources: &HashMap<LanguageId, Vec<CompletionItem>>,
+    precedence: &[LanguageId],
+    primary: &str,
+) -> Vec<(LanguageId, CompletionItem)> {
+    sources
+        .iter()
+        .sorted_by_key(|(language_id, _)| {
+            let rank = precedence
+                .iter()
+                .position(|preferred| preferred == *language_id)
+                .unwrap_or(precedence.len() + usize::from(*language_id != primary));
+            (rank, *language_id)
+        })
+        .flat_map(|(language_id, items)| items.iter().map(move |item| (language_id, item)))
+        .unique_by(|(_, x)| {
+            (
+                x.label.clone(),
+                x.kind.map(|kind| format!("{:?}", kind)),
+                specified_insert_text(x).to_string(),
+            )
+        })
+        .map(|(language_id, item)| (language_id.clone(), item.clone()))
+        .collect()
+}
+
+/// Returns the number of characters between the end of the insert range and the end of the replace
+/// range of a completion, if both are on the same line.
+fn suffix_length(
+    text: &Rope,
+    insert_end: Position,
+    replace_end: Position,
+    offset_encoding: OffsetEncoding,
+) -> usize {
+    if insert_end.line != replace_end.line || replace_end.character <= insert_end.character {
+        return 0;
+    }
+    let start = lsp_position_to_kakoune(&insert_end, text, offset_encoding);
+    let end = lsp_position_to_kakoune(&replace_end, text, offset_encoding);
+    let line = get_line(start.line as usize - 1, text).to_string();
+    line.get(start.column as usize - 1..end.column as usize - 1)
+        .map_or(0, |suffix| suffix.chars().count())
+}
+
+/// Describes the placeholders of an expanded snippet for `lsp-snippets-insert-selections`,
+/// as `<tabstop>|<line>.<column>,<line>.<column>` elements. Lines are relative to the insertion
+/// point, and so are columns on its line; other columns start at 0. The final tabstop `$0` is
+/// 9999, so it sorts last.
+fn snippet_placeholders(snippet: &snippet::Snippet) -> String {
+    snippet
+        .placeholders
+        .iter()
+        .map(|placeholder| {
+            let (start_line, start_column) =
+                snippet::line_and_column(&snippet.text, placeholder.range.start);
+            let last_char_len = snippet.text[..placeholder.range.end]
+                .chars()
+                .next_back()
+                .map_or(0, |c| c.len_utf8());
+            let (end_line, end_column) =
+                snippet::line_and_column(&snippet.text, placeholder.range.end - last_char_len);
+            let tabstop = if placeholder.tabstop == 0 {
+                9999
+            } else {
+                placeholder.tabstop
+            };
+            format!(
+                "{}|{}.{},{}.{}",
+                tabstop, start_line, start_column, end_line, end_column
+            )
+        })
+        .join(" ")
+}
+
+/// Whether the item is something that is called, so its signature is worth showing once it is
+/// inserted with its parentheses.
+fn is_callable(kind: Option<CompletionItemKind>) -> bool {
+    matches!(
+        kind,
+        Some(
+            CompletionItemKind::FUNCTION
+                | CompletionItemKind::METHOD
+                | CompletionItemKind::CONSTRUCTOR
+        )
+    )
+}
+
+/// The text that the server wants to insert for this item.
+fn specified_insert_text(x: &CompletionItem) -> &str {
+    x.text_edit
+        .as_ref()
+        .map(|cte| match cte {
+            CompletionTextEdit::Edit(text_edit) => &text_edit.new_text,
+            CompletionTextEdit::InsertAndReplace(text_edit) => &text_edit.new_text,
+        })
+        .or(x.insert_text.as_ref())
+        .unwrap_or(&x.label)
+}
+
+/// The label of a completion item in the menu, followed by its label details: the detail, like a
+/// signature, and the description, like the module of an import, which are shown in a column.
+struct MenuLabel<'a> {
+    label: Cow<'a, str>,
+    detail: Cow<'a, str>,
+    description: Cow<'a, str>,
+}
+
+impl MenuLabel<'_> {
+    /// The width of the label and its detail.
+    fn width(&self) -> usize {
+        self.label.width() + self.detail.width()
+    }
+}
 
-    ctx.exec(meta, command);
+/// Truncates the label and its detail to `max_width` together, and the description on its own.
+fn menu_label(x: &CompletionItem, max_width: usize) -> MenuLabel<'_> {
+    let label = truncate_label(&x.label, max_width);
+    let details = x.label_details.as_ref();
+    let detail = details.and_then(|details| details.detail.as_deref());
+    let detail = match (detail, max_width) {
+        (None, _) => Cow::Borrowed(""),
+        (Some(detail), 0) => Cow::Borrowed(detail),
+        (Some(_), _) if label.width() + 1 >= max_width => Cow::Borrowed(""),
+        (Some(detail), _) => truncate_label(detail, max_width - label.width()),
+    };
+    let description = details
+        .and_then(|details| details.description.as_deref())
+        .map_or(Cow::Borrowed(""), |description| {
+            truncate_label(description, max_width)
+        });
+    MenuLabel {
+        label,
+        detail,
+        description,
+    }
 }
 
-fn completion_menu_text(x: &CompletionItem) -> String {
+/// Shortens a label to at most `max_width` columns, ending it with an ellipsis if it was cut.
+/// A `max_width` of 0 means no limit.
+fn truncate_label(label: &str, max_width: usize) -> Cow<'_, str> {
+    if max_width == 0 || label.width() <= max_width {
+        return Cow::Borrowed(label);
+    }
+    let mut truncated = String::new();
+    let mut width = 0;
+    for c in label.chars() {
+        let char_width = c.width().unwrap_or(0);
+        if width + char_width + 1 > max_width {
+            break;
+        }
+        truncated.push(c);
+        width += char_width;
+    }
+    truncated.push('…');
+    Cow::Owned(truncated)
+}
+
+fn completion_menu_text(x: &CompletionItem, markdown_options: &MarkdownOptions) -> String {
     // Combine the 'detail' line and the full-text documentation into
     // a single string. If both exist, separate them with a horizontal rule.
     let mut markup = String::new();
@@ -240,11 +840,18 @@ fn completion_menu_text(x: &CompletionItem) -> String {
         }
     }
 
+    if let Some(color) = completion_item_color(x) {
+        markup.push_str(&format!("{{default,{}}}    {{default}} ", color));
+    }
+
     match x.documentation.as_ref() {
         Some(Documentation::String(s)) => markup.push_str(&escape_kakoune_markup(s)),
         Some(Documentation::MarkupContent(content)) => match content.kind {
             MarkupKind::PlainText => markup.push_str(&escape_kakoune_markup(&content.value)),
-            MarkupKind::Markdown => markup.push_str(&markdown_to_kakoune_markup(&content.value)),
+            MarkupKind::Markdown => markup.push_str(&markdown_to_kakoune_markup_with_options(
+                &content.value,
+                markdown_options,
+            )),
         },
         _ => (),
     }
@@ -252,18 +859,76 @@ fn completion_menu_text(x: &CompletionItem) -> String {
     markup
 }
 
+/// Color completions (as sent by tailwindcss-language-server) carry the color in their
+/// documentation, as `#rrggbb` or `rgb(r, g, b)`. Return it as Kakoune color.
+fn completion_item_color(x: &CompletionItem) -> Option<String> {
+    if x.kind != Some(CompletionItemKind::COLOR) {
+        return None;
+    }
+    let color = match x.documentation.as_ref()? {
+        Documentation::String(s) => s,
+        Documentation::MarkupContent(content) => &content.value,
+    }
+    .trim();
+    if let Some(hex) = color.strip_prefix('#') {
+        if !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            return None;
+        }
+        return match hex.len() {
+            3 | 4 => Some(format!(
+                "rgb:{}",
+                hex.chars().take(3).map(|c| format!("{c}{c}")).join("")
+            )),
+            6 | 8 => Some(format!("rgb:{}", &hex[..6])),
+            _ => None,
+        };
+    }
+    let components = color
+        .strip_prefix("rgba(")
+        .or_else(|| color.strip_prefix("rgb("))?
+        .strip_suffix(')')?
+        .split([',', ' ', '/'])
+        .filter(|component| !component.is_empty())
+        .take(3)
+        .map(|component| component.parse::<u8>().ok())
+        .collect::<Option<Vec<_>>>()?;
+    if components.len() != 3 {
+        return None;
+    }
+    Some(format!(
+        "rgb:{:02x}{:02x}{:02x}",
+        components[0], components[1], components[2]
+    ))
+}
+
 pub fn completion_item_resolve(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
     let CompletionItemResolveParams {
         completion_item_index,
         pager_active,
     } = CompletionItemResolveParams::deserialize(params).unwrap();
 
-    if ctx.completion_last_client.is_none() || meta.client != ctx.completion_last_client {
+    let language_id = ctx.language_id.clone();
+    let state = match meta
+        .client
+        .as_ref()
+        .and_then(|client| ctx.clients.get_mut(&(meta.session.clone(), client.clone())))
+    {
+        Some(state) => state,
+        None => return,
+    };
+    let completion_item_index = completion_item_index as usize;
+    if completion_item_index >= state.completion_items.len() {
+        return;
+    }
+    // Items of additional servers are shown as they were sent.
+    let own_item = state.completion_item_sources[completion_item_index] == language_id;
+    if pager_active && !own_item {
         return;
     }
+    let completion_items = &mut state.completion_items;
 
     let (item, detail, documentation) = if pager_active {
-        let item = &ctx.completion_items[completion_item_index as usize];
+        let item = &completion_items[completion_item_index];
         // Stop if there is nothing interesting to resolve.
         if item.detail.is_some() && item.documentation.is_some() {
             return;
@@ -275,11 +940,11 @@ pub fn completion_item_resolve(meta: EditorMeta, params: EditorParams, ctx: &mut
         )
     } else {
         // Since we're the only user of the completion items, we can clear them.
-        let item = ctx
-            .completion_items
+        let item = completion_items
             .drain(..)
-            .nth(completion_item_index as usize)
+            .nth(completion_item_index)
             .unwrap();
+        state.completion_item_sources.clear();
 
         match item.additional_text_edits {
             Some(edits) if !edits.is_empty() => {
@@ -290,6 +955,9 @@ pub fn completion_item_resolve(meta: EditorMeta, params: EditorParams, ctx: &mut
             }
             _ => (),
         }
+        if !own_item {
+            return;
+        }
 
         (item, None, None)
     };
@@ -311,11 +979,14 @@ fn editor_completion_item_resolve(
         if new_item.detail == old_detail || new_item.documentation == old_documentation {
             return;
         }
+        if !info_box::claim(&meta, InfoBoxOwner::CompletionDocs) {
+            return;
+        }
         ctx.exec(
             meta,
             format!(
                 "info -markup -style menu -- %§{}§",
-                completion_menu_text(&new_item).replace('§', "§§")
+                completion_menu_text(&new_item, &ctx.language_config().markdown).replace('§', "§§")
             ),
         );
     } else if let Some(resolved_edits) = new_item.additional_text_edits {
@@ -323,3 +994,239 @@ fn editor_completion_item_resolve(
         apply_text_edits(&meta, &uri, resolved_edits, ctx)
     }
 }
+
+/// Adds the import for the selected identifier without inserting anything. Asks for completions
+/// after the identifier and applies the additional text edits of the items that complete exactly
+/// that identifier. If there is more than one, like for a name that exists in several modules,
+/// the user picks one from a menu.
+pub fn import_symbol(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
+    let params = MainSelectionParams::deserialize(params).unwrap();
+    let (range, _cursor) = parse_kakoune_range(&params.selection_desc);
+    let document = match ctx.documents.get(&meta.buffile) {
+        Some(document) => document,
+        None => return,
+    };
+    if range.start.line != range.end.line {
+        let command = "lsp-show-error 'lsp-import-symbol: select an identifier on a single line'";
+        ctx.exec(meta, command.to_string());
+        return;
+    }
+    let line = document.text.line(range.start.line as usize - 1);
+    let start = line.byte_to_char(range.start.column as usize - 1);
+    let end = (line.byte_to_char(range.end.column as usize - 1) + 1).min(line.len_chars());
+    let identifier = line.slice(start..end).to_string();
+    let identifier = identifier.trim_end_matches('\n').to_string();
+    let after_identifier = KakounePosition {
+        line: range.start.line,
+        column: range.start.column + identifier.len() as u32,
+    };
+    let req_params = CompletionParams {
+        text_document_position: TextDocumentPositionParams {
+            text_document: TextDocumentIdentifier {
+                uri: Url::from_file_path(&meta.buffile).unwrap(),
+            },
+            position: kakoune_position_to_lsp(
+                &after_identifier,
+                &document.text,
+                ctx.offset_encoding,
+            ),
+        },
+        context: Some(CompletionContext {
+            trigger_kind: CompletionTriggerKind::INVOKED,
+            trigger_character: None,
+        }),
+        work_done_progress_params: Default::default(),
+        partial_result_params: Default::default(),
+    };
+    ctx.call::<CompletionWithItemDefaults, _>(
+        meta,
+        req_params,
+        move |ctx: &mut Context, meta, result| {
+            let items = result.map(|items| items.0).unwrap_or_default();
+            let (with_edits, to_resolve): (Vec<_>, Vec<_>) = items
+                .into_iter()
+                .filter(|item| {
+                    item.label.trim() == identifier
+                        || item.filter_text.as_deref().map(str::trim) == Some(&identifier)
+                })
+                .partition(has_additional_text_edits);
+            let can_resolve = ctx
+                .capabilities
+                .as_ref()
+                .and_then(|caps| caps.completion_provider.as_ref())
+                .and_then(|provider| provider.resolve_provider)
+                .unwrap_or(false);
+            if !can_resolve || to_resolve.is_empty() {
+                return editor_import_symbol(meta, &identifier, with_edits, ctx);
+            }
+            ctx.batch_call::<ResolveCompletionItem, _>(
+                meta,
+                to_resolve,
+                move |ctx: &mut Context, meta, resolved| {
+                    let mut items = with_edits;
+                    items.extend(resolved.into_iter().filter(has_additional_text_edits));
+                    editor_import_symbol(meta, &identifier, items, ctx)
+                },
+            );
+        },
+    );
+}
+
+fn has_additional_text_edits(item: &CompletionItem) -> bool {
+    matches!(&item.additional_text_edits, Some(edits) if !edits.is_empty())
+}
+
+fn editor_import_symbol(
+    meta: EditorMeta,
+    identifier: &str,
+    items: Vec<CompletionItem>,
+    ctx: &mut Context,
+) {
+    let uri = Url::from_file_path(&meta.buffile).unwrap();
+    // Servers may offer the same import several times, for example once per overload.
+    let mut items = items
+        .into_iter()
+        .unique_by(|item| format!("{:?}", item.additional_text_edits))
+        .collect::<Vec<_>>();
+    if items.len() == 1 {
+        let edits = items.remove(0).additional_text_edits.unwrap();
+        apply_text_edits(&meta, &uri, edits, ctx);
+        return;
+    }
+    if items.is_empty() {
+        let command = format!(
+            "lsp-show-error {}",
+            editor_quote(&format!("no import found for {}", identifier))
+        );
+        ctx.exec(meta, command);
+        return;
+    }
+    let titles_and_commands = items
+        .into_iter()
+        .map(|item| {
+            let source = item
+                .label_details
+                .as_ref()
+                .and_then(|details| details.description.as_ref())
+                .or(item.detail.as_ref())
+                .map(|source| source.lines().next().unwrap_or_default().to_string());
+            let title = match source {
+                Some(source) => format!("{} ({})", item.label.trim(), source),
+                None => item.label.trim().to_string(),
+            };
+            let edits = item.additional_text_edits.unwrap();
+            let edit = WorkspaceEdit::new(std::iter::once((uri.clone(), edits)).collect());
+            (title, apply_workspace_edit_editor_command(&edit, false))
+        })
+        .unique_by(|(title, _)| title.clone())
+        .map(|(title, command)| format!("{} {}", editor_quote(&title), editor_quote(&command)))
+        .join(" ");
+    ctx.exec(meta, format!("lsp-menu {}", titles_and_commands));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_truncate_label() {
+        assert_eq!(truncate_label("short", 10), "short");
+        assert_eq!(truncate_label("much_too_long", 0), "much_too_long");
+        assert_eq!(truncate_label("much_too_long", 8), "much_to…");
+        // Wide characters take two columns, so only three fit next to the ellipsis.
+        assert_eq!(truncate_label("日本語のラベル", 8), "日本語…");
+        assert_eq!(truncate_label("日本語のラベル", 8).width(), 7);
+    }
+
+    #[test]
+    fn test_merge_completion_sources() {
+        let item = |label: &str| CompletionItem {
+            label: label.to_string(),
+            ..CompletionItem::default()
+        };
+        let mut sources = HashMap::new();
+        sources.insert("css".to_string(), vec![item("b"), item("c")]);
+        sources.insert("html".to_string(), vec![item("a"), item("b"), item("b")]);
+        sources.insert("emmet".to_string(), vec![item("c"), item("d")]);
+        let merged = |precedence: &[LanguageId]| {
+            merge_completion_sources(&sources, precedence, "html")
+                .into_iter()
+                .map(|(language_id, item)| format!("{}:{}", language_id, item.label))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(merged(&[]), ["html:a", "html:b", "css:c", "emmet:d"]);
+        assert_eq!(
+            merged(&["emmet".to_string()]),
+            ["emmet:c", "emmet:d", "html:a", "html:b"]
+        );
+    }
+
+    #[test]
+    fn test_item_defaults() {
+        let range =
+            json!({"start": {"line": 1, "character": 4}, "end": {"line": 1, "character": 6}});
+        let response = json!({
+            "isIncomplete": false,
+            "itemDefaults": {"editRange": range, "insertTextFormat": 2, "data": 7},
+            "items": [
+                {"label": "foo"},
+                {"label": "bar()", "textEditText": "bar($0)", "insertTextFormat": 1},
+                {"label": "baz", "textEdit": {"range": range, "newText": "baz2"}},
+            ],
+        });
+        let items = CompletionItems::deserialize(response).unwrap().0;
+        let edit_text = |item: &CompletionItem| match &item.text_edit {
+            Some(CompletionTextEdit::Edit(edit)) => edit.new_text.clone(),
+            _ => panic!("missing text edit"),
+        };
+        assert_eq!(edit_text(&items[0]), "foo");
+        assert_eq!(items[0].insert_text_format, Some(InsertTextFormat::SNIPPET));
+        assert_eq!(items[0].data, Some(json!(7)));
+        assert_eq!(edit_text(&items[1]), "bar($0)");
+        assert_eq!(
+            items[1].insert_text_format,
+            Some(InsertTextFormat::PLAIN_TEXT)
+        );
+        assert_eq!(edit_text(&items[2]), "baz2");
+
+        let items = CompletionItems::deserialize(json!([{"label": "foo"}]))
+            .unwrap()
+            .0;
+        assert_eq!(items[0].text_edit, None);
+    }
+
+    #[test]
+    fn test_menu_label() {
+        let item = CompletionItem {
+            label: "push".to_string(),
+            label_details: Some(CompletionItemLabelDetails {
+                detail: Some("(value: T)".to_string()),
+                description: Some("alloc::vec::Vec".to_string()),
+            }),
+            ..CompletionItem::default()
+        };
+        let label = menu_label(&item, 0);
+        assert_eq!(label.detail, "(value: T)");
+        assert_eq!(label.width(), 14);
+        assert_eq!(label.description, "alloc::vec::Vec");
+        let label = menu_label(&item, 10);
+        assert_eq!(label.label, "push");
+        assert_eq!(label.detail, "(valu…");
+        assert_eq!(label.description, "alloc::ve…");
+        assert_eq!(menu_label(&item, 5).detail, "");
+    }
+
+    #[test]
+    fn test_suffix_length() {
+        let text = Rope::from_str("let x = fo|ö_bar;\n");
+        let position = |character| Position::new(0, character);
+        assert_eq!(
+            suffix_length(&text, position(10), position(16), OffsetEncoding::Utf16),
+            6
+        );
+        assert_eq!(
+            suffix_length(&text, position(10), position(10), OffsetEncoding::Utf16),
+            0
+        );
+    }
+}
@@ -1,5 +1,6 @@
 use crate::context::*;
 use crate::position::*;
+use crate::types::EditorMeta;
 use crate::util::*;
 use itertools::Itertools;
 use jsonrpc_core::Params;
@@ -154,7 +155,7 @@ pub struct PublishSemanticHighlightingParams {
     pub symbols: Vec<SemanticSymbol>,
 }
 
-pub fn publish_semantic_highlighting(params: Params, ctx: &mut Context) {
+pub fn publish_semantic_highlighting(_meta: EditorMeta, params: Params, ctx: &mut Context) {
     let params: PublishSemanticHighlightingParams =
         params.parse().expect("Failed to parse semhl params");
     let client = None;
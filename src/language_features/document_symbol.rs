@@ -1,7 +1,11 @@
+use crate::capabilities::{
+    server_has_capability, CAPABILITY_DOCUMENT_SYMBOL, CAPABILITY_FOLDING_RANGE,
+};
+use crate::language_features::goto::edit_and_select_command;
 use crate::language_features::hover::editor_hover;
 use crate::markup::escape_kakoune_markup;
 use crate::position::{
-    get_kakoune_position_with_fallback, get_lsp_position, kakoune_position_to_lsp,
+    get_kakoune_position_with_fallback, get_line, get_lsp_position, kakoune_position_to_lsp,
     lsp_range_to_kakoune, parse_kakoune_range,
 };
 use crate::types::*;
@@ -17,22 +21,110 @@ use std::convert::TryInto;
 use std::path::{Path, PathBuf};
 use url::Url;
 
-pub fn text_document_document_symbol(meta: EditorMeta, ctx: &mut Context) {
-    let req_params = DocumentSymbolParams {
-        text_document: TextDocumentIdentifier {
-            uri: Url::from_file_path(&meta.buffile).unwrap(),
-        },
-        partial_result_params: Default::default(),
-        work_done_progress_params: Default::default(),
+type DocumentSymbolsCallback = Box<dyn FnOnce(&mut Context, EditorMeta, DocumentSymbolsResult)>;
+type DocumentSymbolsResult = Option<DocumentSymbolResponse>;
+
+/// The document symbols of a buffer for one version, shared by all features that need them.
+pub enum DocumentSymbols {
+    /// The request is in flight; the callbacks run when its response arrives.
+    Pending {
+        version: i32,
+        subscribers: Vec<(EditorMeta, DocumentSymbolsCallback)>,
+    },
+    Done {
+        version: i32,
+        result: DocumentSymbolsResult,
+    },
+}
+
+/// Runs the callback with the document symbols of the buffer. The symbols are requested at most
+/// once per buffer version, no matter how many features ask for them at the same time.
+pub fn with_document_symbols(
+    meta: EditorMeta,
+    ctx: &mut Context,
+    callback: impl FnOnce(&mut Context, EditorMeta, DocumentSymbolsResult) + 'static,
+) {
+    let version = match ctx.document_version(&meta.session, &meta.buffile) {
+        // Requests with a fifo want the server's response as is.
+        Some(version) if !meta.write_response_to_fifo => version,
+        _ => return request_document_symbols(meta, ctx, callback),
     };
-    ctx.call::<DocumentSymbolRequest, _>(
-        meta,
-        req_params,
-        move |ctx: &mut Context, meta, result| editor_document_symbol(meta, result, ctx),
+    let subscribers = match ctx.document_symbols.remove(&meta.buffile) {
+        Some(DocumentSymbols::Done {
+            version: cached_version,
+            result,
+        }) if cached_version == version => {
+            ctx.document_symbols.insert(
+                meta.buffile.clone(),
+                DocumentSymbols::Done {
+                    version,
+                    result: result.clone(),
+                },
+            );
+            return callback(ctx, meta, result);
+        }
+        Some(DocumentSymbols::Pending {
+            version: pending_version,
+            mut subscribers,
+        }) => {
+            subscribers.push((meta.clone(), Box::new(callback)));
+            if pending_version == version {
+                ctx.document_symbols.insert(
+                    meta.buffile,
+                    DocumentSymbols::Pending {
+                        version,
+                        subscribers,
+                    },
+                );
+                return;
+            }
+            // The buffer changed since, so the pending request's subscribers get the newer
+            // symbols instead.
+            subscribers
+        }
+        _ => vec![(meta.clone(), Box::new(callback) as DocumentSymbolsCallback)],
+    };
+    ctx.document_symbols.insert(
+        meta.buffile.clone(),
+        DocumentSymbols::Pending {
+            version,
+            subscribers,
+        },
     );
+    // Tagged with the version the symbols are for, so a failure only forgets its own request.
+    let mut meta = meta;
+    meta.version = version;
+    request_document_symbols(meta, ctx, move |ctx, meta, result| {
+        let subscribers = match ctx.document_symbols.remove(&meta.buffile) {
+            Some(DocumentSymbols::Pending {
+                version: pending_version,
+                subscribers,
+            }) if pending_version == version => subscribers,
+            Some(other) => {
+                // A newer request took over.
+                ctx.document_symbols.insert(meta.buffile.clone(), other);
+                return;
+            }
+            None => return,
+        };
+        ctx.document_symbols.insert(
+            meta.buffile.clone(),
+            DocumentSymbols::Done {
+                version,
+                result: result.clone(),
+            },
+        );
+        for (meta, callback) in subscribers {
+            callback(ctx, meta, result.clone());
+        }
+    });
 }
 
-pub fn next_or_prev_symbol(meta: EditorMeta, editor_params: EditorParams, ctx: &mut Context) {
+fn request_document_symbols(
+    meta: EditorMeta,
+    ctx: &mut Context,
+    callback: impl FnOnce(&mut Context, EditorMeta, DocumentSymbolsResult) + 'static,
+) {
     let req_params = DocumentSymbolParams {
         text_document: TextDocumentIdentifier {
             uri: Url::from_file_path(&meta.buffile).unwrap(),
@@ -40,13 +132,37 @@ pub fn next_or_prev_symbol(meta: EditorMeta, editor_params: EditorParams, ctx: &
         partial_result_params: Default::default(),
         work_done_progress_params: Default::default(),
     };
-    ctx.call::<DocumentSymbolRequest, _>(
-        meta,
-        req_params,
-        move |ctx: &mut Context, meta, result| {
-            editor_next_or_prev_symbol(meta, editor_params, result, ctx)
-        },
-    );
+    ctx.call::<DocumentSymbolRequest, _>(meta, req_params, callback);
+}
+
+/// Forgets a pending document symbols request of a buffer after the server failed it, so the
+/// next request is sent again. A newer pending request for a later version is kept.
+pub fn document_symbols_failed(meta: &EditorMeta, ctx: &mut Context) {
+    if let Some(DocumentSymbols::Pending { version, .. }) = ctx.document_symbols.get(&meta.buffile)
+    {
+        if *version == meta.version {
+            ctx.document_symbols.remove(&meta.buffile);
+        }
+    }
+}
+
+pub fn text_document_document_symbol(meta: EditorMeta, ctx: &mut Context) {
+    with_document_symbols(meta, ctx, |ctx, meta, result| {
+        editor_document_symbol(meta, result, ctx)
+    });
+}
+
+/// Requests the document symbols only to store them in the `lsp_document_symbols` option.
+pub fn document_symbols_option(meta: EditorMeta, ctx: &mut Context) {
+    with_document_symbols(meta, ctx, |ctx, meta, result| {
+        set_document_symbols_option(meta, result, ctx)
+    });
+}
+
+pub fn next_or_prev_symbol(meta: EditorMeta, editor_params: EditorParams, ctx: &mut Context) {
+    with_document_symbols(meta, ctx, move |ctx, meta, result| {
+        editor_next_or_prev_symbol(meta, editor_params, result, ctx)
+    });
 }
 
 pub trait Symbol<T: Symbol<T>> {
@@ -118,6 +234,11 @@ pub fn editor_document_symbol(
     result: Option<DocumentSymbolResponse>,
     ctx: &mut Context,
 ) {
+    let mut option_meta = meta.clone();
+    option_meta.fifo = None;
+    option_meta.command_fifo = None;
+    option_meta.write_response_to_fifo = false;
+    set_document_symbols_option(option_meta, result.clone(), ctx);
     let content = match result {
         Some(DocumentSymbolResponse::Flat(result)) => {
             if result.is_empty() {
@@ -143,6 +264,84 @@ pub fn editor_document_symbol(
     ctx.exec(meta, command);
 }
 
+/// Stores the symbols of a buffer in its `lsp_document_symbols` option, for plugins like tag bars.
+/// Each element has the format `<line>.<column>|<depth>|<kind>|<name>`, with symbols listed
+/// before their children. The option is cleared when the buffer changes.
+pub fn set_document_symbols_option(
+    meta: EditorMeta,
+    result: Option<DocumentSymbolResponse>,
+    ctx: &mut Context,
+) {
+    fn flatten_symbols<T: Symbol<T>>(
+        items: Vec<T>,
+        meta: &EditorMeta,
+        ctx: &Context,
+        depth: usize,
+        elements: &mut Vec<String>,
+    ) {
+        for symbol in items {
+            // Flat symbol lists may contain symbols of other files.
+            if matches!(symbol.uri(), Some(uri)
+                if uri.to_file_path().ok().as_deref() != Some(Path::new(&meta.buffile)))
+            {
+                continue;
+            }
+            let position = get_kakoune_position_with_fallback(
+                &meta.buffile,
+                symbol.selection_range().start,
+                ctx,
+            );
+            let element = format!(
+                "{}|{}|{:?}|{}",
+                position,
+                depth,
+                symbol.kind(),
+                symbol.name().replace('\n', " ")
+            );
+            elements.push(editor_quote(&element));
+            flatten_symbols(symbol.children(), meta, ctx, depth + 1, elements);
+        }
+    }
+    let mut elements = vec![];
+    match result {
+        Some(DocumentSymbolResponse::Flat(result)) => {
+            flatten_symbols(result, &meta, ctx, 0, &mut elements)
+        }
+        Some(DocumentSymbolResponse::Nested(result)) => {
+            flatten_symbols(result, &meta, ctx, 0, &mut elements)
+        }
+        None => (),
+    }
+    ctx.document_symbols_buffers.insert(meta.buffile.clone());
+    let commands = set_list_option_commands("buffer", "lsp_document_symbols", "", &elements)
+        .into_iter()
+        .map(|command| {
+            format!(
+                "evaluate-commands -buffer {} -verbatim -- {}",
+                editor_quote(&meta.buffile),
+                command
+            )
+        })
+        .collect();
+    ctx.exec_chunked(meta, commands)
+}
+
+/// Clears the `lsp_document_symbols` option of a buffer after it changed, if it was set.
+pub fn invalidate_document_symbols_option(meta: &EditorMeta, ctx: &mut Context) {
+    if !ctx.document_symbols_buffers.remove(&meta.buffile) {
+        return;
+    }
+    let command = format!(
+        "evaluate-commands -buffer {} -verbatim -- set-option buffer lsp_document_symbols",
+        editor_quote(&meta.buffile)
+    );
+    let mut meta = meta.clone();
+    meta.fifo = None;
+    meta.command_fifo = None;
+    meta.write_response_to_fifo = false;
+    ctx.exec(meta, command);
+}
+
 /// Represent list of symbols as filetype=grep buffer content.
 /// Paths are converted into relative to project root.
 pub fn format_symbol<T: Symbol<T>>(items: Vec<T>, meta: &EditorMeta, ctx: &Context) -> String {
@@ -209,6 +408,33 @@ fn symbol_kind_from_string(value: &str) -> Option<SymbolKind> {
     }
 }
 
+/// Parses the symbol kinds given to commands like `lsp-object`. Besides the names of symbol
+/// kinds, "function" stands for functions, methods and constructors, and "class" for classes,
+/// interfaces, structs and enums.
+fn symbol_kinds_from_strings(values: &[String]) -> Result<Vec<SymbolKind>, String> {
+    let mut kinds = vec![];
+    for value in values {
+        match value.as_str() {
+            "function" => kinds.extend([
+                SymbolKind::FUNCTION,
+                SymbolKind::METHOD,
+                SymbolKind::CONSTRUCTOR,
+            ]),
+            "class" => kinds.extend([
+                SymbolKind::CLASS,
+                SymbolKind::INTERFACE,
+                SymbolKind::STRUCT,
+                SymbolKind::ENUM,
+            ]),
+            _ => kinds.push(
+                symbol_kind_from_string(value)
+                    .ok_or_else(|| format!("unknown symbol kind '{}'", value))?,
+            ),
+        }
+    }
+    Ok(kinds)
+}
+
 fn editor_next_or_prev_symbol(
     meta: EditorMeta,
     editor_params: EditorParams,
@@ -218,11 +444,13 @@ fn editor_next_or_prev_symbol(
     let params = NextOrPrevSymbolParams::deserialize(editor_params).unwrap();
     let hover = params.hover;
 
-    let symbol_kinds_query: Vec<SymbolKind> = params
-        .symbol_kinds
-        .iter()
-        .map(|kind_str| symbol_kind_from_string(kind_str).unwrap())
-        .collect::<Vec<_>>();
+    let symbol_kinds_query = match symbol_kinds_from_strings(&params.symbol_kinds) {
+        Ok(kinds) => kinds,
+        Err(err) => {
+            ctx.exec(meta, format!("lsp-show-error {}", editor_quote(&err)));
+            return;
+        }
+    };
 
     let maybe_details = match result {
         None => return,
@@ -278,13 +506,23 @@ fn editor_next_or_prev_for_details(
                 .unwrap()
                 .to_string()
         };
-        let command = format!(
-            "edit -existing -- {} {} {}",
-            editor_quote(&filename_abs),
-            symbol_position.line,
-            symbol_position.column
-        );
-        ctx.exec(meta, command);
+        // Select the symbol's name if it is found at the symbol's position.
+        let name_end = get_file_contents(&filename_abs, ctx).and_then(|text| {
+            let line = get_line(symbol_position.line as usize - 1, &text).to_string();
+            let name = unadorned_name(ctx, &name);
+            let found = line
+                .get(symbol_position.column as usize - 1..)?
+                .starts_with(name);
+            (found && !name.is_empty()).then(|| KakounePosition {
+                line: symbol_position.line,
+                column: symbol_position.column + name.len() as u32 - 1,
+            })
+        });
+        let range = KakouneRange {
+            start: symbol_position,
+            end: name_end.unwrap_or(symbol_position),
+        };
+        ctx.exec(meta, edit_and_select_command(&filename_abs, range));
         return;
     }
 
@@ -482,18 +720,62 @@ fn find_identifier_in_file(
 }
 
 pub fn object(meta: EditorMeta, editor_params: EditorParams, ctx: &mut Context) {
-    let req_params = DocumentSymbolParams {
+    if !server_has_capability(ctx, CAPABILITY_DOCUMENT_SYMBOL)
+        && server_has_capability(ctx, CAPABILITY_FOLDING_RANGE)
+    {
+        return object_from_folding_ranges(meta, editor_params, ctx);
+    }
+    with_document_symbols(meta, ctx, move |ctx, meta, result| {
+        editor_object(meta, editor_params, result, ctx)
+    });
+}
+
+/// Selects objects by folding range, for servers without document symbols. Folding ranges have no
+/// symbol kinds, so all of them are candidates, except for comments and imports.
+fn object_from_folding_ranges(meta: EditorMeta, editor_params: EditorParams, ctx: &mut Context) {
+    let req_params = FoldingRangeParams {
         text_document: TextDocumentIdentifier {
             uri: Url::from_file_path(&meta.buffile).unwrap(),
         },
-        partial_result_params: Default::default(),
         work_done_progress_params: Default::default(),
+        partial_result_params: Default::default(),
     };
-    ctx.call::<DocumentSymbolRequest, _>(
-        meta,
-        req_params,
-        move |ctx: &mut Context, meta, result| editor_object(meta, editor_params, result, ctx),
-    );
+    ctx.call::<FoldingRangeRequest, _>(meta, req_params, move |ctx, meta, result| {
+        let params = ObjectParams::deserialize(editor_params).unwrap();
+        let document = match ctx.documents.get(&meta.buffile) {
+            Some(document) => document,
+            None => return,
+        };
+        let ranges = result
+            .into_iter()
+            .flatten()
+            .filter(|range| {
+                !matches!(
+                    range.kind,
+                    Some(FoldingRangeKind::Comment) | Some(FoldingRangeKind::Imports)
+                )
+            })
+            .flat_map(|range| {
+                // Folding ranges span whole lines, including the end of the last one.
+                let end_column = document
+                    .text
+                    .get_line(range.end_line as usize)
+                    .map_or(1, |line| line.len_bytes().max(1));
+                let range = KakouneRange {
+                    start: KakounePosition {
+                        line: range.start_line + 1,
+                        column: 1,
+                    },
+                    end: KakounePosition {
+                        line: range.end_line + 1,
+                        column: end_column as u32,
+                    },
+                };
+                [(range, range.start), (range, range.end)]
+            })
+            .collect();
+        select_object(meta, params, ranges, ctx);
+    });
 }
 
 fn editor_object(
@@ -504,21 +786,17 @@ fn editor_object(
 ) {
     let params = ObjectParams::deserialize(editor_params).unwrap();
 
-    let selections: Vec<(KakouneRange, KakounePosition)> = params
-        .selections_desc
-        .split_ascii_whitespace()
-        .into_iter()
-        .map(parse_kakoune_range)
-        .collect();
-
-    let symbol_kinds_query: Vec<SymbolKind> = params
-        .symbol_kinds
-        .iter()
-        .map(|kind_str| symbol_kind_from_string(kind_str).unwrap())
-        .collect::<Vec<_>>();
+    let symbol_kinds_query = match symbol_kinds_from_strings(&params.symbol_kinds) {
+        Ok(kinds) => kinds,
+        Err(err) => {
+            let msg = format!("lsp-object: {}", err);
+            ctx.exec(meta, format!("lsp-show-error {}", editor_quote(&msg)));
+            return;
+        }
+    };
 
     let document = ctx.documents.get(&meta.buffile).unwrap();
-    let mut ranges = match result {
+    let ranges = match result {
         None => return,
         Some(DocumentSymbolResponse::Flat(symbols)) => {
             flat_symbol_ranges(ctx, document, symbols, symbol_kinds_query)
@@ -527,6 +805,25 @@ fn editor_object(
             flat_symbol_ranges(ctx, document, symbols, symbol_kinds_query)
         }
     };
+    select_object(meta, params, ranges, ctx);
+}
+
+/// Selects the objects among the given ranges, each listed once with its start and once with its
+/// end as matching position, according to the mode and count.
+fn select_object(
+    meta: EditorMeta,
+    params: ObjectParams,
+    mut ranges: Vec<(KakouneRange, KakounePosition)>,
+    ctx: &mut Context,
+) {
+    let selections: Vec<(KakouneRange, KakounePosition)> = params
+        .selections_desc
+        .split_ascii_whitespace()
+        .into_iter()
+        .map(parse_kakoune_range)
+        .collect();
+
+    let document = ctx.documents.get(&meta.buffile).unwrap();
 
     if ranges.is_empty() {
         ctx.exec(
@@ -1,6 +1,7 @@
 use super::code_action::apply_workspace_edit_editor_command;
 use crate::context::*;
 use crate::types::*;
+use crate::workspace;
 use lsp_types::request::ExecuteCommand;
 use lsp_types::*;
 
@@ -38,3 +39,16 @@ pub fn organize_imports_response(
 
     ctx.exec(meta, select_cmd);
 }
+
+/// Handle "java.apply.workspaceEdit", which jdt.ls uses in code actions and expects the client
+/// to implement.
+pub fn apply_workspace_edit(meta: EditorMeta, params: ExecuteCommandParams, ctx: &mut Context) {
+    for argument in params.arguments {
+        match serde_json::from_value::<WorkspaceEdit>(argument) {
+            Ok(edit) => {
+                workspace::apply_edit(meta.clone(), edit, false, ctx);
+            }
+            Err(err) => error!("Invalid workspace edit: {}", err),
+        }
+    }
+}
@@ -0,0 +1,171 @@
+use crate::context::*;
+use crate::custom_requests::id_to_string;
+use crate::types::*;
+use crate::util::*;
+use jsonrpc_core::{Id, Params};
+use lsp_types::request::ExecuteCommand;
+use lsp_types::*;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use serde_repr::Deserialize_repr;
+
+pub fn apply_all_fixes(meta: EditorMeta, ctx: &mut Context) {
+    let version = match ctx.document_version(&meta.session, &meta.buffile) {
+        Some(version) => version,
+        None => return,
+    };
+    let req_params = ExecuteCommandParams {
+        command: "eslint.applyAllFixes".to_string(),
+        arguments: vec![json!(VersionedTextDocumentIdentifier {
+            uri: Url::from_file_path(&meta.buffile).unwrap(),
+            version,
+        })],
+        ..ExecuteCommandParams::default()
+    };
+    // The server sends the fixes with a workspace/applyEdit request.
+    ctx.call::<ExecuteCommand, _>(meta, req_params, move |_: &mut Context, _, _| ());
+}
+
+#[derive(Deserialize, Debug)]
+struct OpenDocParams {
+    url: String,
+}
+
+/// Handle the custom requests of vscode-eslint's server. They all expect an empty object as result.
+pub fn server_request(method: &str, params: Params, ctx: &mut Context) -> Value {
+    let meta = ctx.meta_for_session(None);
+    match method {
+        "eslint/openDoc" => {
+            if let Ok(params) = params.parse::<OpenDocParams>() {
+                let message = format!("ESLint documentation: {}", params.url);
+                ctx.exec(
+                    meta,
+                    format!("lsp-show-message-info {}", editor_quote(&message)),
+                );
+            }
+        }
+        "eslint/noConfig" => {
+            ctx.exec(
+                meta,
+                "lsp-show-message-warning 'ESLint: no ESLint configuration found'",
+            );
+        }
+        "eslint/noLibrary" => {
+            ctx.exec(
+                meta,
+                "lsp-show-message-error 'ESLint: failed to load the ESLint library'",
+            );
+        }
+        "eslint/probeFailed" => {
+            ctx.exec(
+                meta,
+                "lsp-show-message-error 'ESLint: probing for the ESLint library failed'",
+            );
+        }
+        _ => unreachable!(),
+    }
+    json!({})
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct ConfirmExecutionParams {
+    library_path: String,
+}
+
+/// Handle "eslint/confirmESLintExecution", which older servers send before they run the ESLint
+/// library of a project, since running a project's code can be unsafe. The user picks an answer
+/// from a menu; until then, the server does not run it.
+pub fn confirm_execution(id: Id, params: Params, ctx: &mut Context) {
+    let params: ConfirmExecutionParams = match params.parse() {
+        Ok(params) => params,
+        Err(err) => {
+            ctx.reply(id, Err(err));
+            return;
+        }
+    };
+    // The results of vscode-eslint's ConfirmExecutionResult.
+    const DENY: u8 = 1;
+    const DISABLE: u8 = 3;
+    const APPROVED: u8 = 4;
+    // Unique among all servers, since the session routes the reply to this server by its ID.
+    let id_string = format!("{}-{:x}", id_to_string(&id), rand::random::<u32>());
+    ctx.pending_server_requests.insert(id_string.clone(), id);
+    send_reply_route(&id_string, ctx);
+    let reply = |result: u8| {
+        format!(
+            "lsp-reply-server-request {} {}",
+            editor_quote(&id_string),
+            result
+        )
+    };
+    let message = format!(
+        "ESLint wants to run the ESLint library at {}. Allow it?",
+        params.library_path
+    );
+    let command = format!(
+        "lsp-show-message-request {} {} allow {} deny {} {} {}",
+        editor_quote(&id_string),
+        editor_quote(&message),
+        editor_quote(&reply(APPROVED)),
+        editor_quote(&reply(DENY)),
+        editor_quote("disable ESLint"),
+        editor_quote(&reply(DISABLE)),
+    );
+    let meta = ctx.meta_for_session(None);
+    ctx.exec(meta, command);
+}
+
+/// Tells the session that the reply to the server request goes to this server, which is usually an
+/// additional server of the buffer, or may serve no buffer at all.
+fn send_reply_route(id: &str, ctx: &Context) {
+    let mut params = toml::value::Table::default();
+    params.insert("id".to_string(), toml::Value::String(id.to_string()));
+    params.insert(
+        "language_id".to_string(),
+        toml::Value::String(ctx.language_id.clone()),
+    );
+    params.insert(
+        "root_path".to_string(),
+        toml::Value::String(ctx.root_path.clone()),
+    );
+    let request = EditorRequest {
+        meta: ctx.meta_for_session(None),
+        method: "kak-lsp/reply-route".to_string(),
+        params: toml::Value::Table(params),
+        ranges: None,
+    };
+    if let Err(err) = ctx.session_tx.send(request) {
+        error!("Failed to send the route of a server request: {}", err);
+    }
+}
+
+#[derive(Deserialize_repr, Debug, PartialEq)]
+#[repr(u8)]
+enum Status {
+    Ok = 1,
+    Warn = 2,
+    Error = 3,
+}
+
+#[derive(Deserialize, Debug)]
+struct StatusParams {
+    state: Status,
+}
+
+pub fn status(meta: EditorMeta, params: Params, ctx: &mut Context) {
+    let params: StatusParams = match params.parse() {
+        Ok(params) => params,
+        Err(err) => {
+            warn!("Failed to parse eslint/status params: {}", err);
+            return;
+        }
+    };
+    debug!("ESLint status: {:?}", params.state);
+    if params.state == Status::Error {
+        ctx.exec(
+            meta,
+            "lsp-show-message-error 'ESLint: failed to validate, see the server log for details'",
+        );
+    }
+}
@@ -1,31 +1,226 @@
-use crate::capabilities::{attempt_server_capability, CAPABILITY_FORMATTING};
+use crate::capabilities::{
+    attempt_server_capability, server_has_capability, CAPABILITY_FORMATTING,
+};
 use crate::context::*;
+use crate::modified_lines::{modified_lines, restrict_text_edits};
+use crate::text_edit::split_whole_buffer_edit;
 use crate::types::*;
+use crate::util::*;
+use crossbeam_channel::{bounded, Receiver};
 use lsp_types::request::*;
 use lsp_types::*;
 use serde::Deserialize;
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::thread;
 use url::Url;
 
 pub fn text_document_formatting(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
+    let params = FormattingParams::deserialize(params)
+        .expect("Params should follow FormattingOptions structure");
+    let modified_lines_only = params.modified_lines_only;
+    if !server_has_capability(ctx, CAPABILITY_FORMATTING) {
+        if let Some(formatter) = ctx.language_config().formatter.clone() {
+            return external_formatting(meta, &formatter, modified_lines_only, ctx);
+        }
+    }
     if meta.fifo.is_none() && !attempt_server_capability(ctx, CAPABILITY_FORMATTING) {
         return;
     }
 
-    let params = FormattingOptions::deserialize(params)
-        .expect("Params should follow FormattingOptions structure");
     let req_params = DocumentFormattingParams {
         text_document: TextDocumentIdentifier {
             uri: Url::from_file_path(&meta.buffile).unwrap(),
         },
-        options: params,
+        options: params.options,
         work_done_progress_params: Default::default(),
     };
     ctx.call::<Formatting, _>(
         meta,
         req_params,
         move |ctx: &mut Context, meta: EditorMeta, result: Option<Vec<TextEdit>>| {
-            let text_edits = result.unwrap_or_default();
-            super::range_formatting::editor_range_formatting(meta, text_edits, ctx)
+            apply_formatting(meta, result.unwrap_or_default(), modified_lines_only, ctx)
         },
     );
 }
+
+fn apply_formatting(
+    meta: EditorMeta,
+    mut text_edits: Vec<TextEdit>,
+    modified_lines_only: bool,
+    ctx: &mut Context,
+) {
+    if modified_lines_only {
+        let text = &ctx.documents[&meta.buffile].text;
+        match modified_lines(&meta.buffile, text, "HEAD") {
+            Ok(lines) => text_edits = restrict_text_edits(text_edits, text, &lines),
+            Err(err) => {
+                let command = format!(
+                    "lsp-show-error {}",
+                    editor_quote(&format!("lsp-formatting: {}", err))
+                );
+                ctx.exec(meta, command);
+                return;
+            }
+        }
+    }
+    super::range_formatting::editor_range_formatting(meta, text_edits, ctx)
+}
+
+/// The external formatter running in the background, and the request that started it.
+pub struct RunningFormatter {
+    meta: EditorMeta,
+    command: String,
+    // The version of the buffer that is being formatted.
+    version: i32,
+    modified_lines_only: bool,
+    pub receiver: Receiver<Result<String, String>>,
+}
+
+/// Formats the buffer with the language's `formatter`, for servers that can't format. The
+/// formatter reads the buffer from stdin and writes the formatted text to stdout, in a background
+/// thread. The buffer is then replaced by its output, see `formatter_done`.
+fn external_formatting(
+    meta: EditorMeta,
+    formatter: &ExternalFormatter,
+    modified_lines_only: bool,
+    ctx: &mut Context,
+) {
+    if ctx.running_formatter.is_some() {
+        ctx.exec(meta, "lsp-show-error 'formatter is still running'");
+        return;
+    }
+    let (text, version) = match ctx.documents.get(&meta.buffile) {
+        Some(document) => (
+            document.text.to_string(),
+            ctx.document_version(&meta.session, &meta.buffile).unwrap(),
+        ),
+        None => return,
+    };
+    let (sender, receiver) = bounded(1);
+    let command = formatter.command.clone();
+    let formatter = formatter.clone();
+    let buffile = meta.buffile.clone();
+    let root_path = ctx.root_path.clone();
+    thread::spawn(move || {
+        let _ = sender.send(run_formatter(&formatter, &buffile, &root_path, &text));
+    });
+    ctx.running_formatter = Some(RunningFormatter {
+        meta,
+        command,
+        version,
+        modified_lines_only,
+        receiver,
+    });
+}
+
+/// Replaces the buffer by the output of the external formatter, split up into minimal edits by
+/// diffing it against the buffer. The output is dropped if the buffer changed in the meantime.
+pub fn formatter_done(result: Result<String, String>, ctx: &mut Context) {
+    let RunningFormatter {
+        meta,
+        command,
+        version,
+        modified_lines_only,
+        ..
+    } = match ctx.running_formatter.take() {
+        Some(running) => running,
+        None => return,
+    };
+    let result = match ctx.document_version(&meta.session, &meta.buffile) {
+        Some(current) if current == version => result,
+        _ => Err("the buffer changed while formatting".to_string()),
+    };
+    match result {
+        Ok(formatted) => {
+            let text = &ctx.documents[&meta.buffile].text;
+            let whole_buffer_edit = TextEdit {
+                range: Range {
+                    start: Position::new(0, 0),
+                    end: Position::new(text.len_lines() as u32, 0),
+                },
+                new_text: formatted,
+            };
+            let text_edits = split_whole_buffer_edit(vec![whole_buffer_edit], text);
+            apply_formatting(meta, text_edits, modified_lines_only, ctx)
+        }
+        Err(err) => {
+            let msg = format!("lsp-formatting: formatter `{}` failed: {}", command, err);
+            error!("{}", msg);
+            ctx.exec(meta, format!("lsp-show-error {}", editor_quote(&msg)));
+        }
+    }
+}
+
+fn run_formatter(
+    formatter: &ExternalFormatter,
+    buffile: &str,
+    root_path: &str,
+    text: &str,
+) -> Result<String, String> {
+    let args = formatter
+        .args
+        .iter()
+        .map(|arg| arg.replace("{file}", buffile));
+    let mut child = Command::new(&formatter.command)
+        .args(args)
+        .current_dir(root_path)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|err| err.to_string())?;
+    let mut stdin = child.stdin.take().unwrap();
+    let text = text.to_string();
+    // Write from another thread, so a formatter that writes before reading all input can't block.
+    let writer = thread::spawn(move || stdin.write_all(text.as_bytes()));
+    let output = child.wait_with_output().map_err(|err| err.to_string())?;
+    writer
+        .join()
+        .unwrap()
+        .map_err(|err| format!("failed to write the buffer: {}", err))?;
+    if !output.status.success() {
+        return Err(format!(
+            "{}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    String::from_utf8(output.stdout).map_err(|_| "output is not valid UTF-8".to_string())
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct FormattingParams {
+    #[serde(default)]
+    modified_lines_only: bool,
+    #[serde(flatten)]
+    options: FormattingOptions,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_run_formatter() {
+        let formatter = ExternalFormatter {
+            command: "sh".to_string(),
+            args: vec![
+                "-c".to_string(),
+                "printf '%s\\n' \"$1\"; tr a-z A-Z".to_string(),
+                "sh".to_string(),
+                "{file}".to_string(),
+            ],
+        };
+        assert_eq!(
+            run_formatter(&formatter, "/tmp/x.txt", "/", "foo\nbar\n"),
+            Ok("/tmp/x.txt\nFOO\nBAR\n".to_string())
+        );
+        let failing = ExternalFormatter {
+            command: "false".to_string(),
+            args: vec![],
+        };
+        assert!(run_formatter(&failing, "/tmp/x.txt", "/", "foo\n").is_err());
+    }
+}
@@ -1,27 +1,25 @@
+use crate::capabilities::{server_has_capability, CAPABILITY_DEFINITION, CAPABILITY_DOCUMENT_LINK};
 use crate::context::Context;
+use crate::language_features::prefetch;
 use crate::position::*;
-use crate::types::{EditorMeta, EditorParams, PositionParams};
+use crate::presentation::{choose_command, Choice};
+use crate::types::{
+    EditorMeta, EditorParams, KakounePosition, KakouneRange, PositionParams, Presentation,
+};
 use crate::util::{editor_quote, short_file_path};
 use itertools::Itertools;
-use lsp_types::request::{GotoDefinition, GotoImplementation, GotoTypeDefinition, References};
+use lsp_types::request::{
+    DocumentLinkRequest, DocumentLinkResolve, GotoDefinition, GotoImplementation,
+    GotoTypeDefinition, References,
+};
 use lsp_types::*;
 use serde::Deserialize;
+use std::path::{Path, PathBuf};
 use url::Url;
 
 pub fn goto(meta: EditorMeta, result: Option<GotoDefinitionResponse>, ctx: &mut Context) {
     let locations = match result {
-        Some(GotoDefinitionResponse::Scalar(location)) => vec![location],
-        Some(GotoDefinitionResponse::Array(locations)) => locations,
-        Some(GotoDefinitionResponse::Link(locations)) => locations
-            .into_iter()
-            .map(
-                |LocationLink {
-                     target_uri: uri,
-                     target_range: range,
-                     ..
-                 }| Location { uri, range },
-            )
-            .collect(),
+        Some(result) => definition_locations(result),
         None => return,
     };
     match locations.len() {
@@ -35,23 +33,76 @@ pub fn goto(meta: EditorMeta, result: Option<GotoDefinitionResponse>, ctx: &mut
     }
 }
 
+pub fn definition_locations(result: GotoDefinitionResponse) -> Vec<Location> {
+    match result {
+        GotoDefinitionResponse::Scalar(location) => vec![location],
+        GotoDefinitionResponse::Array(locations) => locations,
+        // Jump to the symbol's name rather than the start of the whole item.
+        GotoDefinitionResponse::Link(locations) => locations
+            .into_iter()
+            .map(
+                |LocationLink {
+                     target_uri: uri,
+                     target_selection_range: range,
+                     ..
+                 }| Location { uri, range },
+            )
+            .collect(),
+    }
+}
+
 pub fn goto_location(meta: EditorMeta, Location { uri, range }: &Location, ctx: &mut Context) {
     let path = uri.to_file_path().unwrap();
     let path_str = path.to_str().unwrap();
     if let Some(contents) = get_file_contents(path_str, ctx) {
-        let pos = lsp_range_to_kakoune(range, &contents, ctx.offset_encoding).start;
-        let command = format!(
-            "evaluate-commands -try-client %opt{{jumpclient}} -verbatim -- edit -existing {} {} {}",
-            editor_quote(path_str),
-            pos.line,
-            pos.column,
-        );
-        ctx.exec(meta, command);
+        let range = lsp_range_to_kakoune(range, &contents, ctx.offset_encoding);
+        ctx.exec(meta, jump_command(path_str, range));
+    }
+}
+
+/// Jump to the range in the jump client.
+fn jump_command(path: &str, range: KakouneRange) -> String {
+    format!(
+        "evaluate-commands -try-client %opt{{jumpclient}} -- {}",
+        editor_quote(&edit_and_select_command(path, range)),
+    )
+}
+
+/// Open a file at the start of the given range. If the range is on a single line, like the name
+/// of a symbol, select it as well, so it can be renamed or searched for right away.
+pub fn edit_and_select_command(path: &str, range: KakouneRange) -> String {
+    let command = format!(
+        "edit -existing -- {} {} {}",
+        editor_quote(path),
+        range.start.line,
+        range.start.column,
+    );
+    if range.start.line == range.end.line && range.start.column < range.end.column {
+        format!("{}\nselect {}", command, range)
+    } else {
+        command
     }
 }
 
 pub fn goto_locations(meta: EditorMeta, locations: &[Location], ctx: &mut Context) {
-    let select_location = locations
+    let presentation = meta.presentation.unwrap_or(Presentation::Buffer);
+    if presentation != Presentation::Buffer {
+        let command = choose_command(presentation, "lsp-menu", &location_choices(locations, ctx));
+        ctx.exec(meta, command);
+        return;
+    }
+    let select_location = format_locations(locations, "", ctx);
+    let command = format!(
+        "lsp-show-goto-choices {} {}",
+        editor_quote(&ctx.root_path),
+        editor_quote(&select_location),
+    );
+    ctx.exec(meta, command);
+}
+
+/// Formats locations for a goto buffer, one line per location, each starting with `prefix`.
+pub fn format_locations(locations: &[Location], prefix: &str, ctx: &Context) -> String {
+    locations
         .iter()
         .group_by(|Location { uri, .. }| uri.to_file_path().unwrap())
         .into_iter()
@@ -68,7 +119,8 @@ pub fn goto_locations(meta: EditorMeta, locations: &[Location], ctx: &mut Contex
                         return "".into();
                     }
                     format!(
-                        "{}:{}:{}:{}",
+                        "{}{}:{}:{}:{}",
+                        prefix,
                         short_file_path(path_str, &ctx.root_path),
                         pos.line,
                         pos.column,
@@ -77,17 +129,48 @@ pub fn goto_locations(meta: EditorMeta, locations: &[Location], ctx: &mut Contex
                 })
                 .join("")
         })
-        .join("");
-    let command = format!(
-        "lsp-show-goto-choices {} {}",
-        editor_quote(&ctx.root_path),
-        editor_quote(&select_location),
-    );
-    ctx.exec(meta, command);
+        .join("")
+}
+
+/// One choice per location, titled like its line in a goto buffer, that jumps to it.
+fn location_choices(locations: &[Location], ctx: &Context) -> Vec<Choice> {
+    locations
+        .iter()
+        .group_by(|Location { uri, .. }| uri.to_file_path().unwrap())
+        .into_iter()
+        .flat_map(|(path, locations)| {
+            let path_str = path.to_str().unwrap();
+            let contents = get_file_contents(path_str, ctx);
+            locations
+                .filter_map(|Location { range, .. }| {
+                    let contents = contents.as_ref()?;
+                    if range.start.line as usize >= contents.len_lines() {
+                        return None;
+                    }
+                    let range = lsp_range_to_kakoune(range, contents, ctx.offset_encoding);
+                    let title = format!(
+                        "{}:{}:{}: {}",
+                        short_file_path(path_str, &ctx.root_path),
+                        range.start.line,
+                        range.start.column,
+                        contents
+                            .line(range.start.line as usize - 1)
+                            .to_string()
+                            .trim(),
+                    );
+                    let command = jump_command(path_str, range);
+                    Some(Choice { title, command })
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect()
 }
 
 pub fn text_document_definition(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
     let params = PositionParams::deserialize(params).unwrap();
+    if let Some(result) = prefetch::prefetched_definition(ctx, &meta, params.position) {
+        return goto(meta, result, ctx);
+    }
     let req_params = GotoDefinitionParams {
         text_document_position_params: TextDocumentPositionParams {
             text_document: TextDocumentIdentifier {
@@ -156,3 +239,277 @@ pub fn text_document_references(meta: EditorMeta, params: EditorParams, ctx: &mu
         goto(meta, result.map(GotoDefinitionResponse::Array), ctx);
     });
 }
+
+/// Go to the file path under the cursor. Tries the document link at the cursor, then the
+/// definition of the symbol at the cursor and finally resolves the path on the file system.
+pub fn goto_file(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
+    let params = PositionParams::deserialize(params).unwrap();
+    let position = params.position;
+    if !server_has_capability(ctx, CAPABILITY_DOCUMENT_LINK) {
+        goto_file_definition(meta, position, ctx);
+        return;
+    }
+    let req_params = DocumentLinkParams {
+        text_document: TextDocumentIdentifier {
+            uri: Url::from_file_path(&meta.buffile).unwrap(),
+        },
+        partial_result_params: Default::default(),
+        work_done_progress_params: Default::default(),
+    };
+    ctx.call::<DocumentLinkRequest, _>(meta, req_params, move |ctx: &mut Context, meta, result| {
+        let lsp_position = match get_lsp_position(&meta.buffile, &position, ctx) {
+            Some(lsp_position) => lsp_position,
+            None => return,
+        };
+        let link = result
+            .unwrap_or_default()
+            .into_iter()
+            .find(|link| link.range.start <= lsp_position && lsp_position < link.range.end);
+        let can_resolve = ctx
+            .capabilities
+            .as_ref()
+            .and_then(|caps| caps.document_link_provider.as_ref())
+            .and_then(|provider| provider.resolve_provider)
+            .unwrap_or(false);
+        match link {
+            Some(DocumentLink {
+                target: Some(target),
+                ..
+            }) => goto_uri(meta, &target, ctx),
+            Some(link) if can_resolve => {
+                ctx.call::<DocumentLinkResolve, _>(
+                    meta,
+                    link,
+                    move |ctx: &mut Context, meta, link| match link.target {
+                        Some(target) => goto_uri(meta, &target, ctx),
+                        None => goto_file_definition(meta, position, ctx),
+                    },
+                );
+            }
+            _ => goto_file_definition(meta, position, ctx),
+        }
+    });
+}
+
+fn goto_file_definition(meta: EditorMeta, position: KakounePosition, ctx: &mut Context) {
+    if !server_has_capability(ctx, CAPABILITY_DEFINITION) {
+        goto_file_path(meta, position, ctx);
+        return;
+    }
+    let lsp_position = match get_lsp_position(&meta.buffile, &position, ctx) {
+        Some(lsp_position) => lsp_position,
+        None => {
+            let msg = format!("{} is not open in the language server", meta.buffile);
+            ctx.exec(meta, format!("lsp-show-error {}", editor_quote(&msg)));
+            return;
+        }
+    };
+    let req_params = GotoDefinitionParams {
+        text_document_position_params: TextDocumentPositionParams {
+            text_document: TextDocumentIdentifier {
+                uri: Url::from_file_path(&meta.buffile).unwrap(),
+            },
+            position: lsp_position,
+        },
+        partial_result_params: Default::default(),
+        work_done_progress_params: Default::default(),
+    };
+    ctx.call::<GotoDefinition, _>(meta, req_params, move |ctx: &mut Context, meta, result| {
+        let found = match &result {
+            Some(GotoDefinitionResponse::Scalar(_)) => true,
+            Some(GotoDefinitionResponse::Array(locations)) => !locations.is_empty(),
+            Some(GotoDefinitionResponse::Link(links)) => !links.is_empty(),
+            None => false,
+        };
+        if found {
+            goto(meta, result, ctx);
+        } else {
+            goto_file_path(meta, position, ctx);
+        }
+    });
+}
+
+/// Opens a document link target. Line numbers are given as fragment, like `file:///a.rs#L10,5`.
+fn goto_uri(meta: EditorMeta, uri: &Url, ctx: &mut Context) {
+    let path = match uri.to_file_path() {
+        Ok(path) => path,
+        Err(()) => {
+            let command = format!(
+                "lsp-show-error {}",
+                editor_quote(&format!("link target is not a file: {}", uri))
+            );
+            ctx.exec(meta, command);
+            return;
+        }
+    };
+    let (line, column) = match uri.fragment().and_then(|f| f.strip_prefix('L')) {
+        Some(fragment) => parse_line_and_column(&fragment.replace(',', ":")),
+        None => (None, None),
+    };
+    edit_file(meta, &path, line, column, ctx);
+}
+
+fn goto_file_path(meta: EditorMeta, position: KakounePosition, ctx: &mut Context) {
+    let document = match ctx.documents.get(&meta.buffile) {
+        Some(document) => document,
+        None => return,
+    };
+    let line = get_line(position.line as usize - 1, &document.text).to_string();
+    let target = path_at(&line, position.column as usize - 1).and_then(|token| {
+        let (path, suffix) = match token.find(':') {
+            Some(i) => (&token[..i], &token[i + 1..]),
+            None => (token, ""),
+        };
+        let path = resolve_path(path, &meta.buffile, &ctx.root_path)?;
+        let (line, column) = parse_line_and_column(suffix);
+        Some((path, line, column))
+    });
+    match target {
+        Some((path, line, column)) => edit_file(meta, &path, line, column, ctx),
+        None => ctx.exec(meta, "lsp-show-error 'no file under cursor'"),
+    }
+}
+
+fn edit_file(
+    meta: EditorMeta,
+    path: &Path,
+    line: Option<u32>,
+    column: Option<u32>,
+    ctx: &mut Context,
+) {
+    let position = match (line, column) {
+        (Some(line), Some(column)) => format!(" {} {}", line, column),
+        (Some(line), None) => format!(" {}", line),
+        _ => "".to_string(),
+    };
+    let command = format!(
+        "evaluate-commands -try-client %opt{{jumpclient}} -verbatim -- edit -existing {}{}",
+        editor_quote(&path.to_string_lossy()),
+        position,
+    );
+    ctx.exec(meta, command);
+}
+
+/// Returns the path-like token around the given byte offset, including a `:line:column` suffix.
+fn path_at(line: &str, offset: usize) -> Option<&str> {
+    let is_path_char = |c: char| c.is_alphanumeric() || "/._-~+@%:".contains(c);
+    if !line.is_char_boundary(offset) || !line[offset..].starts_with(is_path_char) {
+        return None;
+    }
+    let start = line[..offset]
+        .char_indices()
+        .rev()
+        .find(|&(_, c)| !is_path_char(c))
+        .map(|(i, c)| i + c.len_utf8())
+        .unwrap_or(0);
+    let end = line[offset..]
+        .char_indices()
+        .find(|&(_, c)| !is_path_char(c))
+        .map(|(i, _)| offset + i)
+        .unwrap_or(line.len());
+    // Don't include punctuation that typically follows a path in prose.
+    let token = line[start..end].trim_end_matches(|c| ".,:".contains(c));
+    if token.is_empty() {
+        None
+    } else {
+        Some(token)
+    }
+}
+
+/// Parses `line[:column]`, ignoring anything that doesn't look like a number.
+fn parse_line_and_column(suffix: &str) -> (Option<u32>, Option<u32>) {
+    let mut numbers = suffix.split(':').map(|n| n.parse().ok());
+    let line = numbers.next().flatten();
+    let column = line.and(numbers.next().flatten());
+    (line, column)
+}
+
+/// Resolves a path relative to the buffer's directory or the project root.
+fn resolve_path(path: &str, buffile: &str, root_path: &str) -> Option<PathBuf> {
+    let path = match path.strip_prefix("~/") {
+        Some(rest) => dirs::home_dir()?.join(rest),
+        None => PathBuf::from(path),
+    };
+    if path.is_absolute() {
+        return Some(path).filter(|path| path.is_file());
+    }
+    let buffer_dir = Path::new(buffile).parent();
+    buffer_dir
+        .into_iter()
+        .chain(std::iter::once(Path::new(root_path)))
+        .map(|dir| dir.join(&path))
+        .find(|candidate| candidate.is_file())
+}
+
+#[derive(Deserialize)]
+struct DefinitionOfSymbolParams {
+    path: String,
+    // The start of the symbol as reported by workspace/symbol, in Kakoune coordinates.
+    line: u32,
+    column: u32,
+    name: String,
+}
+
+/// Go to the definition of a symbol picked from workspace/symbol results. The definition is
+/// requested at the symbol's name, which may come after its reported start, for example after
+/// `pub fn`. If there is no definition, go to the symbol itself.
+pub fn definition_of_symbol(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
+    let params = DefinitionOfSymbolParams::deserialize(params).unwrap();
+    let contents = match get_file_contents(&params.path, ctx) {
+        Some(contents) => contents,
+        None => return,
+    };
+    let mut position = KakounePosition {
+        line: params.line,
+        column: params.column,
+    };
+    let line = get_line(position.line as usize - 1, &contents).to_string();
+    let column = position.column as usize - 1;
+    if let Some(offset) = line.get(column..).and_then(|rest| rest.find(&params.name)) {
+        position.column = (column + offset + 1) as u32;
+    }
+    let position = kakoune_position_to_lsp(&position, &contents, ctx.offset_encoding);
+    let uri = Url::from_file_path(&params.path).unwrap();
+    let symbol = Location {
+        uri: uri.clone(),
+        range: Range::new(position, position),
+    };
+    let req_params = GotoDefinitionParams {
+        text_document_position_params: TextDocumentPositionParams {
+            text_document: TextDocumentIdentifier { uri },
+            position,
+        },
+        partial_result_params: Default::default(),
+        work_done_progress_params: Default::default(),
+    };
+    ctx.call::<GotoDefinition, _>(meta, req_params, move |ctx: &mut Context, meta, result| {
+        match result.map(definition_locations) {
+            Some(locations) if !locations.is_empty() => {
+                goto(meta, Some(GotoDefinitionResponse::Array(locations)), ctx)
+            }
+            _ => goto_location(meta, &symbol, ctx),
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_path_at() {
+        let line = r#"#include "foo/bar.h" // see src/main.rs:12:5."#;
+        assert_eq!(path_at(line, 12), Some("foo/bar.h"));
+        assert_eq!(path_at(line, 30), Some("src/main.rs:12:5"));
+        assert_eq!(path_at(line, 8), None);
+        assert_eq!(path_at("see ~/notes.txt.", 6), Some("~/notes.txt"));
+    }
+
+    #[test]
+    fn test_parse_line_and_column() {
+        assert_eq!(parse_line_and_column(""), (None, None));
+        assert_eq!(parse_line_and_column("12"), (Some(12), None));
+        assert_eq!(parse_line_and_column("12:5"), (Some(12), Some(5)));
+        assert_eq!(parse_line_and_column("x:5"), (None, None));
+    }
+}
@@ -0,0 +1,35 @@
+use crate::context::*;
+use crate::types::*;
+use crate::workspace;
+use lsp_types::request::ExecuteCommand;
+use lsp_types::*;
+use serde::Deserialize;
+
+/// haskell-language-server prefixes its command names with the server's process ID,
+/// for example `12345:eval:evalCommand`.
+pub fn is_eval_command(command: &str) -> bool {
+    match command.split_once(':') {
+        Some((pid, name)) => {
+            !pid.is_empty() && pid.chars().all(|c| c.is_ascii_digit()) && name == "eval:evalCommand"
+        }
+        None => false,
+    }
+}
+
+/// Run an eval code lens. The server inserts the results below the evaluated comment with a
+/// `workspace/applyEdit` request that arrives before the command finishes, so all that is
+/// left to do here is to let the user know what is going on, since evaluation can be slow.
+pub fn eval(meta: EditorMeta, params: ExecuteCommandParams, ctx: &mut Context) {
+    ctx.exec(
+        meta.clone(),
+        "echo 'haskell-language-server: evaluating...'",
+    );
+    ctx.call::<ExecuteCommand, _>(meta, params, move |ctx: &mut Context, meta, response| {
+        // Older versions return the edit instead of asking us to apply it.
+        if let Some(edit) = response.and_then(|response| WorkspaceEdit::deserialize(response).ok())
+        {
+            workspace::apply_edit(meta.clone(), edit, false, ctx);
+        }
+        ctx.exec(meta, "echo 'haskell-language-server: evaluated'");
+    });
+}
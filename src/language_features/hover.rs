@@ -1,19 +1,33 @@
+use std::collections::HashMap;
 use std::fs;
+use std::process::{Command, Stdio};
+use std::thread;
 
 use crate::capabilities::attempt_server_capability;
-use crate::capabilities::CAPABILITY_HOVER;
+use crate::capabilities::{server_has_capability, CAPABILITY_DEFINITION, CAPABILITY_HOVER};
 use crate::context::*;
-use crate::diagnostics::format_related_information;
+use crate::diagnostics::{
+    diagnostic_code, format_related_information, severity_name, severity_rank,
+};
+use crate::info_box::{self, InfoBoxOwner};
+use crate::language_features::goto::definition_locations;
+use crate::language_features::prefetch;
 use crate::markup::*;
 use crate::position::*;
 use crate::types::*;
+use crate::util::*;
+use crossbeam_channel::{bounded, Receiver};
 use indoc::formatdoc;
 use itertools::Itertools;
 use lsp_types::request::*;
 use lsp_types::*;
+use ropey::Rope;
 use serde::Deserialize;
 use url::Url;
 
+/// The number of columns taken up by the frame and padding of an info box.
+const INFO_BOX_BORDER_WIDTH: usize = 4;
+
 pub fn text_document_hover(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
     if meta.fifo.is_none() && !attempt_server_capability(ctx, CAPABILITY_HOVER) {
         return;
@@ -22,6 +36,7 @@ pub fn text_document_hover(meta: EditorMeta, params: EditorParams, ctx: &mut Con
     let HoverDetails {
         hover_fifo: maybe_hover_fifo,
         hover_client: maybe_hover_client,
+        placement,
     } = HoverDetails::deserialize(params.clone()).unwrap();
 
     let hover_type = match maybe_hover_fifo {
@@ -29,11 +44,14 @@ pub fn text_document_hover(meta: EditorMeta, params: EditorParams, ctx: &mut Con
             fifo,
             client: maybe_hover_client.unwrap(),
         },
-        None => HoverType::InfoBox,
+        None => HoverType::InfoBox { placement },
     };
 
     let params = MainSelectionParams::deserialize(params).unwrap();
     let (range, cursor) = parse_kakoune_range(&params.selection_desc);
+    if let Some(result) = prefetch::prefetched_hover(ctx, &meta, cursor) {
+        return add_definition_preview(meta, hover_type, cursor, range, result, ctx);
+    }
     let req_params = HoverParams {
         text_document_position_params: TextDocumentPositionParams {
             text_document: TextDocumentIdentifier {
@@ -44,10 +62,141 @@ pub fn text_document_hover(meta: EditorMeta, params: EditorParams, ctx: &mut Con
         work_done_progress_params: Default::default(),
     };
     ctx.call::<HoverRequest, _>(meta, req_params, move |ctx: &mut Context, meta, result| {
-        editor_hover(meta, hover_type, cursor, range, result, ctx)
+        add_definition_preview(meta, hover_type, cursor, range, result, ctx)
     });
 }
 
+/// Line prefixes of comments in a filetype, to find the doc comment above a definition. Other
+/// prefixes would take lines like attributes, preprocessor directives or dereferences for comments.
+fn comment_prefixes(filetype: &str) -> &'static [&'static str] {
+    match filetype {
+        "sh" | "python" | "ruby" | "perl" | "toml" | "yaml" | "makefile" | "kak" | "nix" | "r"
+        | "elixir" | "julia" | "crystal" | "fish" | "cmake" | "gdscript" | "nim" => &["#"],
+        "haskell" | "lua" | "sql" | "elm" | "purescript" | "ada" => &["--"],
+        "lisp" | "clojure" | "scheme" | "racket" | "fennel" | "janet" => &[";"],
+        "latex" | "erlang" => &["%"],
+        // The continuation lines of block comments start with "* ", or are just "*".
+        _ => &["//", "/*", "* ", "*/"],
+    }
+}
+
+/// Whether a line, without leading whitespace, is a comment in the filetype.
+fn is_comment(line: &str, filetype: &str) -> bool {
+    let prefixes = comment_prefixes(filetype);
+    prefixes.iter().any(|p| line.starts_with(p)) || (prefixes.contains(&"* ") && line == "*")
+}
+
+/// Shows the hover info, with the start of the hovered symbol's definition if the hover info is
+/// sparse and `hover_definition_lines` is set. Some servers, like those for C, only show a
+/// symbol's type.
+fn add_definition_preview(
+    meta: EditorMeta,
+    hover_type: HoverType,
+    cursor: KakounePosition,
+    range: KakouneRange,
+    result: Option<Hover>,
+    ctx: &mut Context,
+) {
+    let lines = ctx.language_config().hover_definition_lines;
+    let sparse = match &result {
+        Some(hover) => {
+            hover_contents_to_markdown(hover.contents.clone())
+                .trim()
+                .lines()
+                .count()
+                <= 1
+        }
+        None => true,
+    };
+    // Auto-hover runs on every cursor movement, which is too often for another request.
+    if lines == 0 || !sparse || meta.hook || !server_has_capability(ctx, CAPABILITY_DEFINITION) {
+        return editor_hover(meta, hover_type, cursor, range, result, ctx);
+    }
+    let position = match get_lsp_position(&meta.buffile, &cursor, ctx) {
+        Some(position) => position,
+        None => return editor_hover(meta, hover_type, cursor, range, result, ctx),
+    };
+    let req_params = GotoDefinitionParams {
+        text_document_position_params: TextDocumentPositionParams {
+            text_document: TextDocumentIdentifier {
+                uri: Url::from_file_path(&meta.buffile).unwrap(),
+            },
+            position,
+        },
+        partial_result_params: Default::default(),
+        work_done_progress_params: Default::default(),
+    };
+    // If the definition request fails, the hover info is shown without the preview.
+    ctx.batch_call_lenient::<GotoDefinition, _>(
+        meta,
+        vec![req_params],
+        move |ctx: &mut Context, meta, mut definitions| {
+            let preview = definitions
+                .pop()
+                .flatten()
+                .flatten()
+                .map(definition_locations)
+                .and_then(|locations| locations.into_iter().next())
+                .and_then(|location| definition_preview(&meta, &location, cursor, lines, ctx));
+            let result = match preview {
+                Some(preview) => {
+                    let contents = result
+                        .as_ref()
+                        .map(|hover| hover_contents_to_markdown(hover.contents.clone()))
+                        .filter(|contents| !contents.trim().is_empty());
+                    let preview = format!("```{}\n{}\n```", meta.filetype, preview);
+                    Some(Hover {
+                        contents: HoverContents::Markup(MarkupContent {
+                            kind: MarkupKind::Markdown,
+                            value: match contents {
+                                Some(contents) => format!("{}\n\n{}", contents, preview),
+                                None => preview,
+                            },
+                        }),
+                        range: result.and_then(|hover| hover.range),
+                    })
+                }
+                None => result,
+            };
+            editor_hover(meta, hover_type, cursor, range, result, ctx)
+        },
+    );
+}
+
+/// Returns the comment above a definition and its first lines, unless the cursor is on the
+/// definition already.
+fn definition_preview(
+    meta: &EditorMeta,
+    location: &Location,
+    cursor: KakounePosition,
+    lines: usize,
+    ctx: &Context,
+) -> Option<String> {
+    let path = location.uri.to_file_path().ok()?;
+    let path = path.to_str()?;
+    let start = location.range.start.line as usize;
+    if path == meta.buffile && start + 1 == cursor.line as usize {
+        return None;
+    }
+    let text = get_file_contents(path, ctx)?;
+    if start >= text.len_lines() {
+        return None;
+    }
+    let line = |i: usize| text.line(i).to_string().trim_end().to_string();
+    let mut first = start;
+    while first > 0 && first + lines > start {
+        let above = line(first - 1);
+        let above = above.trim_start();
+        if above.is_empty() || !is_comment(above, &meta.filetype) {
+            break;
+        }
+        first -= 1;
+    }
+    let end = (start + lines).min(text.len_lines());
+    let preview = (first..end).map(line).join("\n");
+    Some(preview.trim_end().to_string())
+}
+
 pub fn editor_hover(
     meta: EditorMeta,
     hover_type: HoverType,
@@ -56,51 +205,82 @@ pub fn editor_hover(
     result: Option<Hover>,
     ctx: &mut Context,
 ) {
+    let result = result.map(|hover| rewrite_code_fences(hover, &meta.filetype, ctx));
     let doc = &ctx.documents[&meta.buffile];
     let lsp_range = kakoune_range_to_lsp(&range, &doc.text, ctx.offset_encoding);
     let for_hover_buffer = matches!(hover_type, HoverType::HoverBuffer { .. });
-    let diagnostics = ctx.diagnostics.get(&meta.buffile);
-    let diagnostics = diagnostics
-        .map(|x| {
-            x.iter()
-                .filter(|x| ranges_touch_same_line(x.range, lsp_range))
-                .filter(|x| !x.message.is_empty())
-                .map(|x| {
-                    // Indent line breaks to the same level as the bullet point
-                    let message = (x.message.trim().to_string()
-                        + &format_related_information(x, ctx)
-                            .map(|s| "\n  ".to_string() + &s)
-                            .unwrap_or_default())
-                        .replace('\n', "\n  ");
-                    if for_hover_buffer {
-                        // We are typically creating Markdown, so use a standard Markdown enumerator.
-                        return format!("* {}", message);
-                    }
-
-                    let face = x
-                        .severity
-                        .map(|sev| match sev {
-                            DiagnosticSeverity::ERROR => FACE_INFO_DIAGNOSTIC_ERROR,
-                            DiagnosticSeverity::WARNING => FACE_INFO_DIAGNOSTIC_WARNING,
-                            DiagnosticSeverity::INFORMATION => FACE_INFO_DIAGNOSTIC_INFO,
-                            DiagnosticSeverity::HINT => FACE_INFO_DIAGNOSTIC_HINT,
-                            _ => {
-                                warn!("Unexpected DiagnosticSeverity: {:?}", sev);
-                                FACE_INFO_DEFAULT
-                            }
-                        })
-                        .unwrap_or(FACE_INFO_DEFAULT);
+    // Scratch buffers are not wrapped, info boxes are as wide as the window at most.
+    let wrap_width = if for_hover_buffer {
+        0
+    } else {
+        (meta.window_width as usize).saturating_sub(INFO_BOX_BORDER_WIDTH)
+    };
+    let symbol_start = result
+        .as_ref()
+        .and_then(|result| result.range)
+        .map(|range| lsp_range_to_kakoune(&range, &doc.text, ctx.offset_encoding).start);
+    // Diagnostics of the other servers of the buffer are included, and have a source.
+    let line_diagnostics: Vec<(Option<&str>, &Diagnostic)> = ctx
+        .diagnostics
+        .get(&meta.buffile)
+        .into_iter()
+        .flatten()
+        .map(|x| (x.source.as_deref(), x))
+        .filter(|(_, x)| ranges_touch_same_line(x.range, lsp_range))
+        .filter(|(_, x)| !x.message.is_empty())
+        // Stable, so diagnostics of the same severity stay in order.
+        .sorted_by_key(|(_, x)| severity_rank(x.severity))
+        .collect();
+    // Sources are only worth showing if the diagnostics come from several.
+    let several_sources = line_diagnostics
+        .iter()
+        .map(|(source, _)| source)
+        .unique()
+        .count()
+        > 1;
+    let diagnostics = line_diagnostics
+        .into_iter()
+        .map(|(source, x)| {
+            let label = match source {
+                Some(source) if several_sources => format!("{}: ", source),
+                _ => String::new(),
+            };
+            let message = label
+                + x.message.trim()
+                + &format_related_information(x, ctx)
+                    .map(|s| "\n".to_string() + &s)
+                    .unwrap_or_default();
+            // Leave room for the bullet point.
+            let message = wrap_text(&message, wrap_width.saturating_sub(2));
+            // Indent line breaks to the same level as the bullet point
+            let message = message.replace('\n', "\n  ");
+            if for_hover_buffer {
+                // We are typically creating Markdown, so use a standard Markdown enumerator.
+                return format!("* {}", message);
+            }
 
-                    format!(
-                        "• {{{}}}{}{{{}}}",
-                        face,
-                        escape_kakoune_markup(&message),
-                        FACE_INFO_DEFAULT,
-                    )
+            let face = x
+                .severity
+                .map(|sev| match sev {
+                    DiagnosticSeverity::ERROR => FACE_INFO_DIAGNOSTIC_ERROR,
+                    DiagnosticSeverity::WARNING => FACE_INFO_DIAGNOSTIC_WARNING,
+                    DiagnosticSeverity::INFORMATION => FACE_INFO_DIAGNOSTIC_INFO,
+                    DiagnosticSeverity::HINT => FACE_INFO_DIAGNOSTIC_HINT,
+                    _ => {
+                        warn!("Unexpected DiagnosticSeverity: {:?}", sev);
+                        FACE_INFO_DEFAULT
+                    }
                 })
-                .join("\n")
+                .unwrap_or(FACE_INFO_DEFAULT);
+
+            format!(
+                "• {{{}}}{}{{{}}}",
+                face,
+                escape_kakoune_markup(&message),
+                FACE_INFO_DEFAULT,
+            )
         })
-        .unwrap_or_else(String::new);
+        .join("\n");
 
     let code_lenses = ctx
         .code_lenses
@@ -122,7 +302,7 @@ pub fn editor_hover(
                     }
                     format!(
                         "• {{{}}}{}{{{}}}",
-                        FACE_INFO_DIAGNOSTIC_HINT,
+                        FACE_INFO_CODE_LENS,
                         escape_kakoune_markup(title),
                         FACE_INFO_DEFAULT,
                     )
@@ -131,6 +311,7 @@ pub fn editor_hover(
         })
         .unwrap_or_default();
 
+    let markdown_options = ctx.language_config().markdown;
     let marked_string_to_hover = |ms: MarkedString| {
         if for_hover_buffer {
             match ms {
@@ -144,7 +325,7 @@ pub fn editor_hover(
                 ),
             }
         } else {
-            marked_string_to_kakoune_markup(ms)
+            marked_string_to_kakoune_markup(ms, &markdown_options)
         }
     };
 
@@ -170,27 +351,100 @@ pub fn editor_hover(
                     if for_hover_buffer {
                         contents.value
                     } else {
-                        markdown_to_kakoune_markup(contents.value)
+                        markdown_to_kakoune_markup_with_options(contents.value, &markdown_options)
                     },
                 ),
-                MarkupKind::PlainText => (false, contents.value),
+                MarkupKind::PlainText => (false, wrap_text(&contents.value, wrap_width)),
             },
         },
     };
+    let hover = PendingHover {
+        meta,
+        hover_type,
+        cursor,
+        symbol_start,
+        is_markdown,
+        contents,
+        diagnostics,
+        code_lenses,
+        for_hover_buffer,
+        wrap_width,
+    };
+    // Auto-hover runs on every cursor movement, which is too often for running commands.
+    let hover = if hover.meta.hook {
+        Some(hover)
+    } else {
+        run_hover_providers(hover, ctx)
+    };
+    if let Some(hover) = hover {
+        show_hover(hover, ctx);
+    }
+}
 
+/// Hover info that is ready to be shown, except for the output of hover providers.
+pub struct PendingHover {
+    meta: EditorMeta,
+    hover_type: HoverType,
+    cursor: KakounePosition,
+    symbol_start: Option<KakounePosition>,
+    is_markdown: bool,
+    contents: String,
+    diagnostics: String,
+    code_lenses: String,
+    for_hover_buffer: bool,
+    wrap_width: usize,
+}
+
+/// Hover providers running in the background, and the hover info their output is added to.
+pub struct RunningHoverProviders {
+    hover: PendingHover,
+    providers: Vec<HoverProvider>,
+    pub receiver: Receiver<Vec<Option<String>>>,
+}
+
+fn show_hover(hover: PendingHover, ctx: &mut Context) {
+    let PendingHover {
+        meta,
+        hover_type,
+        cursor,
+        symbol_start,
+        is_markdown,
+        contents,
+        diagnostics,
+        code_lenses,
+        ..
+    } = hover;
     match hover_type {
-        HoverType::InfoBox => {
+        HoverType::InfoBox { placement } => {
             if contents.is_empty() && diagnostics.is_empty() && code_lenses.is_empty() {
                 return;
             }
+            let owner = if meta.hook {
+                InfoBoxOwner::AutoHover
+            } else {
+                InfoBoxOwner::Hover
+            };
+            if !info_box::claim(&meta, owner) {
+                return;
+            }
 
+            let (anchor, style) = info_anchor(placement, cursor, symbol_start);
             let command = format!(
-                "lsp-show-hover {} %§{}§ %§{}§ %§{}§",
-                cursor,
+                "lsp-show-hover {} %§{}§ %§{}§ %§{}§ {}",
+                anchor,
                 contents.replace('§', "§§"),
                 diagnostics.replace('§', "§§"),
                 code_lenses.replace('§', "§§"),
+                style,
             );
+            let command = if placement == Some(InfoPlacement::Docsclient) {
+                format!(
+                    "evaluate-commands -try-client %opt{{docsclient}} %§{}§",
+                    command.replace('§', "§§")
+                )
+            } else {
+                command
+            };
             ctx.exec(meta, command);
         }
         HoverType::Modal {
@@ -209,6 +463,403 @@ pub fn editor_hover(
     };
 }
 
+/// Applies the language's `hover_fences` and `hover_code_as_filetype` to hover info. Some
+/// servers fence the whole hover, which would render it monochrome.
+fn rewrite_code_fences(mut hover: Hover, filetype: &str, ctx: &Context) -> Hover {
+    let config = ctx.language_config();
+    if config.hover_fences.is_empty() && !config.hover_code_as_filetype {
+        return hover;
+    }
+    let code_filetype = config.hover_code_as_filetype.then_some(filetype);
+    let rewrite_marked_string = |ms: MarkedString| match ms {
+        MarkedString::String(markdown) => MarkedString::String(rewrite_markdown_fences(
+            &markdown,
+            &config.hover_fences,
+            code_filetype,
+        )),
+        MarkedString::LanguageString(LanguageString { language, value }) => {
+            let language = if config.hover_code_as_filetype {
+                filetype.to_string()
+            } else {
+                language
+            };
+            match config.hover_fences.get(&language) {
+                Some(replacement) if replacement.is_empty() => MarkedString::String(value),
+                Some(replacement) => MarkedString::LanguageString(LanguageString {
+                    language: replacement.clone(),
+                    value,
+                }),
+                None => MarkedString::LanguageString(LanguageString { language, value }),
+            }
+        }
+    };
+    hover.contents = match hover.contents {
+        HoverContents::Scalar(contents) => HoverContents::Scalar(rewrite_marked_string(contents)),
+        HoverContents::Array(contents) => {
+            HoverContents::Array(contents.into_iter().map(rewrite_marked_string).collect())
+        }
+        HoverContents::Markup(MarkupContent {
+            kind: MarkupKind::Markdown,
+            value,
+        }) => HoverContents::Markup(MarkupContent {
+            kind: MarkupKind::Markdown,
+            value: rewrite_markdown_fences(&value, &config.hover_fences, code_filetype),
+        }),
+        contents => contents,
+    };
+    hover
+}
+
+/// Removes the code fences whose language maps to "" in `rules`, and replaces the language of
+/// those that map to another one. With a `filetype`, it replaces the language of all fences
+/// before the rules are applied.
+fn rewrite_markdown_fences(
+    markdown: &str,
+    rules: &HashMap<String, String>,
+    filetype: Option<&str>,
+) -> String {
+    if rules.is_empty() && filetype.is_none() {
+        return markdown.to_string();
+    }
+    let mut result = String::with_capacity(markdown.len());
+    // The opening fence of the current code block, and whether it is removed.
+    let mut open_fence: Option<(String, bool)> = None;
+    for line in markdown.split_inclusive('\n') {
+        let trimmed = line.trim_start();
+        let fence_len = |c: char| trimmed.chars().take_while(|&x| x == c).count();
+        let fence = match (fence_len('`'), fence_len('~')) {
+            (n, _) if n >= 3 => trimmed[..n].to_string(),
+            (_, n) if n >= 3 => trimmed[..n].to_string(),
+            _ => String::new(),
+        };
+        match &open_fence {
+            Some((open, removed))
+                if !fence.is_empty()
+                    && fence.starts_with(open.as_str())
+                    && trimmed[fence.len()..].trim().is_empty() =>
+            {
+                if !removed {
+                    result.push_str(line);
+                }
+                open_fence = None;
+            }
+            Some(_) => result.push_str(line),
+            None if !fence.is_empty() => {
+                let original = trimmed[fence.len()..]
+                    .split_whitespace()
+                    .next()
+                    .unwrap_or_default();
+                let language = filetype.unwrap_or(original);
+                match rules.get(language).map(String::as_str).or(filetype) {
+                    Some("") => {
+                        open_fence = Some((fence, true));
+                    }
+                    Some(replacement) if replacement != original => {
+                        let indent = &line[..line.len() - trimmed.len()];
+                        let newline = if line.ends_with('\n') { "\n" } else { "" };
+                        result.push_str(&format!("{}{}{}{}", indent, fence, replacement, newline));
+                        open_fence = Some((fence, false));
+                    }
+                    _ => {
+                        result.push_str(line);
+                        open_fence = Some((fence, false));
+                    }
+                }
+            }
+            None => result.push_str(line),
+        }
+    }
+    result
+}
+
+/// Renders hover contents as Markdown, for scratch buffers.
+pub fn hover_contents_to_markdown(contents: HoverContents) -> String {
+    let marked_string_to_markdown = |ms: MarkedString| match ms {
+        MarkedString::String(markdown) => markdown,
+        MarkedString::LanguageString(LanguageString { language, value }) => {
+            format!("```{}\n{}\n```", language, value)
+        }
+    };
+    match contents {
+        HoverContents::Scalar(contents) => marked_string_to_markdown(contents),
+        HoverContents::Array(contents) => contents
+            .into_iter()
+            .map(marked_string_to_markdown)
+            .filter(|markdown| !markdown.is_empty())
+            .join("\n---\n"),
+        HoverContents::Markup(contents) => contents.value,
+    }
+}
+
+/// Shows a report on the diagnostics at the main selection: their messages, related
+/// information and documentation links, followed by the hover info at the first one.
+pub fn explain_error(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
+    let params = MainSelectionParams::deserialize(params).unwrap();
+    let (range, _cursor) = parse_kakoune_range(&params.selection_desc);
+    let document = match ctx.documents.get(&meta.buffile) {
+        Some(document) => document,
+        None => return,
+    };
+    let lsp_range = kakoune_range_to_lsp(&range, &document.text, ctx.offset_encoding);
+    let diagnostics: Vec<Diagnostic> = ctx
+        .diagnostics
+        .get(&meta.buffile)
+        .into_iter()
+        .flatten()
+        .filter(|d| d.range.start <= lsp_range.end && lsp_range.start <= d.range.end)
+        .cloned()
+        .collect();
+    let position = match diagnostics.first() {
+        Some(diagnostic) => diagnostic.range.start,
+        None => {
+            let command = "lsp-show-error 'no diagnostics at the main selection'";
+            ctx.exec(meta, command.to_string());
+            return;
+        }
+    };
+    // The diagnostics are explained even if the server has no hover info for them.
+    if !server_has_capability(ctx, CAPABILITY_HOVER) {
+        show_explanation(meta, &diagnostics, None, ctx);
+        return;
+    }
+    let req_params = HoverParams {
+        text_document_position_params: TextDocumentPositionParams {
+            text_document: TextDocumentIdentifier {
+                uri: Url::from_file_path(&meta.buffile).unwrap(),
+            },
+            position,
+        },
+        work_done_progress_params: Default::default(),
+    };
+    ctx.batch_call_lenient::<HoverRequest, _>(
+        meta,
+        vec![req_params],
+        move |ctx: &mut Context, meta, mut results| {
+            let hover = results.pop().flatten().flatten();
+            show_explanation(meta, &diagnostics, hover, ctx);
+        },
+    );
+}
+
+fn show_explanation(
+    meta: EditorMeta,
+    diagnostics: &[Diagnostic],
+    hover: Option<Hover>,
+    ctx: &mut Context,
+) {
+    let mut report = diagnostics
+        .iter()
+        .map(|d| explain_diagnostic(&meta.buffile, d, ctx))
+        .join("\n\n");
+    if let Some(hover) = hover {
+        let contents = hover_contents_to_markdown(hover.contents);
+        if !contents.trim().is_empty() {
+            report.push_str("\n\n## Hover\n\n");
+            report.push_str(contents.trim());
+        }
+    }
+    report.push('\n');
+    let command = format!(
+        "lsp-show-goto-buffer *explain* {} {}",
+        editor_quote(&ctx.root_path),
+        editor_quote(&report)
+    );
+    ctx.exec(meta, command);
+}
+
+fn explain_diagnostic(buffile: &str, d: &Diagnostic, ctx: &Context) -> String {
+    let p = get_kakoune_position_with_fallback(buffile, d.range.start, ctx);
+    let mut report = format!(
+        "# {}: {}\n\n{}:{}:{}",
+        severity_name(d.severity),
+        d.message.trim(),
+        short_file_path(buffile, &ctx.root_path),
+        p.line,
+        p.column,
+    );
+    match (&d.source, diagnostic_code(d)) {
+        (Some(source), Some(code)) => report.push_str(&format!("\nSource: {} {}", source, code)),
+        (Some(source), None) => report.push_str(&format!("\nSource: {}", source)),
+        (None, Some(code)) => report.push_str(&format!("\nCode: {}", code)),
+        (None, None) => (),
+    }
+    if let Some(description) = &d.code_description {
+        report.push_str(&format!("\nDocumentation: {}", description.href));
+    }
+    if let Some(related) = format_related_information(d, ctx) {
+        report.push_str("\n\n## Related information\n");
+        report.push_str(&related);
+    }
+    report
+}
+
+/// Runs the configured hover providers for the word at the cursor in a background thread, since
+/// they may take a while. Unless a provider is configured to always run, it only runs if the
+/// language server returned no hover info. Returns the hover if there is no provider to run.
+fn run_hover_providers(hover: PendingHover, ctx: &mut Context) -> Option<PendingHover> {
+    let server_has_hover = !hover.contents.is_empty();
+    let providers: Vec<HoverProvider> = ctx.config.language[&ctx.language_id]
+        .hover_providers
+        .iter()
+        .filter(|provider| provider.always || !server_has_hover)
+        .cloned()
+        .collect();
+    let word = match word_at(&ctx.documents[&hover.meta.buffile].text, hover.cursor) {
+        Some(word) if !providers.is_empty() => word,
+        _ => return Some(hover),
+    };
+    let (sender, receiver) = bounded(1);
+    let commands = providers.clone();
+    thread::spawn(move || {
+        let outputs = commands
+            .iter()
+            .map(|provider| run_hover_provider(provider, &word))
+            .collect();
+        let _ = sender.send(outputs);
+    });
+    // A newer hover replaces the one waiting for its providers.
+    ctx.running_hover_providers = Some(RunningHoverProviders {
+        hover,
+        providers,
+        receiver,
+    });
+    None
+}
+
+/// Adds the output of the hover providers below the hover contents, and shows the hover.
+pub fn hover_providers_done(outputs: Vec<Option<String>>, ctx: &mut Context) {
+    let RunningHoverProviders {
+        mut hover,
+        providers,
+        ..
+    } = match ctx.running_hover_providers.take() {
+        Some(running) => running,
+        None => return,
+    };
+    let mut sections = vec![];
+    if !hover.contents.is_empty() {
+        sections.push((hover.is_markdown, std::mem::take(&mut hover.contents)));
+    }
+    for (provider, output) in providers.iter().zip(outputs) {
+        let output = match output {
+            Some(output) => output,
+            None => continue,
+        };
+        let output = if hover.for_hover_buffer {
+            output
+        } else if provider.markdown {
+            markdown_to_kakoune_markup(output)
+        } else {
+            escape_kakoune_markup(&wrap_text(&output, hover.wrap_width))
+        };
+        sections.push((provider.markdown, output));
+    }
+    let is_markdown = sections.iter().any(|(is_markdown, _)| *is_markdown);
+    let separator = if hover.for_hover_buffer {
+        "\n---\n".to_string()
+    } else {
+        format!("\n{{{}}}---{{{}}}\n", FACE_INFO_RULE, FACE_INFO_DEFAULT)
+    };
+    let for_hover_buffer = hover.for_hover_buffer;
+    hover.contents = sections
+        .into_iter()
+        .map(|(section_is_markdown, section)| {
+            if for_hover_buffer && is_markdown && !section_is_markdown {
+                format!("```\n{}\n```", section)
+            } else {
+                section
+            }
+        })
+        .join(&separator);
+    hover.is_markdown = is_markdown;
+    show_hover(hover, ctx);
+}
+
+/// Runs a hover provider, returning its output if it succeeded and printed something.
+fn run_hover_provider(provider: &HoverProvider, word: &str) -> Option<String> {
+    let args = provider.args.iter().map(|arg| arg.replace("{word}", word));
+    let output = match Command::new(&provider.command)
+        .args(args)
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+    {
+        Ok(output) => output,
+        Err(err) => {
+            warn!(
+                "Failed to run hover provider `{}`: {}",
+                provider.command, err
+            );
+            return None;
+        }
+    };
+    if !output.status.success() {
+        debug!(
+            "Hover provider `{}` failed for `{}`: {}",
+            provider.command, word, output.status
+        );
+        return None;
+    }
+    let output = strip_overstrike(&String::from_utf8_lossy(&output.stdout));
+    let output = output.trim();
+    if output.is_empty() {
+        None
+    } else {
+        Some(output.to_string())
+    }
+}
+
+/// Returns the word at the given position, made up of alphanumeric characters and underscores.
+pub fn word_at(text: &Rope, position: KakounePosition) -> Option<String> {
+    let line = get_line((position.line as usize).checked_sub(1)?, text).to_string();
+    let offset = (position.column as usize).checked_sub(1)?;
+    let is_word = |c: char| c.is_alphanumeric() || c == '_';
+    if !line.get(offset..)?.starts_with(is_word) {
+        return None;
+    }
+    let start = line[..offset]
+        .char_indices()
+        .rev()
+        .take_while(|(_, c)| is_word(*c))
+        .last()
+        .map_or(offset, |(i, _)| i);
+    let end = line[offset..]
+        .find(|c| !is_word(c))
+        .map_or(line.len(), |i| offset + i);
+    Some(line[start..end].to_string())
+}
+
+/// Removes the backspace overstrikes that commands like `man` use for bold and underlined text.
+fn strip_overstrike(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    for c in text.chars() {
+        if c == '\x08' {
+            result.pop();
+        } else {
+            result.push(c);
+        }
+    }
+    result
+}
+
+/// Returns the anchor and style arguments for `lsp-show-hover`.
+/// Above and below, the box is aligned with the start of the hovered symbol, so it doesn't
+/// move around as the cursor moves within the symbol.
+fn info_anchor(
+    placement: Option<InfoPlacement>,
+    cursor: KakounePosition,
+    symbol_start: Option<KakounePosition>,
+) -> (KakounePosition, &'static str) {
+    match placement {
+        // An empty style defers to the lsp_hover_anchor option.
+        None => (cursor, "''"),
+        Some(InfoPlacement::Cursor) => (cursor, "cursor"),
+        Some(InfoPlacement::Above) => (symbol_start.unwrap_or(cursor), "above"),
+        Some(InfoPlacement::Below) => (symbol_start.unwrap_or(cursor), "below"),
+        Some(InfoPlacement::Docsclient) => (cursor, "none"),
+    }
+}
+
 fn show_hover_modal(
     meta: EditorMeta,
     ctx: &Context,
@@ -217,6 +868,9 @@ fn show_hover_modal(
     contents: String,
     diagnostics: String,
 ) {
+    if !info_box::claim(&meta, InfoBoxOwner::Hover) {
+        return;
+    }
     let contents = format!("{}\n---\n{}", modal_heading, contents);
     let command = format!(
         "lsp-show-hover modal %§{}§ %§{}§ ''",
@@ -287,3 +941,53 @@ fn show_hover_in_hover_client(
     ctx.exec(meta, command);
     handle.join().unwrap();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_word_at() {
+        let text = Rope::from_str("int main(void) {\n    printf_s(\"ä\");\n}\n");
+        let word = |line, column| word_at(&text, KakounePosition { line, column });
+        assert_eq!(word(1, 1), Some("int".to_string()));
+        assert_eq!(word(1, 7), Some("main".to_string()));
+        assert_eq!(word(2, 12), Some("printf_s".to_string()));
+        assert_eq!(word(1, 4), None);
+        assert_eq!(word(2, 15), Some("ä".to_string()));
+    }
+
+    #[test]
+    fn test_rewrite_markdown_fences() {
+        let rules: HashMap<String, String> = [("text", ""), ("", "rust")]
+            .iter()
+            .map(|(language, replacement)| (language.to_string(), replacement.to_string()))
+            .collect();
+        assert_eq!(
+            rewrite_markdown_fences("```text\n**bold**\n```\n", &rules, None),
+            "**bold**\n"
+        );
+        assert_eq!(
+            rewrite_markdown_fences("doc\n\n```\nfn f()\n```\n\n```c\nint x;\n```", &rules, None),
+            "doc\n\n```rust\nfn f()\n```\n\n```c\nint x;\n```"
+        );
+        // A longer fence contains shorter ones.
+        assert_eq!(
+            rewrite_markdown_fences("````text\n```\na\n```\n````\n", &rules, None),
+            "```\na\n```\n"
+        );
+        // The filetype replaces the language of every fence.
+        assert_eq!(
+            rewrite_markdown_fences("```c++\nint x;\n```\n", &HashMap::new(), Some("cpp")),
+            "```cpp\nint x;\n```\n"
+        );
+    }
+
+    #[test]
+    fn test_strip_overstrike() {
+        assert_eq!(
+            strip_overstrike("N\x08NA\x08AM\x08ME\x08E _\x08l_\x08s"),
+            "NAME ls"
+        );
+    }
+}
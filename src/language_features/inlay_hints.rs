@@ -1,36 +1,52 @@
-use itertools::Itertools;
 use lsp_types::{
-    request::InlayHintRequest, InlayHint, InlayHintLabel, InlayHintParams, Position, Range,
-    TextDocumentIdentifier, Url,
+    request::{InlayHintRequest, InlayHintResolveRequest},
+    GotoDefinitionResponse, InlayHint, InlayHintLabel, InlayHintLabelPartTooltip, InlayHintParams,
+    InlayHintServerCapabilities, InlayHintTooltip, MarkupContent, MarkupKind, OneOf, Position,
+    Range, TextDocumentIdentifier, Url,
 };
 use serde::Deserialize;
 
 use crate::{
     capabilities::{attempt_server_capability, CAPABILITY_INLAY_HINTS},
     context::Context,
-    markup::escape_kakoune_markup,
-    position::lsp_position_to_kakoune,
-    types::{EditorMeta, EditorParams},
-    util::{editor_quote, escape_tuple_element},
+    info_box::{self, InfoBoxOwner},
+    language_features::goto::goto,
+    large_file::is_large_file,
+    markup::{escape_kakoune_markup, markdown_to_kakoune_markup},
+    position::{get_line, lsp_position_to_kakoune},
+    types::{EditorMeta, EditorParams, KakounePosition, PositionParams},
+    util::{editor_quote, escape_tuple_element, set_list_option_commands, viewport_lines},
 };
 
 #[derive(Debug, PartialEq, Clone, Default, Serialize, Deserialize)]
 struct InlayHintsOptions {
     buf_line_count: u32,
+    #[serde(default)]
+    window_range: String,
+    #[serde(default)]
+    viewport_margin: Option<i32>,
 }
 
 pub fn inlay_hints(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
+    if meta.fifo.is_none() && is_large_file(ctx, &meta.buffile) {
+        return;
+    }
     if meta.fifo.is_none() && !attempt_server_capability(ctx, CAPABILITY_INLAY_HINTS) {
         return;
     }
 
     let params = InlayHintsOptions::deserialize(params).unwrap();
+    let (start_line, end_line) = viewport_lines(&params.window_range, params.viewport_margin)
+        .unwrap_or((0, params.buf_line_count));
     let req_params = InlayHintParams {
         work_done_progress_params: Default::default(),
         text_document: TextDocumentIdentifier {
             uri: Url::from_file_path(&meta.buffile).unwrap(),
         },
-        range: Range::new(Position::new(0, 0), Position::new(params.buf_line_count, 0)),
+        range: Range::new(
+            Position::new(start_line, 0),
+            Position::new(end_line.min(params.buf_line_count), 0),
+        ),
     };
     ctx.call::<InlayHintRequest, _>(meta, req_params, move |ctx, meta, response| {
         inlay_hints_response(meta, response.unwrap_or_default(), ctx)
@@ -43,7 +59,7 @@ pub fn inlay_hints_response(meta: EditorMeta, inlay_hints: Vec<InlayHint>, ctx:
         None => return,
     };
     let ranges = inlay_hints
-        .into_iter()
+        .iter()
         .map(
             |InlayHint {
                  position,
@@ -53,9 +69,9 @@ pub fn inlay_hints_response(meta: EditorMeta, inlay_hints: Vec<InlayHint>, ctx:
                  ..
              }| {
                 let position =
-                    lsp_position_to_kakoune(&position, &document.text, ctx.offset_encoding);
+                    lsp_position_to_kakoune(position, &document.text, ctx.offset_encoding);
                 let label = match label {
-                    InlayHintLabel::String(s) => s,
+                    InlayHintLabel::String(s) => s.clone(),
                     InlayHintLabel::LabelParts(parts) => {
                         parts.iter().map(|x| x.value.as_str()).collect()
                     }
@@ -76,15 +92,169 @@ pub fn inlay_hints_response(meta: EditorMeta, inlay_hints: Vec<InlayHint>, ctx:
                 ))
             },
         )
-        .join(" ");
-    let command = format!(
-        "set-option buffer lsp_inlay_hints {} {}",
-        meta.version, ranges
-    );
-    let command = format!(
-        "evaluate-commands -buffer {} -verbatim -- {}",
-        editor_quote(&meta.buffile),
-        command
-    );
-    ctx.exec(meta, command)
+        .collect::<Vec<_>>();
+    ctx.inlay_hints.insert(meta.buffile.clone(), inlay_hints);
+    let commands = set_list_option_commands(
+        "buffer",
+        "lsp_inlay_hints",
+        &meta.version.to_string(),
+        &ranges,
+    )
+    .into_iter()
+    .map(|command| {
+        format!(
+            "evaluate-commands -buffer {} -verbatim -- {}",
+            editor_quote(&meta.buffile),
+            command
+        )
+    })
+    .collect();
+    ctx.exec_chunked(meta, commands)
+}
+
+/// Returns the inlay hints shown at the cursor. A hint is rendered before the character at its
+/// position, so a cursor on the character before the hint counts as well, unless another hint
+/// is anchored right at the cursor.
+fn hints_at_cursor(ctx: &Context, buffile: &str, cursor: KakounePosition) -> Vec<InlayHint> {
+    let (document, hints) = match (ctx.documents.get(buffile), ctx.inlay_hints.get(buffile)) {
+        (Some(document), Some(hints)) => (document, hints),
+        _ => return vec![],
+    };
+    let line = get_line(cursor.line.saturating_sub(1) as usize, &document.text);
+    let byte = (cursor.column.saturating_sub(1) as usize).min(line.len_bytes());
+    let char_len = line
+        .get_char(line.byte_to_char(byte))
+        .map_or(1, |c| c.len_utf8()) as u32;
+    let hints_at = |column: u32| {
+        hints
+            .iter()
+            .filter(|hint| {
+                lsp_position_to_kakoune(&hint.position, &document.text, ctx.offset_encoding)
+                    == KakounePosition {
+                        line: cursor.line,
+                        column,
+                    }
+            })
+            .cloned()
+            .collect::<Vec<_>>()
+    };
+    let hints = hints_at(cursor.column);
+    if hints.is_empty() {
+        hints_at(cursor.column + char_len)
+    } else {
+        hints
+    }
+}
+
+fn can_resolve(ctx: &Context) -> bool {
+    match ctx
+        .capabilities
+        .as_ref()
+        .and_then(|caps| caps.inlay_hint_provider.as_ref())
+    {
+        Some(OneOf::Right(InlayHintServerCapabilities::Options(options))) => {
+            options.resolve_provider.unwrap_or(false)
+        }
+        Some(OneOf::Right(InlayHintServerCapabilities::RegistrationOptions(options))) => {
+            options.inlay_hint_options.resolve_provider.unwrap_or(false)
+        }
+        _ => false,
+    }
+}
+
+/// Resolves the inlay hints at the cursor if the server supports it, then calls `callback`
+/// with the hints.
+fn with_hints_at_cursor<F>(
+    meta: EditorMeta,
+    cursor: KakounePosition,
+    ctx: &mut Context,
+    callback: F,
+) where
+    F: FnOnce(&mut Context, EditorMeta, Vec<InlayHint>) + 'static,
+{
+    let hints = hints_at_cursor(ctx, &meta.buffile, cursor);
+    if hints.is_empty() || !can_resolve(ctx) {
+        callback(ctx, meta, hints);
+        return;
+    }
+    ctx.batch_call::<InlayHintResolveRequest, _>(meta, hints, move |ctx, meta, hints| {
+        callback(ctx, meta, hints)
+    });
+}
+
+/// Jumps to the locations that the label parts of the inlay hint at the cursor point to, for
+/// example the definition of the type in a type hint.
+pub fn inlay_hint_goto(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
+    let position = PositionParams::deserialize(params).unwrap().position;
+    with_hints_at_cursor(meta, position, ctx, |ctx, meta, hints| {
+        let locations = hints
+            .into_iter()
+            .filter_map(|hint| match hint.label {
+                InlayHintLabel::LabelParts(parts) => Some(parts),
+                InlayHintLabel::String(_) => None,
+            })
+            .flatten()
+            .filter_map(|part| part.location)
+            .collect::<Vec<_>>();
+        if locations.is_empty() {
+            ctx.exec(meta, "lsp-show-error 'no inlay hint location at cursor'");
+            return;
+        }
+        goto(meta, Some(GotoDefinitionResponse::Array(locations)), ctx);
+    });
+}
+
+/// Shows the tooltips of the inlay hint at the cursor in the info box.
+pub fn inlay_hint_tooltip(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
+    let position = PositionParams::deserialize(params).unwrap().position;
+    with_hints_at_cursor(meta, position, ctx, move |ctx, meta, hints| {
+        let tooltips = hints
+            .into_iter()
+            .flat_map(|hint| {
+                let tooltip = hint.tooltip.map(|tooltip| match tooltip {
+                    InlayHintTooltip::String(s) => escape_kakoune_markup(&s),
+                    InlayHintTooltip::MarkupContent(content) => markup_content_to_kakoune(content),
+                });
+                let part_tooltips = match hint.label {
+                    InlayHintLabel::LabelParts(parts) => parts
+                        .into_iter()
+                        .filter_map(|part| part.tooltip)
+                        .map(|tooltip| match tooltip {
+                            InlayHintLabelPartTooltip::String(s) => escape_kakoune_markup(&s),
+                            InlayHintLabelPartTooltip::MarkupContent(content) => {
+                                markup_content_to_kakoune(content)
+                            }
+                        })
+                        .collect(),
+                    InlayHintLabel::String(_) => vec![],
+                };
+                tooltip.into_iter().chain(part_tooltips)
+            })
+            .filter(|tooltip| !tooltip.is_empty())
+            .collect::<Vec<_>>();
+        if tooltips.is_empty() {
+            return;
+        }
+        let owner = if meta.hook {
+            InfoBoxOwner::InlayHintTooltip
+        } else {
+            InfoBoxOwner::Hover
+        };
+        if !info_box::claim(&meta, owner) {
+            return;
+        }
+        let command = format!(
+            "lsp-show-hover {} %§{}§ '' '' cursor",
+            position,
+            tooltips.join("\n").replace('§', "§§"),
+        );
+        ctx.exec(meta, command);
+    });
+}
+
+fn markup_content_to_kakoune(content: MarkupContent) -> String {
+    match content.kind {
+        MarkupKind::Markdown => markdown_to_kakoune_markup(content.value),
+        MarkupKind::PlainText => escape_kakoune_markup(&content.value),
+    }
 }
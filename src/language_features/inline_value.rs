@@ -0,0 +1,169 @@
+use std::collections::BTreeMap;
+
+use lsp_types::{
+    request::Request, Position, Range, TextDocumentIdentifier, Url, WorkDoneProgressParams,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    capabilities::{attempt_server_capability, CAPABILITY_INLINE_VALUES},
+    context::Context,
+    large_file::is_large_file,
+    markup::escape_kakoune_markup,
+    position::{get_line, kakoune_range_to_lsp, lsp_position_to_kakoune, parse_kakoune_range},
+    types::{EditorMeta, EditorParams},
+    util::{editor_quote, escape_tuple_element, set_list_option_commands},
+};
+
+pub enum InlineValueRequest {}
+
+impl Request for InlineValueRequest {
+    type Params = InlineValueParams;
+    type Result = Option<Vec<InlineValue>>;
+    const METHOD: &'static str = "textDocument/inlineValue";
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InlineValueParams {
+    #[serde(flatten)]
+    pub work_done_progress_params: WorkDoneProgressParams,
+    pub text_document: TextDocumentIdentifier,
+    pub range: Range,
+    pub context: InlineValueContext,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InlineValueContext {
+    /// The stack frame (as a DAP Id) where the execution has stopped.
+    pub frame_id: i32,
+    /// The document range where execution has stopped.
+    pub stopped_location: Range,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum InlineValue {
+    Text(InlineValueText),
+    VariableLookup(InlineValueVariableLookup),
+    EvaluatableExpression(InlineValueEvaluatableExpression),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InlineValueText {
+    pub range: Range,
+    pub text: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InlineValueVariableLookup {
+    pub range: Range,
+    pub variable_name: Option<String>,
+    pub case_sensitive_lookup: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InlineValueEvaluatableExpression {
+    pub range: Range,
+    pub expression: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct InlineValuesOptions {
+    buf_line_count: u32,
+    frame_id: i32,
+    stopped_location: String,
+}
+
+pub fn inline_values(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
+    if meta.fifo.is_none() && is_large_file(ctx, &meta.buffile) {
+        return;
+    }
+    if meta.fifo.is_none() && !attempt_server_capability(ctx, CAPABILITY_INLINE_VALUES) {
+        return;
+    }
+
+    let params = InlineValuesOptions::deserialize(params).unwrap();
+    let document = match ctx.documents.get(&meta.buffile) {
+        Some(document) => document,
+        None => return,
+    };
+    let (stopped_location, _cursor) = parse_kakoune_range(&params.stopped_location);
+    let stopped_location =
+        kakoune_range_to_lsp(&stopped_location, &document.text, ctx.offset_encoding);
+    let req_params = InlineValueParams {
+        work_done_progress_params: Default::default(),
+        text_document: TextDocumentIdentifier {
+            uri: Url::from_file_path(&meta.buffile).unwrap(),
+        },
+        range: Range::new(Position::new(0, 0), Position::new(params.buf_line_count, 0)),
+        context: InlineValueContext {
+            frame_id: params.frame_id,
+            stopped_location,
+        },
+    };
+    ctx.call::<InlineValueRequest, _>(meta, req_params, move |ctx, meta, response| {
+        inline_values_response(meta, response.unwrap_or_default(), ctx)
+    });
+}
+
+pub fn inline_values_response(
+    meta: EditorMeta,
+    inline_values: Vec<InlineValue>,
+    ctx: &mut Context,
+) {
+    let document = match ctx.documents.get(&meta.buffile) {
+        Some(document) => document,
+        None => return,
+    };
+    // Group the values by line, so we can render them after the end of each line.
+    let mut lines: BTreeMap<u32, Vec<String>> = BTreeMap::new();
+    for inline_value in inline_values {
+        match inline_value {
+            InlineValue::Text(InlineValueText { range, text }) => {
+                lines.entry(range.end.line).or_default().push(text);
+            }
+            // Without a debug adapter, we have no way to evaluate these.
+            InlineValue::VariableLookup(_) | InlineValue::EvaluatableExpression(_) => {
+                debug!(
+                    "Ignoring inline value that needs a debugger: {:?}",
+                    inline_value
+                );
+            }
+        }
+    }
+    let ranges = lines
+        .into_iter()
+        .map(|(line, values)| {
+            let line_text = get_line(line as usize, &document.text);
+            let mut pos = lsp_position_to_kakoune(
+                &Position::new(line, 0),
+                &document.text,
+                ctx.offset_encoding,
+            );
+            pos.column = std::cmp::max(line_text.len_bytes() as u32, 1);
+            let label = escape_tuple_element(&escape_kakoune_markup(&values.join(", ")));
+            editor_quote(&format!("{pos}+0| {{InlineValue}}{label}"))
+        })
+        .collect::<Vec<_>>();
+    let commands = set_list_option_commands(
+        "buffer",
+        "lsp_inline_values",
+        &meta.version.to_string(),
+        &ranges,
+    )
+    .into_iter()
+    .map(|command| {
+        format!(
+            "evaluate-commands -buffer {} -verbatim -- {}",
+            editor_quote(&meta.buffile),
+            command
+        )
+    })
+    .collect();
+    ctx.exec_chunked(meta, commands)
+}
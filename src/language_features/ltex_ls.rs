@@ -0,0 +1,105 @@
+use crate::context::Context;
+use crate::types::EditorMeta;
+use crate::util::editor_quote;
+use crate::workspace;
+use lsp_types::notification::DidChangeConfiguration;
+use lsp_types::{DidChangeConfigurationParams, ExecuteCommandParams};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+
+/// Handle the commands of ltex-ls' code actions. The server expects us to add the words or rules
+/// to its settings, so we remember them and push the updated settings.
+pub fn execute_command(meta: EditorMeta, params: ExecuteCommandParams, ctx: &mut Context) {
+    // Maps each command to the key of its argument and the setting it modifies.
+    let (argument_key, setting, message) = match params.command.as_str() {
+        "_ltex.addToDictionary" => ("words", "dictionary", "added to dictionary"),
+        "_ltex.disableRules" => ("ruleIds", "disabledRules", "disabled rule"),
+        "_ltex.hideFalsePositives" => (
+            "falsePositives",
+            "hiddenFalsePositives",
+            "hid false positive",
+        ),
+        // Other commands like "_ltex.checkDocument" are implemented by the server.
+        _ => return workspace::send_command(meta, params, ctx),
+    };
+    let mut added = vec![];
+    for argument in &params.arguments {
+        let by_language = match argument.get(argument_key).and_then(|v| v.as_object()) {
+            Some(by_language) => by_language,
+            None => {
+                warn!(
+                    "Unexpected arguments for {}: {:?}",
+                    params.command, argument
+                );
+                continue;
+            }
+        };
+        for (language, values) in by_language {
+            let stored = ctx
+                .ltex_settings
+                .entry(setting.to_string())
+                .or_default()
+                .entry(language.clone())
+                .or_default();
+            for value in values.as_array().into_iter().flatten() {
+                if let Some(value) = value.as_str() {
+                    if !stored.iter().any(|v| v == value) {
+                        stored.push(value.to_string());
+                    }
+                    added.push(value.to_string());
+                }
+            }
+        }
+    }
+
+    let ltex = workspace::settings_section("ltex", ctx);
+    ctx.notify::<DidChangeConfiguration>(DidChangeConfigurationParams {
+        settings: json!({ "ltex": ltex }),
+    });
+    // The next full settings push must not be skipped as redundant.
+    ctx.settings_hash = None;
+
+    let message = format!("ltex-ls: {}: {}", message, added.join(", "));
+    ctx.exec(meta, format!("echo {}", editor_quote(&message)));
+}
+
+/// Merge the words and rules added with code actions into the user's `ltex` settings.
+pub fn add_settings(ltex: &mut Value, ctx: &Context) {
+    if ctx.ltex_settings.is_empty() {
+        return;
+    }
+    if !ltex.is_object() {
+        *ltex = json!({});
+    }
+    for (setting, by_language) in &ctx.ltex_settings {
+        let configured = ltex
+            .as_object_mut()
+            .unwrap()
+            .entry(setting.clone())
+            .or_insert_with(|| json!({}));
+        if !configured.is_object() {
+            *configured = json!({});
+        }
+        merge_by_language(configured.as_object_mut().unwrap(), by_language);
+    }
+}
+
+fn merge_by_language(
+    configured: &mut serde_json::Map<String, Value>,
+    by_language: &HashMap<String, Vec<String>>,
+) {
+    for (language, values) in by_language {
+        let list = configured
+            .entry(language.clone())
+            .or_insert_with(|| json!([]));
+        if !list.is_array() {
+            *list = json!([]);
+        }
+        let list = list.as_array_mut().unwrap();
+        for value in values {
+            if !list.iter().any(|v| v.as_str() == Some(value)) {
+                list.push(Value::String(value.clone()));
+            }
+        }
+    }
+}
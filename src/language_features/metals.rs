@@ -0,0 +1,26 @@
+use crate::context::*;
+use crate::progress;
+use crate::types::*;
+use jsonrpc_core::Params;
+use serde::Deserialize;
+
+#[derive(Deserialize, Debug)]
+struct StatusParams {
+    text: String,
+    #[serde(default)]
+    hide: bool,
+}
+
+/// Handle "metals/status", which Metals uses instead of progress reports, for example while it
+/// imports the build.
+pub fn status(meta: EditorMeta, params: Params, ctx: &mut Context) {
+    let params: StatusParams = match params.parse() {
+        Ok(params) => params,
+        Err(err) => {
+            warn!("Failed to parse metals/status params: {}", err);
+            return;
+        }
+    };
+    let status = Some(params.text.trim()).filter(|text| !params.hide && !text.is_empty());
+    progress::set_server_status(meta, status, ctx);
+}
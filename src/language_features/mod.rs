@@ -1,21 +1,34 @@
+pub mod all_selections;
 pub mod call_hierarchy;
 pub mod ccls;
 pub mod clangd;
 pub mod code_action;
 pub mod code_lens;
+pub mod color;
 pub mod completion;
 pub mod cquery;
 pub mod document_symbol;
 pub mod eclipse_jdt_ls;
+pub mod eslint;
 pub mod formatting;
 pub mod goto;
+pub mod haskell_language_server;
 pub mod highlight;
 pub mod hover;
 pub mod inlay_hints;
+pub mod inline_value;
+pub mod ltex_ls;
+pub mod metals;
+pub mod prefetch;
 pub mod range_formatting;
 pub mod rename;
+pub mod review;
 pub mod rust_analyzer;
+pub mod search_docs;
 pub mod selection_range;
 pub mod semantic_tokens;
 pub mod signature_help;
+pub mod terraform_ls;
 pub mod texlab;
+pub mod typescript_language_server;
+pub mod yaml_language_server;
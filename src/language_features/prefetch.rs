@@ -0,0 +1,250 @@
+//! Prefetching of hover info and definitions for the symbols in the visible part of a buffer,
+//! while the editor is idle, so `lsp-hover` and `lsp-definition` can answer from the cache
+//! without waiting for the language server. The cache of a buffer is dropped when it changes.
+
+use crate::capabilities::{server_has_capability, CAPABILITY_DEFINITION, CAPABILITY_HOVER};
+use crate::context::*;
+use crate::large_file::is_large_file;
+use crate::position::*;
+use crate::types::*;
+use crate::util::viewport_lines;
+use lsp_types::request::*;
+use lsp_types::*;
+use ropey::Rope;
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use url::Url;
+
+/// The maximum number of symbols to prefetch per request, to not flood the server.
+const MAX_SYMBOLS_PER_REQUEST: usize = 50;
+/// The maximum number of symbols cached per buffer. The cache starts over when it is full.
+const MAX_CACHED_SYMBOLS: usize = 2000;
+
+/// The start of a symbol, as LSP line and character.
+type SymbolStart = (u32, u32);
+
+/// The prefetched results of a buffer, by the start of the symbol they are for.
+#[derive(Default)]
+pub struct Prefetched {
+    version: i32,
+    requested: HashSet<SymbolStart>,
+    hovers: HashMap<SymbolStart, Option<Hover>>,
+    definitions: HashMap<SymbolStart, Option<GotoDefinitionResponse>>,
+}
+
+fn symbol_key(position: &Position) -> SymbolStart {
+    (position.line, position.character)
+}
+
+#[derive(Deserialize)]
+struct PrefetchParams {
+    window_range: String,
+}
+
+pub fn prefetch(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
+    if is_large_file(ctx, &meta.buffile) {
+        return;
+    }
+    let params = PrefetchParams::deserialize(params).unwrap();
+    let document = match ctx.documents.get(&meta.buffile) {
+        Some(document) => document,
+        None => return,
+    };
+    let version = document.version;
+    let offset_encoding = ctx.offset_encoding;
+    let (start_line, end_line) = match viewport_lines(&params.window_range, Some(0)) {
+        Some(lines) => lines,
+        None => return,
+    };
+    let prefetched = ctx.prefetched.entry(meta.buffile.clone()).or_default();
+    if prefetched.version != version || prefetched.requested.len() >= MAX_CACHED_SYMBOLS {
+        *prefetched = Prefetched {
+            version,
+            ..Prefetched::default()
+        };
+    }
+    let positions: Vec<Position> = symbol_starts(&document.text, start_line, end_line)
+        .into_iter()
+        .map(|start| kakoune_position_to_lsp(&start, &document.text, offset_encoding))
+        .filter(|position| !prefetched.requested.contains(&symbol_key(position)))
+        .take(MAX_SYMBOLS_PER_REQUEST)
+        .collect();
+    if positions.is_empty() {
+        return;
+    }
+    prefetched
+        .requested
+        .extend(positions.iter().map(symbol_key));
+    let uri = Url::from_file_path(&meta.buffile).unwrap();
+    let text_document_position = |position: &Position| TextDocumentPositionParams {
+        text_document: TextDocumentIdentifier { uri: uri.clone() },
+        position: *position,
+    };
+
+    if server_has_capability(ctx, CAPABILITY_HOVER) {
+        let ops = positions
+            .iter()
+            .map(|position| HoverParams {
+                text_document_position_params: text_document_position(position),
+                work_done_progress_params: Default::default(),
+            })
+            .collect();
+        let hover_keys: Vec<SymbolStart> = positions.iter().map(symbol_key).collect();
+        // Failures are not cached, so the hover is requested again when the user asks for it.
+        ctx.batch_call_lenient::<HoverRequest, _>(meta.clone(), ops, move |ctx, meta, results| {
+            if let Some(prefetched) = current_prefetched(ctx, &meta.buffile, version) {
+                prefetched.hovers.extend(
+                    hover_keys
+                        .into_iter()
+                        .zip(results)
+                        .filter_map(|(key, result)| Some((key, result?))),
+                );
+            }
+        });
+    }
+    if server_has_capability(ctx, CAPABILITY_DEFINITION) {
+        let ops = positions
+            .iter()
+            .map(|position| GotoDefinitionParams {
+                text_document_position_params: text_document_position(position),
+                work_done_progress_params: Default::default(),
+                partial_result_params: Default::default(),
+            })
+            .collect();
+        ctx.batch_call_lenient::<GotoDefinition, _>(meta, ops, move |ctx, meta, results| {
+            if let Some(prefetched) = current_prefetched(ctx, &meta.buffile, version) {
+                prefetched.definitions.extend(
+                    positions
+                        .iter()
+                        .map(symbol_key)
+                        .zip(results)
+                        .filter_map(|(key, result)| Some((key, result?))),
+                );
+            }
+        });
+    }
+}
+
+/// Returns the prefetched results of a buffer, if they are for the given version.
+fn current_prefetched<'a>(
+    ctx: &'a mut Context,
+    buffile: &str,
+    version: i32,
+) -> Option<&'a mut Prefetched> {
+    let current = ctx.documents.get(buffile).map(|document| document.version);
+    if current != Some(version) {
+        return None;
+    }
+    ctx.prefetched
+        .get_mut(buffile)
+        .filter(|prefetched| prefetched.version == version)
+}
+
+/// Returns the start of each symbol on the given lines, with `end_line` exclusive.
+fn symbol_starts(text: &Rope, start_line: u32, end_line: u32) -> Vec<KakounePosition> {
+    let end_line = (end_line as usize).min(text.len_lines());
+    let mut starts = vec![];
+    for line_number in (start_line as usize)..end_line {
+        let line = text.line(line_number).to_string();
+        let mut previous_is_word = false;
+        for (column, c) in line.char_indices() {
+            let is_word = is_word_char(c);
+            if is_word && !previous_is_word && !c.is_numeric() {
+                starts.push(KakounePosition {
+                    line: line_number as u32 + 1,
+                    column: column as u32 + 1,
+                });
+            }
+            previous_is_word = is_word;
+        }
+    }
+    starts
+}
+
+fn is_word_char(c: char) -> bool {
+    c == '_' || c.is_alphanumeric()
+}
+
+/// Returns the start of the symbol at the given position, if any.
+fn symbol_start(text: &Rope, position: KakounePosition) -> Option<KakounePosition> {
+    let line = text.get_line(position.line as usize - 1)?.to_string();
+    let cursor = position.column as usize - 1;
+    if !is_word_char(line.get(cursor..)?.chars().next()?) {
+        return None;
+    }
+    let start = line[..cursor]
+        .char_indices()
+        .rev()
+        .take_while(|(_, c)| is_word_char(*c))
+        .last()
+        .map_or(cursor, |(start, _)| start);
+    Some(KakounePosition {
+        line: position.line,
+        column: start as u32 + 1,
+    })
+}
+
+fn prefetched_at<'a>(
+    ctx: &'a Context,
+    meta: &EditorMeta,
+    position: KakounePosition,
+) -> Option<(&'a Prefetched, Position)> {
+    // Requests with a fifo want the server's response as is.
+    if meta.fifo.is_some() || meta.write_response_to_fifo {
+        return None;
+    }
+    let document = ctx.documents.get(&meta.buffile)?;
+    let prefetched = ctx
+        .prefetched
+        .get(&meta.buffile)
+        .filter(|prefetched| prefetched.version == document.version)?;
+    let start = symbol_start(&document.text, position)?;
+    let start = kakoune_position_to_lsp(&start, &document.text, ctx.offset_encoding);
+    Some((prefetched, start))
+}
+
+/// Returns the prefetched hover info for the symbol at the given position, if any.
+pub fn prefetched_hover(
+    ctx: &Context,
+    meta: &EditorMeta,
+    position: KakounePosition,
+) -> Option<Option<Hover>> {
+    let (prefetched, start) = prefetched_at(ctx, meta, position)?;
+    prefetched.hovers.get(&symbol_key(&start)).cloned()
+}
+
+/// Returns the prefetched definition of the symbol at the given position, if any.
+pub fn prefetched_definition(
+    ctx: &Context,
+    meta: &EditorMeta,
+    position: KakounePosition,
+) -> Option<Option<GotoDefinitionResponse>> {
+    let (prefetched, start) = prefetched_at(ctx, meta, position)?;
+    prefetched.definitions.get(&symbol_key(&start)).cloned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_symbol_starts() {
+        let text = Rope::from_str("fn main() {\n    let x1 = 42 + größe;\n}\n");
+        let position = |line, column| KakounePosition { line, column };
+        assert_eq!(
+            symbol_starts(&text, 0, 3),
+            vec![
+                position(1, 1),
+                position(1, 4),
+                position(2, 5),
+                position(2, 9),
+                position(2, 19),
+            ]
+        );
+        assert_eq!(symbol_start(&text, position(2, 10)), Some(position(2, 9)));
+        // The "ö" and "ß" take two bytes each.
+        assert_eq!(symbol_start(&text, position(2, 25)), Some(position(2, 19)));
+        assert_eq!(symbol_start(&text, position(2, 24)), None);
+        assert_eq!(symbol_start(&text, position(2, 12)), None);
+    }
+}
@@ -51,6 +51,7 @@ pub fn editor_range_formatting<T: TextEditish<T>>(
             None,
             text_edits,
             &document.text,
+            None,
             ctx.offset_encoding,
         )
     });
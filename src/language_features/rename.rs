@@ -1,7 +1,11 @@
+use crate::capabilities::{server_has_capability, CAPABILITY_REFERENCES};
 use crate::context::*;
+use crate::edit_conflict;
 use crate::position::*;
 use crate::types::*;
+use crate::util::*;
 
+use itertools::Itertools;
 use lsp_types::request::*;
 use lsp_types::*;
 use serde::Deserialize;
@@ -11,6 +15,7 @@ use super::super::workspace;
 
 pub fn text_document_rename(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
     let params = TextDocumentRenameParams::deserialize(params).unwrap();
+    let write_buffers = params.write_buffers;
     let req_params = RenameParams {
         text_document_position: TextDocumentPositionParams {
             text_document: TextDocumentIdentifier {
@@ -22,15 +27,104 @@ pub fn text_document_rename(meta: EditorMeta, params: EditorParams, ctx: &mut Co
         work_done_progress_params: Default::default(),
     };
     ctx.call::<Rename, _>(meta, req_params, move |ctx: &mut Context, meta, result| {
-        editor_rename(meta, result, ctx)
+        editor_rename(meta, result, write_buffers, ctx)
     });
 }
 
-// TODO handle version, so change is not applied if buffer is modified (and need to show a warning)
-pub fn editor_rename(meta: EditorMeta, result: Option<WorkspaceEdit>, ctx: &mut Context) {
+#[derive(Deserialize)]
+struct RenamePromptParams {
+    name: String,
+    position: KakounePosition,
+}
+
+/// Prompts for the new name of the symbol under the cursor. The prompt tells how many
+/// references will be renamed in how many files, if the server can find references.
+pub fn rename_prompt(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
+    let params = RenamePromptParams::deserialize(params).unwrap();
+    let name = params.name;
+    if !server_has_capability(ctx, CAPABILITY_REFERENCES) {
+        ctx.exec(meta, prompt_command(&name, "New name: "));
+        return;
+    }
+    let position = match get_lsp_position(&meta.buffile, &params.position, ctx) {
+        Some(position) => position,
+        None => {
+            let msg = format!("{} is not open in the language server", meta.buffile);
+            ctx.exec(meta, format!("lsp-show-error {}", editor_quote(&msg)));
+            return;
+        }
+    };
+    let req_params = ReferenceParams {
+        text_document_position: TextDocumentPositionParams {
+            text_document: TextDocumentIdentifier {
+                uri: Url::from_file_path(&meta.buffile).unwrap(),
+            },
+            position,
+        },
+        context: ReferenceContext {
+            include_declaration: true,
+        },
+        partial_result_params: Default::default(),
+        work_done_progress_params: Default::default(),
+    };
+    // If the references fail, the symbol can still be renamed with the plain prompt.
+    ctx.batch_call_lenient::<References, _>(
+        meta,
+        vec![req_params],
+        move |ctx: &mut Context, meta, mut results| {
+            let locations = results.pop().flatten().flatten().unwrap_or_default();
+            let prompt = if locations.is_empty() {
+                "New name: ".to_string()
+            } else {
+                let files = locations
+                    .iter()
+                    .map(|location| &location.uri)
+                    .unique()
+                    .count();
+                format!(
+                    "rename {} {} in {} {} to: ",
+                    locations.len(),
+                    if locations.len() == 1 {
+                        "occurrence"
+                    } else {
+                        "occurrences"
+                    },
+                    files,
+                    if files == 1 { "file" } else { "files" },
+                )
+            };
+            ctx.exec(meta, prompt_command(&name, &prompt));
+        },
+    );
+}
+
+fn prompt_command(name: &str, prompt: &str) -> String {
+    format!(
+        "prompt -init {} {} %{{ lsp-rename %val{{text}} }}",
+        editor_quote(name),
+        editor_quote(prompt),
+    )
+}
+
+pub fn editor_rename(
+    meta: EditorMeta,
+    result: Option<WorkspaceEdit>,
+    write_buffers: bool,
+    ctx: &mut Context,
+) {
     if result.is_none() {
         return;
     }
     let result = result.unwrap();
-    workspace::apply_edit(meta, result, ctx);
+    let buffers = edit_conflict::edited_buffers(&result, ctx);
+    // The edit may wait for the user to confirm it, and then the buffers are written once it is
+    // applied.
+    let response = workspace::apply_edit(meta.clone(), result, write_buffers, ctx);
+    if !write_buffers && response.applied && buffers.len() > 1 {
+        let command = format!(
+            "info -title lsp-rename {}",
+            editor_quote(&format!("Modified buffers:\n{}", buffers.join("\n")))
+        );
+        ctx.exec(meta, command);
+    }
 }
@@ -0,0 +1,279 @@
+//! Hover and definitions in review buffers, like diffs or files at a git revision, which are
+//! not backed by the file they show. Requests are sent for the file under review, at the position
+//! that the cursor is translated to: by finding the diff hunk in the current file, or through a
+//! line diff between the revision and the current file.
+
+use crate::context::*;
+use crate::language_features::goto::goto;
+use crate::language_features::hover::hover_contents_to_markdown;
+use crate::markup::markdown_to_kakoune_markup_with_options;
+use crate::position::*;
+use crate::types::*;
+use crate::util::*;
+use lsp_types::request::*;
+use lsp_types::*;
+use ropey::Rope;
+use serde::Deserialize;
+use url::Url;
+
+#[derive(Deserialize)]
+struct ReviewParams {
+    // "hover" or "definition".
+    request: String,
+    // Whether the review buffer is a unified diff, rather than a revision of the file.
+    diff: bool,
+    text: String,
+    line: u32,
+    column: u32,
+}
+
+/// Sends a request for the file under review at the position of the review buffer's cursor. The
+/// request comes from the file's buffer, which the editor opened for this.
+pub fn review_request(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
+    let params = ReviewParams::deserialize(params).unwrap();
+    let cursor = KakounePosition {
+        line: params.line,
+        column: params.column,
+    };
+    let document = match ctx.documents.get(&meta.buffile) {
+        Some(document) => document,
+        None => {
+            let msg = format!("{} is not open in the language server", meta.buffile);
+            ctx.exec(meta, format!("lsp-show-error {}", editor_quote(&msg)));
+            return;
+        }
+    };
+    let position = if params.diff {
+        diff_position(&params.text, &document.text, cursor)
+    } else {
+        revision_position(&Rope::from_str(&params.text), &document.text, cursor)
+    };
+    let position = match position {
+        Ok(position) => kakoune_position_to_lsp(&position, &document.text, ctx.offset_encoding),
+        Err(msg) => {
+            ctx.exec(meta, format!("lsp-show-error {}", editor_quote(&msg)));
+            return;
+        }
+    };
+    let text_document_position_params = TextDocumentPositionParams {
+        text_document: TextDocumentIdentifier {
+            uri: Url::from_file_path(&meta.buffile).unwrap(),
+        },
+        position,
+    };
+    match params.request.as_str() {
+        "hover" => {
+            let req_params = HoverParams {
+                text_document_position_params,
+                work_done_progress_params: Default::default(),
+            };
+            ctx.call::<HoverRequest, _>(
+                meta,
+                req_params,
+                move |ctx: &mut Context, meta, result| {
+                    let contents = result
+                        .map(|hover| hover_contents_to_markdown(hover.contents))
+                        .unwrap_or_default();
+                    if contents.trim().is_empty() {
+                        return;
+                    }
+                    let command = format!(
+                        "info -markup -title {} -- {}",
+                        editor_quote(short_file_path(&meta.buffile, &ctx.root_path)),
+                        editor_quote(&markdown_to_kakoune_markup_with_options(
+                            contents,
+                            &ctx.language_config().markdown
+                        )),
+                    );
+                    ctx.exec(meta, command);
+                },
+            );
+        }
+        _ => {
+            let req_params = GotoDefinitionParams {
+                text_document_position_params,
+                partial_result_params: Default::default(),
+                work_done_progress_params: Default::default(),
+            };
+            ctx.call::<GotoDefinition, _>(
+                meta,
+                req_params,
+                move |ctx: &mut Context, meta, result| goto(meta, result, ctx),
+            );
+        }
+    }
+}
+
+/// Translates a position in a unified diff to the current file of its hunk. The new side of the
+/// diff may be older than the file, so the hunk is looked up in the file, preferring the place
+/// closest to where the hunk header puts it.
+fn diff_position(
+    diff: &str,
+    current: &Rope,
+    cursor: KakounePosition,
+) -> Result<KakounePosition, String> {
+    let lines: Vec<&str> = diff.lines().collect();
+    let index = cursor.line as usize - 1;
+    let line = lines.get(index).copied().unwrap_or_default();
+    if !(line.starts_with(' ') || line.starts_with('+')) || line.starts_with("+++ ") {
+        return Err("the cursor is not on a line of the new version of a file".to_string());
+    }
+    let header = (0..index)
+        .rev()
+        .find(|&i| lines[i].starts_with("@@ "))
+        .ok_or_else(|| "the cursor is not in a hunk".to_string())?;
+    // The header looks like "@@ -1,5 +1,6 @@", where a missing count means 1.
+    let range = |prefix: char| -> Option<(usize, usize)> {
+        let range = lines[header]
+            .split(' ')
+            .find_map(|range| range.strip_prefix(prefix))?;
+        let mut parts = range.split(',');
+        let start = parts.next()?.parse().ok()?;
+        let count = parts.next().map_or(Some(1), |count| count.parse().ok())?;
+        Some((start, count))
+    };
+    let ((_, old_count), (new_start, new_count)) = range('-')
+        .zip(range('+'))
+        .ok_or_else(|| format!("invalid hunk header: {}", lines[header]))?;
+    // The new side of the hunk, and the index of the cursor's line in it.
+    let mut hunk = vec![];
+    let mut cursor_index = None;
+    let (mut old_seen, mut new_seen) = (0, 0);
+    for (i, line) in lines.iter().enumerate().skip(header + 1) {
+        if old_seen >= old_count && new_seen >= new_count {
+            break;
+        }
+        match line.chars().next() {
+            Some('-') => old_seen += 1,
+            Some('+') => new_seen += 1,
+            Some('\\') => continue,
+            _ => {
+                old_seen += 1;
+                new_seen += 1;
+            }
+        }
+        if line.starts_with('-') {
+            continue;
+        }
+        if i == index {
+            cursor_index = Some(hunk.len());
+        }
+        hunk.push(line.get(1..).unwrap_or_default());
+    }
+    let cursor_index = cursor_index.ok_or_else(|| "the cursor is not in a hunk".to_string())?;
+    let current_line = |i: usize| {
+        let line = current.line(i).to_string();
+        line.trim_end_matches(&['\n', '\r'][..]).to_string()
+    };
+    let expected = new_start.saturating_sub(1);
+    let start = (0..(current.len_lines() + 1).saturating_sub(hunk.len()))
+        .filter(|&start| {
+            hunk.iter()
+                .enumerate()
+                .all(|(i, line)| current_line(start + i) == *line)
+        })
+        .min_by_key(|&start| (start as isize - expected as isize).abs())
+        .ok_or_else(|| "the hunk at the cursor does not match the current file".to_string())?;
+    Ok(KakounePosition {
+        line: (start + cursor_index + 1) as u32,
+        // Skip the column of the diff marker.
+        column: cursor.column.saturating_sub(1).max(1),
+    })
+}
+
+/// Translates a position in a revision of a file to the current version, if its line is
+/// unchanged.
+fn revision_position(
+    revision: &Rope,
+    current: &Rope,
+    cursor: KakounePosition,
+) -> Result<KakounePosition, String> {
+    let oldv: Vec<_> = revision.lines().collect();
+    let newv: Vec<_> = current.lines().collect();
+    // Runs of unchanged lines as (old start, new start, length).
+    struct UnchangedLines(Vec<(usize, usize, usize)>);
+    impl diffs::Diff for UnchangedLines {
+        type Error = ();
+        fn equal(&mut self, old: usize, new: usize, len: usize) -> Result<(), ()> {
+            self.0.push((old, new, len));
+            Ok(())
+        }
+    }
+    let mut unchanged = UnchangedLines(vec![]);
+    let _result = diffs::myers::diff(&mut unchanged, &oldv, 0, oldv.len(), &newv, 0, newv.len());
+    let line = cursor.line as usize - 1;
+    unchanged
+        .0
+        .into_iter()
+        .find(|(old, _, len)| *old <= line && line < old + len)
+        .map(|(old, new, _)| KakounePosition {
+            line: (new + line - old + 1) as u32,
+            column: cursor.column,
+        })
+        .ok_or_else(|| "the line at the cursor was changed since this revision".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use indoc::indoc;
+
+    fn position(line: u32, column: u32) -> KakounePosition {
+        KakounePosition { line, column }
+    }
+
+    #[test]
+    fn test_diff_position() {
+        let diff = indoc! {"
+            --- a/src/main.rs
+            +++ b/src/main.rs
+            @@ -10,4 +10,4 @@ fn main() {
+                 let a = 1;
+            -    let b = 2;
+            +    let b = 3;
+                 let c = 4;
+        "};
+        let context = "\n".repeat(9);
+        let current = Rope::from_str(&format!(
+            "{}    let a = 1;\n    let b = 3;\n    let c = 4;\n",
+            context
+        ));
+        assert_eq!(
+            diff_position(diff, &current, position(4, 9)),
+            Ok(position(10, 8))
+        );
+        assert_eq!(
+            diff_position(diff, &current, position(6, 9)),
+            Ok(position(11, 8))
+        );
+        assert_eq!(
+            diff_position(diff, &current, position(7, 1)),
+            Ok(position(12, 1))
+        );
+        assert!(diff_position(diff, &current, position(5, 9)).is_err());
+        assert!(diff_position(diff, &current, position(2, 1)).is_err());
+        // Lines were inserted above the hunk since the diff was made.
+        let moved = Rope::from_str(&format!("new\n{}", current));
+        assert_eq!(
+            diff_position(diff, &moved, position(6, 9)),
+            Ok(position(12, 8))
+        );
+        let changed = Rope::from_str(&format!("{}    let a = 0;\n", context));
+        assert!(diff_position(diff, &changed, position(6, 9)).is_err());
+    }
+
+    #[test]
+    fn test_revision_position() {
+        let revision = Rope::from_str("a\nb\nc\n");
+        let current = Rope::from_str("new\na\nB\nc\n");
+        assert_eq!(
+            revision_position(&revision, &current, position(1, 1)),
+            Ok(position(2, 1))
+        );
+        assert_eq!(
+            revision_position(&revision, &current, position(3, 1)),
+            Ok(position(4, 1))
+        );
+        assert!(revision_position(&revision, &current, position(2, 1)).is_err());
+    }
+}
@@ -1,9 +1,15 @@
+use crate::capabilities::{attempt_experimental_capability, attempt_server_extension};
 use crate::context::Context;
+use crate::language_features::goto::goto;
 use crate::position::{get_lsp_position, lsp_position_to_kakoune};
+use crate::progress;
+use crate::snippet;
 use crate::text_edit::apply_text_edits;
 use crate::types::{EditorMeta, EditorParams, KakounePosition, PositionParams};
 use crate::util::{editor_escape, editor_quote};
 use crate::workspace;
+use jsonrpc_core::Params;
+use lsp_types::notification::Notification;
 use lsp_types::request::Request;
 use lsp_types::*;
 use serde::{Deserialize, Serialize};
@@ -83,8 +89,16 @@ pub fn apply_source_change(meta: EditorMeta, params: ExecuteCommandParams, ctx:
                             |SnippetTextEdit {
                                  range,
                                  new_text,
-                                 insert_text_format: _, // TODO
-                             }| TextEdit { range, new_text },
+                                 insert_text_format,
+                             }| {
+                                let new_text =
+                                    if insert_text_format == Some(InsertTextFormat::SNIPPET) {
+                                        snippet::plain_text(&new_text)
+                                    } else {
+                                        new_text
+                                    };
+                                TextEdit { range, new_text }
+                            },
                         )
                         .collect();
                     apply_text_edits(&meta, &uri, edits, ctx);
@@ -152,6 +166,9 @@ impl Request for ExpandMacroRequest {
 }
 
 pub fn expand_macro(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
+    if !attempt_server_extension(&meta, ctx, ExpandMacroRequest::METHOD) {
+        return;
+    }
     let params = PositionParams::deserialize(params).unwrap();
     let req_params = ExpandMacroParams {
         text_document: TextDocumentIdentifier {
@@ -172,3 +189,174 @@ fn editor_expand_macro(meta: EditorMeta, result: ExpandMacroResponse, ctx: &mut
     );
     ctx.exec(meta, command);
 }
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct RunFlycheckParams {
+    pub text_document: Option<TextDocumentIdentifier>,
+}
+
+pub enum RunFlycheck {}
+
+impl Notification for RunFlycheck {
+    type Params = RunFlycheckParams;
+    const METHOD: &'static str = "rust-analyzer/runFlycheck";
+}
+
+pub enum CancelFlycheck {}
+
+impl Notification for CancelFlycheck {
+    type Params = ();
+    const METHOD: &'static str = "rust-analyzer/cancelFlycheck";
+}
+
+pub enum ReloadWorkspace {}
+
+impl Request for ReloadWorkspace {
+    type Params = ();
+    type Result = ();
+    const METHOD: &'static str = "rust-analyzer/reloadWorkspace";
+}
+
+pub fn run_flycheck(meta: EditorMeta, ctx: &mut Context) {
+    if !attempt_server_extension(&meta, ctx, RunFlycheck::METHOD) {
+        return;
+    }
+    let params = RunFlycheckParams {
+        text_document: Some(TextDocumentIdentifier {
+            uri: Url::from_file_path(&meta.buffile).unwrap(),
+        }),
+    };
+    ctx.notify::<RunFlycheck>(params);
+}
+
+pub fn cancel_flycheck(meta: EditorMeta, ctx: &mut Context) {
+    if !attempt_server_extension(&meta, ctx, CancelFlycheck::METHOD) {
+        return;
+    }
+    ctx.notify::<CancelFlycheck>(());
+}
+
+pub fn reload_workspace(meta: EditorMeta, ctx: &mut Context) {
+    if !attempt_server_extension(&meta, ctx, ReloadWorkspace::METHOD) {
+        return;
+    }
+    ctx.call::<ReloadWorkspace, _>(meta, (), move |ctx: &mut Context, meta, _| {
+        ctx.exec(meta, "echo 'rust-analyzer: workspace reloaded'");
+    });
+}
+
+pub enum ParentModule {}
+
+impl Request for ParentModule {
+    type Params = TextDocumentPositionParams;
+    type Result = Option<GotoDefinitionResponse>;
+    const METHOD: &'static str = "experimental/parentModule";
+}
+
+/// Jumps to the module declaration of the module at the cursor.
+pub fn parent_module(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
+    if !attempt_experimental_capability(&meta, ctx, "parentModule") {
+        return;
+    }
+    let params = PositionParams::deserialize(params).unwrap();
+    let req_params = TextDocumentPositionParams {
+        text_document: TextDocumentIdentifier {
+            uri: Url::from_file_path(&meta.buffile).unwrap(),
+        },
+        position: get_lsp_position(&meta.buffile, &params.position, ctx).unwrap(),
+    };
+    ctx.call::<ParentModule, _>(meta, req_params, move |ctx: &mut Context, meta, result| {
+        goto(meta, result, ctx)
+    });
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct OpenCargoTomlParams {
+    pub text_document: TextDocumentIdentifier,
+}
+
+pub enum OpenCargoToml {}
+
+impl Request for OpenCargoToml {
+    type Params = OpenCargoTomlParams;
+    type Result = Option<Location>;
+    const METHOD: &'static str = "experimental/openCargoToml";
+}
+
+/// Opens the Cargo.toml of the package of the current file.
+pub fn open_cargo_toml(meta: EditorMeta, ctx: &mut Context) {
+    if !attempt_experimental_capability(&meta, ctx, "openCargoToml") {
+        return;
+    }
+    let req_params = OpenCargoTomlParams {
+        text_document: TextDocumentIdentifier {
+            uri: Url::from_file_path(&meta.buffile).unwrap(),
+        },
+    };
+    ctx.call::<OpenCargoToml, _>(meta, req_params, move |ctx: &mut Context, meta, result| {
+        goto(meta, result.map(GotoDefinitionResponse::Scalar), ctx)
+    });
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct ServerStatusParams {
+    health: String,
+    quiescent: bool,
+    message: Option<String>,
+}
+
+/// Handle "rust-analyzer/serverStatus", which tells whether the workspace is still loading, or
+/// failed to load. The status is shown in `lsp_server_status` until the server is healthy and idle.
+/// When the health gets worse, the user is alerted, so a broken workspace load, like a failing
+/// build script, does not go unnoticed.
+pub fn server_status(meta: EditorMeta, params: Params, ctx: &mut Context) {
+    let params: ServerStatusParams = match params.parse() {
+        Ok(params) => params,
+        Err(err) => {
+            warn!("Failed to parse rust-analyzer/serverStatus params: {}", err);
+            return;
+        }
+    };
+    if params.health != ctx.rust_analyzer_health {
+        let previous = std::mem::replace(&mut ctx.rust_analyzer_health, params.health.clone());
+        if health_severity(&params.health) > health_severity(&previous) {
+            let message = params.message.as_deref().unwrap_or("").trim();
+            ctx.exec(
+                meta.clone(),
+                format!(
+                    "rust-analyzer-show-server-health {} {}",
+                    editor_quote(&params.health),
+                    editor_quote(message)
+                ),
+            );
+        }
+    }
+    let mut status = vec![];
+    if params.health != "ok" {
+        status.push(params.health);
+    }
+    if !params.quiescent {
+        status.push("loading".to_string());
+    }
+    if status.is_empty() {
+        progress::set_server_status(meta, None, ctx);
+        return;
+    }
+    let mut status = status.join(", ");
+    if let Some(message) = params.message {
+        status.push_str(": ");
+        status.push_str(message.trim());
+    }
+    progress::set_server_status(meta, Some(&status), ctx);
+}
+
+fn health_severity(health: &str) -> u8 {
+    match health {
+        "ok" => 0,
+        "warning" => 1,
+        _ => 2,
+    }
+}
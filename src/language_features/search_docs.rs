@@ -0,0 +1,174 @@
+//! Documentation search: the project-wide symbols matching a query, each with its kind and the
+//! first line of its documentation, taken from the hover info at the symbol. The results are
+//! shown in a goto buffer, so it is easy to jump to a symbol.
+
+use crate::capabilities::{
+    attempt_server_capability, server_has_capability, CAPABILITY_HOVER, CAPABILITY_WORKSPACE_SYMBOL,
+};
+use crate::context::*;
+use crate::language_features::hover::hover_contents_to_markdown;
+use crate::position::*;
+use crate::types::*;
+use crate::util::*;
+use lsp_types::request::*;
+use lsp_types::*;
+use serde::Deserialize;
+
+/// The maximum number of symbols to resolve hover info for, to not flood the server.
+const MAX_SYMBOLS: usize = 20;
+
+#[derive(Deserialize)]
+struct SearchDocsParams {
+    query: String,
+}
+
+pub fn search_docs(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
+    if !attempt_server_capability(ctx, CAPABILITY_WORKSPACE_SYMBOL) {
+        return;
+    }
+    let params = SearchDocsParams::deserialize(params).unwrap();
+    let query = params.query;
+    let req_params = WorkspaceSymbolParams {
+        query: query.clone(),
+        work_done_progress_params: Default::default(),
+        partial_result_params: Default::default(),
+    };
+    ctx.call::<WorkspaceSymbol, _>(meta, req_params, move |ctx: &mut Context, meta, result| {
+        let mut symbols = result.unwrap_or_default();
+        symbols.truncate(MAX_SYMBOLS);
+        if symbols.is_empty() {
+            let msg = format!("no symbols matching {}", query);
+            ctx.exec(meta, format!("lsp-show-error {}", editor_quote(&msg)));
+            return;
+        }
+        if !server_has_capability(ctx, CAPABILITY_HOVER) {
+            show_results(meta, &symbols, vec![], ctx);
+            return;
+        }
+        let ops = symbols
+            .iter()
+            .map(|symbol| HoverParams {
+                text_document_position_params: TextDocumentPositionParams {
+                    text_document: TextDocumentIdentifier {
+                        uri: symbol.location.uri.clone(),
+                    },
+                    position: symbol.location.range.start,
+                },
+                work_done_progress_params: Default::default(),
+            })
+            .collect();
+        // A symbol whose hover fails is still listed, only without its documentation.
+        ctx.batch_call_lenient::<HoverRequest, _>(meta, ops, move |ctx, meta, results| {
+            let hovers = results.into_iter().map(Option::flatten).collect();
+            show_results(meta, &symbols, hovers, ctx);
+        });
+    });
+}
+
+fn show_results(
+    meta: EditorMeta,
+    symbols: &[SymbolInformation],
+    hovers: Vec<Option<Hover>>,
+    ctx: &mut Context,
+) {
+    let content: String = symbols
+        .iter()
+        .enumerate()
+        .map(|(i, symbol)| {
+            let doc = hovers
+                .get(i)
+                .cloned()
+                .flatten()
+                .and_then(|hover| first_doc_line(&hover_contents_to_markdown(hover.contents)));
+            let filename = symbol
+                .location
+                .uri
+                .to_file_path()
+                .ok()
+                .and_then(|path| path.to_str().map(String::from))
+                .unwrap_or_default();
+            let position =
+                get_kakoune_position_with_fallback(&filename, symbol.location.range.start, ctx);
+            let mut line = format!(
+                "{}:{}:{}: {:?} {}",
+                short_file_path(&filename, &ctx.root_path),
+                position.line,
+                position.column,
+                symbol.kind,
+                symbol.name,
+            );
+            if let Some(doc) = doc {
+                line.push_str(" - ");
+                line.push_str(&doc);
+            }
+            line.push('\n');
+            line
+        })
+        .collect();
+    let command = format!(
+        "lsp-show-goto-buffer *search-docs* {} {}",
+        editor_quote(&ctx.root_path),
+        editor_quote(&content),
+    );
+    ctx.exec(meta, command);
+}
+
+/// Returns the first line of prose in hover info, skipping code blocks like the signature, rules
+/// and headings.
+fn first_doc_line(markdown: &str) -> Option<String> {
+    let mut fence: Option<&str> = None;
+    for line in markdown.lines() {
+        let trimmed = line.trim();
+        if let Some(marker) = fence {
+            if trimmed.starts_with(marker) {
+                fence = None;
+            }
+            continue;
+        }
+        if trimmed.starts_with("```") {
+            fence = Some("```");
+            continue;
+        }
+        if trimmed.starts_with("~~~") {
+            fence = Some("~~~");
+            continue;
+        }
+        if trimmed.is_empty()
+            || trimmed.starts_with('#')
+            || trimmed.chars().all(|c| c == '-' || c == '*' || c == '_')
+        {
+            continue;
+        }
+        return Some(trimmed.to_string());
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use indoc::indoc;
+
+    #[test]
+    fn test_first_doc_line() {
+        let markdown = indoc! {"
+            ```rust
+            pub fn main()
+            ```
+
+            ---
+
+            Runs the program.
+            More details.
+        "};
+        assert_eq!(
+            first_doc_line(markdown),
+            Some("Runs the program.".to_string())
+        );
+        assert_eq!(first_doc_line("```rust\nstruct A\n```"), None);
+        assert_eq!(
+            first_doc_line("# Heading\n  text  "),
+            Some("text".to_string())
+        );
+    }
+}
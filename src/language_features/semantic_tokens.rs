@@ -1,21 +1,64 @@
 use crate::capabilities::{attempt_server_capability, CAPABILITY_SEMANTIC_TOKENS};
 use crate::context::Context;
+use crate::large_file::is_large_file;
 use crate::position::lsp_range_to_kakoune;
-use crate::types::EditorMeta;
-use crate::util::editor_quote;
-use lsp_types::request::SemanticTokensFullRequest;
+use crate::types::{EditorMeta, EditorParams};
+use crate::util::{editor_quote, set_list_option_commands, viewport_lines};
+use lsp_types::request::{SemanticTokensFullRequest, SemanticTokensRangeRequest};
 use lsp_types::{
     Position, Range, SemanticToken, SemanticTokenModifier, SemanticTokensOptions,
-    SemanticTokensParams, SemanticTokensRegistrationOptions, SemanticTokensResult,
-    SemanticTokensServerCapabilities::*, TextDocumentIdentifier,
+    SemanticTokensParams, SemanticTokensRangeParams, SemanticTokensRangeResult,
+    SemanticTokensRegistrationOptions, SemanticTokensResult, SemanticTokensServerCapabilities::*,
+    TextDocumentIdentifier,
 };
+use serde::Deserialize;
 use url::Url;
 
-pub fn tokens_request(meta: EditorMeta, ctx: &mut Context) {
+#[derive(Debug, Default, Deserialize)]
+struct SemanticTokensRequestOptions {
+    #[serde(default)]
+    window_range: String,
+    #[serde(default)]
+    viewport_margin: Option<i32>,
+}
+
+pub fn tokens_request(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
+    if meta.fifo.is_none() && is_large_file(ctx, &meta.buffile) {
+        return;
+    }
     if meta.fifo.is_none() && !attempt_server_capability(ctx, CAPABILITY_SEMANTIC_TOKENS) {
         return;
     }
 
+    let params = SemanticTokensRequestOptions::deserialize(params).unwrap_or_default();
+    if let Some((start_line, end_line)) =
+        viewport_lines(&params.window_range, params.viewport_margin)
+    {
+        if server_supports_range(ctx) {
+            let req_params = SemanticTokensRangeParams {
+                work_done_progress_params: Default::default(),
+                partial_result_params: Default::default(),
+                text_document: TextDocumentIdentifier {
+                    uri: Url::from_file_path(&meta.buffile).unwrap(),
+                },
+                range: Range::new(Position::new(start_line, 0), Position::new(end_line, 0)),
+            };
+            ctx.call::<SemanticTokensRangeRequest, _>(
+                meta,
+                req_params,
+                move |ctx, meta, response| {
+                    let tokens = match response {
+                        Some(SemanticTokensRangeResult::Tokens(tokens)) => tokens.data,
+                        Some(SemanticTokensRangeResult::Partial(partial)) => partial.data,
+                        None => return,
+                    };
+                    tokens_response(meta, tokens, ctx);
+                },
+            );
+            return;
+        }
+    }
+
     let req_params = SemanticTokensParams {
         partial_result_params: Default::default(),
         text_document: TextDocumentIdentifier {
@@ -24,13 +67,35 @@ pub fn tokens_request(meta: EditorMeta, ctx: &mut Context) {
         work_done_progress_params: Default::default(),
     };
     ctx.call::<SemanticTokensFullRequest, _>(meta, req_params, move |ctx, meta, response| {
-        if let Some(response) = response {
-            tokens_response(meta, response, ctx);
-        }
+        let tokens = match response {
+            Some(SemanticTokensResult::Tokens(tokens)) => tokens.data,
+            Some(SemanticTokensResult::Partial(partial)) => partial.data,
+            None => return,
+        };
+        tokens_response(meta, tokens, ctx);
     });
 }
 
-pub fn tokens_response(meta: EditorMeta, tokens: SemanticTokensResult, ctx: &mut Context) {
+/// Whether the server can compute semantic tokens for part of a document.
+fn server_supports_range(ctx: &Context) -> bool {
+    matches!(
+        ctx.capabilities.as_ref().unwrap().semantic_tokens_provider,
+        Some(SemanticTokensOptions(SemanticTokensOptions {
+            range: Some(true),
+            ..
+        })) | Some(SemanticTokensRegistrationOptions(
+            SemanticTokensRegistrationOptions {
+                semantic_tokens_options: SemanticTokensOptions {
+                    range: Some(true),
+                    ..
+                },
+                ..
+            }
+        ))
+    )
+}
+
+pub fn tokens_response(meta: EditorMeta, tokens: Vec<SemanticToken>, ctx: &mut Context) {
     let legend = match ctx.capabilities.as_ref().unwrap().semantic_tokens_provider {
         Some(SemanticTokensOptions(SemanticTokensOptions { ref legend, .. }))
         | Some(SemanticTokensRegistrationOptions(SemanticTokensRegistrationOptions {
@@ -43,10 +108,6 @@ pub fn tokens_response(meta: EditorMeta, tokens: SemanticTokensResult, ctx: &mut
         Some(document) => document,
         None => return,
     };
-    let tokens = match tokens {
-        SemanticTokensResult::Tokens(tokens) => tokens.data,
-        SemanticTokensResult::Partial(partial) => partial.data,
-    };
     let mut line = 0;
     let mut start = 0;
     let ranges = tokens
@@ -107,17 +168,22 @@ pub fn tokens_response(meta: EditorMeta, tokens: SemanticTokensResult, ctx: &mut
                 best.map(|token_config| format!("{}|{}", range, token_config.face))
             },
         )
-        .collect::<Vec<String>>()
-        .join(" ");
+        .collect::<Vec<String>>();
 
-    let command = format!(
-        "set-option buffer lsp_semantic_tokens {} {}",
-        meta.version, &ranges
-    );
-    let command = format!(
-        "evaluate-commands -buffer {} -verbatim -- {}",
-        editor_quote(&meta.buffile),
-        command
-    );
-    ctx.exec(meta, command)
+    let commands = set_list_option_commands(
+        "buffer",
+        "lsp_semantic_tokens",
+        &meta.version.to_string(),
+        &ranges,
+    )
+    .into_iter()
+    .map(|command| {
+        format!(
+            "evaluate-commands -buffer {} -verbatim -- {}",
+            editor_quote(&meta.buffile),
+            command
+        )
+    })
+    .collect();
+    ctx.exec_chunked(meta, commands)
 }
@@ -1,11 +1,13 @@
 use crate::capabilities::attempt_server_capability;
 use crate::capabilities::CAPABILITY_SIGNATURE_HELP;
 use crate::context::*;
+use crate::info_box::{self, InfoBoxOwner};
 use crate::position::*;
 use crate::types::*;
 use crate::util::*;
 use lsp_types::request::*;
 use lsp_types::*;
+use ropey::Rope;
 use serde::Deserialize;
 use url::Url;
 
@@ -38,18 +40,125 @@ pub fn editor_signature_help(
     result: Option<SignatureHelp>,
     ctx: &mut Context,
 ) {
-    if let Some(result) = result {
+    let active_signature = result.and_then(|result| {
         let active_signature = result.active_signature.unwrap_or(0);
-        if let Some(active_signature) = result.signatures.get(active_signature as usize) {
-            // TODO decide how to use it
-            // let active_parameter = result.active_parameter.unwrap_or(0);
-            let contents = &active_signature.label;
-            let command = format!(
-                "lsp-show-signature-help {} {}",
-                params.position,
-                editor_quote(contents)
-            );
-            ctx.exec(meta, command);
+        result.signatures.into_iter().nth(active_signature as usize)
+    });
+    let active_signature = match active_signature {
+        Some(active_signature) => active_signature,
+        None => {
+            // The cursor left the call.
+            ctx.exec(meta, "lsp-hide-signature-help".to_string());
+            return;
         }
+    };
+    if !info_box::claim(&meta, InfoBoxOwner::SignatureHelp) {
+        return;
+    }
+    let contents = &active_signature.label;
+    let scope = ctx
+        .documents
+        .get(&meta.buffile)
+        .and_then(|document| argument_list(&document.text, params.position));
+    let command = format!(
+        "lsp-show-signature-help {} {} {}",
+        params.position,
+        editor_quote(contents),
+        scope.map(|scope| scope.to_string()).unwrap_or_default(),
+    );
+    ctx.exec(meta, command);
+}
+
+/// How far to look for the parentheses around the cursor, in characters.
+const MAX_ARGUMENT_LIST_LENGTH: usize = 10_000;
+
+/// Returns the argument list around the cursor, from the opening parenthesis to the closing one,
+/// or to the cursor if the call is not closed yet.
+fn argument_list(text: &Rope, cursor: KakounePosition) -> Option<KakouneRange> {
+    let line = text.get_line(cursor.line.checked_sub(1)? as usize)?;
+    let line_start = text.line_to_char(cursor.line as usize - 1);
+    let cursor_index = line_start
+        + line
+            .try_byte_to_char((cursor.column as usize).checked_sub(1)?)
+            .ok()?;
+    let mut depth = 0;
+    let mut open = None;
+    for (i, c) in text
+        .chars_at(cursor_index)
+        .reversed()
+        .take(MAX_ARGUMENT_LIST_LENGTH)
+        .enumerate()
+    {
+        match c {
+            ')' => depth += 1,
+            '(' if depth == 0 => {
+                open = Some(cursor_index - i - 1);
+                break;
+            }
+            '(' => depth -= 1,
+            _ => (),
+        }
+    }
+    let open = open?;
+    let mut depth = 0;
+    let mut close = None;
+    for (i, c) in text
+        .chars_at(cursor_index)
+        .take(MAX_ARGUMENT_LIST_LENGTH)
+        .enumerate()
+    {
+        match c {
+            '(' => depth += 1,
+            ')' if depth == 0 => {
+                close = Some(cursor_index + i);
+                break;
+            }
+            ')' => depth -= 1,
+            _ => (),
+        }
+    }
+    let end = close.unwrap_or_else(|| cursor_index.saturating_sub(1).max(open));
+    Some(KakouneRange {
+        start: char_index_to_kakoune(text, open),
+        end: char_index_to_kakoune(text, end),
+    })
+}
+
+fn char_index_to_kakoune(text: &Rope, index: usize) -> KakounePosition {
+    let line = text.char_to_line(index);
+    let column = text
+        .line(line)
+        .char_to_byte(index - text.line_to_char(line));
+    KakounePosition {
+        line: line as u32 + 1,
+        column: column as u32 + 1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_argument_list() {
+        let text = Rope::from_str("foo(bar(1), größe, baz\n  (2))\nqux(");
+        let position = |line, column| KakounePosition { line, column };
+        let range = |start, end| KakouneRange { start, end };
+        // After "größe", which takes two more bytes than characters.
+        assert_eq!(
+            argument_list(&text, position(1, 20)),
+            Some(range(position(1, 4), position(2, 6)))
+        );
+        // Inside the nested call.
+        assert_eq!(
+            argument_list(&text, position(1, 9)),
+            Some(range(position(1, 8), position(1, 10)))
+        );
+        // An unclosed call ends at the cursor.
+        assert_eq!(
+            argument_list(&text, position(3, 5)),
+            Some(range(position(3, 4), position(3, 4)))
+        );
+        assert_eq!(argument_list(&text, position(1, 1)), None);
     }
 }
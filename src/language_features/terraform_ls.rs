@@ -0,0 +1,75 @@
+use crate::context::Context;
+use crate::types::{EditorMeta, EditorParams};
+use crate::util::editor_quote;
+use itertools::Itertools;
+use lsp_types::request::ExecuteCommand;
+use lsp_types::{ExecuteCommandParams, Url};
+use serde::Deserialize;
+use serde_json::Value;
+use std::path::Path;
+
+#[derive(Deserialize, Debug)]
+struct ModuleCommandParams {
+    command: String,
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct ModuleCallsResult {
+    #[serde(default)]
+    module_calls: Vec<ModuleCall>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ModuleCall {
+    name: String,
+    source_addr: String,
+    #[serde(default)]
+    version: String,
+}
+
+/// Run one of terraform-ls' module commands (`terraform.init`, `terraform.validate` or
+/// `module.calls`) on the module containing the current buffer.
+pub fn module_command(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
+    let params = ModuleCommandParams::deserialize(params).unwrap();
+    let module_dir = Path::new(&meta.buffile)
+        .parent()
+        .unwrap_or_else(|| Path::new(&ctx.root_path));
+    let module_uri = Url::from_directory_path(module_dir).unwrap();
+    let command = params.command;
+    let req_params = ExecuteCommandParams {
+        command: format!("terraform-ls.{}", command),
+        arguments: vec![Value::String(format!("uri={}", module_uri))],
+        work_done_progress_params: Default::default(),
+    };
+    ctx.call::<ExecuteCommand, _>(meta, req_params, move |ctx, meta, result| {
+        let message = match command.as_str() {
+            "module.calls" => {
+                let result = result
+                    .and_then(|result| ModuleCallsResult::deserialize(result).ok())
+                    .unwrap_or_default();
+                if result.module_calls.is_empty() {
+                    ctx.exec(meta, "echo 'terraform-ls: no module calls'");
+                    return;
+                }
+                let calls = result
+                    .module_calls
+                    .iter()
+                    .map(|call| {
+                        if call.version.is_empty() {
+                            format!("{}: {}", call.name, call.source_addr)
+                        } else {
+                            format!("{}: {} {}", call.name, call.source_addr, call.version)
+                        }
+                    })
+                    .join("\n");
+                ctx.exec(
+                    meta,
+                    format!("info -title 'module calls' {}", editor_quote(&calls)),
+                );
+                return;
+            }
+            _ => format!("terraform-ls: {} finished", command),
+        };
+        ctx.exec(meta, format!("echo {}", editor_quote(&message)));
+    });
+}
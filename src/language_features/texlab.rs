@@ -1,3 +1,4 @@
+use crate::capabilities::attempt_server_extension;
 use crate::context::Context;
 use crate::position::get_lsp_position;
 use crate::types::{EditorMeta, EditorParams};
@@ -39,6 +40,9 @@ impl fmt::Display for ForwardSearchResult {
 }
 
 pub fn forward_search(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
+    if !attempt_server_extension(&meta, ctx, ForwardSearch::METHOD) {
+        return;
+    }
     let params = PositionParams::deserialize(params).unwrap();
     let req_params = TextDocumentPositionParams {
         text_document: TextDocumentIdentifier {
@@ -91,6 +95,9 @@ impl fmt::Display for BuildResult {
 }
 
 pub fn build(meta: EditorMeta, _params: EditorParams, ctx: &mut Context) {
+    if !attempt_server_extension(&meta, ctx, Build::METHOD) {
+        return;
+    }
     let req_params = BuildTextDocumentParams {
         text_document: TextDocumentIdentifier {
             uri: Url::from_file_path(&meta.buffile).unwrap(),
@@ -0,0 +1,24 @@
+use crate::context::*;
+use crate::progress;
+use crate::types::*;
+use jsonrpc_core::Params;
+use serde::Deserialize;
+
+#[derive(Deserialize, Debug)]
+struct TypescriptVersionParams {
+    version: String,
+    source: String,
+}
+
+/// Handle "$/typescriptVersion", which tells which TypeScript installation the server picked.
+pub fn typescript_version(meta: EditorMeta, params: Params, ctx: &mut Context) {
+    let params: TypescriptVersionParams = match params.parse() {
+        Ok(params) => params,
+        Err(err) => {
+            warn!("Failed to parse $/typescriptVersion params: {}", err);
+            return;
+        }
+    };
+    let status = format!("TypeScript {} ({})", params.version, params.source);
+    progress::set_server_status(meta, Some(&status), ctx);
+}
@@ -0,0 +1,80 @@
+use crate::context::Context;
+use crate::types::{EditorMeta, EditorParams};
+use crate::util::editor_quote;
+use crate::workspace;
+use itertools::Itertools;
+use lsp_types::notification::DidChangeConfiguration;
+use lsp_types::{DidChangeConfigurationParams, Url};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+#[derive(Deserialize, Debug)]
+struct SetSchemaParams {
+    schema: String,
+}
+
+/// Associate the current buffer with a JSON schema, replacing any previous association.
+/// An empty schema removes the association.
+pub fn set_schema(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
+    let params = SetSchemaParams::deserialize(params).unwrap();
+    if params.schema.is_empty() {
+        ctx.yaml_schemas.remove(&meta.buffile);
+    } else {
+        ctx.yaml_schemas
+            .insert(meta.buffile.clone(), params.schema.clone());
+    }
+
+    let mut yaml = workspace::settings_section("yaml", ctx);
+    if yaml.is_null() {
+        yaml = json!({});
+    }
+    ctx.notify::<DidChangeConfiguration>(DidChangeConfigurationParams {
+        settings: json!({ "yaml": yaml }),
+    });
+    // The next full settings push must not be skipped as redundant.
+    ctx.settings_hash = None;
+
+    let message = if params.schema.is_empty() {
+        "yaml-language-server: schema association removed".to_string()
+    } else {
+        format!("yaml-language-server: using schema {}", params.schema)
+    };
+    ctx.exec(meta, format!("echo {}", editor_quote(&message)));
+}
+
+/// Merge the schema associations made with `lsp-yaml-set-schema` into the `schemas` setting,
+/// on top of whatever schemas the user configured.
+pub fn add_schemas(yaml: &mut Value, ctx: &Context) {
+    if ctx.yaml_schemas.is_empty() {
+        return;
+    }
+    if !yaml.is_object() {
+        *yaml = json!({});
+    }
+    let schemas = yaml
+        .as_object_mut()
+        .unwrap()
+        .entry("schemas")
+        .or_insert_with(|| json!({}));
+    if !schemas.is_object() {
+        *schemas = json!({});
+    }
+    let schemas = schemas.as_object_mut().unwrap();
+    let by_schema = ctx
+        .yaml_schemas
+        .iter()
+        .map(|(buffile, schema)| (schema, buffile))
+        .into_group_map();
+    for (schema, buffiles) in by_schema {
+        let mut patterns = match schemas.remove(schema) {
+            Some(Value::Array(patterns)) => patterns,
+            Some(pattern @ Value::String(_)) => vec![pattern],
+            _ => vec![],
+        };
+        for buffile in buffiles.into_iter().sorted() {
+            let uri = Url::from_file_path(buffile).unwrap();
+            patterns.push(Value::String(uri.to_string()));
+        }
+        schemas.insert(schema.clone(), Value::Array(patterns));
+    }
+}
@@ -1,10 +1,21 @@
 use crate::thread_worker::Worker;
 use crate::types::*;
 use crossbeam_channel::{Receiver, Sender, TryRecvError};
-use jsonrpc_core::{self, Call, Output};
-use std::collections::HashMap;
-use std::io::{self, BufRead, BufReader, BufWriter, Error, ErrorKind, Read, Write};
+use jsonrpc_core::{self, Call, Failure, Id, MethodCall, Notification, Params, Version};
+use serde::Deserialize;
+use serde_json::value::RawValue;
+use serde_json::Value;
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, BufRead, BufReader, BufWriter, Error, ErrorKind, Write};
 use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+
+/// The most recent lines a language server wrote to stderr, shared with the controller so
+/// they can be shown with `lsp-show-server-output`.
+pub type ServerOutput = Arc<Mutex<VecDeque<String>>>;
+
+/// How many lines of stderr to keep per language server.
+const SERVER_OUTPUT_LINES: usize = 1000;
 
 pub struct LanguageServerTransport {
     // The field order is important as it defines the order of drop.
@@ -12,14 +23,16 @@ pub struct LanguageServerTransport {
     // then close all pipes and wait until child process is finished.
     // That helps to ensure that reader loop is not stuck trying to read from the language server.
     pub to_lang_server: Worker<ServerMessage, Void>,
-    pub from_lang_server: Worker<Void, ServerMessage>,
+    pub from_lang_server: Worker<Void, IncomingMessage>,
     pub errors: Worker<Void, Void>,
+    pub pid: u32,
 }
 
 pub fn start(
     cmd: &str,
     args: &[String],
     envs: &HashMap<String, String>,
+    output: ServerOutput,
 ) -> Result<LanguageServerTransport, String> {
     info!("Starting Language server `{} {}`", cmd, args.join(" "));
     let mut child = match Command::new(cmd)
@@ -39,13 +52,13 @@ pub fn start(
         }
     };
 
+    let pid = child.id();
     let writer = BufWriter::new(child.stdin.take().expect("Failed to open stdin"));
     let reader = BufReader::new(child.stdout.take().expect("Failed to open stdout"));
 
     // NOTE 1024 is arbitrary
     let channel_capacity = 1024;
 
-    // XXX temporary way of tracing language server errors
     let mut stderr = BufReader::new(child.stderr.take().expect("Failed to open stderr"));
     let errors = Worker::spawn(
         "Language server errors",
@@ -54,13 +67,18 @@ pub fn start(
             if let Err(TryRecvError::Disconnected) = receiver.try_recv() {
                 return;
             }
-            let mut buf = String::new();
-            match stderr.read_to_string(&mut buf) {
+            let mut buf = vec![];
+            match stderr.read_until(b'\n', &mut buf) {
+                Ok(0) => return,
                 Ok(_) => {
-                    if buf.is_empty() {
-                        return;
+                    let line = String::from_utf8_lossy(&buf);
+                    let line = line.trim_end_matches(&['\r', '\n'][..]);
+                    error!("Language server error: {}", line);
+                    let mut output = output.lock().unwrap();
+                    if output.len() == SERVER_OUTPUT_LINES {
+                        output.pop_front();
                     }
-                    error!("Language server error: {}", buf);
+                    output.push_back(line.to_string());
                 }
                 Err(e) => {
                     error!("Failed to read from language server stderr: {}", e);
@@ -69,7 +87,6 @@ pub fn start(
             }
         },
     );
-    // XXX
 
     let from_lang_server = Worker::spawn(
         "Messages from language server",
@@ -114,13 +131,14 @@ pub fn start(
         to_lang_server,
         from_lang_server,
         errors,
+        pid,
     })
 }
 
 fn reader_loop(
     mut reader: impl BufRead,
     receiver: Receiver<Void>,
-    sender: &Sender<ServerMessage>,
+    sender: &Sender<IncomingMessage>,
 ) -> io::Result<()> {
     let mut headers: HashMap<String, String> = HashMap::default();
     loop {
@@ -154,25 +172,79 @@ fn reader_loop(
         let msg = String::from_utf8(content)
             .map_err(|_| Error::new(ErrorKind::Other, "Failed to read content as UTF-8 string"))?;
         debug!("From server: {}", msg);
-        let output: serde_json::Result<Output> = serde_json::from_str(&msg);
-        match output {
-            Ok(output) => {
-                if sender.send(ServerMessage::Response(output)).is_err() {
-                    return Err(Error::new(ErrorKind::Other, "Failed to send response"));
-                }
-            }
-            Err(_) => {
-                let msg: Call = serde_json::from_str(&msg).map_err(|_| {
-                    Error::new(ErrorKind::Other, "Failed to parse language server message")
-                })?;
-                if sender.send(ServerMessage::Request(msg)).is_err() {
-                    return Err(Error::new(ErrorKind::Other, "Failed to send response"));
-                }
+        let msg = match parse_message(&msg) {
+            Some(msg) => msg,
+            None => {
+                error!("Ignoring malformed message from language server: {}", msg);
+                continue;
             }
+        };
+        if sender.send(msg).is_err() {
+            return Err(Error::new(ErrorKind::Other, "Failed to send response"));
         }
     }
 }
 
+/// The fields of any JSON-RPC message, so we can tell requests from responses in a single pass.
+#[derive(Deserialize)]
+struct RawMessage {
+    #[serde(default)]
+    id: Option<Id>,
+    method: Option<String>,
+    #[serde(default)]
+    params: Option<Value>,
+    result: Option<Box<RawValue>>,
+    error: Option<jsonrpc_core::Error>,
+}
+
+fn parse_message(msg: &str) -> Option<IncomingMessage> {
+    let msg: RawMessage = serde_json::from_str(msg).ok()?;
+    if let Some(method) = msg.method {
+        let params = match msg.params {
+            None | Some(Value::Null) => Params::None,
+            Some(Value::Array(params)) => Params::Array(params),
+            Some(Value::Object(params)) => Params::Map(params),
+            // Requests with malformed params still get an error response.
+            Some(_) => {
+                return msg
+                    .id
+                    .map(|id| IncomingMessage::Request(Call::Invalid { id }))
+            }
+        };
+        let call = match msg.id {
+            Some(id) => Call::MethodCall(MethodCall {
+                jsonrpc: Some(Version::V2),
+                method,
+                params,
+                id,
+            }),
+            None => Call::Notification(Notification {
+                jsonrpc: Some(Version::V2),
+                method,
+                params,
+            }),
+        };
+        return Some(IncomingMessage::Request(call));
+    }
+    let id = msg.id.unwrap_or(Id::Null);
+    let output = match msg.error {
+        Some(error) => RawOutput::Failure(Failure {
+            jsonrpc: Some(Version::V2),
+            error,
+            id,
+        }),
+        None => RawOutput::Success(RawSuccess {
+            jsonrpc: Some(Version::V2),
+            // A null result is parsed as None.
+            result: msg
+                .result
+                .unwrap_or_else(|| RawValue::from_string("null".to_string()).unwrap()),
+            id,
+        }),
+    };
+    Some(IncomingMessage::Response(output))
+}
+
 fn writer_loop(mut writer: impl Write, receiver: &Receiver<ServerMessage>) -> io::Result<()> {
     for request in receiver {
         let request = match request {
@@ -193,3 +265,61 @@ fn writer_loop(mut writer: impl Write, receiver: &Receiver<ServerMessage>) -> io
     debug!("Received signal to stop language server, closing pipe");
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_message() {
+        let msg = r#"{"jsonrpc":"2.0","method":"textDocument/publishDiagnostics","params":{"uri":"file:///a","diagnostics":[]}}"#;
+        match parse_message(msg) {
+            Some(IncomingMessage::Request(Call::Notification(notification))) => {
+                assert_eq!(notification.method, "textDocument/publishDiagnostics");
+                assert!(matches!(notification.params, Params::Map(_)));
+            }
+            msg => panic!("unexpected message: {:?}", msg),
+        }
+        let msg =
+            r#"{"jsonrpc":"2.0","id":3,"method":"workspace/configuration","params":{"items":[]}}"#;
+        assert!(matches!(
+            parse_message(msg),
+            Some(IncomingMessage::Request(Call::MethodCall(MethodCall {
+                id: Id::Num(3),
+                ..
+            })))
+        ));
+        let msg = r#"{"jsonrpc":"2.0","id":7,"method":"custom/request","params":42}"#;
+        assert!(matches!(
+            parse_message(msg),
+            Some(IncomingMessage::Request(Call::Invalid { id: Id::Num(7) }))
+        ));
+        assert!(
+            parse_message(r#"{"jsonrpc":"2.0","method":"custom/notification","params":42}"#)
+                .is_none()
+        );
+        let msg = r#"{"jsonrpc":"2.0","id":4,"result":{"data":[0, 1, 2]}}"#;
+        match parse_message(msg) {
+            Some(IncomingMessage::Response(RawOutput::Success(success))) => {
+                assert_eq!(success.id, Id::Num(4));
+                assert_eq!(success.result.get(), r#"{"data":[0, 1, 2]}"#);
+            }
+            msg => panic!("unexpected message: {:?}", msg),
+        }
+        let msg = r#"{"jsonrpc":"2.0","id":5,"result":null}"#;
+        match parse_message(msg) {
+            Some(IncomingMessage::Response(RawOutput::Success(success))) => {
+                assert_eq!(success.result.get(), "null");
+            }
+            msg => panic!("unexpected message: {:?}", msg),
+        }
+        let msg = r#"{"jsonrpc":"2.0","id":6,"error":{"code":-32601,"message":"nope"}}"#;
+        assert!(matches!(
+            parse_message(msg),
+            Some(IncomingMessage::Response(RawOutput::Failure(Failure {
+                id: Id::Num(6),
+                ..
+            })))
+        ));
+    }
+}
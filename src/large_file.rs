@@ -0,0 +1,72 @@
+//! Large file mode. Buffers with more lines or bytes than configured in `[large_file]` get fewer
+//! features, so opening a huge generated file doesn't freeze Kakoune: semantic tokens, inlay
+//! hints, inline values and code lenses are not requested, and diagnostics are shown less often.
+//! The editor does not request completions, signature help or hover info while typing either, see
+//! `lsp-unless-large-file`, so it only sends the buffer when idle in normal mode or when a command
+//! is run. Changes are still sent in full, there is no further debouncing.
+//! `lsp-large-file-mode-disable` restores all features for a buffer.
+
+use crate::context::Context;
+use crate::types::EditorMeta;
+use crate::util::editor_quote;
+use std::time::Duration;
+
+/// The minimum interval between diagnostics updates of a large file.
+pub const DIAGNOSTICS_UPDATE_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Returns whether the buffer is in large file mode.
+pub fn is_large_file(ctx: &Context, buffile: &str) -> bool {
+    ctx.large_files.contains(buffile)
+}
+
+/// Enters or leaves large file mode after the buffer's contents changed.
+pub fn update_large_file_mode(meta: &EditorMeta, ctx: &mut Context) {
+    let buffile = &meta.buffile;
+    let (lines, bytes) = match ctx.documents.get(buffile) {
+        Some(document) => (document.text.len_lines(), document.text.len_bytes()),
+        None => return,
+    };
+    let config = &ctx.config.large_file;
+    let large = !ctx.large_file_overrides.contains(buffile)
+        && ((config.lines != 0 && lines > config.lines)
+            || (config.bytes != 0 && bytes > config.bytes));
+    if large == is_large_file(ctx, buffile) {
+        return;
+    }
+    let command = if large {
+        ctx.large_files.insert(buffile.clone());
+        let message = format!(
+            "kak-lsp: {} has {} lines, disabled semantic tokens, inlay hints, inline values, code lenses, and completion, signature help and hover while typing. Run lsp-large-file-mode-disable to enable them",
+            buffile, lines
+        );
+        format!(
+            "set-option buffer lsp_large_file true
+             set-option buffer lsp_semantic_tokens %val{{timestamp}}
+             set-option buffer lsp_inlay_hints %val{{timestamp}}
+             set-option buffer lsp_inline_values %val{{timestamp}}
+             echo -markup {}",
+            editor_quote(&format!("{{Information}}{}", message.replace('{', "\\{")))
+        )
+    } else {
+        ctx.large_files.remove(buffile);
+        "set-option buffer lsp_large_file false".to_string()
+    };
+    let command = format!(
+        "evaluate-commands -buffer {} -- {}",
+        editor_quote(buffile),
+        editor_quote(&command)
+    );
+    ctx.exec(meta.clone(), command);
+}
+
+/// Leaves large file mode for the current buffer, for the rest of the session.
+pub fn large_file_mode_disable(meta: EditorMeta, ctx: &mut Context) {
+    ctx.large_file_overrides.insert(meta.buffile.clone());
+    update_large_file_mode(&meta, ctx);
+}
+
+/// Forgets about a closed buffer.
+pub fn forget_large_file(buffile: &str, ctx: &mut Context) {
+    ctx.large_files.remove(buffile);
+    ctx.large_file_overrides.remove(buffile);
+}
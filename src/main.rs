@@ -8,19 +8,36 @@ extern crate slog;
 #[macro_use]
 extern crate slog_scope;
 
+mod auto_detect;
 mod capabilities;
+mod command_output;
 mod context;
 mod controller;
+mod custom_requests;
+mod diagnostic_commands;
 mod diagnostics;
+mod doctor;
+mod edit_conflict;
+mod edit_preview;
 mod editor_transport;
+mod info_box;
 mod language_features;
 mod language_server_transport;
+mod large_file;
 mod markup;
+mod middleware;
+mod modified_lines;
 mod position;
+mod presentation;
 mod progress;
 mod project_root;
+mod query;
+mod resource_usage;
+mod server_cache;
 mod session;
+mod session_state;
 mod settings;
+mod snippet;
 mod text_edit;
 mod text_sync;
 mod thread_worker;
@@ -45,7 +62,11 @@ use std::os::unix::net::UnixStream;
 use std::panic;
 use std::path::Path;
 use std::path::PathBuf;
-use std::process::{Command, Stdio};
+use std::process::{self, Command, Stdio};
+use std::time::Duration;
+
+/// How long to wait for a server that is starting up for the same session.
+const ADOPT_TIMEOUT: Duration = Duration::from_secs(5);
 
 fn main() {
     let matches = App::new("kak-lsp")
@@ -57,11 +78,53 @@ fn main() {
                 .long("kakoune")
                 .help("Generate commands for Kakoune to plug in kak-lsp"),
         )
+        .arg(
+            Arg::with_name("doctor")
+                .long("doctor")
+                .help("Check the configuration and the configured language servers"),
+        )
         .arg(
             Arg::with_name("request")
                 .long("request")
                 .help("Forward stdin to kak-lsp server"),
         )
+        .arg(
+            Arg::with_name("query")
+                .long("query")
+                .value_name("QUERY")
+                .help("Print the language server's response to a query as JSON and exit")
+                .possible_values(&["definition", "hover", "references"])
+                .takes_value(true)
+                .requires_all(&["session", "file", "line", "column", "filetype"]),
+        )
+        .arg(
+            Arg::with_name("file")
+                .long("file")
+                .value_name("FILE")
+                .help("File to query (with --query)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("line")
+                .long("line")
+                .value_name("LINE")
+                .help("1-based line to query (with --query)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("column")
+                .long("column")
+                .value_name("COLUMN")
+                .help("1-based byte column to query (with --query)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("filetype")
+                .long("filetype")
+                .value_name("FILETYPE")
+                .help("Kakoune filetype of the file, like rust (with --query)")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("config")
                 .short("c")
@@ -83,7 +146,7 @@ fn main() {
                 .value_name("SESSION")
                 .help("Session id to communicate via unix socket")
                 .takes_value(true)
-                .required(true),
+                .required_unless("doctor"),
         )
         .arg(
             Arg::with_name("timeout")
@@ -98,6 +161,11 @@ fn main() {
                 .long("initial-request")
                 .help("Read initial request from stdin"),
         )
+        .arg(
+            Arg::with_name("force-new")
+                .long("force-new")
+                .help("Start a new server even if one is already running for the session"),
+        )
         .arg(
             Arg::with_name("v")
                 .short("v")
@@ -144,14 +212,18 @@ fn main() {
         })
         .or_else(|| try_config_dir(dirs::config_dir()));
 
-    if let Some(config_path) = config_path {
+    if let Some(config_path) = &config_path {
         config = fs::read_to_string(config_path).expect("Failed to read config");
     }
 
-    let session = String::from(matches.value_of("session").unwrap());
+    let session = String::from(matches.value_of("session").unwrap_or_default());
 
     let mut config: Config = match toml::from_str(&config) {
         Ok(cfg) => cfg,
+        Err(err) if matches.is_present("doctor") => {
+            println!("error: invalid configuration: {}", err);
+            process::exit(1);
+        }
         Err(err) => {
             consume_stdin_and_report_config_error(&matches, &session, &err);
             panic!("invalid configuration: {}", err)
@@ -159,13 +231,23 @@ fn main() {
     };
 
     config.server.session = session;
+    position::set_validate_positions(config.validate_positions);
+
+    if matches.is_present("doctor") {
+        let _guard = (matches.occurrences_of("v") > 0 || matches.is_present("log"))
+            .then(|| setup_logger(&config, &matches));
+        process::exit(doctor::run(&config, config_path.as_deref()));
+    }
 
     if let Some(timeout) = matches.value_of("timeout") {
         config.server.timeout = timeout.parse().unwrap();
     }
+    config.server.force_new = matches.is_present("force-new");
 
     if matches.is_present("request") {
         request(&config);
+    } else if matches.is_present("query") {
+        process::exit(query(&config, &matches));
     } else {
         // It's important to read input before daemonizing even if we don't use it.
         // Otherwise it will be empty.
@@ -174,20 +256,42 @@ fn main() {
             stdin()
                 .read_to_end(&mut input)
                 .expect("Failed to read stdin");
-            Some(String::from_utf8_lossy(&input).to_string())
+            Some(input)
         } else {
             None
         };
+        // Another kak-lsp may have been started for this session in the meantime, for example by
+        // a second request that raced with the one that spawned us. Let it handle our request.
+        let session = &config.server.session;
+        if !config.server.force_new
+            && editor_transport::adopt_running_server(
+                session,
+                initial_request.as_deref(),
+                Duration::from_secs(0),
+            )
+        {
+            process::exit(0);
+        }
         let mut pid_path = util::temp_dir();
-        pid_path.push(format!("{}.pid", config.server.session));
+        pid_path.push(format!("{}.pid", session));
         if matches.is_present("daemonize") {
-            if let Err(e) = Daemonize::new()
-                .pid_file(&pid_path)
-                .working_directory(std::env::current_dir().unwrap())
-                .start()
-            {
+            let mut daemonize =
+                Daemonize::new().working_directory(std::env::current_dir().unwrap());
+            // The old server holds the lock on the pid file until it exits.
+            if !config.server.force_new {
+                daemonize = daemonize.pid_file(&pid_path);
+            }
+            if let Err(e) = daemonize.start() {
+                // Most likely, another server is starting up and holds the pid file.
+                if editor_transport::adopt_running_server(
+                    session,
+                    initial_request.as_deref(),
+                    ADOPT_TIMEOUT,
+                ) {
+                    process::exit(0);
+                }
                 println!("Failed to daemonize process: {:?}", e);
-                goodbye(&config.server.session, 1);
+                goodbye(session, 1);
             }
         }
         // Setting up the logger after potential daemonization,
@@ -219,21 +323,106 @@ fn request(config: &Config) {
     stdin()
         .read_to_end(&mut input)
         .expect("Failed to read stdin");
+    let args = env::args()
+        .filter(|arg| arg != "--request")
+        .collect::<Vec<_>>();
+    send_request(config, &input, &args);
+}
+
+/// Sends a request to the server of the session, starting one with `args` if there is none.
+fn send_request(config: &Config, input: &[u8], args: &[String]) {
     let mut path = util::temp_dir();
+    if let Err(err) = util::check_private_dir(&path) {
+        eprintln!("kak-lsp: refusing to connect to session socket: {}", err);
+        process::exit(1);
+    }
     path.push(&config.server.session);
     if let Ok(mut stream) = UnixStream::connect(&path) {
+        // If the request can't be parsed, send it as is, so the server logs the error.
+        let request = editor_transport::encode_request(input).unwrap_or_else(|_| input.to_vec());
         stream
-            .write_all(&input)
+            .write_all(&request)
             .expect("Failed to send stdin to server");
     } else {
-        spin_up_server(&input);
+        spin_up_server(input, args);
     }
 }
 
-fn spin_up_server(input: &[u8]) {
-    let args = env::args()
-        .filter(|arg| arg != "--request")
-        .collect::<Vec<_>>();
+/// How long `--query` waits for the language server, which may need to start first.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(60);
+
+fn query(config: &Config, matches: &ArgMatches) -> i32 {
+    let number = |name: &str| -> Option<u32> { matches.value_of(name)?.parse().ok() };
+    let (line, column) = match (number("line"), number("column")) {
+        (Some(line), Some(column)) if line > 0 && column > 0 => (line, column),
+        _ => {
+            eprintln!("kak-lsp: --line and --column must be positive numbers");
+            return 1;
+        }
+    };
+    let query = query::Query {
+        session: &config.server.session,
+        query: matches.value_of("query").unwrap(),
+        file: Path::new(matches.value_of("file").unwrap()),
+        filetype: matches.value_of("filetype").unwrap(),
+        line,
+        column,
+    };
+    let fifo = match temp_fifo() {
+        Some(fifo) => fifo,
+        None => {
+            eprintln!("kak-lsp: failed to create fifo for the response");
+            return 1;
+        }
+    };
+    let request = match query::editor_request(&query, &fifo.path) {
+        Ok(request) => request,
+        Err(err) => {
+            eprintln!("kak-lsp: {}", err);
+            return 1;
+        }
+    };
+    // A server started for the query keeps running, so later queries are fast.
+    let mut args = vec![env::args().next().unwrap()];
+    for name in ["config", "session", "timeout", "log"] {
+        if let Some(value) = matches.value_of(name) {
+            args.push(format!("--{}", name));
+            args.push(value.to_string());
+        }
+    }
+    args.extend((0..matches.occurrences_of("v")).map(|_| "-v".to_string()));
+    send_request(config, request.as_bytes(), &args);
+
+    let (sender, receiver) = crossbeam_channel::bounded(1);
+    let path = fifo.path.clone();
+    // Reading blocks until the server writes the response.
+    std::thread::spawn(move || {
+        let _ = sender.send(fs::read_to_string(path));
+    });
+    let response = match receiver.recv_timeout(QUERY_TIMEOUT) {
+        Ok(Ok(response)) => response,
+        Ok(Err(err)) => {
+            eprintln!("kak-lsp: failed to read the response: {}", err);
+            return 1;
+        }
+        Err(_) => {
+            eprintln!("kak-lsp: no response after {:?}", QUERY_TIMEOUT);
+            return 1;
+        }
+    };
+    match query::response_result(&response) {
+        Ok(result) => {
+            println!("{}", serde_json::to_string_pretty(&result).unwrap());
+            0
+        }
+        Err(err) => {
+            eprintln!("kak-lsp: {}", err);
+            1
+        }
+    }
+}
+
+fn spin_up_server(input: &[u8], args: &[String]) {
     let mut cmd = Command::new(&args[0]);
     let mut child = cmd
         .args(&args[1..])
@@ -301,7 +490,7 @@ fn consume_stdin_and_report_config_error(
     let request: EditorRequest = toml::from_str(&data).expect("Failed to parse request");
     assert!(request.meta.session == session);
 
-    let editor = match editor_transport::start(session, None) {
+    let editor = match editor_transport::start(session, None, false) {
         Ok(ed) => ed,
         Err(_code) => return,
     };
@@ -1,6 +1,8 @@
+use crate::types::MarkdownOptions;
 use lsp_types::*;
-use pulldown_cmark::{Event, Parser, Tag};
+use pulldown_cmark::{Event, LinkType, Parser, Tag};
 use std::fmt::Write as _;
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
 
 pub const FACE_INFO_DEFAULT: &str = "InfoDefault";
 
@@ -18,6 +20,8 @@ pub const FACE_INFO_DIAGNOSTIC_HINT: &str = "InfoDiagnosticHint";
 pub const FACE_INFO_DIAGNOSTIC_INFO: &str = "InfoDiagnosticInformation";
 pub const FACE_INFO_DIAGNOSTIC_WARNING: &str = "InfoDiagnosticWarning";
 
+pub const FACE_INFO_CODE_LENS: &str = "InfoCodeLens";
+
 /// Espace backslashes and opening braces for Kakoune markup strings
 pub fn escape_kakoune_markup(s: &str) -> String {
     s.replace('\\', r"\\").replace('{', r"\{")
@@ -25,6 +29,14 @@ pub fn escape_kakoune_markup(s: &str) -> String {
 
 /// Transpile Markdown into Kakoune's markup syntax using faces for highlighting
 pub fn markdown_to_kakoune_markup<S: AsRef<str>>(markdown: S) -> String {
+    markdown_to_kakoune_markup_with_options(markdown, &MarkdownOptions::default())
+}
+
+/// Transpile Markdown into Kakoune's markup syntax, cleaning it up as configured for the server
+pub fn markdown_to_kakoune_markup_with_options<S: AsRef<str>>(
+    markdown: S,
+    options: &MarkdownOptions,
+) -> String {
     let markdown = markdown.as_ref();
     let parser = Parser::new(markdown);
     let mut markup = String::with_capacity(markdown.len());
@@ -44,6 +56,13 @@ pub fn markdown_to_kakoune_markup<S: AsRef<str>>(markdown: S) -> String {
     // when the inner tag ends. Markdown example: ``[`code` link](...)``
     // The stack allows to track whatever face a closing tag needs to emit.
     let mut face_stack: Vec<String> = vec![];
+    // Link targets shown as footnotes, and the target of the link being rendered, if it gets one.
+    let mut footnotes: Vec<String> = vec![];
+    let mut footnote_target: Option<String> = None;
+    // State to indicate an HTML comment that spans several HTML events
+    let mut is_html_comment = false;
+    // Where the current paragraph starts, to drop it if stripping HTML leaves it empty
+    let mut paragraph_start = 0;
 
     /// Get the current base face, either the top face on the stack
     /// or a fallback
@@ -65,6 +84,7 @@ pub fn markdown_to_kakoune_markup<S: AsRef<str>>(markdown: S) -> String {
         match e {
             Event::Start(tag) => match tag {
                 Tag::Paragraph => {
+                    paragraph_start = markup.len();
                     // Block quotes with empty lines are parsed into paragraphes.
                     // However, even for the first of such paragraphs, `Tag::Blockquote`
                     // is emitted first.
@@ -137,7 +157,16 @@ pub fn markdown_to_kakoune_markup<S: AsRef<str>>(markdown: S) -> String {
                 // nicely.
                 // We'll only show the link title for now, which should be enough to search in the
                 // relevant resource.
-                Tag::Link(_, _, _) => {
+                Tag::Link(link_type, url, _) => {
+                    // Links to anchors in the same document lead nowhere, and autolinks show
+                    // their target already.
+                    if options.link_footnotes
+                        && !url.is_empty()
+                        && !url.starts_with('#')
+                        && !matches!(link_type, LinkType::Autolink | LinkType::Email)
+                    {
+                        footnote_target = Some(url.to_string());
+                    }
                     face_stack.push(FACE_INFO_LINK.into());
                     let _ = write!(markup, "{{{}}}", FACE_INFO_LINK);
                 }
@@ -145,7 +174,13 @@ pub fn markdown_to_kakoune_markup<S: AsRef<str>>(markdown: S) -> String {
                 tag => warn!("Unsupported Markdown tag: {:?}", tag),
             },
             Event::End(t) => match t {
-                Tag::Paragraph => markup.push('\n'),
+                Tag::Paragraph => {
+                    if options.strip_html && markup[paragraph_start..].trim().is_empty() {
+                        markup.truncate(paragraph_start);
+                    } else {
+                        markup.push('\n')
+                    }
+                }
                 Tag::Heading(_) => {
                     let base_face = pop_base_face(&mut face_stack);
                     let _ = writeln!(markup, "{{{}}}", base_face);
@@ -171,10 +206,24 @@ pub fn markdown_to_kakoune_markup<S: AsRef<str>>(markdown: S) -> String {
                     }
                 }
                 Tag::Item => (),
-                Tag::Emphasis | Tag::Strong | Tag::Strikethrough | Tag::Link(_, _, _) => {
+                Tag::Emphasis | Tag::Strong | Tag::Strikethrough => {
                     let base_face = pop_base_face(&mut face_stack);
                     let _ = write!(markup, "{{{}}}", base_face);
                 }
+                Tag::Link(_, _, _) => {
+                    let base_face = pop_base_face(&mut face_stack);
+                    let _ = write!(markup, "{{{}}}", base_face);
+                    if let Some(target) = footnote_target.take() {
+                        let number = match footnotes.iter().position(|t| *t == target) {
+                            Some(i) => i + 1,
+                            None => {
+                                footnotes.push(target);
+                                footnotes.len()
+                            }
+                        };
+                        let _ = write!(markup, "[{}]", number);
+                    }
+                }
                 Tag::Image(_, _, _) => (),
                 tag => warn!("Unsupported Markdown tag: {:?}", tag),
             },
@@ -201,7 +250,14 @@ pub fn markdown_to_kakoune_markup<S: AsRef<str>>(markdown: S) -> String {
                     base_face
                 );
             }
-            Event::Html(html) => markup.push_str(&escape_kakoune_markup(&html)),
+            Event::Html(html) => {
+                if options.strip_html {
+                    let text = strip_html_tags(&html, &mut is_html_comment);
+                    markup.push_str(&escape_kakoune_markup(&text))
+                } else {
+                    markup.push_str(&escape_kakoune_markup(&html))
+                }
+            }
             Event::FootnoteReference(_) => warn!("Unsupported Markdown event: {:?}", e),
             // Soft breaks should be kept in `<pre>`-style blocks.
             // Anywhere else, let the renderer handle line breaks.
@@ -225,17 +281,105 @@ pub fn markdown_to_kakoune_markup<S: AsRef<str>>(markdown: S) -> String {
 
     // Trim trailing whitespace. In some cases a face has been added after the trailing whitespace,
     // so we need to strip that first.
-    markup
+    let mut markup = markup
         .strip_suffix(&format!("{{{}}}", FACE_INFO_DEFAULT))
         .unwrap_or(&markup)
         .trim()
-        .to_string()
+        .to_string();
+    if !footnotes.is_empty() {
+        markup.push('\n');
+    }
+    for (i, target) in footnotes.iter().enumerate() {
+        let _ = write!(
+            markup,
+            "\n[{}] {{{}}}{}{{{}}}",
+            i + 1,
+            FACE_INFO_LINK,
+            escape_kakoune_markup(target),
+            FACE_INFO_DEFAULT
+        );
+    }
+    markup
+}
+
+/// The HTML tags that servers put in documentation. Anything else in angle brackets, like the
+/// generics in `Vec<String>`, is text.
+const HTML_TAGS: &[&str] = &["a", "br", "details", "div", "img", "p", "span", "summary"];
+
+/// Removes the tags and comments of an HTML fragment, keeping its text. Line break tags become
+/// newlines, and lines without text are dropped. `is_comment` tracks comments that span several
+/// fragments.
+fn strip_html_tags(html: &str, is_comment: &mut bool) -> String {
+    let mut text = String::new();
+    for line in html.split_inclusive('\n') {
+        let mut line_text = String::new();
+        let mut has_line_break = false;
+        let mut rest = line;
+        loop {
+            if *is_comment {
+                match rest.find("-->") {
+                    Some(end) => {
+                        *is_comment = false;
+                        rest = &rest[end + "-->".len()..];
+                    }
+                    None => break,
+                }
+            }
+            let start = match rest.find('<') {
+                Some(start) => start,
+                None => {
+                    line_text.push_str(rest);
+                    break;
+                }
+            };
+            line_text.push_str(&rest[..start]);
+            rest = &rest[start..];
+            if let Some(comment) = rest.strip_prefix("<!--") {
+                *is_comment = true;
+                rest = comment;
+                continue;
+            }
+            let tag = rest.find('>').and_then(|end| {
+                let tag = rest[1..end].trim_start_matches('/').trim_end_matches('/');
+                let name = tag.split_whitespace().next().unwrap_or_default();
+                let name = HTML_TAGS
+                    .iter()
+                    .find(|known| name.eq_ignore_ascii_case(known))?;
+                Some((end, *name))
+            });
+            let (end, name) = match tag {
+                Some(tag) => tag,
+                None => {
+                    line_text.push('<');
+                    rest = &rest[1..];
+                    continue;
+                }
+            };
+            if name == "br" {
+                line_text.push('\n');
+                has_line_break = true;
+            }
+            rest = &rest[end + 1..];
+        }
+        if !line_text.trim().is_empty() || has_line_break {
+            text.push_str(&line_text);
+        }
+    }
+    text.replace("&nbsp;", " ")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&")
 }
 
 /// Transpile the contents of an `lsp_types::MarkedString` into Kakoune markup
-pub fn marked_string_to_kakoune_markup(contents: MarkedString) -> String {
+pub fn marked_string_to_kakoune_markup(
+    contents: MarkedString,
+    options: &MarkdownOptions,
+) -> String {
     match contents {
-        MarkedString::String(s) => markdown_to_kakoune_markup(s),
+        MarkedString::String(s) => markdown_to_kakoune_markup_with_options(s, options),
         MarkedString::LanguageString(s) => {
             format!(
                 "{{{}}}{}{{{}}}",
@@ -246,3 +390,125 @@ pub fn marked_string_to_kakoune_markup(contents: MarkedString) -> String {
         }
     }
 }
+
+/// Wrap lines of plain text so that they fit into the given number of columns, measured in
+/// display width. Lines are broken at whitespace and around double-width characters, since CJK
+/// text doesn't separate words with spaces. Other words that are longer than a line are not
+/// broken. A width of 0 means no wrapping.
+pub fn wrap_text(text: &str, width: usize) -> String {
+    if width == 0 {
+        return text.to_string();
+    }
+    let mut wrapped = String::with_capacity(text.len());
+    for (i, line) in text.split('\n').enumerate() {
+        if i > 0 {
+            wrapped.push('\n');
+        }
+        if line.width() <= width {
+            wrapped.push_str(line);
+            continue;
+        }
+        // Keep the indentation of wrapped lines, so list items stay aligned.
+        let indent = &line[..line.len() - line.trim_start().len()];
+        wrapped.push_str(indent);
+        let mut column = indent.width();
+        let mut line_is_empty = true;
+        for word in line.split_whitespace() {
+            for (j, segment) in unbreakable_segments(word).into_iter().enumerate() {
+                let separator = if j == 0 && !line_is_empty { 1 } else { 0 };
+                let segment_width = segment.width();
+                if !line_is_empty && column + separator + segment_width > width {
+                    wrapped.push('\n');
+                    wrapped.push_str(indent);
+                    column = indent.width();
+                } else if separator != 0 {
+                    wrapped.push(' ');
+                    column += 1;
+                }
+                wrapped.push_str(segment);
+                column += segment_width;
+                line_is_empty = false;
+            }
+        }
+    }
+    wrapped
+}
+
+/// Split a word before and after each double-width character.
+fn unbreakable_segments(word: &str) -> Vec<&str> {
+    let mut segments = vec![];
+    let mut start = 0;
+    let mut previous_is_wide = false;
+    for (i, c) in word.char_indices() {
+        let is_wide = c.width() == Some(2);
+        if i > start && (is_wide || previous_is_wide) {
+            segments.push(&word[start..i]);
+            start = i;
+        }
+        previous_is_wide = is_wide;
+    }
+    segments.push(&word[start..]);
+    segments
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wrap_text() {
+        assert_eq!(wrap_text("short line", 0), "short line");
+        assert_eq!(wrap_text("short line", 20), "short line");
+        assert_eq!(
+            wrap_text("Type 'string' is not assignable to type 'number'.", 20),
+            "Type 'string' is not\nassignable to type\n'number'."
+        );
+        assert_eq!(
+            wrap_text("  indented text is wrapped", 12),
+            "  indented\n  text is\n  wrapped"
+        );
+        assert_eq!(
+            wrap_text("unbreakable_identifier", 10),
+            "unbreakable_identifier"
+        );
+    }
+
+    #[test]
+    fn test_wrap_text_double_width() {
+        // Each of these characters takes up two columns.
+        let wrapped = wrap_text("型が一致しません", 10);
+        assert_eq!(wrapped, "型が一致し\nません");
+        assert!(wrapped.lines().all(|line| line.width() <= 10));
+        assert_eq!(
+            wrap_text("値 foo は未定義です", 8),
+            "値 foo\nは未定義\nです"
+        );
+    }
+
+    #[test]
+    fn test_markdown_to_kakoune_markup_double_width() {
+        assert_eq!(
+            markdown_to_kakoune_markup("関数の**説明**\n\n- 項目"),
+            "関数の{+b@InfoDefault}説明{InfoDefault}\n\n  {InfoBullet}- {InfoDefault}項目"
+        );
+    }
+
+    #[test]
+    fn test_markdown_options() {
+        let options = MarkdownOptions {
+            strip_html: true,
+            link_footnotes: true,
+        };
+        let markdown = "See [Println](https://pkg.go.dev/fmt#Println), [this](#section) and \
+                        [Println](https://pkg.go.dev/fmt#Println)<br/>again.\n\n\
+                        <a name=\"x\"></a>\n<!-- a\ncomment -->\n<p>Some &amp; text</p>\n\n\
+                        Returns <code>Vec<String></code>.\n";
+        assert_eq!(
+            markdown_to_kakoune_markup_with_options(markdown, &options),
+            "See {InfoLink}Println{InfoDefault}[1], {InfoLink}this{InfoDefault} and \
+             {InfoLink}Println{InfoDefault}[1]\nagain.\nSome & text\n\n\
+             Returns <code>Vec<String></code>.\n\n\
+             [1] {InfoLink}https://pkg.go.dev/fmt#Println{InfoDefault}"
+        );
+    }
+}
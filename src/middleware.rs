@@ -0,0 +1,173 @@
+//! Configurable rewrites of the params of requests and notifications sent to a language server,
+//! and of the results it returns, as an escape hatch for servers that use non-conforming field
+//! names.
+//!
+//! Paths are dot-separated object keys, like `context.triggerKind`. A key followed by `[]`
+//! applies the rest of the path to each element of the array at that key, like
+//! `items[].insertText`; a path starting with `[]` does the same for a top-level array.
+
+use crate::types::{Middleware, MiddlewareTarget};
+use jsonrpc_core::Params;
+use serde_json::value::RawValue;
+use serde_json::{Map, Value};
+
+fn matching<'a>(
+    middleware: &'a [Middleware],
+    method: &'a str,
+    target: MiddlewareTarget,
+) -> impl Iterator<Item = &'a Middleware> {
+    middleware
+        .iter()
+        .filter(move |m| m.method == method && m.target == target)
+}
+
+/// Rewrites the params of a request or notification to the language server.
+pub fn rewrite_params(middleware: &[Middleware], method: &str, params: Params) -> Params {
+    if matching(middleware, method, MiddlewareTarget::Params)
+        .next()
+        .is_none()
+    {
+        return params;
+    }
+    let mut value = match params {
+        Params::None => Value::Null,
+        Params::Array(params) => Value::Array(params),
+        Params::Map(params) => Value::Object(params),
+    };
+    for m in matching(middleware, method, MiddlewareTarget::Params) {
+        rewrite(m, &mut value);
+    }
+    match value {
+        Value::Array(params) => Params::Array(params),
+        Value::Object(params) => Params::Map(params),
+        _ => Params::None,
+    }
+}
+
+/// Rewrites the result of a request to the language server.
+pub fn rewrite_result(
+    middleware: &[Middleware],
+    method: &str,
+    result: Box<RawValue>,
+) -> Box<RawValue> {
+    if matching(middleware, method, MiddlewareTarget::Result)
+        .next()
+        .is_none()
+    {
+        return result;
+    }
+    let mut value: Value = match serde_json::from_str(result.get()) {
+        Ok(value) => value,
+        Err(_) => return result,
+    };
+    for m in matching(middleware, method, MiddlewareTarget::Result) {
+        rewrite(m, &mut value);
+    }
+    serde_json::value::to_raw_value(&value).unwrap_or(result)
+}
+
+fn rewrite(middleware: &Middleware, value: &mut Value) {
+    for (path, name) in &middleware.rename {
+        visit(value, &segments(path), false, &mut |map, key| {
+            if let Some(renamed) = map.remove(key) {
+                map.insert(name.clone(), renamed);
+            }
+        });
+    }
+    for (path, new_value) in &middleware.set {
+        visit(value, &segments(path), true, &mut |map, key| {
+            map.insert(key.to_string(), new_value.clone());
+        });
+    }
+    for path in &middleware.remove {
+        visit(value, &segments(path), false, &mut |map, key| {
+            map.remove(key);
+        });
+    }
+}
+
+fn segments(path: &str) -> Vec<&str> {
+    path.split('.').collect()
+}
+
+/// Calls `f` with each object that the path leads to, and the last key of the path.
+fn visit(
+    value: &mut Value,
+    segments: &[&str],
+    create: bool,
+    f: &mut dyn FnMut(&mut Map<String, Value>, &str),
+) {
+    let (segment, rest) = match segments.split_first() {
+        Some(split) => split,
+        None => return,
+    };
+    let (key, each) = match segment.strip_suffix("[]") {
+        Some(key) => (key, true),
+        None => (*segment, false),
+    };
+    if rest.is_empty() && !each {
+        if let Value::Object(map) = value {
+            f(map, key);
+        }
+        return;
+    }
+    let child = if key.is_empty() {
+        value
+    } else {
+        match value {
+            Value::Object(map) if create && !each => map
+                .entry(key.to_string())
+                .or_insert_with(|| Value::Object(Map::new())),
+            Value::Object(map) => match map.get_mut(key) {
+                Some(child) => child,
+                None => return,
+            },
+            _ => return,
+        }
+    };
+    if !each {
+        return visit(child, rest, create, f);
+    }
+    if let Value::Array(elements) = child {
+        for element in elements {
+            visit(element, rest, create, f);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_rewrite() {
+        let middleware: Middleware = toml::from_str(
+            r#"
+            method = "textDocument/completion"
+            target = "result"
+            rename = { "items[].insert_text" = "insertText" }
+            set = { "items[].data.source" = "fixed", "isIncomplete" = false }
+            remove = ["items[].sortText"]
+            "#,
+        )
+        .unwrap();
+        let mut value = json!({
+            "items": [
+                { "label": "a", "insert_text": "a()", "sortText": "1" },
+                { "label": "b" },
+            ]
+        });
+        rewrite(&middleware, &mut value);
+        assert_eq!(
+            value,
+            json!({
+                "isIncomplete": false,
+                "items": [
+                    { "label": "a", "insertText": "a()", "data": { "source": "fixed" } },
+                    { "label": "b", "data": { "source": "fixed" } },
+                ]
+            })
+        );
+    }
+}
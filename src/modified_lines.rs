@@ -0,0 +1,179 @@
+//! Lines of a buffer that changed since a git revision. When the `lsp_modified_lines_only`
+//! option is set, formatting and code actions run on save only change the lines modified since
+//! the last commit, which keeps diffs small in code bases that were never formatted. Likewise,
+//! `lsp-diagnostics-modified-lines-enable` hides diagnostics on lines that were not modified.
+
+use crate::text_edit::{split_whole_buffer_edit, TextEditish};
+use lsp_types::*;
+use ropey::Rope;
+use std::path::Path;
+use std::process::{Command, Output};
+
+/// A range of line numbers, starting at 0, with an exclusive end.
+pub type LineRange = std::ops::Range<u32>;
+
+/// The modified lines of a buffer version, as computed in the background, for the revision `base`.
+pub struct ModifiedLines {
+    pub buffile: String,
+    pub version: Option<i32>,
+    pub base: String,
+    pub lines: Result<Vec<LineRange>, String>,
+}
+
+/// Returns the ranges of lines in `text` that differ from the version of `buffile` at the git
+/// revision `base`. All lines are modified if the file did not exist at that revision.
+pub fn modified_lines(buffile: &str, text: &Rope, base: &str) -> Result<Vec<LineRange>, String> {
+    let path = Path::new(buffile);
+    let (dir, name) = match (path.parent(), path.file_name()) {
+        (Some(dir), Some(name)) => (dir, name.to_string_lossy()),
+        _ => return Err(format!("{} is not a file", buffile)),
+    };
+    let git = |args: &[&str]| -> Result<Output, String> {
+        Command::new("git")
+            .arg("-C")
+            .arg(dir)
+            .args(args)
+            .output()
+            .map_err(|e| format!("failed to run git: {}", e))
+    };
+    if !git(&["rev-parse", "--show-toplevel"])?.status.success() {
+        return Err(format!("{} is not in a git repository", buffile));
+    }
+    let revision = format!("{}^{{commit}}", base);
+    if !git(&["rev-parse", "--verify", "--quiet", &revision])?
+        .status
+        .success()
+    {
+        return Err(format!("unknown git revision `{}`", base));
+    }
+    let committed = git(&["show", &format!("{}:./{}", base, name)])?;
+    if !committed.status.success() {
+        // Not committed yet, so every line is new.
+        let all_lines = 0..text.len_lines() as u32;
+        return Ok(vec![all_lines]);
+    }
+    let committed = Rope::from_str(&String::from_utf8_lossy(&committed.stdout));
+    Ok(changed_lines(&committed, text))
+}
+
+/// Returns the ranges of lines in `new` that were inserted or replaced compared to `old`.
+fn changed_lines(old: &Rope, new: &Rope) -> Vec<LineRange> {
+    let oldv = old.lines().collect::<Vec<_>>();
+    let newv = new.lines().collect::<Vec<_>>();
+    struct ChangedLines(Vec<LineRange>);
+    impl diffs::Diff for ChangedLines {
+        type Error = ();
+        fn insert(&mut self, _o: usize, n: usize, new_len: usize) -> Result<(), ()> {
+            self.0.push(n as u32..(n + new_len) as u32);
+            Ok(())
+        }
+        fn replace(&mut self, _o: usize, _len: usize, n: usize, new_len: usize) -> Result<(), ()> {
+            self.insert(0, n, new_len)
+        }
+    }
+    let mut changed = ChangedLines(vec![]);
+    let _result = diffs::patience::diff(&mut changed, &oldv, 0, oldv.len(), &newv, 0, newv.len());
+    changed.0
+}
+
+/// Drops the text edits that touch none of the given lines. A text edit that replaces the whole
+/// buffer is split up first.
+pub fn restrict_text_edits<T: TextEditish<T>>(
+    text_edits: Vec<T>,
+    text: &Rope,
+    lines: &[LineRange],
+) -> Vec<T> {
+    split_whole_buffer_edit(text_edits, text)
+        .into_iter()
+        .filter(|text_edit| touches_lines(&text_edit.as_ref().range, lines))
+        .collect()
+}
+
+/// Returns whether `range` touches any of the given lines.
+pub fn touches_lines(range: &Range, lines: &[LineRange]) -> bool {
+    let Range { start, end } = *range;
+    // A range that ends at the start of a line does not touch that line.
+    let last_line = if end.character == 0 && end.line > start.line {
+        end.line - 1
+    } else {
+        end.line
+    };
+    lines
+        .iter()
+        .any(|lines| lines.start <= last_line && start.line < lines.end)
+}
+
+/// Restricts the text edits to the document `uri` in a workspace edit to the given lines.
+/// Edits to other documents are left alone.
+pub fn restrict_workspace_edit(
+    edit: &mut WorkspaceEdit,
+    uri: &Url,
+    text: &Rope,
+    lines: &[LineRange],
+) {
+    if let Some(changes) = edit.changes.as_mut() {
+        if let Some(text_edits) = changes.get_mut(uri) {
+            *text_edits = restrict_text_edits(std::mem::take(text_edits), text, lines);
+        }
+    }
+    let restrict = |document_edit: &mut TextDocumentEdit| {
+        if document_edit.text_document.uri == *uri {
+            document_edit.edits =
+                restrict_text_edits(std::mem::take(&mut document_edit.edits), text, lines);
+        }
+    };
+    match edit.document_changes.as_mut() {
+        Some(DocumentChanges::Edits(document_edits)) => {
+            document_edits.iter_mut().for_each(restrict)
+        }
+        Some(DocumentChanges::Operations(operations)) => {
+            for operation in operations {
+                if let DocumentChangeOperation::Edit(document_edit) = operation {
+                    restrict(document_edit);
+                }
+            }
+        }
+        None => (),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn edit(start_line: u32, end_line: u32, new_text: &str) -> TextEdit {
+        TextEdit {
+            range: Range {
+                start: Position {
+                    line: start_line,
+                    character: 0,
+                },
+                end: Position {
+                    line: end_line,
+                    character: 0,
+                },
+            },
+            new_text: new_text.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_restrict_text_edits() {
+        let old = Rope::from_str("fn a() {}\nfn b() {}\nfn c() {}\nfn d() {}\n");
+        let new = Rope::from_str("fn a() {}\nfn b( ) {}\nfn c() {}\nfn  e() {}\nfn d() {}\n");
+        let lines = changed_lines(&old, &new);
+        assert_eq!(lines, vec![1..2, 3..4]);
+
+        let text_edits = vec![
+            edit(0, 1, "fn a() {}\n"),
+            edit(1, 2, "fn b() {}\n"),
+            edit(2, 2, "\n"),
+            edit(3, 4, "fn e() {}\n"),
+        ];
+        let restricted = restrict_text_edits(text_edits, &new, &lines);
+        assert_eq!(
+            restricted,
+            vec![edit(1, 2, "fn b() {}\n"), edit(3, 4, "fn e() {}\n")]
+        );
+    }
+}
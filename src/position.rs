@@ -29,26 +29,52 @@ use crate::{context::Context, util::read_document};
 use lsp_types::*;
 use ropey::{Rope, RopeSlice};
 use std::cmp::min;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 pub const EOL_OFFSET: u32 = 1_000_000;
 
+/// Whether conversions between LSP and Kakoune positions are checked by converting back, see
+/// `validate_positions` in the config.
+static VALIDATE_POSITIONS: AtomicBool = AtomicBool::new(false);
+
+pub fn set_validate_positions(enabled: bool) {
+    VALIDATE_POSITIONS.store(enabled, Ordering::Relaxed);
+}
+
 /// Convert LSP Range to Kakoune's range-spec.
 pub fn lsp_range_to_kakoune(
     range: &Range,
     text: &Rope,
     offset_encoding: OffsetEncoding,
 ) -> KakouneRange {
-    match offset_encoding {
+    let kakoune_range = match offset_encoding {
         OffsetEncoding::Utf8 => lsp_range_to_kakoune_utf_8_code_units(range),
         // Not a proper UTF-16 code units handling, but works within BMP
         OffsetEncoding::Utf16 => lsp_range_to_kakoune_utf_8_code_points(range, text),
+    };
+    // The end is adjusted to be inclusive, so only the start converts back.
+    if VALIDATE_POSITIONS.load(Ordering::Relaxed) {
+        validate_lsp_position(&range.start, &kakoune_range.start, text, offset_encoding);
     }
+    kakoune_range
 }
 
 pub fn lsp_position_to_kakoune(
     position: &Position,
     text: &Rope,
     offset_encoding: OffsetEncoding,
+) -> KakounePosition {
+    let kakoune_position = convert_lsp_position(position, text, offset_encoding);
+    if VALIDATE_POSITIONS.load(Ordering::Relaxed) {
+        validate_lsp_position(position, &kakoune_position, text, offset_encoding);
+    }
+    kakoune_position
+}
+
+fn convert_lsp_position(
+    position: &Position,
+    text: &Rope,
+    offset_encoding: OffsetEncoding,
 ) -> KakounePosition {
     match offset_encoding {
         OffsetEncoding::Utf8 => lsp_position_to_kakoune_utf_8_code_units(position),
@@ -72,6 +98,18 @@ pub fn kakoune_position_to_lsp(
     position: &KakounePosition,
     text: &Rope,
     offset_encoding: OffsetEncoding,
+) -> Position {
+    let lsp_position = convert_kakoune_position(position, text, offset_encoding);
+    if VALIDATE_POSITIONS.load(Ordering::Relaxed) {
+        validate_kakoune_position(position, &lsp_position, text, offset_encoding);
+    }
+    lsp_position
+}
+
+fn convert_kakoune_position(
+    position: &KakounePosition,
+    text: &Rope,
+    offset_encoding: OffsetEncoding,
 ) -> Position {
     match offset_encoding {
         OffsetEncoding::Utf8 => kakoune_position_to_lsp_utf_8_code_units(position),
@@ -80,6 +118,87 @@ pub fn kakoune_position_to_lsp(
     }
 }
 
+/// Returns the position that an LSP position converts back to, if it differs. Positions past the
+/// end of their line are clamped on purpose, so they are not checked.
+fn lsp_round_trip_mismatch(
+    position: &Position,
+    converted: &KakounePosition,
+    text: &Rope,
+    offset_encoding: OffsetEncoding,
+) -> Option<Position> {
+    if position.line as usize >= text.len_lines() {
+        return None;
+    }
+    let line = text.line(position.line as usize);
+    let line_length = match offset_encoding {
+        OffsetEncoding::Utf8 => line.len_bytes(),
+        OffsetEncoding::Utf16 => line.len_chars(),
+    };
+    if position.character as usize >= line_length {
+        return None;
+    }
+    let back = convert_kakoune_position(converted, text, offset_encoding);
+    (back != *position).then_some(back)
+}
+
+/// Returns the position that a Kakoune position converts back to, if it differs. Positions past
+/// the end of their line are not checked.
+fn kakoune_round_trip_mismatch(
+    position: &KakounePosition,
+    converted: &Position,
+    text: &Rope,
+    offset_encoding: OffsetEncoding,
+) -> Option<KakounePosition> {
+    let line_idx = position.line.checked_sub(1)? as usize;
+    if line_idx >= text.len_lines()
+        || position.column == 0
+        || position.column as usize > text.line(line_idx).len_bytes()
+    {
+        return None;
+    }
+    let back = convert_lsp_position(converted, text, offset_encoding);
+    (back != *position).then_some(back)
+}
+
+fn validate_lsp_position(
+    position: &Position,
+    converted: &KakounePosition,
+    text: &Rope,
+    offset_encoding: OffsetEncoding,
+) {
+    if let Some(back) = lsp_round_trip_mismatch(position, converted, text, offset_encoding) {
+        error!(
+            "Position round trip mismatch ({:?}): LSP {}:{} -> Kakoune {} -> LSP {}:{} in line {:?}",
+            offset_encoding,
+            position.line,
+            position.character,
+            converted,
+            back.line,
+            back.character,
+            get_line(position.line as usize, text).to_string(),
+        );
+    }
+}
+
+fn validate_kakoune_position(
+    position: &KakounePosition,
+    converted: &Position,
+    text: &Rope,
+    offset_encoding: OffsetEncoding,
+) {
+    if let Some(back) = kakoune_round_trip_mismatch(position, converted, text, offset_encoding) {
+        error!(
+            "Position round trip mismatch ({:?}): Kakoune {} -> LSP {}:{} -> Kakoune {} in line {:?}",
+            offset_encoding,
+            position,
+            converted.line,
+            converted.character,
+            back,
+            get_line(position.line as usize - 1, text).to_string(),
+        );
+    }
+}
+
 /// Wrapper for kakoune_position_to_lsp which uses context to get buffer content and offset encoding.
 pub fn get_lsp_position(
     filename: &str,
@@ -340,6 +459,33 @@ fn lsp_position_to_kakoune_utf_8_code_units(position: &Position) -> KakounePosit
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_round_trip_mismatch() {
+        let text = Rope::from_str("a→b\n");
+        let encoding = OffsetEncoding::Utf16;
+        let position = Position::new(0, 2);
+        let converted = convert_lsp_position(&position, &text, encoding);
+        assert_eq!(converted, KakounePosition { line: 1, column: 5 });
+        assert_eq!(
+            lsp_round_trip_mismatch(&position, &converted, &text, encoding),
+            None
+        );
+        // A column inside the bytes of "→" has no character offset of its own.
+        let position = KakounePosition { line: 1, column: 3 };
+        let converted = convert_kakoune_position(&position, &text, encoding);
+        assert_eq!(
+            kakoune_round_trip_mismatch(&position, &converted, &text, encoding),
+            Some(KakounePosition { line: 1, column: 2 })
+        );
+        // Positions past the end of the text are clamped on purpose.
+        let position = Position::new(3, 0);
+        let converted = convert_lsp_position(&position, &text, encoding);
+        assert_eq!(
+            lsp_round_trip_mismatch(&position, &converted, &text, encoding),
+            None
+        );
+    }
+
     #[test]
     fn lsp_range_to_kakoune_utf_8_code_units_bol_insert() {
         assert_eq!(
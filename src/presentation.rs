@@ -0,0 +1,75 @@
+//! Presentation of responses with several results, like definitions, references or code actions.
+//! They are shown in a goto buffer, in Kakoune's menu or in a prompt that completes them, as
+//! chosen by the `lsp_result_presentation` option or the option of the request, like
+//! `lsp_references_presentation`.
+
+use crate::types::Presentation;
+use crate::util::editor_quote;
+use itertools::Itertools;
+
+/// A result to choose from: its title and the command to run when it is chosen.
+pub struct Choice {
+    pub title: String,
+    pub command: String,
+}
+
+/// Returns the command that lets the user pick one of the choices. `prompt` is the command that
+/// shows them in a prompt; it takes the same arguments as `menu`. Results that have no goto
+/// buffer line are shown in the prompt instead of a buffer.
+pub fn choose_command(presentation: Presentation, prompt: &str, choices: &[Choice]) -> String {
+    let command = match presentation {
+        Presentation::Menu => "menu",
+        Presentation::Buffer | Presentation::Prompt => prompt,
+    };
+    let args = choices
+        .iter()
+        .map(|choice| {
+            // Menu items are a single line.
+            let title = choice.title.lines().next().unwrap_or_default();
+            format!("{} {}", editor_quote(title), editor_quote(&choice.command))
+        })
+        .join(" ");
+    format!("{} {}", command, args)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::EditorRequest;
+
+    #[test]
+    fn test_choose_command() {
+        let choices = vec![
+            Choice {
+                title: "Import 'a'\nfrom b".to_string(),
+                command: "lsp-execute-command a".to_string(),
+            },
+            Choice {
+                title: "Inline".to_string(),
+                command: "echo b".to_string(),
+            },
+        ];
+        assert_eq!(
+            choose_command(Presentation::Menu, "lsp-menu", &choices),
+            "menu 'Import ''a''' 'lsp-execute-command a' 'Inline' 'echo b'"
+        );
+        assert_eq!(
+            choose_command(Presentation::Buffer, "lsp-menu", &choices[1..]),
+            "lsp-menu 'Inline' 'echo b'"
+        );
+    }
+
+    #[test]
+    fn test_unknown_presentation() {
+        let request = |presentation: &str| {
+            let request = format!(
+                "session = \"s\"\nbuffile = \"/a.rs\"\nfiletype = \"rust\"\nversion = 1\n\
+                 presentation = \"{}\"\nmethod = \"textDocument/references\"\n[params]\n",
+                presentation
+            );
+            toml::from_str::<EditorRequest>(&request).unwrap()
+        };
+        assert_eq!(request("menu").meta.presentation, Some(Presentation::Menu));
+        assert_eq!(request("menuu").meta.presentation, None);
+    }
+}
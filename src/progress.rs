@@ -1,3 +1,4 @@
+use crate::command_output;
 use crate::context::Context;
 use crate::types::{EditorMeta, EditorParams};
 use crate::util::editor_quote;
@@ -70,6 +71,8 @@ pub fn dollar_progress(meta: EditorMeta, params: Params, ctx: &mut Context) {
     }
 
     let token = &params.token;
+    let ProgressParamsValue::WorkDone(progress) = &params.value;
+    command_output::progress(token, progress, ctx);
     match params.value {
         ProgressParamsValue::WorkDone(WorkDoneProgress::Begin(begin)) => {
             match ctx.work_done_progress.get_mut(&params.token) {
@@ -89,6 +92,7 @@ pub fn dollar_progress(meta: EditorMeta, params: Params, ctx: &mut Context) {
                         false,
                     );
                     *progress = Some(begin);
+                    let command = format!("{}\n{}", command, indexing_status_command(ctx));
                     ctx.exec(meta, command);
                 }
                 None => {
@@ -118,6 +122,7 @@ pub fn dollar_progress(meta: EditorMeta, params: Params, ctx: &mut Context) {
                     progress.cancellable = report.cancellable;
                     progress.message = report.message;
                     progress.percentage = report.percentage;
+                    let command = format!("{}\n{}", command, indexing_status_command(ctx));
                     ctx.exec(meta, command);
                 }
                 Some(None) => {
@@ -141,6 +146,7 @@ pub fn dollar_progress(meta: EditorMeta, params: Params, ctx: &mut Context) {
                 Some(Some(WorkDoneProgressBegin { title, .. })) => {
                     let command =
                         handle_progress_command(token, &title, false, &message, &Some(100), true);
+                    let command = format!("{}\n{}", command, indexing_status_command(ctx));
                     ctx.exec(meta, command);
                 }
                 Some(None) => {
@@ -161,3 +167,51 @@ pub fn dollar_progress(meta: EditorMeta, params: Params, ctx: &mut Context) {
         }
     }
 }
+
+/// Updates this server's entry in the `lsp_indexing_status` option. The entry summarizes the
+/// least advanced piece of ongoing work as "<percentage> <title>: <message>", where the
+/// percentage is "?" if the server did not send one. The entry is removed once all work is done.
+fn indexing_status_command(ctx: &Context) -> String {
+    let least_advanced = ctx
+        .work_done_progress
+        .values()
+        .flatten()
+        .min_by_key(|progress| progress.percentage.unwrap_or(0));
+    match least_advanced {
+        Some(progress) => {
+            let percentage = progress
+                .percentage
+                .map(|percentage| percentage.to_string())
+                .unwrap_or_else(|| "?".to_string());
+            let mut status = format!("{}={} {}", ctx.language_id, percentage, progress.title);
+            if let Some(message) = &progress.message {
+                status.push_str(": ");
+                status.push_str(message);
+            }
+            format!(
+                "set-option -add global lsp_indexing_status {}",
+                editor_quote(&status)
+            )
+        }
+        None => format!(
+            "set-option -remove global lsp_indexing_status {}",
+            editor_quote(&format!("{}=", ctx.language_id))
+        ),
+    }
+}
+
+/// Sets this server's entry in the `lsp_server_status` option, as reported by server-specific
+/// notifications like rust-analyzer's "rust-analyzer/serverStatus". `None` removes the entry.
+pub fn set_server_status(meta: EditorMeta, status: Option<&str>, ctx: &mut Context) {
+    let command = match status {
+        Some(status) => format!(
+            "set-option -add global lsp_server_status {}",
+            editor_quote(&format!("{}={}", ctx.language_id, status))
+        ),
+        None => format!(
+            "set-option -remove global lsp_server_status {}",
+            editor_quote(&format!("{}=", ctx.language_id))
+        ),
+    };
+    ctx.exec(meta, command);
+}
@@ -0,0 +1,124 @@
+//! One-shot queries from the command line, like `kak-lsp -s query --query definition --file
+//! src/main.rs --line 10 --column 5 --filetype rust`. The request is sent to the kak-lsp server
+//! of the session like any editor request, but asks for the language server's response to be
+//! written as JSON to a fifo, which is printed to stdout.
+
+use serde_json::Value;
+use std::path::Path;
+
+/// The queries that can be made, and the LSP methods they are sent as.
+pub const QUERY_METHODS: &[(&str, &str)] = &[
+    ("definition", "textDocument/definition"),
+    ("hover", "textDocument/hover"),
+    ("references", "textDocument/references"),
+];
+
+pub struct Query<'a> {
+    pub session: &'a str,
+    pub query: &'a str,
+    pub file: &'a Path,
+    pub filetype: &'a str,
+    // Kakoune coordinates: 1-based, the column counts bytes.
+    pub line: u32,
+    pub column: u32,
+}
+
+/// Builds the editor request for the query, whose response is written to `fifo`.
+pub fn editor_request(query: &Query, fifo: &str) -> Result<String, String> {
+    let method = QUERY_METHODS
+        .iter()
+        .find(|(name, _)| *name == query.query)
+        .map(|(_, method)| *method)
+        .ok_or_else(|| format!("unknown query `{}`", query.query))?;
+    let buffile = query
+        .file
+        .canonicalize()
+        .map_err(|err| format!("{}: {}", query.file.display(), err))?;
+    let buffile = buffile
+        .to_str()
+        .ok_or_else(|| format!("{}: path is not valid UTF-8", query.file.display()))?;
+
+    let string = |value: &str| toml::Value::from(value).to_string();
+    let params = if method == "textDocument/hover" {
+        format!(
+            "selectionDesc = \"{}.{},{}.{}\"",
+            query.line, query.column, query.line, query.column
+        )
+    } else {
+        format!(
+            "[params.position]\nline = {}\ncolumn = {}",
+            query.line, query.column
+        )
+    };
+    Ok(format!(
+        "session = {}\nbuffile = {}\nfiletype = {}\nversion = 0\nmethod = {}\nfifo = {}\nwrite_response_to_fifo = true\n[params]\n{}\n",
+        string(query.session),
+        string(buffile),
+        string(query.filetype),
+        string(method),
+        string(fifo),
+        params,
+    ))
+}
+
+/// Turns what the server wrote to the fifo into the result to print, or an error message.
+/// Errors that happen before the request reaches the language server come as editor commands.
+pub fn response_result(response: &str) -> Result<Value, String> {
+    let response: Value = match serde_json::from_str(response) {
+        Ok(response) => response,
+        Err(_) => return Err(response.trim().to_string()),
+    };
+    if let Some(error) = response.get("error") {
+        let message = error
+            .get("message")
+            .and_then(|message| message.as_str())
+            .unwrap_or_default();
+        return Err(format!("language server error: {}", message));
+    }
+    Ok(response.get("result").cloned().unwrap_or(Value::Null))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::EditorRequest;
+
+    #[test]
+    fn test_editor_request() {
+        let file = std::env::current_dir().unwrap().join("Cargo.toml");
+        let query = Query {
+            session: "query",
+            query: "definition",
+            file: &file,
+            filetype: "toml",
+            line: 3,
+            column: 7,
+        };
+        let request = editor_request(&query, "/tmp/fifo").unwrap();
+        let request: EditorRequest = toml::from_str(&request).unwrap();
+        assert_eq!(request.method, "textDocument/definition");
+        assert_eq!(request.meta.buffile, file.to_str().unwrap());
+        assert_eq!(request.meta.fifo.as_deref(), Some("/tmp/fifo"));
+        assert!(request.meta.write_response_to_fifo);
+        assert_eq!(request.params["position"]["line"].as_integer(), Some(3));
+
+        let query = Query {
+            query: "implementation",
+            ..query
+        };
+        assert!(editor_request(&query, "/tmp/fifo").is_err());
+    }
+
+    #[test]
+    fn test_response_result() {
+        assert_eq!(
+            response_result(r#"{"jsonrpc": "2.0", "result": [1], "id": 1}"#),
+            Ok(serde_json::json!([1]))
+        );
+        assert!(response_result(r#"{"error": {"code": 1, "message": "no"}, "id": 1}"#).is_err());
+        assert_eq!(
+            response_result("lsp-show-error 'not configured'\n"),
+            Err("lsp-show-error 'not configured'".to_string())
+        );
+    }
+}
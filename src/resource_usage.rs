@@ -0,0 +1,186 @@
+//! Resource usage of a language server, shown by `lsp-status`: the memory and CPU time of its
+//! process and the processes it started, like tsserver for typescript-language-server. It is
+//! sampled from /proc whenever the status is shown, so it is only available on Linux.
+
+use std::collections::HashMap;
+use std::fs;
+use std::time::Instant;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Sample {
+    rss_bytes: u64,
+    cpu_percent: f64,
+}
+
+pub struct ResourceUsage {
+    pid: u32,
+    // When the CPU time of the processes was last read, and its value in clock ticks.
+    last_cpu_time: Option<(Instant, u64)>,
+    latest: Option<Sample>,
+}
+
+impl ResourceUsage {
+    pub fn new(pid: u32) -> Self {
+        let mut usage = ResourceUsage {
+            pid,
+            last_cpu_time: None,
+            latest: None,
+        };
+        usage.sample();
+        usage
+    }
+
+    /// Reads the current usage. The CPU usage is averaged since the previous sample, which is
+    /// taken when the server starts.
+    pub fn sample(&mut self) {
+        let now = Instant::now();
+        let (cpu_ticks, rss_pages) = match process_tree_usage(self.pid) {
+            Some(usage) => usage,
+            None => return,
+        };
+        let (ticks_per_second, page_size) = unsafe {
+            (
+                libc::sysconf(libc::_SC_CLK_TCK),
+                libc::sysconf(libc::_SC_PAGESIZE),
+            )
+        };
+        let previous = self.last_cpu_time.replace((now, cpu_ticks));
+        let (since, previous_ticks) = match previous {
+            Some(previous) => previous,
+            // The first sample only sets the baseline for CPU usage.
+            None => return,
+        };
+        let elapsed = now.duration_since(since).as_secs_f64();
+        if elapsed <= 0.0 || ticks_per_second <= 0 {
+            return;
+        }
+        let cpu_seconds = cpu_ticks.saturating_sub(previous_ticks) as f64 / ticks_per_second as f64;
+        self.latest = Some(Sample {
+            rss_bytes: rss_pages * page_size.max(0) as u64,
+            cpu_percent: 100.0 * cpu_seconds / elapsed,
+        });
+    }
+
+    /// Describes the latest usage.
+    pub fn summary(&self) -> String {
+        let latest = match &self.latest {
+            Some(latest) => latest,
+            None => return "unavailable".to_string(),
+        };
+        format!(
+            "\n  memory: {:.1} MiB\n  cpu: {:.1}%",
+            latest.rss_bytes as f64 / (1024.0 * 1024.0),
+            latest.cpu_percent,
+        )
+    }
+}
+
+/// Returns the CPU time in clock ticks and the resident memory in pages of the process and its
+/// descendants.
+fn process_tree_usage(pid: u32) -> Option<(u64, u64)> {
+    // Kernels without CONFIG_PROC_CHILDREN don't list children, so all processes are scanned for
+    // their parents instead.
+    let mut parents: Option<HashMap<u32, Vec<u32>>> = None;
+    let mut usage = (0, 0);
+    let mut tree = vec![pid];
+    let mut i = 0;
+    while i < tree.len() {
+        let id = tree[i];
+        i += 1;
+        // Processes may exit while we look.
+        let stat = match fs::read_to_string(format!("/proc/{}/stat", id))
+            .ok()
+            .and_then(|stat| parse_stat(&stat))
+        {
+            Some(stat) => stat,
+            None if id == pid => return None,
+            None => continue,
+        };
+        usage = (usage.0 + stat.cpu_ticks, usage.1 + stat.rss_pages);
+        match (&parents, children(id)) {
+            (None, Some(children)) => tree.extend(children),
+            _ => {
+                let parents = parents.get_or_insert_with(children_by_parent);
+                tree.extend(parents.get(&id).into_iter().flatten());
+            }
+        }
+    }
+    Some(usage)
+}
+
+/// Returns the child processes of a process, from the children of each of its threads, or None
+/// if the kernel does not list them.
+fn children(pid: u32) -> Option<Vec<u32>> {
+    let tasks = fs::read_dir(format!("/proc/{}/task", pid)).ok()?;
+    let mut children = vec![];
+    for task in tasks.flatten() {
+        let ids = fs::read_to_string(task.path().join("children")).ok()?;
+        children.extend(
+            ids.split_whitespace()
+                .filter_map(|id| id.parse::<u32>().ok()),
+        );
+    }
+    Some(children)
+}
+
+/// Returns the child processes of all processes, by their parent.
+fn children_by_parent() -> HashMap<u32, Vec<u32>> {
+    let mut children: HashMap<u32, Vec<u32>> = HashMap::new();
+    for entry in fs::read_dir("/proc").into_iter().flatten().flatten() {
+        let id: u32 = match entry
+            .file_name()
+            .to_str()
+            .and_then(|name| name.parse().ok())
+        {
+            Some(id) => id,
+            None => continue,
+        };
+        if let Some(stat) = fs::read_to_string(entry.path().join("stat"))
+            .ok()
+            .and_then(|stat| parse_stat(&stat))
+        {
+            children.entry(stat.ppid).or_default().push(id);
+        }
+    }
+    children
+}
+
+#[derive(Debug, PartialEq)]
+struct ProcessStat {
+    ppid: u32,
+    cpu_ticks: u64,
+    rss_pages: u64,
+}
+
+/// Parses /proc/<pid>/stat, see proc(5).
+fn parse_stat(stat: &str) -> Option<ProcessStat> {
+    // The command name comes second, in parentheses, and may contain spaces and parentheses.
+    let fields: Vec<&str> = stat[stat.rfind(')')? + 1..].split_whitespace().collect();
+    // Fields are numbered from 1, and the first field after the command name is the third.
+    let field = |number: usize| fields.get(number - 3)?.parse::<u64>().ok();
+    Some(ProcessStat {
+        ppid: field(4)? as u32,
+        cpu_ticks: field(14)? + field(15)?,
+        rss_pages: field(24)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_stat() {
+        let stat = "4242 (rust (analyzer)) S 4200 4242 4200 0 -1 4194304 91 0 0 0 150 25 0 0 20 0 \
+                    12 0 1234 1000000 2048 18446744073709551615";
+        assert_eq!(
+            parse_stat(stat),
+            Some(ProcessStat {
+                ppid: 4200,
+                cpu_ticks: 175,
+                rss_pages: 2048,
+            })
+        );
+        assert_eq!(parse_stat("4242 (truncated) S 4200"), None);
+    }
+}
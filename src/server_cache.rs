@@ -0,0 +1,275 @@
+use crate::context::*;
+use crate::types::*;
+use crate::util::*;
+use indoc::formatdoc;
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fs;
+use std::path::PathBuf;
+use std::time::Instant;
+
+/// What we remember about a language server between kak-lsp runs, per project and language.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct ServerCacheEntry {
+    pub root_path: String,
+    pub language_id: String,
+    pub server_name: Option<String>,
+    pub server_version: Option<String>,
+    pub capabilities: Value,
+    pub settings_hash: Option<u64>,
+}
+
+/// Changes between the capabilities advertised by the previous and the current server run.
+#[derive(Clone, Debug, PartialEq)]
+pub enum CapabilityChange {
+    Added(String),
+    Removed(String),
+    Changed(String),
+}
+
+fn cache_path(root_path: &str, language_id: &str) -> Option<PathBuf> {
+    let mut path = dirs::cache_dir()?;
+    path.push("kak-lsp");
+    path.push("servers");
+    path.push(format!(
+        "{:016x}.json",
+        fnv1a(format!("{}\0{}", language_id, root_path).as_bytes())
+    ));
+    Some(path)
+}
+
+/// Loads the cache entry for the current project, if any.
+pub fn load(ctx: &Context) -> Option<ServerCacheEntry> {
+    let path = cache_path(&ctx.root_path, &ctx.language_id)?;
+    let contents = fs::read_to_string(&path).ok()?;
+    match serde_json::from_str(&contents) {
+        Ok(entry) => Some(entry),
+        Err(e) => {
+            warn!("Ignoring malformed server cache {}: {}", path.display(), e);
+            None
+        }
+    }
+}
+
+/// Writes the cache entry for the current project.
+pub fn store(ctx: &Context) {
+    let entry = match ctx.server_cache.as_ref() {
+        Some(entry) => entry,
+        None => return,
+    };
+    let path = match cache_path(&ctx.root_path, &ctx.language_id) {
+        Some(path) => path,
+        None => return,
+    };
+    let result = path
+        .parent()
+        .map_or(Ok(()), fs::create_dir_all)
+        .and_then(|()| fs::write(&path, serde_json::to_string_pretty(entry).unwrap()));
+    if let Err(e) = result {
+        warn!("Failed to write server cache {}: {}", path.display(), e);
+    }
+}
+
+/// Records the capabilities from the initialize response, remembering what changed since the
+/// server was last started for this project.
+pub fn record_capabilities(
+    ctx: &mut Context,
+    capabilities: Value,
+    server_name: Option<String>,
+    server_version: Option<String>,
+) {
+    let previous = load(ctx);
+    ctx.capability_changes = previous
+        .as_ref()
+        .map(|previous| diff_capabilities(&previous.capabilities, &capabilities))
+        .unwrap_or_default();
+    ctx.server_cache = Some(ServerCacheEntry {
+        root_path: ctx.root_path.clone(),
+        language_id: ctx.language_id.clone(),
+        server_name,
+        server_version,
+        capabilities,
+        settings_hash: previous.and_then(|previous| previous.settings_hash),
+    });
+    store(ctx);
+}
+
+/// Returns false if these settings were already pushed to the running server.
+pub fn record_settings(ctx: &mut Context, settings: &Value) -> bool {
+    let hash = fnv1a(settings.to_string().as_bytes());
+    if ctx.settings_hash == Some(hash) {
+        return false;
+    }
+    ctx.settings_hash = Some(hash);
+    if let Some(entry) = ctx.server_cache.as_mut() {
+        if entry.settings_hash != Some(hash) {
+            entry.settings_hash = Some(hash);
+            store(ctx);
+        }
+    }
+    true
+}
+
+/// Computes the changes between two capability objects, as dotted paths to the leaves.
+pub fn diff_capabilities(old: &Value, new: &Value) -> Vec<CapabilityChange> {
+    fn walk(prefix: &str, old: &Value, new: &Value, changes: &mut Vec<CapabilityChange>) {
+        let join = |key: &str| {
+            if prefix.is_empty() {
+                key.to_string()
+            } else {
+                format!("{}.{}", prefix, key)
+            }
+        };
+        match (old, new) {
+            (Value::Object(old), Value::Object(new)) => {
+                for (key, old_value) in old {
+                    match new.get(key) {
+                        Some(new_value) => walk(&join(key), old_value, new_value, changes),
+                        None => changes.push(CapabilityChange::Removed(join(key))),
+                    }
+                }
+                for key in new.keys() {
+                    if !old.contains_key(key) {
+                        changes.push(CapabilityChange::Added(join(key)));
+                    }
+                }
+            }
+            (old, new) if old != new => changes.push(CapabilityChange::Changed(prefix.to_string())),
+            _ => (),
+        }
+    }
+    let mut changes = vec![];
+    walk("", old, new, &mut changes);
+    changes
+}
+
+/// 64-bit FNV-1a, which unlike std's hasher is stable across Rust versions.
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+pub fn status(meta: EditorMeta, ctx: &mut Context) {
+    let server = match ctx.server_cache.as_ref() {
+        Some(ServerCacheEntry {
+            server_name: Some(name),
+            server_version,
+            ..
+        }) => match server_version {
+            Some(version) => format!("{} {}", name, version),
+            None => name.clone(),
+        },
+        _ => ctx.config.language[&ctx.language_id].command.clone(),
+    };
+    let settings = match ctx.settings_hash {
+        Some(hash) => format!("{:016x}", hash),
+        None => "not sent".to_string(),
+    };
+    let changes = if ctx.capability_changes.is_empty() {
+        "none".to_string()
+    } else {
+        ctx.capability_changes
+            .iter()
+            .map(|change| match change {
+                CapabilityChange::Added(path) => format!("\n  + {}", path),
+                CapabilityChange::Removed(path) => format!("\n  - {}", path),
+                CapabilityChange::Changed(path) => format!("\n  ~ {}", path),
+            })
+            .collect::<String>()
+    };
+    let now = Instant::now();
+    let outstanding = ctx
+        .response_waitlist
+        .values()
+        .sorted_by_key(|request| request.sent)
+        .map(|request| {
+            format!(
+                "\n  {} for {}s{}",
+                request.method,
+                now.duration_since(request.sent).as_secs(),
+                if request.reported_stale {
+                    ", probably dropped"
+                } else {
+                    ""
+                }
+            )
+        })
+        .collect::<String>();
+    let outstanding = if outstanding.is_empty() {
+        "none".to_string()
+    } else {
+        outstanding
+    };
+    let resources = match &mut ctx.resource_usage {
+        Some(usage) => {
+            usage.sample();
+            usage.summary()
+        }
+        None => "unavailable".to_string(),
+    };
+    let cache = cache_path(&ctx.root_path, &ctx.language_id)
+        .map(|path| path.display().to_string())
+        .unwrap_or_else(|| "unavailable".to_string());
+    let command = formatdoc!(
+        "info 'language: {}
+         server: {}
+         root: {}
+         offset encoding: {}
+         settings hash: {}
+         reads settings changes: {}
+         capability changes since last start: {}
+         outstanding requests: {}
+         resource usage: {}
+         cache: {}'",
+        ctx.language_id,
+        editor_escape(&server),
+        editor_escape(&ctx.root_path),
+        match ctx.offset_encoding {
+            OffsetEncoding::Utf8 => "utf-8",
+            OffsetEncoding::Utf16 => "utf-16",
+        },
+        settings,
+        if ctx.did_change_configuration_support {
+            "yes"
+        } else {
+            "unknown"
+        },
+        editor_escape(&changes),
+        editor_escape(&outstanding),
+        editor_escape(&resources),
+        editor_escape(&cache),
+    );
+    ctx.exec(meta, command);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_diff_capabilities() {
+        let old = json!({
+            "hoverProvider": true,
+            "completionProvider": {"triggerCharacters": ["."]},
+            "renameProvider": true,
+        });
+        let new = json!({
+            "hoverProvider": true,
+            "completionProvider": {"triggerCharacters": [".", ":"], "resolveProvider": true},
+            "inlayHintProvider": true,
+        });
+        assert_eq!(
+            diff_capabilities(&old, &new),
+            vec![
+                CapabilityChange::Changed("completionProvider.triggerCharacters".to_string()),
+                CapabilityChange::Added("completionProvider.resolveProvider".to_string()),
+                CapabilityChange::Removed("renameProvider".to_string()),
+                CapabilityChange::Added("inlayHintProvider".to_string()),
+            ]
+        );
+        assert_eq!(diff_capabilities(&new, &new), vec![]);
+    }
+}
@@ -1,21 +1,43 @@
+use crate::auto_detect::detect_server;
 use crate::controller;
 use crate::editor_transport;
+use crate::info_box;
 use crate::project_root::find_project_root;
+use crate::session_state;
 use crate::thread_worker::Worker;
 use crate::types::*;
 use crate::util::*;
-use crossbeam_channel::{after, never, select, Sender};
+use crossbeam_channel::{after, never, select, unbounded, Sender, TrySendError};
+use itertools::Itertools;
 use lsp_types::notification::Notification;
+use lsp_types::request::Request;
 use lsp_types::*;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::time::Duration;
 
+/// How often notifications that wait for a busy controller are retried.
+const BACKLOG_RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
 struct ControllerHandle {
     worker: Worker<EditorRequest, Void>,
+    // Notifications that did not fit into the controller's channel, in order. They are sent
+    // once the controller catches up, since dropping them would desync the language server.
+    backlog: VecDeque<EditorRequest>,
 }
 
 type Controllers = HashMap<Route, ControllerHandle>;
 
+/// Text synchronization requests for routes whose language server has `lazy_start` set and
+/// has not been started yet. They are replayed when the server is started.
+type LazyRequests = HashMap<Route, Vec<EditorRequest>>;
+
+/// Additional languages whose servers receive a buffer, as set by the `lsp_servers` option.
+/// Keyed by editor session and buffer file.
+type ExtraServers = HashMap<(SessionId, String), Vec<LanguageId>>;
+
+/// The filetype each buffer was last synchronized with, keyed by editor session and buffer file.
+type DocumentFiletypes = HashMap<(SessionId, String), String>;
+
 /// Start the main event loop.
 ///
 /// This function starts editor transport and routes incoming editor requests to controllers.
@@ -24,19 +46,49 @@ type Controllers = HashMap<Route, ControllerHandle>;
 ///
 /// `initial_request` could be passed to avoid extra synchronization churn if event loop is started
 /// as a result of request from editor.
-pub fn start(config: &Config, initial_request: Option<String>) -> i32 {
+pub fn start(config: &Config, initial_request: Option<Vec<u8>>) -> i32 {
     info!("Starting main event loop");
 
-    let editor = editor_transport::start(&config.server.session, initial_request);
+    let editor = editor_transport::start(
+        &config.server.session,
+        initial_request,
+        config.server.force_new,
+    );
     if let Err(code) = editor {
         return code;
     }
     let editor = editor.unwrap();
 
-    let languages = config.language.clone();
-    let filetypes = filetype_to_language_id_map(config);
+    let mut config = config.clone();
+    let mut languages = config.language.clone();
+    let mut filetypes = Filetypes {
+        configured: filetype_to_language_id_map(&config),
+        detected: HashMap::default(),
+    };
 
     let mut controllers: Controllers = HashMap::default();
+    // Requests from controllers to other controllers, like diagnostics of additional servers.
+    let (to_session, from_controllers) = unbounded::<EditorRequest>();
+    let mut lazy_requests: LazyRequests = HashMap::default();
+    let mut extra_servers: ExtraServers = HashMap::default();
+    let mut document_filetypes: DocumentFiletypes = HashMap::default();
+    let mut sessions_with_faces: HashSet<SessionId> = HashSet::default();
+    // Editor sessions that sent requests and did not exit yet.
+    let mut sessions: HashSet<SessionId> = HashSet::default();
+    // Filetypes for which the user was offered to start a detected language server.
+    let mut offered_servers: HashSet<(SessionId, String)> = HashSet::default();
+    // The language server found on PATH for each unconfigured filetype, if any.
+    let mut detected_servers: DetectedServers = HashMap::default();
+    // The server that waits for the reply to each server request that names its route.
+    let mut reply_routes: HashMap<String, Route> = HashMap::default();
+
+    rehydrate(
+        &mut controllers,
+        &mut lazy_requests,
+        &config,
+        editor.to_editor.sender(),
+        &to_session,
+    );
 
     let timeout = config.server.timeout;
 
@@ -46,6 +98,11 @@ pub fn start(config: &Config, initial_request: Option<String>) -> i32 {
         } else {
             never()
         };
+        let backlog_channel = if flush_backlogs(&mut controllers) {
+            never()
+        } else {
+            after(BACKLOG_RETRY_INTERVAL)
+        };
 
         select! {
             recv(timeout_channel) -> _ => {
@@ -53,6 +110,18 @@ pub fn start(config: &Config, initial_request: Option<String>) -> i32 {
                 break 'event_loop
             }
 
+            recv(backlog_channel) -> _ => continue 'event_loop,
+
+            recv(from_controllers) -> request => {
+                // We hold a sender, so the channel is never disconnected.
+                let request = request.unwrap();
+                if request.method == "kak-lsp/reply-route" {
+                    record_reply_route(&mut reply_routes, &config, &request);
+                    continue 'event_loop;
+                }
+                route_to_primary_server(&mut controllers, &mut lazy_requests, &config, &languages, &filetypes, &document_filetypes, request, editor.to_editor.sender(), &to_session);
+            }
+
             recv(editor.from_editor) -> request  => {
                 // editor.receiver was closed, either because of the unrecoverable error or timeout
                 // nothing we can do except to gracefully exit by stopping session
@@ -70,11 +139,72 @@ pub fn start(config: &Config, initial_request: Option<String>) -> i32 {
                 }
                 // editor exited, we need to cleanup associated controllers
                 if request.method == notification::Exit::METHOD {
-                    exit_editor_session(&mut controllers, &request);
+                    lazy_requests.retain(|route, _| route.session != request.meta.session);
+                    for requests in lazy_requests.values_mut() {
+                        requests.retain(|pending| pending.meta.session != request.meta.session);
+                    }
+                    sessions.remove(&request.meta.session);
+                    extra_servers.retain(|(session, _), _| *session != request.meta.session);
+                    document_filetypes.retain(|(session, _), _| *session != request.meta.session);
+                    sessions_with_faces.remove(&request.meta.session);
+                    offered_servers.retain(|(session, _)| *session != request.meta.session);
+                    filetypes.detected.retain(|(session, _), _| *session != request.meta.session);
+                    session_state::remove_session(&request.meta.session);
+                    exit_editor_session(&mut controllers, &request, sessions.is_empty());
+                    continue 'event_loop;
+                }
+
+                sessions.insert(request.meta.session.clone());
+
+                // The reply goes to the server that asked, whatever the buffer.
+                if let Some(route) = take_reply_route(&mut reply_routes, &request) {
+                    route_request(&mut controllers, &mut lazy_requests, &config, route, request, editor.to_editor.sender(), &to_session);
+                    continue 'event_loop;
+                }
+
+                // an editor client closed, each controller drops the state it kept for it
+                if request.method == "kak-lsp/client-close" {
+                    close_client(&mut controllers, request, editor.to_editor.sender());
                     continue 'event_loop;
                 }
 
-                let language_id = filetypes.get(&request.meta.filetype);
+                if !config.faces.is_empty() && sessions_with_faces.insert(request.meta.session.clone()) {
+                    set_faces(&config, &request.meta.session, editor.to_editor.sender());
+                }
+
+                if request.method == "kak-lsp/stop-server" {
+                    stop_servers(&mut controllers, &mut lazy_requests, &languages, &filetypes, request, editor.to_editor.sender());
+                    continue 'event_loop;
+                }
+
+                if request.method == notification::DidCloseTextDocument::METHOD {
+                    document_filetypes.remove(&(request.meta.session.clone(), request.meta.buffile.clone()));
+                    close_document(&mut controllers, &mut lazy_requests, &mut extra_servers, request, editor.to_editor.sender());
+                    continue 'event_loop;
+                }
+
+                if let Some(close) = close_if_filetype_changed(&mut document_filetypes, &request) {
+                    close_document(&mut controllers, &mut lazy_requests, &mut extra_servers, close, editor.to_editor.sender());
+                }
+
+                if config.auto_detect_servers && filetypes.get(&request.meta.session, &request.meta.filetype).is_none() {
+                    if request.method == "kak-lsp/start-detected-server" {
+                        if let Some((language_id, language)) = detect_server_cached(&mut detected_servers, &request.meta.filetype) {
+                            info!("Starting detected language server `{}`", language.command);
+                            // Only this editor session uses the server. A configured language of
+                            // the same name takes precedence.
+                            for filetype in &language.filetypes {
+                                filetypes.detected.insert((request.meta.session.clone(), filetype.clone()), language_id.clone());
+                            }
+                            languages.entry(language_id.clone()).or_insert_with(|| language.clone());
+                            config.language.entry(language_id).or_insert(language);
+                        }
+                    } else if offer_detected_server(&mut offered_servers, &mut detected_servers, &request, editor.to_editor.sender()) {
+                        continue 'event_loop;
+                    }
+                }
+
+                let language_id = filetypes.get(&request.meta.session, &request.meta.filetype);
                 if language_id.is_none() {
                     debug!(
                         "Language server is not configured for filetype `{}`",
@@ -107,41 +237,22 @@ pub fn start(config: &Config, initial_request: Option<String>) -> i32 {
 
                 let root_path = find_project_root(language_id, &languages[language_id].roots, &request.meta.buffile);
                 let route = Route {
-                    session: request.meta.session.clone(),
+                    session: route_session(&config, &request.meta.session),
                     language: language_id.clone(),
                     root: root_path.clone(),
                 };
 
-                debug!("Routing editor request to {:?}", route);
-
-                use std::collections::hash_map::Entry;
-                match controllers.entry(route.clone()) {
-                    Entry::Occupied(controller_entry) => {
-                        if let Err(err) = controller_entry.get().worker.sender().send(request.clone())  {
-                            if let Some(fifo) = request.meta.fifo {
-                                cancel_blocking_request(fifo);
-                            }
-                            controller_entry.remove();
-                            error!("Failed to send message to controller: {}", err);
-                            continue 'event_loop;
-                        }
-                    }
-                    Entry::Vacant(controller_entry) => {
-                        // As Kakoune triggers BufClose after KakEnd we don't want to spawn a
-                        // new controller in that case. In normal situation it's unlikely to
-                        // get didClose message without running controller, unless it crashed
-                        // before. In that case didClose can be safely ignored as well.
-                        if request.method != notification::DidCloseTextDocument::METHOD {
-                            debug!("Spawning a new controller for {:?}", route);
-                            controller_entry.insert(spawn_controller(
-                                config.clone(),
-                                route,
-                                request,
-                                editor.to_editor.sender().clone(),
-                            ));
-                        }
-                    }
+                let to_editor = editor.to_editor.sender();
+                let document_language_id = document_language_id(language_id, &languages[language_id], &request.meta.filetype);
+                let extra = extra_routes(&mut extra_servers, &config, &languages, language_id, &request);
+                let secondary_servers = extra.len();
+                for extra_route in extra {
+                    let mut request = request.clone();
+                    mark_secondary(&mut request, &document_language_id);
+                    route_request(&mut controllers, &mut lazy_requests, &config, extra_route, request, to_editor, &to_session);
                 }
+                let request = count_secondary_servers(request, secondary_servers);
+                route_request(&mut controllers, &mut lazy_requests, &config, route, request, to_editor, &to_session);
             }
         }
     }
@@ -149,6 +260,716 @@ pub fn start(config: &Config, initial_request: Option<String>) -> i32 {
     0
 }
 
+/// Routes a request from a controller to the primary server of its buffer, that is, the server for
+/// the filetype the buffer was last synchronized with.
+#[allow(clippy::too_many_arguments)]
+fn route_to_primary_server(
+    controllers: &mut Controllers,
+    lazy_requests: &mut LazyRequests,
+    config: &Config,
+    languages: &HashMap<LanguageId, LanguageConfig>,
+    filetypes: &Filetypes,
+    document_filetypes: &DocumentFiletypes,
+    mut request: EditorRequest,
+    to_editor: &Sender<EditorResponse>,
+    to_session: &Sender<EditorRequest>,
+) {
+    let key = (request.meta.session.clone(), request.meta.buffile.clone());
+    let language_id = match document_filetypes.get(&key).and_then(|filetype| {
+        filetypes
+            .get(&request.meta.session, filetype)
+            .map(|language| (filetype, language))
+    }) {
+        Some((filetype, language_id)) => {
+            request.meta.filetype = filetype.clone();
+            language_id
+        }
+        None => return,
+    };
+    // Only running servers can use the request.
+    let route = Route {
+        session: route_session(config, &request.meta.session),
+        language: language_id.clone(),
+        root: find_project_root(
+            language_id,
+            &languages[language_id].roots,
+            &request.meta.buffile,
+        ),
+    };
+    if !controllers.contains_key(&route) {
+        return;
+    }
+    route_request(
+        controllers,
+        lazy_requests,
+        config,
+        route,
+        request,
+        to_editor,
+        to_session,
+    );
+}
+
+/// Start the language servers that were running before kak-lsp was restarted, so they reopen their
+/// documents. Lazily started servers will reopen them once they are started.
+fn rehydrate(
+    controllers: &mut Controllers,
+    lazy_requests: &mut LazyRequests,
+    config: &Config,
+    to_editor: &Sender<EditorResponse>,
+    to_session: &Sender<EditorRequest>,
+) {
+    // Shared servers don't know which session each buffer belongs to after a restart.
+    if config.server.share_servers {
+        return;
+    }
+    let session = &config.server.session;
+    for state in session_state::load_session(session) {
+        match config.language.get(&state.language_id) {
+            Some(language) if !language.lazy_start => (),
+            _ => continue,
+        }
+        info!(
+            "Restoring {} language server in project {}",
+            state.language_id, state.root_path
+        );
+        let route = Route {
+            session: session.clone(),
+            language: state.language_id,
+            root: state.root_path,
+        };
+        let request = EditorRequest {
+            meta: EditorMeta {
+                session: session.clone(),
+                ..EditorMeta::default()
+            },
+            method: "kak-lsp/rehydrate".to_string(),
+            params: toml::Value::Table(toml::value::Table::default()),
+            ranges: None,
+        };
+        route_request(
+            controllers,
+            lazy_requests,
+            config,
+            route,
+            request,
+            to_editor,
+            to_session,
+        );
+    }
+}
+
+/// The language of each filetype, from the configuration or, for the editor session that accepted
+/// it, from a detected language server.
+struct Filetypes {
+    configured: HashMap<String, LanguageId>,
+    detected: HashMap<(SessionId, String), LanguageId>,
+}
+
+impl Filetypes {
+    fn get(&self, session: &str, filetype: &str) -> Option<&LanguageId> {
+        self.configured.get(filetype).or_else(|| {
+            self.detected
+                .get(&(session.to_string(), filetype.to_string()))
+        })
+    }
+}
+
+type DetectedServers = HashMap<String, Option<(LanguageId, LanguageConfig)>>;
+
+/// Like `detect_server`, but only searches PATH once per filetype.
+fn detect_server_cached(
+    detected_servers: &mut DetectedServers,
+    filetype: &str,
+) -> Option<(LanguageId, LanguageConfig)> {
+    detected_servers
+        .entry(filetype.to_string())
+        .or_insert_with(|| detect_server(filetype))
+        .clone()
+}
+
+/// Offer to start a language server that was detected on PATH for the request's filetype, once per
+/// editor session and filetype. Returns whether the request was handled that way.
+fn offer_detected_server(
+    offered_servers: &mut HashSet<(SessionId, String)>,
+    detected_servers: &mut DetectedServers,
+    request: &EditorRequest,
+    to_editor: &Sender<EditorResponse>,
+) -> bool {
+    let filetype = &request.meta.filetype;
+    if request.meta.client.is_none()
+        || offered_servers.contains(&(request.meta.session.clone(), filetype.clone()))
+    {
+        return false;
+    }
+    let language = match detect_server_cached(detected_servers, filetype) {
+        Some((_, language)) => language,
+        None => return false,
+    };
+    offered_servers.insert((request.meta.session.clone(), filetype.clone()));
+    let command = format!(
+        "lsp-offer-detected-server {} {}",
+        editor_quote(filetype),
+        editor_quote(&language.command)
+    );
+    // If the editor is expecting a fifo response, give it one, so it won't hang.
+    if let Some(ref fifo) = request.meta.fifo {
+        std::fs::write(fifo, "nop").expect("Failed to write command to fifo");
+    }
+    let response = EditorResponse {
+        meta: EditorMeta {
+            fifo: None,
+            ..request.meta.clone()
+        },
+        command: command.into(),
+    };
+    if let Err(err) = to_editor.send(response) {
+        error!("Failed to send detected server to editor: {err}");
+    }
+    true
+}
+
+/// Apply the faces from the `[faces]` section of the configuration to an editor session.
+fn set_faces(config: &Config, session: &str, to_editor: &Sender<EditorResponse>) {
+    let command = config
+        .faces
+        .iter()
+        .sorted()
+        .map(|(name, face)| {
+            format!(
+                "set-face global {} {}",
+                editor_quote(name),
+                editor_quote(face)
+            )
+        })
+        .join("\n");
+    let response = EditorResponse {
+        meta: EditorMeta {
+            session: session.to_string(),
+            ..EditorMeta::default()
+        },
+        command: command.into(),
+    };
+    if let Err(err) = to_editor.send(response) {
+        error!("Failed to send faces to editor: {err}");
+    }
+}
+
+fn route_request(
+    controllers: &mut Controllers,
+    lazy_requests: &mut LazyRequests,
+    config: &Config,
+    route: Route,
+    request: EditorRequest,
+    to_editor: &Sender<EditorResponse>,
+    to_session: &Sender<EditorRequest>,
+) {
+    debug!("Routing editor request to {:?}", route);
+
+    use std::collections::hash_map::Entry;
+    match controllers.entry(route.clone()) {
+        Entry::Occupied(mut controller_entry) => {
+            if let Some(request) =
+                send_to_controller(&route, controller_entry.get_mut(), request, to_editor)
+            {
+                if let Some(fifo) = request.meta.fifo {
+                    cancel_blocking_request(fifo);
+                }
+                controller_entry.remove();
+                error!("Failed to send message to controller: channel disconnected");
+            }
+        }
+        Entry::Vacant(controller_entry) => {
+            // Servers stopped with lsp-stop-server have an entry, so they are started again
+            // like lazily started ones.
+            let lazy =
+                config.language[&route.language].lazy_start || lazy_requests.contains_key(&route);
+            if lazy && is_passive(&request) {
+                defer_lazy_request(lazy_requests, route, request);
+                return;
+            }
+            debug!("Spawning a new controller for {:?}", route);
+            let mut requests = lazy_requests.remove(&route).unwrap_or_default();
+            requests.push(request);
+            let mut requests = requests.into_iter();
+            let controller = spawn_controller(
+                config.clone(),
+                route,
+                requests.next().unwrap(),
+                to_editor.clone(),
+                to_session.clone(),
+            );
+            for request in requests {
+                if let Err(err) = controller.worker.sender().send(request) {
+                    error!("Failed to send message to controller: {}", err);
+                }
+            }
+            controller_entry.insert(controller);
+        }
+    }
+}
+
+/// Sends a request to a controller without blocking, so a language server that stopped responding
+/// doesn't hold up requests to all other servers. If the controller is busy, notifications wait
+/// in its backlog, and other requests are dropped; so are requests that would overtake waiting
+/// notifications. Returns the request if the controller is gone.
+fn send_to_controller(
+    route: &Route,
+    controller: &mut ControllerHandle,
+    request: EditorRequest,
+    to_editor: &Sender<EditorResponse>,
+) -> Option<EditorRequest> {
+    let request = if controller.backlog.is_empty() {
+        match controller.worker.sender().try_send(request) {
+            Ok(()) => return None,
+            Err(TrySendError::Full(request)) => request,
+            Err(TrySendError::Disconnected(request)) => return Some(request),
+        }
+    } else {
+        request
+    };
+    if is_notification(&request) {
+        debug!(
+            "{} language server is busy, queueing {}",
+            route.language, request.method
+        );
+        queue_notification(&mut controller.backlog, request);
+    } else {
+        warn!(
+            "{} language server is busy, dropping request {}",
+            route.language, request.method
+        );
+        reject_busy_request(route, request, to_editor);
+    }
+    None
+}
+
+/// Whether a request keeps the language server's view of the editor in sync, so it must not be
+/// dropped.
+fn is_notification(request: &EditorRequest) -> bool {
+    matches!(
+        request.method.as_str(),
+        notification::DidOpenTextDocument::METHOD
+            | notification::DidChangeTextDocument::METHOD
+            | notification::DidCloseTextDocument::METHOD
+            | notification::DidSaveTextDocument::METHOD
+            | notification::DidChangeConfiguration::METHOD
+            | "kak-lsp/client-close"
+            | "kak-lsp/secondary-diagnostics"
+            | "kak-lsp/replyServerRequest"
+            | "kak-lsp/secondary-completion"
+    )
+}
+
+/// Adds a notification to a backlog. Every change carries the whole buffer, so a change replaces
+/// a waiting change of the same buffer.
+fn queue_notification(backlog: &mut VecDeque<EditorRequest>, request: EditorRequest) {
+    let is_change = |pending: &EditorRequest| {
+        pending.method == notification::DidChangeTextDocument::METHOD
+            && pending.meta.session == request.meta.session
+            && pending.meta.buffile == request.meta.buffile
+    };
+    if is_change(&request) {
+        let last_of_buffer = backlog.iter_mut().rev().find(|pending| {
+            pending.meta.session == request.meta.session
+                && pending.meta.buffile == request.meta.buffile
+        });
+        if let Some(pending) = last_of_buffer {
+            if is_change(pending) {
+                *pending = request;
+                return;
+            }
+        }
+    }
+    backlog.push_back(request);
+}
+
+/// Sends waiting notifications to controllers that caught up. Returns whether all backlogs are
+/// empty.
+fn flush_backlogs(controllers: &mut Controllers) -> bool {
+    let mut done = true;
+    for controller in controllers.values_mut() {
+        while let Some(request) = controller.backlog.pop_front() {
+            match controller.worker.sender().try_send(request) {
+                Ok(()) => (),
+                Err(TrySendError::Full(request)) => {
+                    controller.backlog.push_front(request);
+                    done = false;
+                    break;
+                }
+                // The controller is removed when the next request is routed to it.
+                Err(TrySendError::Disconnected(_)) => {
+                    controller.backlog.clear();
+                }
+            }
+        }
+    }
+    done
+}
+
+/// Routes to the additional servers of the request's buffer. Only text synchronization is sent
+/// to additional servers, so they know about the buffer, and completion requests, whose results
+/// they send to the primary server.
+fn extra_routes(
+    extra_servers: &mut ExtraServers,
+    config: &Config,
+    languages: &HashMap<LanguageId, LanguageConfig>,
+    language_id: &str,
+    request: &EditorRequest,
+) -> Vec<Route> {
+    let key = (request.meta.session.clone(), request.meta.buffile.clone());
+    let method = request.method.as_str();
+    let servers = match method {
+        notification::DidOpenTextDocument::METHOD | notification::DidChangeTextDocument::METHOD => {
+            let servers: Vec<LanguageId> = request
+                .params
+                .get("servers")
+                .and_then(|servers| servers.as_array())
+                .into_iter()
+                .flatten()
+                .filter_map(|server| server.as_str().map(|s| s.to_string()))
+                .collect();
+            if servers.is_empty() {
+                extra_servers.remove(&key);
+            } else {
+                extra_servers.insert(key, servers.clone());
+            }
+            servers
+        }
+        notification::DidSaveTextDocument::METHOD | request::Completion::METHOD => {
+            extra_servers.get(&key).cloned().unwrap_or_default()
+        }
+        _ => return vec![],
+    };
+    servers
+        .into_iter()
+        .filter(|server| server != language_id)
+        .filter_map(|server| match languages.get(&server) {
+            Some(language) => Some(Route {
+                session: route_session(config, &request.meta.session),
+                root: find_project_root(&server, &language.roots, &request.meta.buffile),
+                language: server,
+            }),
+            None => {
+                warn!(
+                    "Language server `{}` from lsp_servers is not configured",
+                    server
+                );
+                None
+            }
+        })
+        .collect()
+}
+
+/// Remembers the server that sent a server request, so the reply reaches it even if it is an
+/// additional server of the buffer, or serves no buffer of the editor session.
+fn record_reply_route(
+    reply_routes: &mut HashMap<String, Route>,
+    config: &Config,
+    request: &EditorRequest,
+) {
+    let param = |name: &str| {
+        request
+            .params
+            .get(name)
+            .and_then(|value| value.as_str())
+            .map(String::from)
+    };
+    if let (Some(id), Some(language), Some(root)) =
+        (param("id"), param("language_id"), param("root_path"))
+    {
+        let route = Route {
+            session: route_session(config, &request.meta.session),
+            language,
+            root,
+        };
+        reply_routes.insert(id, route);
+    }
+}
+
+/// The server that waits for a reply to a server request, if it recorded its route.
+fn take_reply_route(
+    reply_routes: &mut HashMap<String, Route>,
+    request: &EditorRequest,
+) -> Option<Route> {
+    if request.method != "kak-lsp/replyServerRequest" {
+        return None;
+    }
+    let id = request.params.get("id")?.as_str()?;
+    reply_routes.remove(id)
+}
+
+/// Tells the primary server how many additional servers answer a completion request too, so it
+/// shows the menu once all of them answered.
+fn count_secondary_servers(mut request: EditorRequest, count: usize) -> EditorRequest {
+    if request.method == request::Completion::METHOD && count > 0 {
+        if let Some(params) = request.params.as_table_mut() {
+            params.insert(
+                "secondary_servers".to_string(),
+                toml::Value::Integer(count as i64),
+            );
+        }
+    }
+    request
+}
+
+/// Additional servers should see the buffer in the primary server's language.
+fn mark_secondary(request: &mut EditorRequest, language_id: &str) {
+    if let Some(params) = request.params.as_table_mut() {
+        params.insert("secondary".to_string(), toml::Value::Boolean(true));
+        let has_language_id = params
+            .get("language_id")
+            .and_then(|language_id| language_id.as_str())
+            .filter(|language_id| !language_id.is_empty())
+            .is_some();
+        if !has_language_id {
+            params.insert(
+                "language_id".to_string(),
+                toml::Value::String(language_id.to_string()),
+            );
+        }
+    }
+}
+
+/// Buffers may be opened before their filetype is final, for example when a modeline overrides
+/// the detected filetype. When the filetype of a buffer changes, returns the request to close it,
+/// so it is opened again by the servers for its new filetype, with the right language ID.
+fn close_if_filetype_changed(
+    document_filetypes: &mut DocumentFiletypes,
+    request: &EditorRequest,
+) -> Option<EditorRequest> {
+    if request.meta.buffile.is_empty()
+        || request.meta.filetype.is_empty()
+        || !matches!(
+            request.method.as_str(),
+            notification::DidOpenTextDocument::METHOD | notification::DidChangeTextDocument::METHOD
+        )
+    {
+        return None;
+    }
+    let key = (request.meta.session.clone(), request.meta.buffile.clone());
+    let previous = document_filetypes.insert(key, request.meta.filetype.clone())?;
+    if previous == request.meta.filetype {
+        return None;
+    }
+    debug!(
+        "Filetype of {} changed from {} to {}, reopening it",
+        request.meta.buffile, previous, request.meta.filetype
+    );
+    let mut meta = request.meta.clone();
+    meta.fifo = None;
+    meta.command_fifo = None;
+    meta.write_response_to_fifo = false;
+    Some(EditorRequest {
+        meta,
+        method: notification::DidCloseTextDocument::METHOD.to_string(),
+        params: toml::Value::Table(toml::value::Table::default()),
+        ranges: None,
+    })
+}
+
+/// Closes a buffer in all language servers of its session, since it may have been opened under a
+/// different filetype, or in additional servers. Servers that don't have it open ignore it.
+fn close_document(
+    controllers: &mut Controllers,
+    lazy_requests: &mut LazyRequests,
+    extra_servers: &mut ExtraServers,
+    request: EditorRequest,
+    to_editor: &Sender<EditorResponse>,
+) {
+    let session = &request.meta.session;
+    let buffile = &request.meta.buffile;
+    extra_servers.remove(&(session.clone(), buffile.clone()));
+    for (_, requests) in lazy_requests
+        .iter_mut()
+        .filter(|(route, _)| serves(route, session))
+    {
+        requests
+            .retain(|pending| pending.meta.session != *session || pending.meta.buffile != *buffile);
+    }
+    for (route, controller) in controllers
+        .iter_mut()
+        .filter(|(route, _)| serves(route, session))
+    {
+        if send_to_controller(route, controller, request.clone(), to_editor).is_some() {
+            warn!(
+                "Failed to close {} in {} language server",
+                buffile, route.language
+            );
+        }
+    }
+}
+
+fn close_client(
+    controllers: &mut Controllers,
+    request: EditorRequest,
+    to_editor: &Sender<EditorResponse>,
+) {
+    let session = &request.meta.session;
+    info_box::forget_client(session, request.meta.client.as_deref().unwrap_or_default());
+    for (route, controller) in controllers
+        .iter_mut()
+        .filter(|(route, _)| serves(route, session))
+    {
+        if send_to_controller(route, controller, request.clone(), to_editor).is_some() {
+            warn!(
+                "Failed to close client {:?} in {} language server",
+                request.meta.client, route.language
+            );
+        }
+    }
+}
+
+/// Whether a request is sent automatically by the editor rather than explicitly by the user,
+/// so it should not start a language server that has `lazy_start` set.
+pub fn is_passive(request: &EditorRequest) -> bool {
+    request.meta.hook
+        || matches!(
+            request.method.as_str(),
+            notification::DidOpenTextDocument::METHOD
+                | notification::DidChangeTextDocument::METHOD
+                | notification::DidCloseTextDocument::METHOD
+                | notification::DidSaveTextDocument::METHOD
+        )
+}
+
+/// Stop the language server of the request's buffer, or all servers of the language given as
+/// parameter. They are started again once an LSP feature is explicitly used in one of their
+/// buffers, like lazily started servers.
+fn stop_servers(
+    controllers: &mut Controllers,
+    lazy_requests: &mut LazyRequests,
+    languages: &HashMap<LanguageId, LanguageConfig>,
+    filetypes: &Filetypes,
+    request: EditorRequest,
+    to_editor: &Sender<EditorResponse>,
+) {
+    let session = &request.meta.session;
+    let language = request
+        .params
+        .get("language")
+        .and_then(|language| language.as_str())
+        .filter(|language| !language.is_empty());
+    let stop: Box<dyn Fn(&Route) -> bool> = match language {
+        Some(language) if !languages.contains_key(language) => {
+            let message = format!("language `{}` is not configured", language);
+            return show_stop_servers_result(request.meta, &message, true, to_editor);
+        }
+        Some(language) => {
+            let language = language.to_string();
+            Box::new(move |route| route.language == language)
+        }
+        None => match filetypes.get(session, &request.meta.filetype) {
+            Some(language) => {
+                let root =
+                    find_project_root(language, &languages[language].roots, &request.meta.buffile);
+                let language = language.clone();
+                Box::new(move |route| route.language == language && route.root == root)
+            }
+            None => {
+                let message = format!(
+                    "Language server is not configured for filetype `{}`",
+                    request.meta.filetype
+                );
+                return show_stop_servers_result(request.meta, &message, true, to_editor);
+            }
+        },
+    };
+    let routes: Vec<Route> = controllers
+        .keys()
+        .filter(|route| serves(route, session) && stop(route))
+        .cloned()
+        .collect();
+    if routes.is_empty() {
+        let message = "no matching language server is running";
+        return show_stop_servers_result(request.meta, message, true, to_editor);
+    }
+    let exit = EditorRequest {
+        method: notification::Exit::METHOD.to_string(),
+        params: toml::Value::Table(toml::value::Table::default()),
+        ..request.clone()
+    };
+    for route in &routes {
+        info!("Stopping {} in project {}", route.language, route.root);
+        let controller = controllers.remove(route).unwrap();
+        if controller.worker.sender().send(exit.clone()).is_err() {
+            error!("Failed to send stop message to language server");
+        }
+        drop(controller);
+        session_state::remove(&route.session, &route.language, &route.root);
+        lazy_requests.insert(route.clone(), vec![]);
+    }
+    let message = format!(
+        "stopped {}",
+        routes
+            .iter()
+            .map(|route| format!("{} language server in {}", route.language, route.root))
+            .join(", ")
+    );
+    show_stop_servers_result(request.meta, &message, false, to_editor);
+}
+
+fn show_stop_servers_result(
+    meta: EditorMeta,
+    message: &str,
+    error: bool,
+    to_editor: &Sender<EditorResponse>,
+) {
+    let command = if error {
+        format!("lsp-show-error {}", editor_quote(message))
+    } else {
+        format!("echo {}", editor_quote(&format!("kak-lsp: {}", message)))
+    };
+    let response = EditorResponse {
+        meta,
+        command: command.into(),
+    };
+    if let Err(err) = to_editor.send(response) {
+        error!("Failed to send command to editor: {err}");
+    }
+}
+
+/// Remember the latest contents of each buffer, so the language server sees them once it is
+/// started. Other passive requests like auto-hover are dropped, they will be outdated by then.
+fn defer_lazy_request(lazy_requests: &mut LazyRequests, route: Route, request: EditorRequest) {
+    let method = request.method.as_str();
+    let is_text_sync = method == notification::DidOpenTextDocument::METHOD
+        || method == notification::DidChangeTextDocument::METHOD;
+    if !is_text_sync {
+        return;
+    }
+    debug!("Deferring {} until the language server is started", method);
+    let requests = lazy_requests.entry(route).or_default();
+    requests.retain(|pending| pending.meta.buffile != request.meta.buffile);
+    requests.push(request);
+}
+
+/// Tell the user that a request was dropped because its language server has too many requests
+/// queued up.
+fn reject_busy_request(route: &Route, request: EditorRequest, to_editor: &Sender<EditorResponse>) {
+    let command = format!(
+        "lsp-show-error {}",
+        editor_quote(&format!(
+            "{} language server is not responding, dropping request",
+            route.language
+        ))
+    );
+    if let Some(fifo) = request.meta.fifo {
+        std::fs::write(fifo, command).expect("Failed to write command to fifo");
+        return;
+    }
+    if request.meta.hook {
+        return;
+    }
+    let response = EditorResponse {
+        meta: request.meta,
+        command: command.into(),
+    };
+    if let Err(err) = to_editor.send(response) {
+        error!("Failed to send error to editor: {err}");
+    }
+}
+
 /// When server is not running it's better to cancel blocking request.
 /// Because server can take a long time to initialize or can fail to start.
 /// We assume that it's less annoying for user to just repeat command later
@@ -160,13 +981,30 @@ fn cancel_blocking_request(fifo: String) {
 }
 
 /// Reap controllers associated with editor session.
-fn exit_editor_session(controllers: &mut Controllers, request: &EditorRequest) {
+fn exit_editor_session(controllers: &mut Controllers, request: &EditorRequest, last_session: bool) {
     info!(
         "Editor session `{}` closed, shutting down associated language servers",
         request.meta.session
     );
+    let session_closed = EditorRequest {
+        method: "kak-lsp/session-closed".to_string(),
+        ..request.clone()
+    };
     controllers.retain(|route, controller| {
-        if route.session == request.meta.session {
+        // Shared servers keep running for the other sessions, they only close the buffers of
+        // this one.
+        if route.session.is_empty() && !last_session {
+            if controller
+                .worker
+                .sender()
+                .send(session_closed.clone())
+                .is_err()
+            {
+                error!("Failed to send session end to language server");
+            }
+            return true;
+        }
+        if serves(route, &request.meta.session) {
             info!("Exit {} in project {}", route.language, route.root);
             // to notify kak-lsp about editor session end we use the same `exit` notification as
             // used in LSP spec to notify language server to exit, thus we can just clone request
@@ -181,6 +1019,21 @@ fn exit_editor_session(controllers: &mut Controllers, request: &EditorRequest) {
     });
 }
 
+/// The session of the route of a request from `session`. With `share_servers`, a language server
+/// serves all sessions that edit its project, so the route has no session.
+fn route_session(config: &Config, session: &str) -> SessionId {
+    if config.server.share_servers {
+        SessionId::new()
+    } else {
+        session.to_string()
+    }
+}
+
+/// Whether the controller of `route` serves the editor session `session`.
+fn serves(route: &Route, session: &str) -> bool {
+    route.session.is_empty() || route.session == session
+}
+
 /// Shut down all language servers and exit.
 fn stop_session(controllers: &mut Controllers) {
     let request = EditorRequest {
@@ -203,13 +1056,17 @@ fn spawn_controller(
     route: Route,
     request: EditorRequest,
     to_editor: Sender<EditorResponse>,
+    to_session: Sender<EditorRequest>,
 ) -> ControllerHandle {
     // NOTE 1024 is arbitrary
     let channel_capacity = 1024;
 
     let worker = Worker::spawn("Controller", channel_capacity, move |receiver, _| {
-        controller::start(to_editor, receiver, &route, request, config);
+        controller::start(to_editor, to_session, receiver, &route, request, config);
     });
 
-    ControllerHandle { worker }
+    ControllerHandle {
+        worker,
+        backlog: VecDeque::new(),
+    }
 }
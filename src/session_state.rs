@@ -0,0 +1,176 @@
+//! Session state that survives restarts of kak-lsp, for example after an upgrade.
+//!
+//! Each controller records the documents it has open and the last diagnostics it received, from
+//! the language server and from `diagnostic_commands`. When kak-lsp is started again for the same
+//! editor session, the language servers are started right away and the documents are reopened,
+//! without the user having to touch every buffer. The state is removed when the editor session
+//! ends.
+
+use crate::context::*;
+use crate::server_cache::fnv1a;
+use crate::util::*;
+use lsp_types::Diagnostic;
+use ropey::Rope;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+/// How long changes are collected before the state is written, so busy servers that publish
+/// diagnostics on every keystroke don't cause a write each time.
+const STORE_DELAY: Duration = Duration::from_secs(5);
+
+type Diagnostics = HashMap<String, Vec<Diagnostic>>;
+
+/// What we remember about a controller, per editor session, language and project.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct RouteState {
+    pub language_id: String,
+    pub root_path: String,
+    pub documents: Vec<DocumentState>,
+    pub diagnostics: Diagnostics,
+    #[serde(default)]
+    pub command_diagnostics: Diagnostics,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct DocumentState {
+    pub buffile: String,
+    pub secondary: bool,
+    pub language_id: String,
+}
+
+fn session_dir(session: &str) -> PathBuf {
+    let mut path = temp_dir();
+    path.push(format!("{}.state", session));
+    path
+}
+
+fn state_path(session: &str, language_id: &str, root_path: &str) -> PathBuf {
+    let mut path = session_dir(session);
+    path.push(format!(
+        "{:016x}.json",
+        fnv1a(format!("{}\0{}", language_id, root_path).as_bytes())
+    ));
+    path
+}
+
+/// Loads the state of all controllers of an editor session.
+pub fn load_session(session: &str) -> Vec<RouteState> {
+    let entries = match fs::read_dir(session_dir(session)) {
+        Ok(entries) => entries,
+        Err(_) => return vec![],
+    };
+    entries
+        .filter_map(|entry| {
+            let path = entry.ok()?.path();
+            let contents = fs::read_to_string(&path).ok()?;
+            match serde_json::from_str(&contents) {
+                Ok(state) => Some(state),
+                Err(e) => {
+                    warn!("Ignoring malformed session state {}: {}", path.display(), e);
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+/// Loads the state of the current controller, reading the documents from disk.
+/// Documents that can no longer be read are skipped. Their buffers may have unsaved changes, so
+/// they start at version 0, to accept the next change from the editor whatever its timestamp.
+pub fn load(ctx: &Context) -> Option<(HashMap<String, Document>, Diagnostics, Diagnostics)> {
+    if ctx.config.server.share_servers {
+        return None;
+    }
+    let path = state_path(&ctx.session, &ctx.language_id, &ctx.root_path);
+    let contents = fs::read_to_string(&path).ok()?;
+    let state: RouteState = match serde_json::from_str(&contents) {
+        Ok(state) => state,
+        Err(e) => {
+            warn!("Ignoring malformed session state {}: {}", path.display(), e);
+            return None;
+        }
+    };
+    let documents = state
+        .documents
+        .into_iter()
+        .filter_map(|document| match read_utf8_document(&document.buffile) {
+            Ok(text) => Some((
+                document.buffile,
+                Document {
+                    version: 0,
+                    server_version: 0,
+                    text: Rope::from_str(&text),
+                    secondary: document.secondary,
+                    language_id: document.language_id,
+                },
+            )),
+            Err(err) => {
+                warn!("Not reopening {}: {}", document.buffile, err);
+                None
+            }
+        })
+        .collect();
+    Some((documents, state.diagnostics, state.command_diagnostics))
+}
+
+/// Writes the state of the current controller soon, see `STORE_DELAY`.
+pub fn schedule_store(ctx: &mut Context) {
+    if ctx.session_state_due.is_none() {
+        ctx.session_state_due = Some(Instant::now() + STORE_DELAY);
+    }
+}
+
+/// Writes the state of the current controller.
+pub fn store(ctx: &mut Context) {
+    ctx.session_state_due = None;
+    // Shared servers are not restored, see `rehydrate`.
+    if ctx.config.server.share_servers {
+        return;
+    }
+    let state = RouteState {
+        language_id: ctx.language_id.clone(),
+        root_path: ctx.root_path.clone(),
+        documents: ctx
+            .documents
+            .iter()
+            .map(|(buffile, document)| DocumentState {
+                buffile: buffile.clone(),
+                secondary: document.secondary,
+                language_id: document.language_id.clone(),
+            })
+            .collect(),
+        diagnostics: ctx.diagnostics.clone(),
+        command_diagnostics: ctx.command_diagnostics.clone(),
+    };
+    let path = state_path(&ctx.session, &ctx.language_id, &ctx.root_path);
+    let result = path
+        .parent()
+        .map_or(Ok(()), fs::create_dir_all)
+        .and_then(|()| fs::write(&path, serde_json::to_string(&state).unwrap()));
+    if let Err(e) = result {
+        warn!("Failed to write session state {}: {}", path.display(), e);
+    }
+}
+
+/// Forgets the state of a controller whose language server was stopped.
+pub fn remove(session: &str, language_id: &str, root_path: &str) {
+    let path = state_path(session, language_id, root_path);
+    if let Err(e) = fs::remove_file(&path) {
+        if e.kind() != std::io::ErrorKind::NotFound {
+            warn!("Failed to remove session state {}: {}", path.display(), e);
+        }
+    }
+}
+
+/// Forgets the state of an editor session that has ended.
+pub fn remove_session(session: &str) {
+    let path = session_dir(session);
+    if let Err(e) = fs::remove_dir_all(&path) {
+        if e.kind() != std::io::ErrorKind::NotFound {
+            warn!("Failed to remove session state {}: {}", path.display(), e);
+        }
+    }
+}
@@ -1,7 +1,14 @@
 use crate::context::*;
 use crate::types::*;
 use crate::util::*;
+use crossbeam_channel::bounded;
 use serde_json::Value;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::Duration;
+
+/// How long `initialization_options_command` may run. The server is not started until it is done.
+const INITIALIZATION_OPTIONS_TIMEOUT: Duration = Duration::from_secs(10);
 
 pub fn request_dynamic_configuration_from_kakoune(
     meta: &EditorMeta,
@@ -21,6 +28,15 @@ pub fn request_initialization_options_from_kakoune(
     meta: &EditorMeta,
     ctx: &mut Context,
 ) -> Option<Value> {
+    // A server started by `--query` may have no editor, which would never answer.
+    let started_by_query = ctx
+        .pending_requests
+        .iter()
+        .any(|request| request.meta.write_response_to_fifo);
+    if started_by_query && !kakoune_session_exists(&meta.session) {
+        let lang = ctx.config.language.get(&ctx.language_id).unwrap();
+        return configured_section(ctx, lang.settings.as_ref());
+    }
     request_dynamic_configuration_from_kakoune(meta, ctx);
     let settings = ctx
         .dynamic_config
@@ -41,6 +57,69 @@ pub fn request_initialization_options_from_kakoune(
     configured_section(ctx, lang.settings.as_ref())
 }
 
+/// Runs the language's `initialization_options_command`, if any, in the project root, and returns
+/// its output parsed as JSON. Failures are reported, so the settings are used instead.
+pub fn initialization_options_from_command(meta: &EditorMeta, ctx: &mut Context) -> Option<Value> {
+    let config = ctx.language_config();
+    let command = config.initialization_options_command.clone()?;
+    let result = Command::new("sh")
+        .arg("-c")
+        .arg(&command)
+        .envs(&config.envs)
+        .current_dir(&ctx.root_path)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|err| err.to_string())
+        .and_then(|child| {
+            let pid = child.id();
+            let (sender, receiver) = bounded(1);
+            thread::spawn(move || {
+                let _ = sender.send(child.wait_with_output());
+            });
+            match receiver.recv_timeout(INITIALIZATION_OPTIONS_TIMEOUT) {
+                Ok(output) => output.map_err(|err| err.to_string()),
+                Err(_) => {
+                    unsafe { libc::kill(pid as libc::pid_t, libc::SIGKILL) };
+                    Err(format!(
+                        "timed out after {:?}",
+                        INITIALIZATION_OPTIONS_TIMEOUT
+                    ))
+                }
+            }
+        })
+        .and_then(|output| {
+            if output.status.success() {
+                serde_json::from_slice(&output.stdout).map_err(|err| err.to_string())
+            } else {
+                Err(format!(
+                    "{}: {}",
+                    output.status,
+                    String::from_utf8_lossy(&output.stderr).trim()
+                ))
+            }
+        });
+    match result {
+        Ok(options) => {
+            debug!("initialization options from `{}`:\n{}", command, options);
+            Some(options)
+        }
+        Err(err) => {
+            let msg = format!(
+                "initialization_options_command `{}` failed: {}",
+                command, err
+            );
+            error!("{}", msg);
+            ctx.exec(
+                meta.clone(),
+                format!("lsp-show-error {}", editor_quote(&msg)),
+            );
+            None
+        }
+    }
+}
+
 pub fn configured_section(ctx: &Context, settings: Option<&Value>) -> Option<Value> {
     settings.and_then(|settings| {
         ctx.config
@@ -99,54 +178,65 @@ pub fn request_legacy_initialization_options_from_kakoune(
     }
 }
 
-fn insert_value<'a, 'b, P>(
-    target: &'b mut serde_json::Map<String, Value>,
-    mut path: P,
-    local_key: String,
-    value: Value,
-) -> Result<(), String>
-where
-    P: Iterator<Item = &'a str>,
-    P: 'a,
-{
-    match path.next() {
-        Some(key) => {
-            let maybe_new_target = target
-                .entry(key)
-                .or_insert_with(|| Value::Object(serde_json::Map::new()))
-                .as_object_mut();
-
-            if maybe_new_target.is_none() {
-                return Err(format!(
-                    "Expected path {:?} to be object, found {:?}",
-                    key, &maybe_new_target,
-                ));
-            }
+/// A setting with this value is removed, since TOML has no null.
+const DELETE_MARKER: &str = "!delete";
+
+fn is_delete_marker(value: &Value) -> bool {
+    value.is_null() || value.as_str() == Some(DELETE_MARKER)
+}
 
-            insert_value(maybe_new_target.unwrap(), path, local_key, value)
+/// Sets the value at the given path of keys. Objects are merged into existing objects key by key.
+/// Their keys are kept as they are, since they may contain dots, like URLs or globs. Other values,
+/// including arrays, replace existing values; so do objects that are in the way of a path.
+/// Deletion markers remove the key.
+fn insert_value(target: &mut serde_json::Map<String, Value>, path: &[&str], value: Value) {
+    let (key, path) = match path.split_first() {
+        Some(split) => split,
+        None => return,
+    };
+    if !path.is_empty() {
+        let child = target
+            .entry(*key)
+            .or_insert_with(|| Value::Object(serde_json::Map::new()));
+        if !child.is_object() {
+            *child = Value::Object(serde_json::Map::new());
+        }
+        return insert_value(child.as_object_mut().unwrap(), path, value);
+    }
+    if is_delete_marker(&value) {
+        target.remove(*key);
+        return;
+    }
+    match (target.get_mut(*key), value) {
+        (Some(Value::Object(old)), Value::Object(new)) => merge_object(old, new),
+        (_, Value::Object(new)) => {
+            let mut object = serde_json::Map::new();
+            merge_object(&mut object, new);
+            target.insert(key.to_string(), Value::Object(object));
+        }
+        (_, value) => {
+            target.insert(key.to_string(), value);
         }
-        None => match target.insert(local_key, value) {
-            Some(old_value) => Err(format!("Replaced old value: {:?}", old_value)),
-            None => Ok(()),
-        },
     }
 }
-// Take flattened tables like "a.b = 1" and produce "{"a":{"b":1}}".
+
+fn merge_object(
+    target: &mut serde_json::Map<String, Value>,
+    value: serde_json::Map<String, Value>,
+) {
+    for (key, value) in value {
+        insert_value(target, &[&key], value);
+    }
+}
+
+// Take flattened tables like "a.b = 1" and produce "{"a":{"b":1}}". Only the top-level keys are
+// split at dots.
 pub fn explode_string_table(
     raw_settings: &toml::value::Table,
 ) -> serde_json::value::Map<String, Value> {
     let mut settings = serde_json::Map::new();
 
     for (raw_key, raw_value) in raw_settings.iter() {
-        let mut key_parts = raw_key.split('.');
-        let local_key = match key_parts.next_back() {
-            Some(name) => name,
-            None => {
-                warn!("Got a setting with an empty local name: {:?}", raw_key);
-                continue;
-            }
-        };
-
         let value: Value = match raw_value.clone().try_into() {
             Ok(value) => value,
             Err(e) => {
@@ -154,15 +244,54 @@ pub fn explode_string_table(
                 continue;
             }
         };
-
-        match insert_value(&mut settings, key_parts, local_key.into(), value) {
-            Ok(_) => (),
-            Err(e) => {
-                warn!("Could not set {:?} to {:?}: {}", raw_key, raw_value, e);
-                continue;
-            }
-        }
+        let path: Vec<&str> = raw_key.split('.').collect();
+        insert_value(&mut settings, &path, value);
     }
 
     settings
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn explode(toml: &str) -> Value {
+        Value::Object(explode_string_table(&toml::from_str(toml).unwrap()))
+    }
+
+    #[test]
+    fn test_explode_string_table() {
+        assert_eq!(
+            explode(
+                r#"
+                "a.b" = 1
+                "a.c" = { d = true, "e.f" = "g" }
+                "a.c.h" = [1, 2]
+                "yaml.schemas" = { "https://json.schemastore.org/github-workflow.json" = "*.yml" }
+                "a.i" = [{ "*.md" = 1 }]
+                "#
+            ),
+            json!({
+                "a": {"b": 1, "c": {"d": true, "e.f": "g", "h": [1, 2]}, "i": [{"*.md": 1}]},
+                "yaml": {"schemas": {"https://json.schemastore.org/github-workflow.json": "*.yml"}}
+            })
+        );
+    }
+
+    #[test]
+    fn test_explode_string_table_replace_and_delete() {
+        assert_eq!(
+            explode(
+                r#"
+                "eslint.rules" = { semi = "error", quotes = ["error", "double"] }
+                "eslint.rules.quotes" = ["warn", "single"]
+                "eslint.rules.semi" = "!delete"
+                "x" = 1
+                "x.y" = 2
+                "#
+            ),
+            json!({"eslint": {"rules": {"quotes": ["warn", "single"]}}, "x": {"y": 2}})
+        );
+    }
+}
@@ -0,0 +1,312 @@
+//! Parsing of LSP snippets, for the `selections` snippet backend.
+//!
+//! The snippet is expanded to the text to insert, and the position of each placeholder relative
+//! to the insertion point, so Kakoune only needs to insert the text and add the placeholders to
+//! `lsp_snippets_placeholders`. Unlike the regex-based `perl` backend, this supports nested
+//! placeholders, choices and variables.
+
+use std::ops::Range;
+
+#[derive(Debug, PartialEq)]
+pub struct Snippet {
+    pub text: String,
+    pub placeholders: Vec<Placeholder>,
+}
+
+/// A placeholder, with the byte range of its default text in the expanded snippet.
+/// Placeholders without default text are expanded to a single space, so they can be selected.
+#[derive(Debug, PartialEq)]
+pub struct Placeholder {
+    pub tabstop: u32,
+    pub range: Range<usize>,
+}
+
+/// Expands a snippet. Variables are replaced by their default text, if any, and choices by
+/// their first option. Text that doesn't parse as snippet syntax is inserted verbatim.
+pub fn parse(snippet: &str) -> Snippet {
+    parse_with(snippet, true)
+}
+
+/// Expands a snippet to plain text, leaving placeholders without default text empty.
+pub fn plain_text(snippet: &str) -> String {
+    parse_with(snippet, false).text
+}
+
+fn parse_with(snippet: &str, fill_empty_placeholders: bool) -> Snippet {
+    let mut parser = Parser {
+        chars: snippet.chars().collect(),
+        pos: 0,
+        fill_empty_placeholders,
+        snippet: Snippet {
+            text: String::new(),
+            placeholders: vec![],
+        },
+    };
+    parser.parse_any(false);
+    parser.snippet
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    fill_empty_placeholders: bool,
+    snippet: Snippet,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Parses text and snippet elements. Inside a placeholder, stops before an unescaped `}`.
+    fn parse_any(&mut self, in_placeholder: bool) {
+        while let Some(c) = self.peek() {
+            match c {
+                '}' if in_placeholder => return,
+                '\\' if matches!(self.chars.get(self.pos + 1), Some('$' | '}' | '\\')) => {
+                    self.snippet.text.push(self.chars[self.pos + 1]);
+                    self.pos += 2;
+                }
+                '$' => {
+                    let start = self.pos;
+                    let text_len = self.snippet.text.len();
+                    let placeholders_len = self.snippet.placeholders.len();
+                    self.pos += 1;
+                    if !self.parse_dollar() {
+                        // Not snippet syntax after all, insert it verbatim.
+                        self.pos = start + 1;
+                        self.snippet.text.truncate(text_len);
+                        self.snippet.placeholders.truncate(placeholders_len);
+                        self.snippet.text.push('$');
+                    }
+                }
+                _ => {
+                    self.snippet.text.push(c);
+                    self.pos += 1;
+                }
+            }
+        }
+    }
+
+    /// Parses what follows a `$`. Returns false if it is not valid snippet syntax.
+    fn parse_dollar(&mut self) -> bool {
+        if let Some(tabstop) = self.parse_int() {
+            self.push_placeholder(tabstop, self.snippet.text.len());
+            return true;
+        }
+        if self.skip_variable_name() {
+            return true;
+        }
+        if !self.eat('{') {
+            return false;
+        }
+        if let Some(tabstop) = self.parse_int() {
+            let start = self.snippet.text.len();
+            if self.eat('}') {
+                self.push_placeholder(tabstop, start);
+                return true;
+            }
+            if self.eat(':') {
+                self.parse_any(true);
+                if !self.eat('}') {
+                    return false;
+                }
+                self.push_placeholder(tabstop, start);
+                return true;
+            }
+            if self.eat('|') {
+                let choice = match self.parse_choice() {
+                    Some(choice) => choice,
+                    None => return false,
+                };
+                self.snippet.text.push_str(&choice);
+                self.push_placeholder(tabstop, start);
+                return true;
+            }
+            return false;
+        }
+        if !self.skip_variable_name() {
+            return false;
+        }
+        if self.eat('}') {
+            return true;
+        }
+        if self.eat(':') {
+            self.parse_any(true);
+            return self.eat('}');
+        }
+        if self.eat('/') {
+            // A transform, which needs the variable's value, so skip it.
+            while let Some(c) = self.peek() {
+                self.pos += 1;
+                match c {
+                    '\\' => self.pos += 1,
+                    '}' => return true,
+                    _ => (),
+                }
+            }
+        }
+        false
+    }
+
+    fn parse_int(&mut self) -> Option<u32> {
+        let start = self.pos;
+        while matches!(self.peek(), Some(c) if c.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        self.chars[start..self.pos]
+            .iter()
+            .collect::<String>()
+            .parse()
+            .ok()
+    }
+
+    fn skip_variable_name(&mut self) -> bool {
+        if !matches!(self.peek(), Some(c) if c == '_' || c.is_ascii_alphabetic()) {
+            return false;
+        }
+        while matches!(self.peek(), Some(c) if c == '_' || c.is_ascii_alphanumeric()) {
+            self.pos += 1;
+        }
+        true
+    }
+
+    /// Parses the options of a choice up to the closing `|}` and returns the first one.
+    fn parse_choice(&mut self) -> Option<String> {
+        let mut options = vec![String::new()];
+        while let Some(c) = self.peek() {
+            self.pos += 1;
+            match c {
+                '\\' => {
+                    let escaped = self.peek()?;
+                    if !matches!(escaped, '$' | '}' | '\\' | ',' | '|') {
+                        options.last_mut().unwrap().push('\\');
+                    }
+                    options.last_mut().unwrap().push(escaped);
+                    self.pos += 1;
+                }
+                ',' => options.push(String::new()),
+                '|' => {
+                    return if self.eat('}') {
+                        options.into_iter().next()
+                    } else {
+                        None
+                    }
+                }
+                _ => options.last_mut().unwrap().push(c),
+            }
+        }
+        None
+    }
+
+    fn push_placeholder(&mut self, tabstop: u32, start: usize) {
+        if self.fill_empty_placeholders && self.snippet.text.len() == start {
+            self.snippet.text.push(' ');
+        }
+        self.snippet.placeholders.push(Placeholder {
+            tabstop,
+            range: start..self.snippet.text.len(),
+        });
+    }
+}
+
+/// Indents all lines but the first of a snippet with `indent`, like the line the snippet is
+/// inserted into. Tabs at the start of a line are replaced with `indent_unit`.
+pub fn indent(snippet: Snippet, indent: &str, indent_unit: &str) -> Snippet {
+    let mut text = String::new();
+    // The offset in the new text of each byte offset of the old text.
+    let mut offsets = Vec::with_capacity(snippet.text.len() + 1);
+    let mut line_start = false;
+    for (i, c) in snippet.text.char_indices() {
+        while offsets.len() <= i {
+            offsets.push(text.len());
+        }
+        if line_start && c == '\t' {
+            text.push_str(indent_unit);
+            continue;
+        }
+        line_start = false;
+        text.push(c);
+        if c == '\n' {
+            text.push_str(indent);
+            line_start = true;
+        }
+    }
+    while offsets.len() <= snippet.text.len() {
+        offsets.push(text.len());
+    }
+    let placeholders = snippet
+        .placeholders
+        .into_iter()
+        .map(|Placeholder { tabstop, range }| Placeholder {
+            tabstop,
+            range: offsets[range.start]..offsets[range.end],
+        })
+        .collect();
+    Snippet { text, placeholders }
+}
+
+/// Returns the line offset and byte column, both starting at 0, of a byte offset in `text`.
+pub fn line_and_column(text: &str, offset: usize) -> (usize, usize) {
+    let before = &text[..offset];
+    match before.rfind('\n') {
+        Some(newline) => (before.matches('\n').count(), offset - newline - 1),
+        None => (0, offset),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn placeholders(snippet: &Snippet) -> Vec<(u32, &str)> {
+        snippet
+            .placeholders
+            .iter()
+            .map(|p| (p.tabstop, &snippet.text[p.range.clone()]))
+            .collect()
+    }
+
+    #[test]
+    fn test_parse() {
+        let snippet = parse(r"foo(${1:a, ${2:b}}, $3)$0");
+        assert_eq!(snippet.text, "foo(a, b,  ) ");
+        assert_eq!(
+            placeholders(&snippet),
+            vec![(2, "b"), (1, "a, b"), (3, " "), (0, " ")]
+        );
+
+        let snippet = parse(r"${1|one,t\,wo|} ${TM_FILENAME:file} \$1 ${2:\}} $ 5");
+        assert_eq!(snippet.text, "one file $1 } $ 5");
+        assert_eq!(placeholders(&snippet), vec![(1, "one"), (2, "}")]);
+
+        let snippet = parse("${1:unterminated");
+        assert_eq!(snippet.text, "${1:unterminated");
+        assert_eq!(placeholders(&snippet), vec![]);
+    }
+
+    #[test]
+    fn test_plain_text() {
+        assert_eq!(plain_text(r"foo(${1:a, ${2:b}}, $3)$0"), "foo(a, b, )");
+    }
+
+    #[test]
+    fn test_indent() {
+        let snippet = indent(parse("fn $1() {\n\t$0\n}"), "    ", "  ");
+        assert_eq!(snippet.text, "fn  () {\n       \n    }");
+        assert_eq!(placeholders(&snippet), vec![(1, " "), (0, " ")]);
+        let end = snippet.placeholders[1].range.end;
+        assert_eq!(line_and_column(&snippet.text, end - 1), (1, 6));
+        let start = snippet.placeholders[0].range.start;
+        assert_eq!(line_and_column(&snippet.text, start), (0, 3));
+    }
+}
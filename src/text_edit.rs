@@ -77,17 +77,19 @@ pub fn apply_annotated_text_edits<T: TextEditish<T>>(
     edits: Vec<T>,
     ctx: &Context,
 ) {
-    if let Some(document) = uri
-        .to_file_path()
-        .ok()
-        .and_then(|path| path.to_str().and_then(|buffile| ctx.documents.get(buffile)))
-    {
+    if let Some((document, version)) = uri.to_file_path().ok().and_then(|path| {
+        let buffile = path.to_str()?;
+        let document = ctx.documents.get(buffile)?;
+        Some((document, ctx.document_version(&meta.session, buffile)?))
+    }) {
         let meta = meta.clone();
         match apply_text_edits_to_buffer(
             &meta.client,
             Some(uri),
             edits,
             &document.text,
+            // The buffer may have been modified since we last saw it.
+            Some(version),
             ctx.offset_encoding,
         ) {
             Some(cmd) => ctx.exec(meta, cmd),
@@ -206,6 +208,38 @@ pub fn apply_text_edits_to_file<T: TextEditish<T>>(
         })
 }
 
+/// Returns the text with the edits applied, or None if an edit is out of range.
+pub fn apply_text_edits_to_text<T: TextEditish<T>>(
+    text: &Rope,
+    text_edits: &[T],
+    offset_encoding: OffsetEncoding,
+) -> Option<Rope> {
+    let char_index = |position: Position| {
+        let line = position.line as usize;
+        if line >= text.len_lines() {
+            return None;
+        }
+        let character = position.character as usize;
+        let line_text = text.line(line);
+        // The end of a line without newline, like the last one, is a valid position too.
+        let offset = character_to_offset(offset_encoding, line_text, character)
+            .or_else(|| (character == line_text.len_chars()).then_some(character))?;
+        Some(text.line_to_char(line) + offset)
+    };
+    let mut edits: Vec<&TextEdit> = text_edits.iter().map(|te| te.as_ref()).collect();
+    edits.sort_by_key(|edit| (edit.range.start.line, edit.range.start.character));
+    let mut result = text.clone();
+    // Back to front, so the positions of the remaining edits stay valid. Inserts at the same
+    // position end up in their original order.
+    for edit in edits.into_iter().rev() {
+        let start = char_index(edit.range.start)?;
+        let end = char_index(edit.range.end)?;
+        result.remove(start..end);
+        result.insert(start, &edit.new_text);
+    }
+    Some(result)
+}
+
 // Adapted from std/src/sys/unix/mod.rs.
 fn cvt(t: i32) -> std::io::Result<i32> {
     if t == -1 {
@@ -271,10 +305,13 @@ fn byte_to_offset_utf_8_code_units(line: RopeSlice, character: usize) -> Option<
     }
 }
 
+/// If `timestamp` is given, it is the buffer timestamp that `text` corresponds to. Kakoune
+/// adjusts the edited ranges for any modifications made after that timestamp.
 pub fn lsp_text_edits_to_kakoune<T: TextEditish<T>>(
     client: &Option<String>,
     mut text_edits: Vec<T>,
     text: &Rope,
+    timestamp: Option<i32>,
     offset_encoding: OffsetEncoding,
 ) -> Option<String> {
     // Empty text edits processed as a special case because Kakoune's `select` command
@@ -285,41 +322,8 @@ pub fn lsp_text_edits_to_kakoune<T: TextEditish<T>>(
 
     // If the text edit just replaces the whole buffer, compute a minimal edit sequence to
     // maintain selections better.
-    if client.is_some() && text_edits.len() == 1 {
-        let range = text_edits[0].as_ref().range;
-
-        let text_begin = Position {
-            line: 0,
-            character: 0,
-        };
-        let last_line = text.line(text.len_lines() - 1);
-        let missing_eol = {
-            let line_len = last_line.len_chars();
-            line_len == 0 || last_line.char(line_len - 1) == '\n'
-        };
-        let text_end = if missing_eol {
-            Position {
-                line: (text.len_lines() - 1) as _,
-                character: (last_line.len_chars().saturating_sub(1)) as _,
-            }
-        } else {
-            Position {
-                line: text.len_lines() as _,
-                character: 0,
-            }
-        };
-
-        if range.start == text_begin && range.end >= text_end {
-            text_edits = minimal_edit_sequence(
-                text,
-                &Rope::from_str(&text_edits[0].as_ref().new_text),
-                if missing_eol { Some(text_end) } else { None },
-            );
-            debug!("Computed edit script to split up whole-buffer text edit");
-            for te in &text_edits {
-                debug!("{:?}", te.as_ref());
-            }
-        }
+    if client.is_some() {
+        text_edits = split_whole_buffer_edit(text_edits, text);
     }
 
     // Adjoin selections detection and Kakoune side editing relies on edits being ordered left to
@@ -427,9 +431,12 @@ pub fn lsp_text_edits_to_kakoune<T: TextEditish<T>>(
 
     if !selections_desc.is_empty() {
         apply_edits = formatdoc!(
-            "select {}
+            "select {}{}
              execute-keys -save-regs \"\" Z
              {}",
+            timestamp
+                .map(|timestamp| format!("-timestamp {} ", timestamp))
+                .unwrap_or_default(),
             selections_desc,
             apply_edits
         );
@@ -443,9 +450,11 @@ pub fn apply_text_edits_to_buffer<T: TextEditish<T>>(
     uri: Option<&Url>,
     text_edits: Vec<T>,
     text: &Rope,
+    timestamp: Option<i32>,
     offset_encoding: OffsetEncoding,
 ) -> Option<String> {
-    let apply_edits = lsp_text_edits_to_kakoune(client, text_edits, text, offset_encoding)?;
+    let apply_edits =
+        lsp_text_edits_to_kakoune(client, text_edits, text, timestamp, offset_encoding)?;
 
     let maybe_buffile = uri
         .and_then(|uri| uri.to_file_path().ok())
@@ -492,6 +501,50 @@ pub fn apply_text_edits_to_buffer<T: TextEditish<T>>(
     ))
 }
 
+/// If the only text edit replaces the whole buffer, returns a minimal edit sequence that has the
+/// same effect. Other text edits are returned as is.
+pub fn split_whole_buffer_edit<T: TextEditish<T>>(text_edits: Vec<T>, text: &Rope) -> Vec<T> {
+    if text_edits.len() != 1 {
+        return text_edits;
+    }
+    let range = text_edits[0].as_ref().range;
+
+    let text_begin = Position {
+        line: 0,
+        character: 0,
+    };
+    let last_line = text.line(text.len_lines() - 1);
+    let missing_eol = {
+        let line_len = last_line.len_chars();
+        line_len == 0 || last_line.char(line_len - 1) == '\n'
+    };
+    let text_end = if missing_eol {
+        Position {
+            line: (text.len_lines() - 1) as _,
+            character: (last_line.len_chars().saturating_sub(1)) as _,
+        }
+    } else {
+        Position {
+            line: text.len_lines() as _,
+            character: 0,
+        }
+    };
+
+    if range.start != text_begin || range.end < text_end {
+        return text_edits;
+    }
+    let text_edits: Vec<T> = minimal_edit_sequence(
+        text,
+        &Rope::from_str(&text_edits[0].as_ref().new_text),
+        if missing_eol { Some(text_end) } else { None },
+    );
+    debug!("Computed edit script to split up whole-buffer text edit");
+    for te in &text_edits {
+        debug!("{:?}", te.as_ref());
+    }
+    text_edits
+}
+
 #[derive(PartialEq, Eq, Clone, Copy)]
 enum KakouneTextEditCommand {
     InsertBefore,
@@ -627,6 +680,24 @@ mod tests {
         })
     }
 
+    #[test]
+    pub fn apply_text_edits_to_text_in_order() {
+        let text = Rope::from_str("fn main() {}\nfoo");
+        let text_edits = vec![
+            edit(1, 3, 1, 3, "();"),
+            edit(0, 0, 0, 0, "use a;\n"),
+            edit(0, 0, 0, 0, "use b;\n"),
+            edit(0, 3, 0, 7, "start"),
+        ];
+        let result = apply_text_edits_to_text(&text, &text_edits, OffsetEncoding::Utf16);
+        assert_eq!(
+            result.map(|text| text.to_string()).as_deref(),
+            Some("use a;\nuse b;\nfn start() {}\nfoo();")
+        );
+        let out_of_range = vec![edit(2, 0, 2, 0, "x")];
+        assert!(apply_text_edits_to_text(&text, &out_of_range, OffsetEncoding::Utf16).is_none());
+    }
+
     #[test]
     pub fn lsp_text_edits_to_kakoune_issue_521() {
         let text_edits = vec![
@@ -638,7 +709,8 @@ mod tests {
             edit(0, 21, 0, 21, "{CStr, CString}"),
         ];
         let buffer = Rope::from_str("use std::ffi::CString;");
-        let result = lsp_text_edits_to_kakoune(&None, text_edits, &buffer, OffsetEncoding::Utf8);
+        let result =
+            lsp_text_edits_to_kakoune(&None, text_edits, &buffer, None, OffsetEncoding::Utf8);
         let expected = indoc!(
             r#"select 1.5,1.12 1.15,1.21
                execute-keys -save-regs "" Z
@@ -652,7 +724,8 @@ mod tests {
     pub fn lsp_text_edits_to_kakoune_insert_adjacent_to_replace() {
         let text_edits = vec![edit(0, 1, 0, 1, "inserted"), edit(0, 2, 0, 3, "replaced")];
         let buffer = Rope::from_str("0123");
-        let result = lsp_text_edits_to_kakoune(&None, text_edits, &buffer, OffsetEncoding::Utf8);
+        let result =
+            lsp_text_edits_to_kakoune(&None, text_edits, &buffer, None, OffsetEncoding::Utf8);
         let expected = indoc!(
             r#"select 1.2,1.2 1.3,1.3
                execute-keys -save-regs "" Z
@@ -683,7 +756,8 @@ mod tests {
         _ => {}
     }",
         );
-        let result = lsp_text_edits_to_kakoune(&None, text_edits, &buffer, OffsetEncoding::Utf8);
+        let result =
+            lsp_text_edits_to_kakoune(&None, text_edits, &buffer, None, OffsetEncoding::Utf8);
         let expected = indoc!(
             r#"select 1.5,1.9 1.11,1.13 2.9,2.14
                execute-keys -save-regs "" Z
@@ -749,7 +823,8 @@ mod tests {
                }
                "#
         ));
-        let result = lsp_text_edits_to_kakoune(&None, text_edits, &buffer, OffsetEncoding::Utf8);
+        let result =
+            lsp_text_edits_to_kakoune(&None, text_edits, &buffer, None, OffsetEncoding::Utf8);
 
         let expected = indoc!(
             r#"select 1.5,1.19 2.1,2.24 4.4,4.7 5.9,5.15 5.17,5.17 5.19,5.51 5.53,7.6 7.8,7.36 7.38,7.39 8.2,13.1000000
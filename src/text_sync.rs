@@ -1,16 +1,43 @@
 use crate::context::*;
+use crate::edit_conflict;
 use crate::language_features::code_lens::text_document_code_lens;
+use crate::language_features::document_symbol::invalidate_document_symbols_option;
+use crate::large_file::*;
+use crate::session_state;
 use crate::types::*;
+use crate::util::{document_language_id, editor_quote};
+use itertools::Itertools;
 use lsp_types::notification::*;
 use lsp_types::*;
 use ropey::Rope;
 use serde::Deserialize;
+use std::time::Duration;
 use url::Url;
 
+/// How often to look for documents whose buffers were deleted without telling the server.
+pub const ORPHANED_DOCUMENTS_CHECK_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
 pub fn text_document_did_open(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
+    let editor_params = params.clone();
     let params = TextDocumentDidOpenParams::deserialize(params)
         .expect("Params should follow TextDocumentDidOpenParams structure");
-    let language_id = ctx.language_id.clone();
+    let secondary = params.secondary;
+    ctx.invalid_utf8_buffers.remove(&meta.buffile);
+    let language_id = if params.language_id.is_empty() {
+        document_language_id(&ctx.language_id, ctx.language_config(), &meta.filetype)
+    } else {
+        params.language_id
+    };
+    // The editor opens a buffer again when its filetype is set, which may happen more than once
+    // while the buffer is loaded, for example when a modeline overrides the detected filetype.
+    if let Some(document) = ctx.documents.get(&meta.buffile) {
+        if document.language_id == language_id {
+            text_document_did_change(meta, editor_params, ctx);
+            return;
+        }
+        // Servers only learn the language ID of a document when it is opened.
+        text_document_did_close(meta.clone(), ctx);
+    }
     let params = DidOpenTextDocumentParams {
         text_document: TextDocumentItem {
             uri: Url::from_file_path(&meta.buffile).unwrap(),
@@ -21,11 +48,22 @@ pub fn text_document_did_open(meta: EditorMeta, params: EditorParams, ctx: &mut
     };
     let document = Document {
         version: meta.version,
+        server_version: meta.version,
         text: Rope::from_str(&params.text_document.text),
+        secondary,
+        language_id: params.text_document.language_id.clone(),
     };
     ctx.documents.insert(meta.buffile.clone(), document);
+    record_buffer_version(&meta, ctx);
+    edit_conflict::record_disk_state(&meta.buffile, ctx);
     ctx.notify::<DidOpenTextDocument>(params);
-    text_document_code_lens(meta, ctx);
+    session_state::schedule_store(ctx);
+    if !secondary {
+        update_large_file_mode(&meta, ctx);
+        if !is_large_file(ctx, &meta.buffile) {
+            text_document_code_lens(meta, ctx);
+        }
+    }
 }
 
 pub fn text_document_did_change(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
@@ -33,24 +71,43 @@ pub fn text_document_did_change(meta: EditorMeta, params: EditorParams, ctx: &mu
         .expect("Params should follow TextDocumentDidChangeParams structure");
     let uri = Url::from_file_path(&meta.buffile).unwrap();
     let version = meta.version;
-    let old_version = ctx
+    let language_id = ctx
         .documents
         .get(&meta.buffile)
-        .map(|doc| doc.version)
-        .unwrap_or(0);
+        .map(|doc| doc.language_id.clone())
+        .unwrap_or_else(|| ctx.language_id.clone());
+    // Other sessions that share the server have unrelated timestamps for the same buffer.
+    let old_version = match ctx.buffer_versions.get(&meta.buffile) {
+        Some(versions) => versions.get(&meta.session).copied().unwrap_or(0),
+        None => ctx
+            .documents
+            .get(&meta.buffile)
+            .map_or(0, |document| document.version),
+    };
     if old_version >= version {
         return;
     }
+    // A shared server must not see the version go back when another session changes the buffer.
+    let server_version = ctx
+        .documents
+        .get(&meta.buffile)
+        .map_or(version, |document| version.max(document.server_version + 1));
+    record_buffer_version(&meta, ctx);
+    ctx.invalid_utf8_buffers.remove(&meta.buffile);
     let document = Document {
         version,
+        server_version,
         text: Rope::from_str(&params.draft),
+        secondary: params.secondary,
+        language_id,
     };
     ctx.documents.insert(meta.buffile.clone(), document);
     ctx.diagnostics.insert(meta.buffile.clone(), Vec::new());
+    ctx.prefetched.remove(&meta.buffile);
     let req_params = DidChangeTextDocumentParams {
         text_document: VersionedTextDocumentIdentifier {
             uri,
-            version: meta.version,
+            version: server_version,
         },
         content_changes: vec![TextDocumentContentChangeEvent {
             range: None,
@@ -59,19 +116,138 @@ pub fn text_document_did_change(meta: EditorMeta, params: EditorParams, ctx: &mu
         }],
     };
     ctx.notify::<DidChangeTextDocument>(req_params);
-    text_document_code_lens(meta, ctx);
+    if !params.secondary {
+        invalidate_document_symbols_option(&meta, ctx);
+        update_large_file_mode(&meta, ctx);
+        if !is_large_file(ctx, &meta.buffile) {
+            text_document_code_lens(meta, ctx);
+        }
+    }
+}
+
+/// Remembers that the session of `meta` has the buffer open at the version of `meta`, and that
+/// the server has its content.
+fn record_buffer_version(meta: &EditorMeta, ctx: &mut Context) {
+    ctx.buffer_sessions
+        .insert(meta.buffile.clone(), meta.session.clone());
+    ctx.buffer_versions
+        .entry(meta.buffile.clone())
+        .or_default()
+        .insert(meta.session.clone(), meta.version);
 }
 
 pub fn text_document_did_close(meta: EditorMeta, ctx: &mut Context) {
-    ctx.documents.remove(&meta.buffile);
+    // A shared server keeps the buffer open while other sessions have it open.
+    if let Some(versions) = ctx.buffer_versions.get_mut(&meta.buffile) {
+        versions.remove(&meta.session);
+        if let Some((session, version)) = versions.iter().next() {
+            let (session, version) = (session.clone(), *version);
+            if ctx.buffer_session(&meta.buffile) == meta.session {
+                ctx.buffer_sessions.insert(meta.buffile.clone(), session);
+                if let Some(document) = ctx.documents.get_mut(&meta.buffile) {
+                    document.version = version;
+                }
+            }
+            return;
+        }
+    }
+    ctx.invalid_utf8_buffers.remove(&meta.buffile);
+    ctx.buffer_sessions.remove(&meta.buffile);
+    ctx.buffer_versions.remove(&meta.buffile);
+    if ctx.documents.remove(&meta.buffile).is_none() {
+        // Buffers are closed in all language servers of a session, including those that never
+        // saw them.
+        return;
+    }
+    forget_large_file(&meta.buffile, ctx);
+    ctx.disk_mtimes.remove(&meta.buffile);
+    ctx.diagnostics_last_update.remove(&meta.buffile);
+    ctx.pending_diagnostics.remove(&meta.buffile);
+    ctx.secondary_diagnostics.remove(&meta.buffile);
+    ctx.modified_lines.remove(&meta.buffile);
+    ctx.document_symbols_buffers.remove(&meta.buffile);
+    ctx.document_symbols.remove(&meta.buffile);
+    ctx.inlay_hints.remove(&meta.buffile);
+    ctx.prefetched.remove(&meta.buffile);
     let uri = Url::from_file_path(&meta.buffile).unwrap();
     let params = DidCloseTextDocumentParams {
         text_document: TextDocumentIdentifier { uri },
     };
     ctx.notify::<DidCloseTextDocument>(params);
+    session_state::schedule_store(ctx);
+}
+
+/// Asks the editor to close the documents whose buffers no longer exist, in case their `BufClose`
+/// hook did not run, for example because kak-lsp was disabled at the time.
+pub fn close_orphaned_documents(ctx: &mut Context) {
+    if ctx.documents.is_empty() {
+        return;
+    }
+    // Each session is asked about its own buffers.
+    let by_session = ctx
+        .documents
+        .keys()
+        .map(|buffile| (ctx.buffer_session(buffile).to_string(), buffile))
+        .into_group_map();
+    for (session, buffiles) in by_session {
+        let command = buffiles
+            .into_iter()
+            .map(|buffile| {
+                let buffile = editor_quote(buffile);
+                format!(
+                    "try %[ evaluate-commands -buffer {} nop ] catch %[ lsp-did-close-orphan {} ]",
+                    buffile, buffile
+                )
+            })
+            .join("\n");
+        let mut meta = ctx.meta_for_session(None);
+        meta.session = session;
+        ctx.exec(meta, command);
+    }
+}
+
+/// Closes the buffers and forgets the clients of an editor session that ended, while the language
+/// server keeps running for other sessions.
+pub fn close_session(meta: EditorMeta, ctx: &mut Context) {
+    let buffiles: Vec<String> = ctx
+        .buffer_versions
+        .iter()
+        .filter(|(_, versions)| versions.contains_key(&meta.session))
+        .map(|(buffile, _)| buffile.clone())
+        .collect();
+    for buffile in buffiles {
+        let mut meta = meta.clone();
+        meta.buffile = buffile;
+        text_document_did_close(meta, ctx);
+    }
+    ctx.clients
+        .retain(|(session, _), _| *session != meta.session);
+    if ctx.session == meta.session {
+        if let Some(session) = ctx.buffer_sessions.values().next() {
+            ctx.session = session.clone();
+        }
+    }
+}
+
+/// Stops sending a buffer that is not valid UTF-8 to the language server, since LSP requires
+/// UTF-8, and tells the user once. Requests for the buffer are ignored until it is valid again.
+pub fn reject_invalid_utf8(meta: EditorMeta, ctx: &mut Context) {
+    if ctx.documents.contains_key(&meta.buffile) {
+        text_document_did_close(meta.clone(), ctx);
+    }
+    if !ctx.invalid_utf8_buffers.insert(meta.buffile.clone()) {
+        return;
+    }
+    warn!("Not sending {} to the language server", meta.buffile);
+    let message = format!(
+        "{} is not valid UTF-8, which language servers require. Convert it to UTF-8, for example with iconv",
+        meta.buffile
+    );
+    ctx.exec(meta, format!("lsp-show-error {}", editor_quote(&message)));
 }
 
 pub fn text_document_did_save(meta: EditorMeta, ctx: &mut Context) {
+    edit_conflict::record_disk_state(&meta.buffile, ctx);
     let text = match ctx.capabilities.as_ref().unwrap().text_document_sync {
         Some(TextDocumentSyncCapability::Options(TextDocumentSyncOptions {
             save:
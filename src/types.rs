@@ -1,7 +1,8 @@
-use jsonrpc_core::{Call, Output, Params};
+use jsonrpc_core::{Call, Failure, Id, Output, Params, Version};
 use lsp_types::{DiagnosticSeverity, Position, Range, SemanticTokenModifier};
 use serde::de::{MapAccess, SeqAccess, Visitor};
 use serde::{de::Error as SerdeError, Deserialize, Deserializer, Serialize};
+use serde_json::value::RawValue;
 use serde_json::Value;
 use std::borrow::Cow;
 use std::collections::HashMap;
@@ -23,6 +24,81 @@ pub struct Config {
     pub snippet_support: bool,
     #[serde(default)]
     pub semantic_tokens: SemanticTokenConfig,
+    /// Offer to start well-known language servers found on PATH for filetypes without a language.
+    #[serde(default)]
+    pub auto_detect_servers: bool,
+    /// Kakoune faces to set when an editor session connects, keyed by face name.
+    #[serde(default)]
+    pub faces: HashMap<String, String>,
+    #[serde(default)]
+    pub large_file: LargeFileConfig,
+    #[serde(default)]
+    pub diagnostics: DiagnosticsConfig,
+    /// Check every conversion between LSP and Kakoune positions by converting back, and log
+    /// mismatches. This is for debugging kak-lsp.
+    #[serde(default)]
+    pub validate_positions: bool,
+}
+
+/// Buffers with more lines or bytes than this are in large file mode. 0 means no limit.
+#[derive(Clone, Deserialize, Debug)]
+#[serde(default)]
+pub struct LargeFileConfig {
+    pub lines: usize,
+    pub bytes: usize,
+}
+
+impl Default for LargeFileConfig {
+    fn default() -> Self {
+        LargeFileConfig {
+            lines: 100_000,
+            bytes: 10_000_000,
+        }
+    }
+}
+
+/// The least severe diagnostics shown by each display: the flags in the left margin, the
+/// underlines, the inlay diagnostics after each line and the `lsp-diagnostics` list.
+#[derive(Clone, Deserialize, Debug)]
+#[serde(default)]
+pub struct DiagnosticsConfig {
+    pub gutter: SeverityThreshold,
+    pub underline: SeverityThreshold,
+    pub inline: SeverityThreshold,
+    pub list: SeverityThreshold,
+}
+
+impl Default for DiagnosticsConfig {
+    fn default() -> Self {
+        DiagnosticsConfig {
+            gutter: SeverityThreshold::Hint,
+            underline: SeverityThreshold::Hint,
+            inline: SeverityThreshold::Hint,
+            list: SeverityThreshold::Hint,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum SeverityThreshold {
+    Error,
+    Warning,
+    Info,
+    Hint,
+}
+
+impl SeverityThreshold {
+    /// Whether diagnostics of this severity are shown. Diagnostics without severity are warnings.
+    pub fn includes(self, severity: Option<DiagnosticSeverity>) -> bool {
+        let threshold = match self {
+            SeverityThreshold::Error => DiagnosticSeverity::ERROR,
+            SeverityThreshold::Warning => DiagnosticSeverity::WARNING,
+            SeverityThreshold::Info => DiagnosticSeverity::INFORMATION,
+            SeverityThreshold::Hint => DiagnosticSeverity::HINT,
+        };
+        severity.unwrap_or(DiagnosticSeverity::WARNING) <= threshold
+    }
 }
 
 #[derive(Clone, Default, Deserialize, Debug)]
@@ -37,6 +113,12 @@ pub struct ServerConfig {
     pub session: String,
     #[serde(default)]
     pub timeout: u64,
+    /// Whether editor sessions that edit the same project share its language servers.
+    #[serde(default)]
+    pub share_servers: bool,
+    /// Set by --force-new, not configurable.
+    #[serde(skip)]
+    pub force_new: bool,
 }
 
 #[derive(Clone, Deserialize, Debug)]
@@ -49,13 +131,160 @@ pub struct LanguageConfig {
     pub args: Vec<String>,
     #[serde(default)]
     pub envs: HashMap<String, String>,
+    /// LSP language IDs of buffers by filetype, for servers that handle several languages.
+    /// Defaults to the name of the language section.
+    #[serde(default)]
+    pub language_ids: HashMap<String, String>,
     pub settings_section: Option<String>,
     pub settings: Option<Value>,
+    /// A shell command run in the project root whose JSON output is sent as initializationOptions,
+    /// instead of the settings section.
+    pub initialization_options_command: Option<String>,
     pub offset_encoding: Option<OffsetEncoding>,
+    #[serde(default)]
+    pub lazy_start: bool,
+    #[serde(default)]
+    pub forward_server_requests: Vec<String>,
+    #[serde(default)]
+    pub forward_server_notifications: Vec<String>,
+    #[serde(default)]
+    pub reinitialize_on_settings_change: bool,
+    /// Minimum time between diagnostics updates of a buffer, in milliseconds. Diagnostics that
+    /// arrive in between are merged, and the latest ones are shown once the interval is over.
+    #[serde(default)]
+    pub diagnostics_update_interval_ms: u64,
+    /// Maximum number of diagnostics shown per file, 1000 by default.
+    pub max_diagnostics_per_file: Option<usize>,
+    /// Formats buffers if the server can't.
+    pub formatter: Option<ExternalFormatter>,
+    #[serde(default)]
+    pub hover_providers: Vec<HoverProvider>,
+    /// If the hover info has at most one line, add this many lines of the definition of the
+    /// hovered symbol, and the comment above it. 0 disables it.
+    #[serde(default)]
+    pub hover_definition_lines: usize,
+    /// Rewrites the code fences in hover info by their language: an empty value removes the
+    /// fence, so its content is rendered as Markdown, others replace the language. The key ""
+    /// matches fences without a language.
+    #[serde(default)]
+    pub hover_fences: HashMap<String, String>,
+    /// Give the code blocks of hover info the buffer's filetype instead of the language the
+    /// server gives, so the hover buffer highlights them like the buffer.
+    #[serde(default)]
+    pub hover_code_as_filetype: bool,
+    #[serde(default)]
+    pub middleware: Vec<Middleware>,
+    /// Commands whose edits are shown as diff to be applied or discarded, or "*" for all.
+    #[serde(default)]
+    pub preview_edits_of_commands: Vec<String>,
+    /// Commands whose progress and log messages are shown in a fifo buffer, or "*" for all.
+    #[serde(default)]
+    pub stream_output_of_commands: Vec<String>,
+    /// Workspace edits above any of these thresholds are shown as diff and need confirmation.
+    pub confirm_edits: Option<ConfirmEdits>,
+    /// Commands like test runners whose output is parsed into diagnostics, for
+    /// `lsp-diagnostic-commands`.
+    #[serde(default)]
+    pub diagnostic_commands: Vec<DiagnosticCommand>,
+    /// A completion request that is still in flight when a newer one is sent within this many
+    /// milliseconds is cancelled, 500 by default. 0 disables it.
+    pub completion_coalesce_window_ms: Option<u64>,
+    /// Languages whose completions win over identical ones from other servers of a buffer (see
+    /// `lsp_servers`), first to last. The buffer's primary server comes next, then the others.
+    #[serde(default)]
+    pub completion_precedence: Vec<LanguageId>,
+    /// Cleanups of the Markdown documentation the server sends, before it is rendered.
+    #[serde(default)]
+    pub markdown: MarkdownOptions,
     // This does nothing, but is kept so we can still parse old configs.
     pub workaround_server_sends_plaintext_labeled_as_markdown: Option<bool>,
 }
 
+/// A command whose output is shown below the hover info, like `man` or a dictionary.
+/// The argument `{word}` is replaced by the word at the cursor.
+#[derive(Clone, Deserialize, Debug)]
+pub struct HoverProvider {
+    pub command: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// Render the output as Markdown instead of plain text.
+    #[serde(default)]
+    pub markdown: bool,
+    /// Also show the output if the language server has hover info.
+    #[serde(default)]
+    pub always: bool,
+}
+
+/// Thresholds for workspace edits, above which they are only applied once confirmed. Unset
+/// thresholds are not checked.
+#[derive(Clone, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ConfirmEdits {
+    pub files: Option<usize>,
+    pub edits: Option<usize>,
+    /// Creations, renames and deletions of files.
+    pub file_operations: Option<usize>,
+}
+
+/// A command like a test runner or linter, run in the project root. Each match of `pattern` in
+/// its output is a diagnostic. The pattern needs the named groups `file` and `line`, and may have
+/// `column`, `end_line`, `end_column`, `severity` and `message`.
+#[derive(Clone, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct DiagnosticCommand {
+    pub command: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+    pub pattern: String,
+    /// The severity of matches without a `severity` group, "error" by default.
+    pub severity: Option<String>,
+}
+
+/// Cleanups for servers whose documentation contains Markdown that renders as noise, like raw
+/// HTML or long link targets.
+#[derive(Clone, Copy, Default, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct MarkdownOptions {
+    /// Remove HTML tags and comments, keeping their text.
+    #[serde(default)]
+    pub strip_html: bool,
+    /// Number links and list their targets at the end, like footnotes.
+    #[serde(default)]
+    pub link_footnotes: bool,
+}
+
+/// A command that formats its stdin to stdout. The argument `{file}` is replaced by the path of
+/// the buffer.
+#[derive(Clone, Deserialize, Debug)]
+pub struct ExternalFormatter {
+    pub command: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+}
+
+/// A rewrite of the params sent to the language server, or the result it returns, for servers
+/// that don't follow the spec. See the `middleware` module for the path syntax.
+#[derive(Clone, Deserialize, Debug)]
+pub struct Middleware {
+    pub method: String,
+    pub target: MiddlewareTarget,
+    /// Renames the last key of each path to the given name.
+    #[serde(default)]
+    pub rename: HashMap<String, String>,
+    /// Sets each path to the given value, creating missing objects on the way.
+    #[serde(default)]
+    pub set: HashMap<String, Value>,
+    #[serde(default)]
+    pub remove: Vec<String>,
+}
+
+#[derive(Clone, Copy, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum MiddlewareTarget {
+    Params,
+    Result,
+}
+
 #[derive(Clone, Deserialize, Debug)]
 pub struct DynamicLanguageConfig {
     pub settings: Option<Value>,
@@ -121,7 +350,7 @@ pub struct SemanticTokenFace {
 
 // Editor
 
-#[derive(Clone, Debug, Default, Deserialize)]
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct EditorMeta {
     pub session: String,
     pub client: Option<String>,
@@ -134,11 +363,18 @@ pub struct EditorMeta {
     pub write_response_to_fifo: bool,
     #[serde(default)]
     pub hook: bool,
+    /// The width of the client's window, used to wrap text in info boxes. 0 if unknown.
+    #[serde(default)]
+    pub window_width: u32,
+    /// How to present several results, if the request's option or `lsp_result_presentation`
+    /// is set.
+    #[serde(default, deserialize_with = "deserialize_presentation")]
+    pub presentation: Option<Presentation>,
 }
 
 pub type EditorParams = toml::Value;
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct EditorRequest {
     #[serde(flatten)]
     pub meta: EditorMeta,
@@ -164,7 +400,7 @@ pub struct Route {
     pub root: RootPath,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct EditorCompletion {
     pub offset: u32,
 }
@@ -172,18 +408,38 @@ pub struct EditorCompletion {
 #[derive(Deserialize, Debug)]
 pub struct TextDocumentDidOpenParams {
     pub draft: String,
+    #[serde(default)]
+    pub language_id: String,
+    #[serde(default)]
+    pub secondary: bool,
 }
 
 #[derive(Deserialize, Debug)]
 pub struct TextDocumentDidChangeParams {
     pub draft: String,
+    #[serde(default)]
+    pub secondary: bool,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct TextDocumentCompletionParams {
     pub position: KakounePosition,
     pub completion: EditorCompletion,
     pub have_kakoune_feature_filtertext: bool,
+    #[serde(default)]
+    pub label_max_width: usize,
+    /// The indentwidth option, where 0 means indenting with tabs.
+    #[serde(default)]
+    pub indent_width: usize,
+    /// "replace" to replace the rest of the word after the cursor by completions that offer
+    /// both, otherwise they are inserted.
+    #[serde(default)]
+    pub insert_mode: String,
+    #[serde(default)]
+    pub secondary: bool,
+    /// The number of additional servers of the buffer that answer the request too.
+    #[serde(default)]
+    pub secondary_servers: usize,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -208,6 +464,7 @@ pub struct MainSelectionParams {
 pub struct HoverDetails {
     pub hover_fifo: Option<String>,
     pub hover_client: Option<String>,
+    pub placement: Option<InfoPlacement>,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -223,6 +480,45 @@ pub struct CodeActionsParams {
     pub selection_desc: String,
     pub perform_code_action: bool,
     pub code_action_pattern: Option<String>,
+    #[serde(default)]
+    pub modified_lines_only: bool,
+}
+
+/// Diagnostics that an additional server of a buffer sends to its primary server.
+#[derive(Clone, Deserialize, Debug)]
+pub struct SecondaryDiagnosticsParams {
+    pub language_id: LanguageId,
+    pub offset_encoding: OffsetEncoding,
+    // As JSON, since TOML has no null.
+    pub diagnostics: String,
+}
+
+/// Completions that an additional server of a buffer sends to its primary server.
+#[derive(Clone, Deserialize, Debug)]
+pub struct SecondaryCompletionParams {
+    pub language_id: LanguageId,
+    pub offset_encoding: OffsetEncoding,
+    pub position: KakounePosition,
+    // As JSON, since TOML has no null.
+    pub items: String,
+}
+
+#[derive(Clone, Deserialize, Debug)]
+pub struct DiagnosticsModifiedLinesParams {
+    pub base: Option<String>,
+}
+
+#[derive(Clone, Deserialize, Debug)]
+pub struct DiagnosticsExportParams {
+    pub path: String,
+    pub format: DiagnosticsExportFormat,
+}
+
+#[derive(Clone, Copy, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum DiagnosticsExportFormat {
+    Json,
+    Sarif,
 }
 
 #[derive(Clone, Deserialize, Debug)]
@@ -254,6 +550,8 @@ pub struct ObjectParams {
 pub struct TextDocumentRenameParams {
     pub position: KakounePosition,
     pub new_name: String,
+    #[serde(default)]
+    pub write_buffers: bool,
 }
 
 #[derive(Clone, Deserialize, Debug)]
@@ -266,6 +564,7 @@ pub struct SelectionRangePositionParams {
 
 // Language Server
 
+/// A message to the language server.
 // XXX serde(untagged) ?
 #[derive(Debug)]
 pub enum ServerMessage {
@@ -273,6 +572,29 @@ pub enum ServerMessage {
     Response(Output),
 }
 
+/// A message from the language server.
+#[derive(Debug)]
+pub enum IncomingMessage {
+    Request(Call),
+    Response(RawOutput),
+}
+
+/// A response from the language server. Results are kept as unparsed JSON, so large ones like
+/// semantic tokens are parsed only once, straight into the type the request's callback expects,
+/// and can be forwarded to the editor without a round trip through `serde_json::Value`.
+#[derive(Debug)]
+pub enum RawOutput {
+    Success(RawSuccess),
+    Failure(Failure),
+}
+
+#[derive(Debug, Serialize)]
+pub struct RawSuccess {
+    pub jsonrpc: Option<Version>,
+    pub result: Box<RawValue>,
+    pub id: Id,
+}
+
 pub trait IntoParams {
     fn into_params(self) -> Result<Params, Error>;
 }
@@ -303,7 +625,9 @@ pub struct KakounePosition {
 
 #[derive(PartialEq)]
 pub enum HoverType {
-    InfoBox,
+    InfoBox {
+        placement: Option<InfoPlacement>,
+    },
     Modal {
         modal_heading: String,
         do_after: String,
@@ -314,6 +638,54 @@ pub enum HoverType {
     },
 }
 
+/// Where to show an info box, see the `lsp_info_placement` option.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum InfoPlacement {
+    Cursor,
+    /// Above or below the start of the hovered symbol.
+    Above,
+    Below,
+    /// Unanchored, in the client named by the `docsclient` option.
+    Docsclient,
+}
+
+/// How to present several results, see the `lsp_result_presentation` option.
+#[derive(Clone, Copy, Debug, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Presentation {
+    /// In a goto buffer, one line per result.
+    Buffer,
+    /// In Kakoune's menu.
+    Menu,
+    /// In a prompt that completes the results.
+    Prompt,
+}
+
+/// Unknown presentations are ignored with a warning, so a typo in one of the options doesn't make
+/// the whole request fail to parse.
+fn deserialize_presentation<'de, D>(deserializer: D) -> Result<Option<Presentation>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let presentation = match Option::<String>::deserialize(deserializer)? {
+        Some(presentation) => presentation,
+        None => return Ok(None),
+    };
+    Ok(match presentation.as_str() {
+        "buffer" => Some(Presentation::Buffer),
+        "menu" => Some(Presentation::Menu),
+        "prompt" => Some(Presentation::Prompt),
+        _ => {
+            warn!(
+                "Ignoring unknown presentation `{}`, expected buffer, menu or prompt",
+                presentation
+            );
+            None
+        }
+    })
+}
+
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub struct KakouneRange {
     pub start: KakounePosition,
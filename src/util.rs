@@ -1,3 +1,4 @@
+use crate::editor_transport;
 use crate::types::*;
 use std::io::{stderr, stdout, Write};
 use std::os::unix::fs::DirBuilderExt;
@@ -26,6 +27,42 @@ pub fn temp_dir() -> path::PathBuf {
     path
 }
 
+/// Checks that only the current user can access the directory at `path`, so other users can
+/// neither connect to our session sockets nor plant their own.
+pub fn check_private_dir(path: &Path) -> Result<(), String> {
+    use std::os::unix::fs::MetadataExt;
+    let metadata = fs::symlink_metadata(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    if !metadata.is_dir() {
+        return Err(format!("{} is not a directory", path.display()));
+    }
+    if metadata.uid() != unsafe { libc::getuid() } {
+        return Err(format!("{} is owned by another user", path.display()));
+    }
+    if metadata.mode() & 0o077 != 0 {
+        return Err(format!(
+            "{} is accessible by other users, run `chmod 700 {}`",
+            path.display(),
+            path.display()
+        ));
+    }
+    Ok(())
+}
+
+/// Whether a Kakoune session with this name is running.
+pub fn kakoune_session_exists(session: &str) -> bool {
+    process::Command::new("kak")
+        .arg("-l")
+        .stdin(process::Stdio::null())
+        .stderr(process::Stdio::null())
+        .output()
+        .map(|output| {
+            String::from_utf8_lossy(&output.stdout)
+                .lines()
+                .any(|line| line == session)
+        })
+        .unwrap_or(false)
+}
+
 pub struct TempFifo {
     pub path: String,
 }
@@ -82,16 +119,46 @@ pub fn escape_tuple_element(s: &str) -> String {
     s.replace('\\', "\\\\").replace('|', "\\|")
 }
 
+/// The approximate maximum length of a command that sets a list option.
+/// Kakoune parses a command in full before running it, which gets slow for huge commands.
+const MAX_LIST_OPTION_COMMAND_LENGTH: usize = 64 * 1024;
+
+/// Build the commands to set a list option, like one of type "completions" or "range-specs",
+/// to `header` (e.g. the timestamp) followed by `elements`. Long lists are split into chunks;
+/// the first command sets the option and the others append to it with `set-option -add`.
+pub fn set_list_option_commands(
+    scope: &str,
+    option: &str,
+    header: &str,
+    elements: &[String],
+) -> Vec<String> {
+    let mut commands = vec![];
+    let mut command = format!("set-option {} {} {}", scope, option, header);
+    for element in elements {
+        if command.len() + element.len() >= MAX_LIST_OPTION_COMMAND_LENGTH {
+            commands.push(command);
+            command = format!("set-option -add {} {}", scope, option);
+        }
+        command.push(' ');
+        command.push_str(element);
+    }
+    commands.push(command);
+    commands
+}
+
 // Cleanup and gracefully exit
 pub fn goodbye(session: &str, code: i32) {
     if code == 0 {
         let path = temp_dir();
         let sock_path = path.join(session);
         let pid_path = path.join(format!("{}.pid", session));
-        if fs::remove_file(sock_path).is_err() {
+        // With --force-new, a newer server may have replaced our socket and pid file.
+        if editor_transport::owns_socket(&sock_path) && fs::remove_file(sock_path).is_err() {
             warn!("Failed to remove socket file");
         };
-        if pid_path.exists() && fs::remove_file(pid_path).is_err() {
+        let owns_pid_file =
+            fs::read_to_string(&pid_path).is_ok_and(|pid| pid.trim() == process::id().to_string());
+        if owns_pid_file && fs::remove_file(pid_path).is_err() {
             warn!("Failed to remove pid file");
         };
     }
@@ -113,12 +180,61 @@ pub fn filetype_to_language_id_map(config: &Config) -> HashMap<String, String> {
     filetypes
 }
 
+/// Returns the LSP language ID of a buffer of the given filetype, handled by the server of the
+/// given language section.
+pub fn document_language_id(
+    language_id: &str,
+    language: &LanguageConfig,
+    filetype: &str,
+) -> String {
+    language
+        .language_ids
+        .get(filetype)
+        .cloned()
+        .unwrap_or_else(|| language_id.to_string())
+}
+
+/// Returns the LSP lines `[start, end)` of the visible part of a window, extended by `margin`
+/// lines above and below. `window_range` is Kakoune's `%val{window_range}`. Returns None if the
+/// whole buffer is wanted, because the margin is negative or the window range is unknown.
+pub fn viewport_lines(window_range: &str, margin: Option<i32>) -> Option<(u32, u32)> {
+    let margin = margin.filter(|margin| *margin >= 0)? as u32;
+    let mut fields = window_range.split_whitespace().map(str::parse::<u32>);
+    let top = fields.next()?.ok()?;
+    let _column = fields.next()?.ok()?;
+    let height = fields.next()?.ok()?;
+    Some((top.saturating_sub(margin), top + height + margin))
+}
+
+/// Resolves the command like the shell does.
+pub fn find_command(command: &str) -> Option<path::PathBuf> {
+    if command.contains('/') {
+        let path = path::PathBuf::from(command);
+        return if path.is_file() { Some(path) } else { None };
+    }
+    env::var_os("PATH").and_then(|paths| {
+        env::split_paths(&paths)
+            .map(|dir| dir.join(command))
+            .find(|path| path.is_file())
+    })
+}
+
 pub fn read_document(filename: &str) -> io::Result<String> {
     // We can ignore invalid UTF-8 since we only use this to compute positions.  The width of
     // the replacement character is 1, which should usually be correct.
     Ok(String::from_utf8_lossy(&fs::read(filename)?).to_string())
 }
 
+/// Reads a document to send to a language server, which must be valid UTF-8.
+pub fn read_utf8_document(filename: &str) -> io::Result<String> {
+    String::from_utf8(fs::read(filename)?).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} is not valid UTF-8", filename),
+        )
+    })
+}
+
 pub fn short_file_path<'a>(target: &'a str, current_dir: &str) -> &'a str {
     Path::new(target)
         .strip_prefix(current_dir)
@@ -126,3 +242,39 @@ pub fn short_file_path<'a>(target: &'a str, current_dir: &str) -> &'a str {
         .and_then(|p| p.to_str())
         .unwrap_or(target)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_viewport_lines() {
+        assert_eq!(viewport_lines("100 0 40 80", Some(20)), Some((80, 160)));
+        assert_eq!(viewport_lines("10 0 40 80", Some(20)), Some((0, 70)));
+        assert_eq!(viewport_lines("10 0 40 80", Some(-1)), None);
+        assert_eq!(viewport_lines("10 0 40 80", None), None);
+        assert_eq!(viewport_lines("", Some(20)), None);
+    }
+
+    #[test]
+    fn test_set_list_option_commands() {
+        assert_eq!(
+            set_list_option_commands("buffer", "lsp_inlay_hints", "1", &[]),
+            vec!["set-option buffer lsp_inlay_hints 1"]
+        );
+        let element = "x".repeat(MAX_LIST_OPTION_COMMAND_LENGTH / 3);
+        let elements = vec![element.clone(); 5];
+        let commands = set_list_option_commands("buffer", "lsp_inlay_hints", "1", &elements);
+        assert_eq!(commands.len(), 3);
+        assert!(commands[0].starts_with("set-option buffer lsp_inlay_hints 1 x"));
+        assert!(commands[1].starts_with("set-option -add buffer lsp_inlay_hints x"));
+        assert!(commands
+            .iter()
+            .all(|command| command.len() < MAX_LIST_OPTION_COMMAND_LENGTH));
+        let elements_in_commands: usize = commands
+            .iter()
+            .map(|command| command.matches(&element).count())
+            .sum();
+        assert_eq!(elements_in_commands, 5);
+    }
+}
@@ -1,19 +1,38 @@
+use crate::command_output;
 use crate::context::*;
-use crate::language_features::{document_symbol, rust_analyzer};
+use crate::edit_conflict;
+use crate::edit_preview;
+use crate::language_features::code_lens::text_document_code_lens;
+use crate::language_features::{
+    document_symbol, eclipse_jdt_ls, haskell_language_server, ltex_ls, rust_analyzer,
+    yaml_language_server,
+};
+use crate::large_file::is_large_file;
+use crate::position::{get_file_contents, lsp_range_to_kakoune};
+use crate::server_cache;
 use crate::settings::*;
 use crate::text_edit::{apply_annotated_text_edits, apply_text_edits};
 use crate::types::*;
 use crate::util::*;
-use jsonrpc_core::Params;
+use itertools::Itertools;
+use jsonrpc_core::{Id, Params};
 use lsp_types::notification::*;
 use lsp_types::request::*;
 use lsp_types::*;
+use ropey::Rope;
 use serde::Deserialize;
 use serde_json::{self, Value};
+use std::collections::HashMap;
 use std::fs;
 use std::io;
 
 pub fn did_change_configuration(meta: EditorMeta, mut params: EditorParams, ctx: &mut Context) {
+    let request = EditorRequest {
+        meta: meta.clone(),
+        method: DidChangeConfiguration::METHOD.to_string(),
+        params: params.clone(),
+        ranges: None,
+    };
     let mut default_settings = toml::value::Table::new();
 
     let raw_settings = params
@@ -39,7 +58,7 @@ pub fn did_change_configuration(meta: EditorMeta, mut params: EditorParams, ctx:
         .language
         .get(&ctx.language_id)
         .and_then(|lang| lang.settings.as_ref());
-    let settings = configured_section(ctx, settings).unwrap_or_else(|| {
+    let mut settings = configured_section(ctx, settings).unwrap_or_else(|| {
         if !raw_settings.is_empty() {
             Value::Object(explode_string_table(raw_settings))
         } else {
@@ -47,25 +66,73 @@ pub fn did_change_configuration(meta: EditorMeta, mut params: EditorParams, ctx:
             configured_section(ctx, language.settings.as_ref()).unwrap_or_default()
         }
     });
+    if let Some(sections) = settings.as_object_mut() {
+        for (section, value) in sections {
+            add_client_side_settings(section, value, ctx);
+        }
+    }
 
+    // The first settings after initialization are the ones the server was initialized with.
+    let initialized_with_settings = ctx.settings_hash.is_none();
+    if !server_cache::record_settings(ctx, &settings) {
+        debug!("Settings are unchanged, not sending workspace/didChangeConfiguration");
+        return;
+    }
+    if !initialized_with_settings
+        && !ctx.did_change_configuration_support
+        && ctx.config.language[&ctx.language_id].reinitialize_on_settings_change
+    {
+        // Some servers only read their settings at initialization, so restart them.
+        let message = format!(
+            "{} language server does not support settings changes, restarting it",
+            ctx.language_id
+        );
+        info!("{}", message);
+        ctx.exec(meta, format!("echo {}", editor_quote(&message)));
+        ctx.restart_request = Some(request);
+        return;
+    }
     let params = DidChangeConfigurationParams { settings };
     ctx.notify::<DidChangeConfiguration>(params);
 }
 
-pub fn configuration(params: Params, ctx: &mut Context) -> Result<Value, jsonrpc_core::Error> {
-    let params = params.parse::<ConfigurationParams>()?;
-
-    let settings = ctx
-        .dynamic_config
+/// All settings sections, from `lsp_config` if set, or else from kak-lsp.toml.
+fn all_settings(ctx: &Context) -> Option<&Value> {
+    ctx.dynamic_config
         .language
         .get(&ctx.language_id)
-        .and_then(|cfg| cfg.settings.as_ref().cloned())
+        .and_then(|cfg| cfg.settings.as_ref())
         .or_else(|| {
             ctx.config
                 .language
                 .get(&ctx.language_id)
-                .and_then(|conf| conf.settings.as_ref().cloned())
-        });
+                .and_then(|conf| conf.settings.as_ref())
+        })
+}
+
+/// The given settings section, including settings that kak-lsp maintains on behalf of the user.
+pub fn settings_section(section: &str, ctx: &Context) -> Value {
+    let mut value = all_settings(ctx)
+        .and_then(|settings| settings.get(section))
+        .cloned()
+        .unwrap_or(Value::Null);
+    add_client_side_settings(section, &mut value, ctx);
+    value
+}
+
+/// Some servers expect the client to modify their settings, for example to add a word to a
+/// dictionary. Merge those modifications into the user's settings.
+fn add_client_side_settings(section: &str, value: &mut Value, ctx: &Context) {
+    match section {
+        "ltex" => ltex_ls::add_settings(value, ctx),
+        "yaml" => yaml_language_server::add_schemas(value, ctx),
+        _ => (),
+    }
+}
+
+pub fn configuration(params: Params, ctx: &mut Context) -> Result<Value, jsonrpc_core::Error> {
+    let params = params.parse::<ConfigurationParams>()?;
+    ctx.did_change_configuration_support = true;
 
     let items = params
         .items
@@ -80,10 +147,7 @@ pub fn configuration(params: Params, ctx: &mut Context) -> Result<Value, jsonrpc
                 // The specification isn't clear about whether you should
                 // reply with just the value or with `json!({ section: <value> })`.
                 // Tests indicate the former.
-                .map(|section| match &settings {
-                    None => Value::Null,
-                    Some(settings) => settings.get(section).unwrap_or(&Value::Null).clone(),
-                })
+                .map(|section| settings_section(section, ctx))
                 .unwrap_or(Value::Null)
         })
         .collect::<Vec<Value>>();
@@ -117,6 +181,58 @@ pub fn editor_workspace_symbol(
     ctx.exec(meta, command);
 }
 
+#[derive(Deserialize)]
+struct WorkspaceSymbolCandidatesParams {
+    query: String,
+}
+
+/// Writes the candidates of the workspace symbol prompt to the fifo its completion script waits on,
+/// one symbol matching the prompt text per line, as `<name>  <path>:<line>:<column>` with Kakoune
+/// coordinates. Paths are relative to the root.
+pub fn workspace_symbol_candidates(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
+    let params = WorkspaceSymbolCandidatesParams::deserialize(params)
+        .expect("Params should follow WorkspaceSymbolCandidatesParams structure");
+    let req_params = WorkspaceSymbolParams {
+        query: params.query,
+        partial_result_params: Default::default(),
+        work_done_progress_params: Default::default(),
+    };
+    ctx.call::<WorkspaceSymbol, _>(meta, req_params, move |ctx: &mut Context, meta, result| {
+        let mut contents: HashMap<String, Option<Rope>> = HashMap::new();
+        let candidates = result
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|symbol| {
+                let path = symbol.location.uri.to_file_path().ok()?;
+                let filename = path.to_str()?;
+                let text = contents
+                    .entry(filename.to_string())
+                    .or_insert_with(|| get_file_contents(filename, ctx))
+                    .as_ref()?;
+                let position =
+                    lsp_range_to_kakoune(&symbol.location.range, text, ctx.offset_encoding).start;
+                Some(format!(
+                    "{}  {}:{}:{}",
+                    symbol.name.replace('\n', " "),
+                    short_file_path(filename, &ctx.root_path),
+                    position.line,
+                    position.column
+                ))
+            })
+            .join("\n");
+        let mut root_meta = meta.clone();
+        root_meta.fifo = None;
+        ctx.exec(
+            root_meta,
+            format!(
+                "set-option global lsp_workspace_symbol_root {}",
+                editor_quote(&ctx.root_path)
+            ),
+        );
+        ctx.exec(meta, candidates);
+    });
+}
+
 #[derive(Deserialize)]
 struct EditorExecuteCommand {
     command: String,
@@ -129,19 +245,70 @@ pub fn execute_command(meta: EditorMeta, params: EditorParams, ctx: &mut Context
     let req_params = ExecuteCommandParams {
         command: params.command,
         // arguments is quoted to avoid parsing issues
-        arguments: serde_json::from_str(&params.arguments).unwrap(),
+        arguments: match serde_json::from_str(&params.arguments).unwrap() {
+            Value::Array(arguments) => arguments,
+            // Commands without arguments, as sent by code lenses.
+            Value::Null => vec![],
+            // Some servers (e.g. haskell-language-server) take a single object.
+            argument => vec![argument],
+        },
         work_done_progress_params: Default::default(),
     };
-    match &*req_params.command {
-        "rust-analyzer.applySourceChange" => {
-            rust_analyzer::apply_source_change(meta, req_params, ctx);
-        }
-        _ => {
-            ctx.call::<ExecuteCommand, _>(meta, req_params, move |_: &mut Context, _, _| ());
+    let handler = COMMAND_HANDLERS
+        .iter()
+        .find(|(name, _)| name.matches(&req_params.command))
+        .map_or(send_command as CommandHandler, |(_, handler)| *handler);
+    handler(meta, req_params, ctx);
+}
+
+type CommandHandler = fn(EditorMeta, ExecuteCommandParams, &mut Context);
+
+/// The names of the commands a handler is for.
+enum CommandName {
+    Is(&'static str),
+    StartsWith(&'static str),
+    Matches(fn(&str) -> bool),
+}
+
+impl CommandName {
+    fn matches(&self, command: &str) -> bool {
+        match self {
+            CommandName::Is(name) => command == *name,
+            CommandName::StartsWith(prefix) => command.starts_with(prefix),
+            CommandName::Matches(predicate) => predicate(command),
         }
     }
 }
 
+/// Server-specific handlers of commands, for commands that the client is expected to implement,
+/// or whose results need special treatment. The first match wins; all other commands are sent
+/// to the server as is.
+const COMMAND_HANDLERS: &[(CommandName, CommandHandler)] = &[
+    (
+        CommandName::Is("rust-analyzer.applySourceChange"),
+        rust_analyzer::apply_source_change,
+    ),
+    (CommandName::StartsWith("_ltex."), ltex_ls::execute_command),
+    (
+        CommandName::Matches(haskell_language_server::is_eval_command),
+        haskell_language_server::eval,
+    ),
+    (
+        CommandName::Is("java.apply.workspaceEdit"),
+        eclipse_jdt_ls::apply_workspace_edit,
+    ),
+];
+
+/// Asks the server to execute a command.
+pub fn send_command(meta: EditorMeta, params: ExecuteCommandParams, ctx: &mut Context) {
+    ctx.executing_command = Some((meta.clone(), params.command.clone()));
+    command_output::start(&meta, &params.command, ctx);
+    ctx.call::<ExecuteCommand, _>(meta, params, move |ctx: &mut Context, _, _| {
+        ctx.executing_command = None;
+        command_output::command_returned(ctx);
+    });
+}
+
 pub fn apply_document_resource_op(
     _meta: &EditorMeta,
     op: ResourceOp,
@@ -204,7 +371,63 @@ pub fn apply_document_resource_op(
 }
 
 // TODO handle version, so change is not applied if buffer is modified (and need to show a warning)
+/// Applies a workspace edit, unless it touches buffers whose files changed on disk, in which case
+/// the user is asked what to do first. With `write_buffers`, the buffers it touched are written
+/// once it is applied.
 pub fn apply_edit(
+    meta: EditorMeta,
+    edit: WorkspaceEdit,
+    write_buffers: bool,
+    ctx: &mut Context,
+) -> ApplyWorkspaceEditResponse {
+    let edit = match edit_preview::confirm_large_edit(None, meta.clone(), edit, write_buffers, ctx)
+    {
+        Some(edit) => edit,
+        None => {
+            return ApplyWorkspaceEditResponse {
+                applied: false,
+                failure_reason: Some("waiting for confirmation".to_string()),
+                failed_change: None,
+            }
+        }
+    };
+    let conflicts = edit_conflict::changed_on_disk(&edit, ctx);
+    if !conflicts.is_empty() {
+        edit_conflict::ask(None, meta, edit, conflicts, write_buffers, ctx);
+        return ApplyWorkspaceEditResponse {
+            applied: false,
+            failure_reason: Some("files changed on disk".to_string()),
+            failed_change: None,
+        };
+    }
+    apply_edit_and_write(meta, edit, write_buffers, ctx)
+}
+
+/// Applies a workspace edit that needs no more checks, and writes the open buffers it touched if
+/// `write_buffers` is set.
+pub fn apply_edit_and_write(
+    meta: EditorMeta,
+    edit: WorkspaceEdit,
+    write_buffers: bool,
+    ctx: &mut Context,
+) -> ApplyWorkspaceEditResponse {
+    let buffers = if write_buffers {
+        edit_conflict::edited_buffers(&edit, ctx)
+    } else {
+        vec![]
+    };
+    let response = apply_edit_unchecked(meta.clone(), edit, ctx);
+    if response.applied && !buffers.is_empty() {
+        let command = buffers
+            .iter()
+            .map(|buffile| format!("evaluate-commands -buffer {} write", editor_quote(buffile)))
+            .join("\n");
+        ctx.exec(meta, command);
+    }
+    response
+}
+
+pub fn apply_edit_unchecked(
     meta: EditorMeta,
     edit: WorkspaceEdit,
     ctx: &mut Context,
@@ -263,15 +486,70 @@ pub fn apply_edit_from_editor(meta: EditorMeta, params: EditorParams, ctx: &mut
     let edit = WorkspaceEdit::deserialize(serde_json::from_str::<Value>(&params.edit).unwrap())
         .expect("Failed to parse edit");
 
-    apply_edit(meta, edit, ctx);
+    apply_edit(meta, edit, false, ctx);
 }
 
-pub fn apply_edit_from_server(
-    params: Params,
-    ctx: &mut Context,
-) -> Result<Value, jsonrpc_core::Error> {
-    let params: ApplyWorkspaceEditParams = params.parse()?;
+pub fn apply_edit_from_server(id: Id, params: Params, ctx: &mut Context) {
+    let params: ApplyWorkspaceEditParams = match params.parse() {
+        Ok(params) => params,
+        Err(err) => {
+            ctx.reply(id, Err(err));
+            return;
+        }
+    };
     let meta = ctx.meta_for_session(None);
-    let response = apply_edit(meta, params.edit, ctx);
-    Ok(serde_json::to_value(response).unwrap())
+    // Replied to once the user confirms or discards the edit.
+    let edit = match edit_preview::confirm_large_edit(
+        Some(id.clone()),
+        meta.clone(),
+        params.edit,
+        false,
+        ctx,
+    ) {
+        Some(edit) => edit,
+        None => return,
+    };
+    let conflicts = edit_conflict::changed_on_disk(&edit, ctx);
+    if !conflicts.is_empty() {
+        // Replied to once the user decides what to do.
+        edit_conflict::ask(Some(id), meta, edit, conflicts, false, ctx);
+        return;
+    }
+    let response = apply_edit_unchecked(meta, edit, ctx);
+    ctx.reply(id, Ok(serde_json::to_value(response).unwrap()));
+}
+
+/// Handles the server's request to refresh a feature, which it sends when results it gave
+/// earlier became stale, for example after a configuration or dependency change. The results
+/// are dropped and requested again.
+pub fn refresh(method: &str, ctx: &mut Context) -> Result<Value, jsonrpc_core::Error> {
+    match method {
+        CodeLensRefresh::METHOD => {
+            ctx.code_lenses.clear();
+            let buffiles: Vec<_> = ctx
+                .documents
+                .iter()
+                .filter(|(_, document)| !document.secondary)
+                .map(|(buffile, document)| (buffile.clone(), document.version))
+                .collect();
+            for (buffile, version) in buffiles {
+                if !is_large_file(ctx, &buffile) {
+                    let meta = ctx.meta_for_buffer_version(None, &buffile, version);
+                    text_document_code_lens(meta, ctx);
+                }
+            }
+        }
+        // These are requested by the editor for the buffers shown in its clients.
+        SemanticTokensRefresh::METHOD => {
+            let meta = ctx.meta_for_session(None);
+            ctx.exec(meta, "lsp-refresh-in-clients semantic-tokens");
+        }
+        InlayHintRefreshRequest::METHOD => {
+            ctx.inlay_hints.clear();
+            let meta = ctx.meta_for_session(None);
+            ctx.exec(meta, "lsp-refresh-in-clients inlay-hints");
+        }
+        _ => (),
+    }
+    Ok(Value::Null)
 }